@@ -0,0 +1,105 @@
+use crate::sys::atomic::{AtomicU32, Ordering};
+use crate::sys::{wait, wake_all};
+
+/// Lets a fixed number of threads wait for each other, over and over.
+pub struct Barrier {
+    /// The number of threads per round.
+    n: u32,
+    /// Threads that have arrived in the current round.
+    arrived: AtomicU32,
+    /// Bumped when a round completes. Waiters park on this rather than on
+    /// `arrived`, which fast threads may already bump for the next round.
+    generation: AtomicU32,
+}
+
+/// Returned by [Barrier::wait].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BarrierWaitResult(bool);
+
+impl BarrierWaitResult {
+    /// Whether this thread was the leader of its round. Exactly one
+    /// thread per round is.
+    pub fn is_leader(&self) -> bool {
+        self.0
+    }
+}
+
+impl Barrier {
+    /// Create a barrier for rounds of `n` threads. A barrier for 0 threads
+    /// behaves like one for 1.
+    ///
+    /// # Panics
+    ///
+    /// If `n` doesn't fit in a `u32`.
+    pub const fn new(n: usize) -> Self {
+        assert!(n <= u32::MAX as usize, "too many threads");
+        Self {
+            n: if n == 0 { 1 } else { n as u32 },
+            arrived: AtomicU32::new(0),
+            generation: AtomicU32::new(0),
+        }
+    }
+
+    /// Wait until all `n` threads of this round have called `wait`. The
+    /// last one to arrive becomes the leader and wakes the others.
+    pub fn wait(&self) -> BarrierWaitResult {
+        // The round can't complete before we arrive, so this is ours.
+        let generation = self.generation.load(Ordering::Acquire);
+        if self.arrived.fetch_add(1, Ordering::AcqRel) + 1 == self.n {
+            // Reset before starting the next round, which threads only
+            // enter after seeing the new generation.
+            self.arrived.store(0, Ordering::Relaxed);
+            self.generation.fetch_add(1, Ordering::Release);
+            wake_all(&self.generation);
+            return BarrierWaitResult(true);
+        }
+        while self.generation.load(Ordering::Acquire) == generation {
+            wait(&self.generation, generation);
+        }
+        BarrierWaitResult(false)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::sync::atomic::AtomicUsize;
+
+    #[test]
+    fn phases_stay_in_step() {
+        const THREADS: usize = 8;
+        let rounds = if cfg!(miri) { 5 } else { 100 };
+        let barrier = Barrier::new(THREADS);
+        let phase: Vec<AtomicUsize> = (0..THREADS).map(|_| AtomicUsize::new(0)).collect();
+        let leaders = AtomicUsize::new(0);
+
+        std::thread::scope(|s| {
+            for me in 0..THREADS {
+                let (barrier, phase, leaders) = (&barrier, &phase, &leaders);
+                s.spawn(move || {
+                    for round in 1..=rounds {
+                        phase[me].store(round, Ordering::Relaxed);
+                        if barrier.wait().is_leader() {
+                            leaders.fetch_add(1, Ordering::Relaxed);
+                        }
+                        // Everyone reached this round, and nobody got past
+                        // the next barrier.
+                        for p in phase {
+                            let p = p.load(Ordering::Relaxed);
+                            assert!(p == round || p == round + 1);
+                        }
+                    }
+                });
+            }
+        });
+
+        assert_eq!(leaders.load(Ordering::Relaxed), rounds);
+    }
+
+    #[test]
+    fn single_thread_is_always_leader() {
+        let barrier = Barrier::new(1);
+        assert!(barrier.wait().is_leader());
+        assert!(barrier.wait().is_leader());
+    }
+}
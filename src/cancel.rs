@@ -0,0 +1,264 @@
+use crate::mutex::Mutex;
+use crate::semaphore::AcquireError;
+use crate::sys::atomic::{AtomicBool, AtomicU32, Ordering};
+use crate::sys::{wait, wait_until, wake_all, Instant};
+use std::fmt;
+use std::sync::Arc;
+
+/// Cancels the waits of every clone of its [CancelToken], such as on
+/// shutdown, where threads blocked on locks would otherwise hang it.
+///
+/// Only waits given a token are cancelled, through methods like
+/// [Mutex::lock_cancellable] and
+/// [Semaphore::acquire_cancellable](crate::semaphore::Semaphore::acquire_cancellable);
+/// other waiters on the same locks keep waiting. One token can be used
+/// with any number of locks.
+///
+/// ```
+/// use xlock::cancel::CancelHandle;
+/// use xlock::mutex::Mutex;
+///
+/// let shutdown = CancelHandle::new();
+/// let lock = Mutex::new(0);
+/// let held = lock.lock();
+/// let token = shutdown.token();
+/// std::thread::scope(|s| {
+///     let worker = s.spawn(|| lock.lock_cancellable(&token).is_err());
+///     shutdown.cancel();
+///     assert!(worker.join().unwrap());
+/// });
+/// drop(held);
+/// ```
+#[derive(Clone, Default)]
+pub struct CancelHandle {
+    shared: Arc<Shared>,
+}
+
+/// Lets waits on locks be cancelled by its [CancelHandle]. Cheap to clone:
+/// clones share the handle.
+#[derive(Clone)]
+pub struct CancelToken {
+    shared: Arc<Shared>,
+}
+
+/// The error of a wait given up because its [CancelToken] was cancelled.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Cancelled;
+
+impl fmt::Display for Cancelled {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("wait cancelled")
+    }
+}
+
+impl std::error::Error for Cancelled {}
+
+#[derive(Default)]
+struct Shared {
+    cancelled: AtomicBool,
+    /// Addresses of the words that waiters with a token are parked on, one
+    /// entry per waiter, for [CancelHandle::cancel] to wake.
+    parked: Mutex<Vec<usize>>,
+}
+
+impl CancelHandle {
+    /// Create a handle that hasn't been cancelled.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// A token cancelled by this handle.
+    pub fn token(&self) -> CancelToken {
+        CancelToken {
+            shared: self.shared.clone(),
+        }
+    }
+
+    /// Cancel the token: waits given it fail from now on, and those
+    /// parked already are woken. Returns once every one of them has
+    /// stopped waiting. Cancelling again does nothing.
+    pub fn cancel(&self) {
+        self.shared.cancelled.store(true, Ordering::SeqCst);
+        // A waiter registered just before cancelling may not have parked
+        // yet, so a single wake could go unseen: keep waking until every
+        // registered waiter has noticed and left.
+        loop {
+            let parked = self.shared.parked.lock();
+            if parked.is_empty() {
+                break;
+            }
+            for &word in parked.iter() {
+                wake_all(word as *const AtomicU32);
+            }
+            drop(parked);
+            std::thread::yield_now();
+        }
+    }
+
+    /// Whether the handle was cancelled.
+    pub fn is_cancelled(&self) -> bool {
+        self.shared.cancelled.load(Ordering::SeqCst)
+    }
+}
+
+impl CancelToken {
+    /// Whether the token was cancelled, so waits given it fail.
+    pub fn is_cancelled(&self) -> bool {
+        self.shared.cancelled.load(Ordering::SeqCst)
+    }
+
+    /// If `word` is `value` and the token wasn't cancelled, wait until
+    /// woken, until `deadline`, or until cancelled. Like
+    /// [wait](crate::sys::wait), this can return spuriously.
+    pub(crate) fn park(
+        &self,
+        word: &AtomicU32,
+        value: u32,
+        deadline: Option<Instant>,
+    ) -> Result<(), AcquireError> {
+        let address = word as *const AtomicU32 as usize;
+        self.shared.parked.lock().push(address);
+        // Registered before this look: a cancel either is seen here or
+        // finds us and wakes the word until we leave.
+        let parked = if self.is_cancelled() {
+            Err(AcquireError::Cancelled)
+        } else {
+            match deadline {
+                None => {
+                    wait(word, value);
+                    Ok(())
+                }
+                Some(deadline) if wait_until(word, value, deadline) => Ok(()),
+                Some(_) => Err(AcquireError::Timeout),
+            }
+        };
+        {
+            let mut registered = self.shared.parked.lock();
+            // The word stays borrowed until this is removed, so the
+            // address is never woken after the lock is gone.
+            let index = registered.iter().position(|&a| a == address);
+            registered.swap_remove(index.expect("waiter not registered"));
+        }
+        match parked {
+            Ok(()) if self.is_cancelled() => Err(AcquireError::Cancelled),
+            parked => parked,
+        }
+    }
+}
+
+impl fmt::Debug for CancelHandle {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("CancelHandle")
+            .field("cancelled", &self.is_cancelled())
+            .finish()
+    }
+}
+
+impl fmt::Debug for CancelToken {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("CancelToken")
+            .field("cancelled", &self.is_cancelled())
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::semaphore::Semaphore;
+    use std::time::Duration;
+
+    #[test]
+    fn cancel_wakes_only_waiters_with_the_token() {
+        let shutdown = CancelHandle::new();
+        let lock = Mutex::new(0);
+        let held = lock.lock();
+        std::thread::scope(|s| {
+            let cancellable: Vec<_> = (0..2)
+                .map(|_| {
+                    let token = shutdown.token();
+                    let lock = &lock;
+                    s.spawn(move || lock.lock_cancellable(&token).map(drop))
+                })
+                .collect();
+            let other = s.spawn(|| *lock.lock() += 1);
+            // Cancelling before they park fails them just the same, but
+            // give them time to.
+            std::thread::sleep(Duration::from_millis(20));
+            shutdown.cancel();
+            for waiter in cancellable {
+                assert_eq!(waiter.join().unwrap(), Err(Cancelled));
+            }
+            std::thread::sleep(Duration::from_millis(20));
+            assert!(!other.is_finished());
+            drop(held);
+        });
+        assert_eq!(*lock.lock(), 1);
+        assert!(!lock.is_locked());
+    }
+
+    #[test]
+    fn cancelled_token_fails_at_once() {
+        let shutdown = CancelHandle::new();
+        let token = shutdown.token();
+        let sem = Semaphore::new(1);
+        assert!(sem.acquire_cancellable(&token).is_ok());
+        shutdown.cancel();
+        assert!(token.is_cancelled());
+        assert_eq!(
+            sem.acquire_cancellable(&token).unwrap_err(),
+            AcquireError::Cancelled
+        );
+        assert_eq!(sem.available_permits(), 1);
+    }
+
+    #[test]
+    fn cancel_racing_a_release_passes_the_wakeup_on() {
+        let shutdown = CancelHandle::new();
+        let token = shutdown.token();
+        let sem = Semaphore::new(1);
+        let held = sem.acquire().unwrap();
+        std::thread::scope(|s| {
+            let cancelled = s.spawn(|| sem.acquire_cancellable(&token).map(drop));
+            while sem.waiters() != 1 {
+                std::thread::yield_now();
+            }
+            let other = s.spawn(|| {
+                let start = Instant::now();
+                drop(sem.acquire_timeout(Duration::from_secs(2)).unwrap());
+                start.elapsed()
+            });
+            while sem.waiters() != 2 {
+                std::thread::yield_now();
+            }
+            std::thread::sleep(Duration::from_millis(20));
+            // Cancelled, but not yet woken by the handle: the release's
+            // wakeup reaches the first waiter, which must pass it on.
+            shutdown.shared.cancelled.store(true, Ordering::SeqCst);
+            drop(held);
+            assert_eq!(cancelled.join().unwrap(), Err(AcquireError::Cancelled));
+            // Not left parked until its timeout with a permit free.
+            assert!(other.join().unwrap() < Duration::from_secs(1));
+        });
+        shutdown.cancel();
+    }
+
+    #[test]
+    fn cancelled_semaphore_waiter_leaves_count_alone() {
+        let shutdown = CancelHandle::new();
+        let sem = Semaphore::new(2);
+        let held = sem.acquire_many(2).unwrap();
+        let token = shutdown.token();
+        std::thread::scope(|s| {
+            let waiter = s.spawn(|| sem.acquire_cancellable(&token).map(drop));
+            while sem.waiters() == 0 {
+                std::thread::yield_now();
+            }
+            shutdown.cancel();
+            assert_eq!(waiter.join().unwrap(), Err(AcquireError::Cancelled));
+        });
+        assert_eq!(sem.waiters(), 0);
+        drop(held);
+        assert_eq!(sem.available_permits(), 2);
+    }
+}
@@ -0,0 +1,484 @@
+//! A bounded multi-producer, multi-consumer channel.
+//!
+//! Two semaphores count the free slots and the queued items, and a
+//! [Mutex] guards the queue itself. Senders wait on the first, receivers
+//! on the second, and the last handle of either side closes the
+//! semaphore the other side waits on.
+//!
+//! ```
+//! use xlock::channel::sync_channel;
+//!
+//! let (tx, rx) = sync_channel(4);
+//! std::thread::scope(|s| {
+//!     s.spawn(move || {
+//!         for i in 0..10 {
+//!             tx.send(i).unwrap();
+//!         }
+//!     });
+//!     assert_eq!(rx.iter().sum::<i32>(), 45);
+//! });
+//! ```
+
+use crate::mutex::Mutex;
+use crate::sem::{RawSem, MAX_PERMITS};
+use crate::semaphore::{AcquireError, TryAcquireError};
+use crate::sys::atomic::{AtomicUsize, Ordering};
+use std::collections::VecDeque;
+use std::fmt;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// Create a channel holding up to `capacity` items, returning its two
+/// halves. Both can be cloned, and each item is received once.
+///
+/// Sending waits while the channel is full and receiving while it is
+/// empty. Once every [Receiver] is gone sending fails, and once every
+/// [Sender] is gone receiving fails after the queued items are taken.
+///
+/// # Panics
+///
+/// If `capacity` is 0, since there is no rendezvous mode, or more than
+/// [Semaphore::MAX_PERMITS](crate::semaphore::Semaphore::MAX_PERMITS).
+pub fn sync_channel<T>(capacity: usize) -> (Sender<T>, Receiver<T>) {
+    assert!(capacity != 0, "channel capacity must be nonzero");
+    assert!(
+        capacity <= MAX_PERMITS as usize,
+        "channel capacity too large"
+    );
+    let chan = Arc::new(Chan {
+        slots: RawSem::new(capacity as u32),
+        items: RawSem::with_available(capacity as u32, 0),
+        queue: Mutex::new(VecDeque::with_capacity(capacity)),
+        senders: AtomicUsize::new(1),
+        receivers: AtomicUsize::new(1),
+    });
+    (
+        Sender {
+            chan: Arc::clone(&chan),
+        },
+        Receiver { chan },
+    )
+}
+
+/// The state both halves share.
+struct Chan<T> {
+    /// One access per free slot. Closed when the last receiver goes.
+    slots: RawSem,
+    /// One access per queued item. Closed when the last sender goes,
+    /// after which receivers take what is left straight from the queue.
+    items: RawSem,
+    queue: Mutex<VecDeque<T>>,
+    senders: AtomicUsize,
+    receivers: AtomicUsize,
+}
+
+/// The sending half of a [sync_channel].
+pub struct Sender<T> {
+    chan: Arc<Chan<T>>,
+}
+
+/// The receiving half of a [sync_channel].
+pub struct Receiver<T> {
+    chan: Arc<Chan<T>>,
+}
+
+/// Why a waiting send failed. Holds the item that wasn't sent.
+#[derive(Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum SendError<T> {
+    /// Every receiver is gone.
+    Disconnected(T),
+    /// No slot became free before the timeout.
+    Timeout(T),
+}
+
+/// Why a non-blocking send failed. Holds the item that wasn't sent.
+#[derive(Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum TrySendError<T> {
+    /// The channel is full; waiting could succeed.
+    Full(T),
+    /// Every receiver is gone.
+    Disconnected(T),
+}
+
+/// Why a waiting receive failed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum RecvError {
+    /// Every sender is gone and the channel is empty.
+    Disconnected,
+    /// No item arrived before the timeout.
+    Timeout,
+}
+
+/// Why a non-blocking receive failed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum TryRecvError {
+    /// The channel is empty; waiting could succeed.
+    Empty,
+    /// Every sender is gone and the channel is empty.
+    Disconnected,
+}
+
+impl<T> Chan<T> {
+    /// Queue `value` into a slot already taken.
+    fn push(&self, value: T) {
+        self.queue.lock().push_back(value);
+        // Held back since creation or by the receive that took the item
+        // before, so this can't exceed the capacity.
+        self.items.restore(1);
+    }
+
+    /// Take the item whose access was just taken from `items`, freeing
+    /// its slot. Once the senders are gone, a receiver that found `items`
+    /// closed may have drained it already, leaving nothing.
+    fn pop(&self) -> Result<T, RecvError> {
+        let value = self.queue.lock().pop_front();
+        match value {
+            Some(value) => {
+                self.slots.restore(1);
+                Ok(value)
+            }
+            None if self.items.is_closed() => Err(RecvError::Disconnected),
+            None => panic!("channel item missing"),
+        }
+    }
+
+    /// Take an item left after the senders are gone.
+    fn drain(&self) -> Option<T> {
+        self.queue.lock().pop_front()
+    }
+}
+
+impl<T> Sender<T> {
+    /// Send `value`, waiting while the channel is full. Fails, handing
+    /// `value` back, once every receiver is gone.
+    pub fn send(&self, value: T) -> Result<(), SendError<T>> {
+        match self.chan.slots.acquire() {
+            Ok(()) => {
+                self.chan.push(value);
+                Ok(())
+            }
+            Err(_) => Err(SendError::Disconnected(value)),
+        }
+    }
+
+    /// Send `value` if a slot is free right now.
+    pub fn try_send(&self, value: T) -> Result<(), TrySendError<T>> {
+        match self.chan.slots.try_acquire() {
+            Ok(()) => {
+                self.chan.push(value);
+                Ok(())
+            }
+            Err(TryAcquireError::Closed) => Err(TrySendError::Disconnected(value)),
+            Err(_) => Err(TrySendError::Full(value)),
+        }
+    }
+
+    /// Send `value`, waiting at most `timeout` for a free slot.
+    pub fn send_timeout(&self, value: T, timeout: Duration) -> Result<(), SendError<T>> {
+        match self.chan.slots.acquire_until(Instant::now() + timeout) {
+            Ok(()) => {
+                self.chan.push(value);
+                Ok(())
+            }
+            Err(AcquireError::Timeout) => Err(SendError::Timeout(value)),
+            Err(_) => Err(SendError::Disconnected(value)),
+        }
+    }
+
+    /// Whether every receiver is gone.
+    pub fn is_disconnected(&self) -> bool {
+        self.chan.slots.is_closed()
+    }
+}
+
+impl<T> Receiver<T> {
+    /// Take an item, waiting while the channel is empty. Fails once every
+    /// sender is gone and nothing is left.
+    pub fn recv(&self) -> Result<T, RecvError> {
+        match self.chan.items.acquire() {
+            Ok(()) => self.chan.pop(),
+            Err(_) => self.chan.drain().ok_or(RecvError::Disconnected),
+        }
+    }
+
+    /// Take an item if one is queued right now.
+    pub fn try_recv(&self) -> Result<T, TryRecvError> {
+        match self.chan.items.try_acquire() {
+            Ok(()) => self.chan.pop().map_err(|_| TryRecvError::Disconnected),
+            Err(TryAcquireError::Closed) => self.chan.drain().ok_or(TryRecvError::Disconnected),
+            Err(_) => Err(TryRecvError::Empty),
+        }
+    }
+
+    /// Take an item, waiting at most `timeout` for one.
+    pub fn recv_timeout(&self, timeout: Duration) -> Result<T, RecvError> {
+        match self.chan.items.acquire_until(Instant::now() + timeout) {
+            Ok(()) => self.chan.pop(),
+            Err(AcquireError::Timeout) => Err(RecvError::Timeout),
+            Err(_) => self.chan.drain().ok_or(RecvError::Disconnected),
+        }
+    }
+
+    /// Receive items until every sender is gone and the channel is empty.
+    pub fn iter(&self) -> impl Iterator<Item = T> + '_ {
+        std::iter::from_fn(|| self.recv().ok())
+    }
+
+    /// Whether every sender is gone. Items may still be queued.
+    pub fn is_disconnected(&self) -> bool {
+        self.chan.items.is_closed()
+    }
+}
+
+impl<T> Clone for Sender<T> {
+    fn clone(&self) -> Self {
+        self.chan.senders.fetch_add(1, Ordering::Relaxed);
+        Self {
+            chan: Arc::clone(&self.chan),
+        }
+    }
+}
+
+impl<T> Clone for Receiver<T> {
+    fn clone(&self) -> Self {
+        self.chan.receivers.fetch_add(1, Ordering::Relaxed);
+        Self {
+            chan: Arc::clone(&self.chan),
+        }
+    }
+}
+
+impl<T> Drop for Sender<T> {
+    fn drop(&mut self) {
+        if self.chan.senders.fetch_sub(1, Ordering::AcqRel) == 1 {
+            // Wakes every waiting receiver to drain what is left.
+            self.chan.items.close();
+        }
+    }
+}
+
+impl<T> Drop for Receiver<T> {
+    fn drop(&mut self) {
+        if self.chan.receivers.fetch_sub(1, Ordering::AcqRel) == 1 {
+            self.chan.slots.close();
+        }
+    }
+}
+
+impl<T> SendError<T> {
+    /// The item that wasn't sent.
+    pub fn into_inner(self) -> T {
+        match self {
+            SendError::Disconnected(value) | SendError::Timeout(value) => value,
+        }
+    }
+}
+
+impl<T> TrySendError<T> {
+    /// The item that wasn't sent.
+    pub fn into_inner(self) -> T {
+        match self {
+            TrySendError::Full(value) | TrySendError::Disconnected(value) => value,
+        }
+    }
+}
+
+impl<T> fmt::Debug for Sender<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Sender").finish_non_exhaustive()
+    }
+}
+
+impl<T> fmt::Debug for Receiver<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Receiver").finish_non_exhaustive()
+    }
+}
+
+/// Leaves out the item, so `T` needn't be `Debug`.
+impl<T> fmt::Debug for SendError<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SendError::Disconnected(_) => f.write_str("Disconnected(..)"),
+            SendError::Timeout(_) => f.write_str("Timeout(..)"),
+        }
+    }
+}
+
+/// Leaves out the item, so `T` needn't be `Debug`.
+impl<T> fmt::Debug for TrySendError<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TrySendError::Full(_) => f.write_str("Full(..)"),
+            TrySendError::Disconnected(_) => f.write_str("Disconnected(..)"),
+        }
+    }
+}
+
+impl<T> fmt::Display for SendError<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SendError::Disconnected(_) => f.write_str("sending on a disconnected channel"),
+            SendError::Timeout(_) => f.write_str("timed out waiting to send"),
+        }
+    }
+}
+
+impl<T> fmt::Display for TrySendError<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TrySendError::Full(_) => f.write_str("sending on a full channel"),
+            TrySendError::Disconnected(_) => f.write_str("sending on a disconnected channel"),
+        }
+    }
+}
+
+impl fmt::Display for RecvError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RecvError::Disconnected => f.write_str("receiving on a disconnected channel"),
+            RecvError::Timeout => f.write_str("timed out waiting to receive"),
+        }
+    }
+}
+
+impl fmt::Display for TryRecvError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TryRecvError::Empty => f.write_str("receiving on an empty channel"),
+            TryRecvError::Disconnected => f.write_str("receiving on a disconnected channel"),
+        }
+    }
+}
+
+impl<T> std::error::Error for SendError<T> {}
+impl<T> std::error::Error for TrySendError<T> {}
+impl std::error::Error for RecvError {}
+impl std::error::Error for TryRecvError {}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn many_producers_and_consumers() {
+        const PRODUCERS: u64 = 4;
+        let items = if cfg!(miri) { 20 } else { 2_000 };
+        let (tx, rx) = sync_channel(8);
+        let received = AtomicUsize::new(0);
+        let sum = std::thread::scope(|s| {
+            for p in 0..PRODUCERS {
+                let tx = tx.clone();
+                s.spawn(move || {
+                    for i in 0..items {
+                        tx.send(p * items + i).unwrap();
+                    }
+                });
+            }
+            drop(tx);
+            let consumers: Vec<_> = (0..4)
+                .map(|_| {
+                    let (rx, received) = (rx.clone(), &received);
+                    s.spawn(move || {
+                        rx.iter()
+                            .inspect(|_| {
+                                received.fetch_add(1, Ordering::Relaxed);
+                            })
+                            .sum::<u64>()
+                    })
+                })
+                .collect();
+            consumers
+                .into_iter()
+                .map(|c| c.join().unwrap())
+                .sum::<u64>()
+        });
+        let n = PRODUCERS * items;
+        assert_eq!(received.into_inner() as u64, n);
+        assert_eq!(sum, n * (n - 1) / 2);
+    }
+
+    #[test]
+    fn full_channel_blocks_until_recv() {
+        let (tx, rx) = sync_channel(1);
+        tx.send(1).unwrap();
+        assert_eq!(tx.try_send(2), Err(TrySendError::Full(2)));
+        assert_eq!(
+            tx.send_timeout(2, Duration::from_millis(10))
+                .unwrap_err()
+                .into_inner(),
+            2
+        );
+        std::thread::scope(|s| {
+            let sender = s.spawn(|| tx.send(2).unwrap());
+            std::thread::sleep(Duration::from_millis(20));
+            assert!(!sender.is_finished());
+            assert_eq!(rx.recv(), Ok(1));
+        });
+        assert_eq!(rx.try_recv(), Ok(2));
+        assert_eq!(rx.try_recv(), Err(TryRecvError::Empty));
+        assert_eq!(
+            rx.recv_timeout(Duration::from_millis(10)),
+            Err(RecvError::Timeout)
+        );
+    }
+
+    #[test]
+    fn dropping_senders_disconnects_after_draining() {
+        let (tx, rx) = sync_channel(4);
+        tx.send(1).unwrap();
+        let tx2 = tx.clone();
+        drop(tx);
+        tx2.send(2).unwrap();
+        std::thread::scope(|s| {
+            let rx = &rx;
+            assert_eq!(rx.recv(), Ok(1));
+            assert_eq!(rx.recv(), Ok(2));
+            let receiver = s.spawn(move || rx.recv());
+            std::thread::sleep(Duration::from_millis(20));
+            assert!(!receiver.is_finished());
+            drop(tx2);
+            assert_eq!(receiver.join().unwrap(), Err(RecvError::Disconnected));
+        });
+        assert!(rx.is_disconnected());
+        assert_eq!(rx.try_recv(), Err(TryRecvError::Disconnected));
+    }
+
+    #[test]
+    fn drain_racing_a_taken_item_disconnects() {
+        let (tx, rx) = sync_channel(4);
+        tx.send(1).unwrap();
+        // A receiver has taken the item's access but not the item yet
+        // when the last sender goes.
+        assert!(rx.chan.items.try_acquire().is_ok());
+        drop(tx);
+        // Another receiver finds `items` closed and drains the item.
+        assert_eq!(rx.recv(), Ok(1));
+        assert_eq!(rx.chan.pop(), Err(RecvError::Disconnected));
+    }
+
+    #[test]
+    fn dropping_receivers_disconnects_senders() {
+        let (tx, rx) = sync_channel(1);
+        tx.send(1).unwrap();
+        std::thread::scope(|s| {
+            let sender = s.spawn(|| tx.send(2));
+            std::thread::sleep(Duration::from_millis(20));
+            assert!(!sender.is_finished());
+            drop(rx);
+            assert_eq!(sender.join().unwrap(), Err(SendError::Disconnected(2)));
+        });
+        assert!(tx.is_disconnected());
+        assert_eq!(tx.try_send(3), Err(TrySendError::Disconnected(3)));
+    }
+
+    #[test]
+    #[should_panic = "capacity must be nonzero"]
+    fn zero_capacity() {
+        let _ = sync_channel::<()>(0);
+    }
+}
@@ -0,0 +1,235 @@
+use crate::mutex::MutexGuard;
+use crate::sys::atomic::{AtomicU32, Ordering};
+use crate::sys::{wait, wait_until, wake_all, wake_one};
+use std::time::{Duration, Instant};
+
+/// A condition variable for waiting on a [Mutex](crate::mutex::Mutex)
+/// until some condition on its value holds.
+///
+/// Wakeups may be spurious, so always re-check the condition after
+/// [Condvar::wait] returns. Using one condvar with several mutexes is
+/// allowed; a notification then wakes waiters of all of them.
+pub struct Condvar {
+    /// Bumped by every notification. Waiters park on it.
+    counter: AtomicU32,
+}
+
+/// Whether a timed wait on a [Condvar] returned because of its timeout.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct WaitTimeoutResult(bool);
+
+impl WaitTimeoutResult {
+    /// Whether the wait ran out of time.
+    pub fn timed_out(&self) -> bool {
+        self.0
+    }
+}
+
+impl Condvar {
+    /// Create a new Condvar.
+    pub const fn new() -> Self {
+        Self {
+            counter: AtomicU32::new(0),
+        }
+    }
+
+    /// Unlock the mutex, wait for a notification and lock it again.
+    ///
+    /// Unlocking and starting to wait happen atomically with respect to
+    /// notifications: one sent after the guard was taken away can't be
+    /// missed. May also return without any notification.
+    pub fn wait<'a, T: ?Sized>(&self, guard: MutexGuard<'a, T>) -> MutexGuard<'a, T> {
+        // Read the counter before unlocking, so a notify after any change
+        // made under the lock always moves it before we park.
+        let counter = self.counter.load(Ordering::Relaxed);
+        let mutex = guard.mutex();
+        drop(guard);
+        wait(&self.counter, counter);
+        mutex.lock()
+    }
+
+    /// Like [Condvar::wait], but give up after `timeout`. The mutex is
+    /// locked again before returning either way.
+    pub fn wait_timeout<'a, T: ?Sized>(
+        &self,
+        guard: MutexGuard<'a, T>,
+        timeout: Duration,
+    ) -> (MutexGuard<'a, T>, WaitTimeoutResult) {
+        self.wait_deadline(guard, Instant::now() + timeout)
+    }
+
+    /// Wait for as long as `condition` holds, re-checking it after every
+    /// wakeup. Returns with the mutex locked and `condition` false.
+    pub fn wait_while<'a, T: ?Sized, F>(
+        &self,
+        mut guard: MutexGuard<'a, T>,
+        mut condition: F,
+    ) -> MutexGuard<'a, T>
+    where
+        F: FnMut(&mut T) -> bool,
+    {
+        while condition(&mut guard) {
+            guard = self.wait(guard);
+        }
+        guard
+    }
+
+    /// Like [Condvar::wait_while], but give up after `timeout` in total,
+    /// however many wakeups happen in between. Returns whether it timed
+    /// out with `condition` still holding.
+    pub fn wait_timeout_while<'a, T: ?Sized, F>(
+        &self,
+        mut guard: MutexGuard<'a, T>,
+        timeout: Duration,
+        mut condition: F,
+    ) -> (MutexGuard<'a, T>, WaitTimeoutResult)
+    where
+        F: FnMut(&mut T) -> bool,
+    {
+        let deadline = Instant::now() + timeout;
+        loop {
+            if !condition(&mut guard) {
+                return (guard, WaitTimeoutResult(false));
+            }
+            if Instant::now() >= deadline {
+                return (guard, WaitTimeoutResult(true));
+            }
+            guard = self.wait_deadline(guard, deadline).0;
+        }
+    }
+
+    fn wait_deadline<'a, T: ?Sized>(
+        &self,
+        guard: MutexGuard<'a, T>,
+        deadline: Instant,
+    ) -> (MutexGuard<'a, T>, WaitTimeoutResult) {
+        let counter = self.counter.load(Ordering::Relaxed);
+        let mutex = guard.mutex();
+        drop(guard);
+        // This returns early on a notification or spuriously, and only
+        // reports a deadline that had already passed; check the clock.
+        wait_until(&self.counter, counter, deadline);
+        let timed_out = Instant::now() >= deadline;
+        (mutex.lock(), WaitTimeoutResult(timed_out))
+    }
+
+    /// Wake one waiting thread.
+    pub fn notify_one(&self) {
+        self.counter.fetch_add(1, Ordering::Relaxed);
+        wake_one(&self.counter);
+    }
+
+    /// Wake every waiting thread.
+    pub fn notify_all(&self) {
+        self.counter.fetch_add(1, Ordering::Relaxed);
+        wake_all(&self.counter);
+    }
+}
+
+impl Default for Condvar {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::mutex::Mutex;
+    use std::collections::VecDeque;
+
+    #[test]
+    fn work_queue() {
+        let queue = Mutex::new((VecDeque::new(), false));
+        let nonempty = Condvar::new();
+        let consumed = AtomicU32::new(0);
+        let items = if cfg!(miri) { 10 } else { 1_000 };
+
+        std::thread::scope(|s| {
+            for _ in 0..4 {
+                s.spawn(|| loop {
+                    let mut guard = queue.lock();
+                    let item = loop {
+                        if let Some(item) = guard.0.pop_front() {
+                            break item;
+                        }
+                        if guard.1 {
+                            return;
+                        }
+                        guard = nonempty.wait(guard);
+                    };
+                    drop(guard);
+                    std::hint::black_box(item);
+                    consumed.fetch_add(1, Ordering::Relaxed);
+                });
+            }
+
+            for i in 0..items {
+                queue.lock().0.push_back(i);
+                nonempty.notify_one();
+            }
+            queue.lock().1 = true;
+            nonempty.notify_all();
+        });
+
+        assert_eq!(consumed.load(Ordering::Relaxed), items);
+    }
+
+    #[test]
+    fn wait_timeout_while_never_true() {
+        let m = Mutex::new(false);
+        let cv = Condvar::new();
+        let start = Instant::now();
+        let (guard, result) =
+            cv.wait_timeout_while(m.lock(), Duration::from_millis(50), |ready| !*ready);
+        assert!(result.timed_out());
+        assert!(!*guard);
+        assert!(start.elapsed() >= Duration::from_millis(50));
+    }
+
+    #[test]
+    fn wait_timeout_while_true_before_deadline() {
+        let m = Mutex::new(0);
+        let cv = Condvar::new();
+        std::thread::scope(|s| {
+            s.spawn(|| {
+                // Wake the waiter early a few times for nothing, then
+                // make the condition true well before the deadline.
+                for value in 1..=3 {
+                    std::thread::sleep(Duration::from_millis(10));
+                    *m.lock() = value;
+                    cv.notify_all();
+                }
+            });
+            let (guard, result) =
+                cv.wait_timeout_while(m.lock(), Duration::from_secs(10), |v| *v < 3);
+            assert!(!result.timed_out());
+            assert_eq!(*guard, 3);
+        });
+    }
+
+    #[test]
+    fn wait_while_rechecks_condition() {
+        let m = Mutex::new(0);
+        let cv = Condvar::new();
+        std::thread::scope(|s| {
+            s.spawn(|| {
+                for _ in 0..5 {
+                    *m.lock() += 1;
+                    cv.notify_one();
+                }
+            });
+            let guard = cv.wait_while(m.lock(), |v| *v < 5);
+            assert_eq!(*guard, 5);
+        });
+    }
+
+    #[test]
+    fn wait_timeout_relocks() {
+        let m = Mutex::new(());
+        let cv = Condvar::new();
+        let (_guard, result) = cv.wait_timeout(m.lock(), Duration::from_millis(10));
+        assert!(result.timed_out());
+        assert!(m.try_lock().is_none());
+    }
+}
@@ -0,0 +1,181 @@
+use crate::mutex::Mutex;
+use crate::semaphore::{AcquireError, Semaphore, SemaphorePermit, TryAcquireError};
+use std::collections::VecDeque;
+use std::fmt;
+use std::time::{Duration, Instant};
+
+/// A semaphore whose permits only become available again a fixed
+/// cooldown after they are released, such as to respect a per-resource
+/// rate limit of an external API.
+///
+/// Unlike a [RateLimiter](crate::ratelimit::RateLimiter), which spaces out
+/// acquisitions, this bounds how many are in use and keeps each slot idle
+/// for the cooldown after every use. Cooled-down slots are freed lazily,
+/// by later acquisitions; one waiting for a permit wakes up as the next
+/// slot finishes cooling down.
+///
+/// ```
+/// use std::time::Duration;
+/// use xlock::cooldown::CooldownSemaphore;
+///
+/// let sem = CooldownSemaphore::new(1, Duration::from_millis(10));
+/// drop(sem.acquire().unwrap());
+/// assert!(sem.try_acquire().is_err());
+/// // Waits out the cooldown.
+/// let _permit = sem.acquire().unwrap();
+/// ```
+pub struct CooldownSemaphore {
+    sem: Semaphore,
+    cooldown: Duration,
+    /// When each released slot finishes cooling down, earliest first.
+    cooling: Mutex<VecDeque<Instant>>,
+}
+
+/// A permit from a [CooldownSemaphore]. Dropping it starts its slot's
+/// cooldown.
+#[must_use = "the permit is released as soon as it is dropped"]
+pub struct CooldownPermit<'a> {
+    sem: &'a CooldownSemaphore,
+    /// Always `Some` until dropped.
+    permit: Option<SemaphorePermit<'a>>,
+}
+
+impl CooldownSemaphore {
+    /// Create a semaphore handing out at most `permits` at a time, each
+    /// slot unavailable for `cooldown` after its permit is released.
+    ///
+    /// # Panics
+    ///
+    /// If `permits` is more than [Semaphore::MAX_PERMITS].
+    pub const fn new(permits: u32, cooldown: Duration) -> Self {
+        Self {
+            sem: Semaphore::new(permits),
+            cooldown,
+            cooling: Mutex::new(VecDeque::new()),
+        }
+    }
+
+    /// Take a permit, waiting until one is available and cooled down.
+    /// Fails once the semaphore is closed.
+    pub fn acquire(&self) -> Result<CooldownPermit<'_>, AcquireError> {
+        loop {
+            let permit = match self.cool_down() {
+                // Look again once the next slot has cooled down.
+                Some(ready) => match self.sem.acquire_or_recheck(ready) {
+                    Err(AcquireError::Timeout) => continue,
+                    permit => permit?,
+                },
+                None => self.sem.acquire()?,
+            };
+            return Ok(self.wrap(permit));
+        }
+    }
+
+    /// Take a permit only if one is available and cooled down right now.
+    pub fn try_acquire(&self) -> Result<CooldownPermit<'_>, TryAcquireError> {
+        self.cool_down();
+        let permit = self.sem.try_acquire()?;
+        Ok(self.wrap(permit))
+    }
+
+    /// How long a slot stays unavailable after its permit is released.
+    pub fn cooldown(&self) -> Duration {
+        self.cooldown
+    }
+
+    /// Permits free right now, not counting slots still cooling down.
+    /// Only a snapshot.
+    pub fn available_permits(&self) -> u32 {
+        self.cool_down();
+        self.sem.available_permits()
+    }
+
+    /// Close the semaphore: every waiter wakes up with
+    /// [AcquireError::Closed], and so do all later acquisitions.
+    pub fn close(&self) {
+        self.sem.close();
+    }
+
+    fn wrap<'a>(&'a self, permit: SemaphorePermit<'a>) -> CooldownPermit<'a> {
+        CooldownPermit {
+            sem: self,
+            permit: Some(permit),
+        }
+    }
+
+    /// Free the slots that have cooled down, returning when the next of
+    /// the others will.
+    fn cool_down(&self) -> Option<Instant> {
+        let now = Instant::now();
+        let mut cooling = self.cooling.lock();
+        let mut ready = 0;
+        while cooling.front().is_some_and(|&at| at <= now) {
+            cooling.pop_front();
+            ready += 1;
+        }
+        let next = cooling.front().copied();
+        drop(cooling);
+        // Each cooled-down slot was consumed by a dropped permit.
+        self.sem.release(ready as u32);
+        next
+    }
+}
+
+impl Drop for CooldownPermit<'_> {
+    fn drop(&mut self) {
+        let permit = self.permit.take().expect("permit dropped");
+        let mut cooling = self.sem.cooling.lock();
+        // Taken under the lock, so the queue stays in order.
+        cooling.push_back(Instant::now() + self.sem.cooldown);
+        permit.consume();
+    }
+}
+
+impl fmt::Debug for CooldownSemaphore {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("CooldownSemaphore")
+            .field("cooldown", &self.cooldown)
+            .field("semaphore", &self.sem)
+            .finish_non_exhaustive()
+    }
+}
+
+impl fmt::Debug for CooldownPermit<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("CooldownPermit").finish_non_exhaustive()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn released_permits_wait_out_the_cooldown() {
+        let cooldown = Duration::from_millis(50);
+        let sem = CooldownSemaphore::new(2, cooldown);
+        let permits = (sem.acquire().unwrap(), sem.acquire().unwrap());
+        let released = Instant::now();
+        drop(permits);
+        assert_eq!(sem.available_permits(), 0);
+        assert!(sem.try_acquire().is_err());
+        let a = sem.acquire().unwrap();
+        let b = sem.acquire().unwrap();
+        assert!(released.elapsed() >= cooldown);
+        drop((a, b));
+        std::thread::sleep(cooldown);
+        assert_eq!(sem.available_permits(), 2);
+    }
+
+    #[test]
+    fn close_fails_a_waiter() {
+        let sem = CooldownSemaphore::new(1, Duration::from_secs(60));
+        drop(sem.acquire().unwrap());
+        std::thread::scope(|s| {
+            let waiter = s.spawn(|| sem.acquire().map(drop));
+            std::thread::sleep(Duration::from_millis(20));
+            sem.close();
+            assert_eq!(waiter.join().unwrap(), Err(AcquireError::Closed));
+        });
+    }
+}
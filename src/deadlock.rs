@@ -0,0 +1,325 @@
+//! Deadlock detection, with the `deadlock_detection` feature.
+//!
+//! While the feature is on, every [Mutex](crate::mutex::Mutex) and
+//! [RwLock](crate::rwlock::RwLock) records who holds it and who is blocked
+//! on it in a global registry. [check_deadlock] walks the resulting
+//! wait-for graph and reports the threads that can never wake up again.
+//! Without the feature none of this is compiled in.
+//!
+//! A watchdog thread can check periodically:
+//!
+//! ```no_run
+//! use std::time::Duration;
+//!
+//! std::thread::spawn(|| loop {
+//!     std::thread::sleep(Duration::from_secs(10));
+//!     for (i, cycle) in xlock::deadlock::check_deadlock().iter().enumerate() {
+//!         eprintln!("deadlock #{i}:");
+//!         for thread in cycle {
+//!             eprintln!("{:?} blocked at\n{}", thread.thread_id(), thread.backtrace());
+//!         }
+//!     }
+//! });
+//! ```
+
+use std::backtrace::Backtrace;
+use std::sync::{Arc, Mutex};
+use std::thread::ThreadId;
+
+/// A thread caught in a deadlock, as reported by [check_deadlock].
+#[derive(Debug, Clone)]
+pub struct DeadlockedThread {
+    thread_id: ThreadId,
+    lock: usize,
+    backtrace: Arc<Backtrace>,
+}
+
+impl DeadlockedThread {
+    /// The deadlocked thread.
+    pub fn thread_id(&self) -> ThreadId {
+        self.thread_id
+    }
+
+    /// The address of the lock the thread is blocked on.
+    pub fn lock_address(&self) -> usize {
+        self.lock
+    }
+
+    /// Where the thread blocked. Only captured when backtraces are
+    /// enabled, e.g. with `RUST_BACKTRACE=1`.
+    pub fn backtrace(&self) -> &Backtrace {
+        &self.backtrace
+    }
+}
+
+struct Registry {
+    /// Locks held, by address, and the thread that took each.
+    holds: Vec<(usize, ThreadId)>,
+    waits: Vec<Waiter>,
+}
+
+struct Waiter {
+    thread: ThreadId,
+    lock: usize,
+    /// Set for an upgrader waiting on the other readers of a lock it holds
+    /// itself.
+    ignore_own: bool,
+    backtrace: Arc<Backtrace>,
+}
+
+static REGISTRY: Mutex<Registry> = Mutex::new(Registry {
+    holds: Vec::new(),
+    waits: Vec::new(),
+});
+
+fn registry() -> std::sync::MutexGuard<'static, Registry> {
+    REGISTRY.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+/// Record that the current thread took the lock at `lock`.
+pub(crate) fn acquired(lock: usize) {
+    let thread = std::thread::current().id();
+    registry().holds.push((lock, thread));
+}
+
+/// Record that a hold on the lock at `lock` was given up, preferably the
+/// current thread's: a guard may have been sent elsewhere.
+pub(crate) fn released(lock: usize) {
+    let thread = std::thread::current().id();
+    let mut registry = registry();
+    let holds = &mut registry.holds;
+    let i = holds
+        .iter()
+        .position(|&h| h == (lock, thread))
+        .or_else(|| holds.iter().position(|&(l, _)| l == lock));
+    if let Some(i) = i {
+        holds.swap_remove(i);
+    }
+}
+
+/// A hold on a lock, released from the registry when dropped.
+pub(crate) struct Held(usize);
+
+/// Record the current thread as holding the lock at `lock` for as long as
+/// the returned value lives.
+pub(crate) fn hold(lock: usize) -> Held {
+    acquired(lock);
+    Held(lock)
+}
+
+impl Drop for Held {
+    fn drop(&mut self) {
+        released(self.0);
+    }
+}
+
+/// The current thread blocking on a lock, cleared when dropped.
+pub(crate) struct Waiting(());
+
+/// Record the current thread as blocked on the lock at `lock` until the
+/// returned value is dropped. With `ignore_own`, the thread's own hold on
+/// the lock doesn't count as blocking it.
+pub(crate) fn wait_for(lock: usize, ignore_own: bool) -> Waiting {
+    let waiter = Waiter {
+        thread: std::thread::current().id(),
+        lock,
+        ignore_own,
+        backtrace: Arc::new(Backtrace::capture()),
+    };
+    registry().waits.push(waiter);
+    Waiting(())
+}
+
+impl Drop for Waiting {
+    fn drop(&mut self) {
+        let thread = std::thread::current().id();
+        registry().waits.retain(|w| w.thread != thread);
+    }
+}
+
+/// Find the threads that are deadlocked right now, grouped by cycle: the
+/// threads of a group each wait for a lock held by another in the group.
+/// Only locks from this crate are seen.
+pub fn check_deadlock() -> Vec<Vec<DeadlockedThread>> {
+    let registry = registry();
+    let waits = &registry.waits;
+    // Blocked threads are the nodes. A thread points at every blocked
+    // thread holding the lock it waits for; threads that aren't blocked
+    // will release their locks eventually and can't be part of a cycle.
+    let edges: Vec<Vec<usize>> = waits
+        .iter()
+        .map(|w| {
+            registry
+                .holds
+                .iter()
+                .filter(|&&(lock, holder)| lock == w.lock && !(w.ignore_own && holder == w.thread))
+                .filter_map(|&(_, holder)| waits.iter().position(|o| o.thread == holder))
+                .collect()
+        })
+        .collect();
+
+    strongly_connected(&edges)
+        .into_iter()
+        .filter(|scc| scc.len() > 1 || edges[scc[0]].contains(&scc[0]))
+        .map(|scc| {
+            scc.into_iter()
+                .map(|i| DeadlockedThread {
+                    thread_id: waits[i].thread,
+                    lock: waits[i].lock,
+                    backtrace: waits[i].backtrace.clone(),
+                })
+                .collect()
+        })
+        .collect()
+}
+
+/// Tarjan's algorithm: the strongly connected components of a graph
+/// given as adjacency lists.
+fn strongly_connected(edges: &[Vec<usize>]) -> Vec<Vec<usize>> {
+    struct State<'a> {
+        edges: &'a [Vec<usize>],
+        index: Vec<Option<usize>>,
+        low: Vec<usize>,
+        on_stack: Vec<bool>,
+        stack: Vec<usize>,
+        next: usize,
+        sccs: Vec<Vec<usize>>,
+    }
+
+    fn visit(st: &mut State<'_>, v: usize) {
+        st.index[v] = Some(st.next);
+        st.low[v] = st.next;
+        st.next += 1;
+        st.stack.push(v);
+        st.on_stack[v] = true;
+        for &w in &st.edges[v] {
+            match st.index[w] {
+                None => {
+                    visit(st, w);
+                    st.low[v] = st.low[v].min(st.low[w]);
+                }
+                Some(i) if st.on_stack[w] => st.low[v] = st.low[v].min(i),
+                Some(_) => {}
+            }
+        }
+        if Some(st.low[v]) == st.index[v] {
+            let mut scc = Vec::new();
+            loop {
+                let w = st.stack.pop().unwrap();
+                st.on_stack[w] = false;
+                scc.push(w);
+                if w == v {
+                    break;
+                }
+            }
+            st.sccs.push(scc);
+        }
+    }
+
+    let n = edges.len();
+    let mut st = State {
+        edges,
+        index: vec![None; n],
+        low: vec![0; n],
+        on_stack: vec![false; n],
+        stack: Vec::new(),
+        next: 0,
+        sccs: Vec::new(),
+    };
+    for v in 0..n {
+        if st.index[v].is_none() {
+            visit(&mut st, v);
+        }
+    }
+    st.sccs
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::mutex::Mutex;
+    use crate::rwlock::RwLock;
+    use std::sync::Barrier;
+    use std::time::{Duration, Instant};
+
+    /// Wait for the checker to report a cycle involving `threads`.
+    fn find_cycle(threads: &[ThreadId]) -> Vec<Vec<DeadlockedThread>> {
+        let deadline = Instant::now() + Duration::from_secs(10);
+        loop {
+            let cycles: Vec<_> = check_deadlock()
+                .into_iter()
+                .filter(|c| c.iter().any(|t| threads.contains(&t.thread_id())))
+                .collect();
+            if !cycles.is_empty() || Instant::now() > deadline {
+                return cycles;
+            }
+            std::thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    #[test]
+    fn no_false_positives() {
+        let m = Mutex::new(0);
+        std::thread::scope(|s| {
+            for _ in 0..4 {
+                s.spawn(|| {
+                    for _ in 0..100 {
+                        *m.lock() += 1;
+                        let me = std::thread::current().id();
+                        assert!(check_deadlock()
+                            .iter()
+                            .all(|c| c.iter().all(|t| t.thread_id() != me)));
+                    }
+                });
+            }
+        });
+    }
+
+    // The deadlocked threads are leaked, which miri reports as an error.
+    #[cfg_attr(miri, ignore)]
+    #[test]
+    fn reports_two_mutex_cycle() {
+        let a: &'static Mutex<()> = Box::leak(Box::new(Mutex::new(())));
+        let b: &'static Mutex<()> = Box::leak(Box::new(Mutex::new(())));
+        let barrier: &'static Barrier = Box::leak(Box::new(Barrier::new(2)));
+        let t1 = std::thread::spawn(move || {
+            let _a = a.lock();
+            barrier.wait();
+            let _b = b.lock();
+        });
+        let t2 = std::thread::spawn(move || {
+            let _b = b.lock();
+            barrier.wait();
+            let _a = a.lock();
+        });
+        let threads = [t1.thread().id(), t2.thread().id()];
+
+        let cycles = find_cycle(&threads);
+        assert_eq!(cycles.len(), 1);
+        let mut found: Vec<_> = cycles[0].iter().map(|t| t.thread_id()).collect();
+        found.sort_by_key(|id| threads.iter().position(|t| t == id));
+        assert_eq!(found, threads);
+    }
+
+    #[cfg_attr(miri, ignore)]
+    #[test]
+    fn reports_rwlock_cycle() {
+        let lock: &'static RwLock<()> = Box::leak(Box::new(RwLock::new(())));
+        let m: &'static Mutex<()> = Box::leak(Box::new(Mutex::new(())));
+        let barrier: &'static Barrier = Box::leak(Box::new(Barrier::new(2)));
+        let t1 = std::thread::spawn(move || {
+            let _read = lock.read();
+            barrier.wait();
+            let _m = m.lock();
+        });
+        let t2 = std::thread::spawn(move || {
+            let _m = m.lock();
+            barrier.wait();
+            let _write = lock.write();
+        });
+        let threads = [t1.thread().id(), t2.thread().id()];
+        let cycles = find_cycle(&threads);
+        assert_eq!(cycles.len(), 1);
+        assert_eq!(cycles[0].len(), 2);
+    }
+}
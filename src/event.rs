@@ -0,0 +1,205 @@
+use crate::sys::atomic::{AtomicU32, Ordering};
+use crate::sys::{wait, wait_until, wake_all, wake_one};
+use std::time::{Duration, Instant};
+
+/// Set in `state` while the event is set.
+const SET: u32 = 1;
+/// Added to `state` by every [Event::set] of a manual-reset event.
+const GENERATION: u32 = 2;
+
+/// How an [Event] behaves once set.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EventMode {
+    /// Stays set, releasing every waiter, until [Event::reset].
+    ManualReset,
+    /// Releases a single waiter, which resets it.
+    AutoReset,
+}
+
+/// A gate threads wait on until another thread sets it.
+pub struct Event {
+    mode: EventMode,
+    /// [SET], plus for a manual-reset event a count of sets in the
+    /// higher bits.
+    state: AtomicU32,
+}
+
+impl Event {
+    /// Create a new event that isn't set.
+    pub const fn new(mode: EventMode) -> Self {
+        Self {
+            mode,
+            state: AtomicU32::new(0),
+        }
+    }
+
+    /// Set the event. A manual-reset event releases all current and
+    /// future waiters until reset; an auto-reset event releases exactly
+    /// one, now or whenever one comes along.
+    pub fn set(&self) {
+        match self.mode {
+            EventMode::ManualReset => {
+                let mut s = self.state.load(Ordering::Relaxed);
+                loop {
+                    if s & SET != 0 {
+                        return;
+                    }
+                    match self.state.compare_exchange_weak(
+                        s,
+                        s.wrapping_add(GENERATION) | SET,
+                        Ordering::Release,
+                        Ordering::Relaxed,
+                    ) {
+                        Ok(_) => break,
+                        Err(e) => s = e,
+                    }
+                }
+                wake_all(&self.state);
+            }
+            EventMode::AutoReset => {
+                if self.state.fetch_or(SET, Ordering::Release) & SET == 0 {
+                    wake_one(&self.state);
+                }
+            }
+        }
+    }
+
+    /// Reset the event, so new waiters wait again.
+    ///
+    /// A manual-reset event is level-triggered at wake time: threads that
+    /// were waiting when it was set return from [Event::wait] even if it
+    /// is reset before they get to run.
+    pub fn reset(&self) {
+        self.state.fetch_and(!SET, Ordering::Relaxed);
+    }
+
+    /// Whether the event is set right now.
+    pub fn is_set(&self) -> bool {
+        self.state.load(Ordering::Relaxed) & SET != 0
+    }
+
+    /// Wait until the event is set.
+    pub fn wait(&self) {
+        self.wait_inner(None);
+    }
+
+    /// Wait until the event is set, giving up after `timeout`. Returns
+    /// whether it was set.
+    pub fn wait_timeout(&self, timeout: Duration) -> bool {
+        self.wait_inner(Some(Instant::now() + timeout))
+    }
+
+    fn wait_inner(&self, deadline: Option<Instant>) -> bool {
+        let mut s = self.state.load(Ordering::Acquire);
+        loop {
+            if s & SET != 0 {
+                match self.mode {
+                    EventMode::ManualReset => return true,
+                    EventMode::AutoReset => match self.state.compare_exchange(
+                        s,
+                        s & !SET,
+                        Ordering::Acquire,
+                        Ordering::Acquire,
+                    ) {
+                        Ok(_) => return true,
+                        Err(e) => {
+                            s = e;
+                            continue;
+                        }
+                    },
+                }
+            }
+            match deadline {
+                None => wait(&self.state, s),
+                Some(deadline) => {
+                    if !wait_until(&self.state, s, deadline) {
+                        return false;
+                    }
+                }
+            }
+            let now = self.state.load(Ordering::Acquire);
+            // From unset, only a set changes a manual-reset event's state,
+            // even if a reset followed.
+            if self.mode == EventMode::ManualReset && now != s {
+                return true;
+            }
+            s = now;
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::sync::atomic::AtomicUsize;
+
+    #[test]
+    fn manual_reset_releases_everyone() {
+        let event = Event::new(EventMode::ManualReset);
+        std::thread::scope(|s| {
+            for _ in 0..4 {
+                s.spawn(|| event.wait());
+            }
+            std::thread::sleep(Duration::from_millis(20));
+            event.set();
+        });
+        // Stays set for later waiters.
+        event.wait();
+        event.reset();
+        assert!(!event.is_set());
+        assert!(!event.wait_timeout(Duration::from_millis(10)));
+    }
+
+    #[test]
+    fn reset_right_after_set_still_releases_waiters() {
+        let rounds = if cfg!(miri) { 3 } else { 50 };
+        let event = Event::new(EventMode::ManualReset);
+        for _ in 0..rounds {
+            std::thread::scope(|s| {
+                let waiters: Vec<_> = (0..4).map(|_| s.spawn(|| event.wait())).collect();
+                std::thread::sleep(Duration::from_millis(1));
+                event.set();
+                event.reset();
+                // Threads that parked before the set return even though the
+                // event is reset by now. Ones that hadn't parked yet might
+                // see it reset and wait for the next round.
+                while waiters.iter().any(|w| !w.is_finished()) {
+                    event.set();
+                    event.reset();
+                    std::thread::yield_now();
+                }
+            });
+        }
+    }
+
+    #[test]
+    fn auto_reset_releases_one_per_set() {
+        let event = Event::new(EventMode::AutoReset);
+        let released = AtomicUsize::new(0);
+        std::thread::scope(|s| {
+            for _ in 0..3 {
+                s.spawn(|| {
+                    event.wait();
+                    released.fetch_add(1, Ordering::SeqCst);
+                });
+            }
+            for expected in 1..=3 {
+                event.set();
+                while released.load(Ordering::SeqCst) < expected {
+                    std::thread::yield_now();
+                }
+                std::thread::sleep(Duration::from_millis(10));
+                assert_eq!(released.load(Ordering::SeqCst), expected);
+            }
+        });
+        assert!(!event.is_set());
+    }
+
+    #[test]
+    fn auto_reset_set_without_waiters_is_kept() {
+        let event = Event::new(EventMode::AutoReset);
+        event.set();
+        assert!(event.wait_timeout(Duration::from_millis(10)));
+        assert!(!event.wait_timeout(Duration::from_millis(10)));
+    }
+}
@@ -0,0 +1,164 @@
+use crate::sys::atomic::{AtomicU32, Ordering};
+use crate::sys::{wait, wake_all};
+use std::cell::UnsafeCell;
+use std::marker::PhantomData;
+use std::ops::{Deref, DerefMut};
+
+/// A mutex that hands out the lock in strict arrival order.
+///
+/// [Mutex](crate::mutex::Mutex) lets a thread that just unlocked take the
+/// lock straight back, ahead of parked waiters: good for throughput, but a
+/// waiter can be passed over many times in a row. `FairMutex` is a ticket
+/// lock instead: every locker draws a ticket and waits for it to be
+/// served, so nobody is overtaken. The price is a handoff on every
+/// contended unlock, even when the releasing thread would want the lock
+/// again right away, and waking every waiter so the right one proceeds.
+pub struct FairMutex<T: ?Sized> {
+    /// The ticket the next locker draws.
+    next_ticket: AtomicU32,
+    /// The ticket allowed to hold the lock. Waiters park on this word.
+    now_serving: AtomicU32,
+    value: UnsafeCell<T>,
+}
+
+/// SAFETY: Only the holder of the served ticket accesses the value.
+unsafe impl<T: ?Sized> Sync for FairMutex<T> where T: Send {}
+
+/// A guard that represents exclusive access to a [FairMutex]'s value.
+pub struct FairMutexGuard<'a, T: ?Sized> {
+    mutex: &'a FairMutex<T>,
+    /// Sharing the guard shares `&T`, so it is only `Sync` if `T` is.
+    _marker: PhantomData<&'a mut T>,
+}
+
+impl<T> FairMutex<T> {
+    /// Create a new fair mutex guarding `value`.
+    pub const fn new(value: T) -> Self {
+        Self {
+            next_ticket: AtomicU32::new(0),
+            now_serving: AtomicU32::new(0),
+            value: UnsafeCell::new(value),
+        }
+    }
+
+    /// Consume the mutex and return the protected value.
+    pub fn into_inner(self) -> T {
+        self.value.into_inner()
+    }
+}
+
+impl<T: ?Sized> FairMutex<T> {
+    /// Lock the mutex, waiting until every thread that asked earlier has
+    /// had its turn.
+    pub fn lock(&self) -> FairMutexGuard<'_, T> {
+        let ticket = self.next_ticket.fetch_add(1, Ordering::SeqCst);
+        loop {
+            let serving = self.now_serving.load(Ordering::SeqCst);
+            if serving == ticket {
+                return FairMutexGuard {
+                    mutex: self,
+                    _marker: PhantomData,
+                };
+            }
+            wait(&self.now_serving, serving);
+        }
+    }
+
+    /// Lock the mutex only if it is free and nobody is waiting for it.
+    pub fn try_lock(&self) -> Option<FairMutexGuard<'_, T>> {
+        let serving = self.now_serving.load(Ordering::SeqCst);
+        self.next_ticket
+            .compare_exchange(
+                serving,
+                serving.wrapping_add(1),
+                Ordering::SeqCst,
+                Ordering::Relaxed,
+            )
+            .ok()?;
+        Some(FairMutexGuard {
+            mutex: self,
+            _marker: PhantomData,
+        })
+    }
+
+    /// Borrow the protected value mutably, without locking.
+    pub fn get_mut(&mut self) -> &mut T {
+        self.value.get_mut()
+    }
+}
+
+impl<T: ?Sized> Drop for FairMutexGuard<'_, T> {
+    fn drop(&mut self) {
+        let mutex = self.mutex;
+        let served = mutex
+            .now_serving
+            .fetch_add(1, Ordering::SeqCst)
+            .wrapping_add(1);
+        // A locker draws its ticket before looking at `now_serving`, so
+        // either it sees this increment or we see its ticket here.
+        if mutex.next_ticket.load(Ordering::SeqCst) != served {
+            wake_all(&mutex.now_serving);
+        }
+    }
+}
+
+impl<T: ?Sized> Deref for FairMutexGuard<'_, T> {
+    type Target = T;
+    fn deref(&self) -> &T {
+        unsafe { &*self.mutex.value.get() }
+    }
+}
+
+impl<T: ?Sized> DerefMut for FairMutexGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.mutex.value.get() }
+    }
+}
+
+#[cfg(all(test, feature = "std"))]
+mod test {
+    use super::*;
+
+    #[test]
+    fn excludes() {
+        let m = FairMutex::new(0);
+        std::thread::scope(|s| {
+            for _ in 0..4 {
+                s.spawn(|| {
+                    for _ in 0..100 {
+                        *m.lock() += 1;
+                    }
+                });
+            }
+        });
+        assert_eq!(m.into_inner(), 400);
+    }
+
+    #[test]
+    fn try_lock_respects_queue() {
+        let m = FairMutex::new(());
+        let guard = m.try_lock().unwrap();
+        assert!(m.try_lock().is_none());
+        drop(guard);
+        assert!(m.try_lock().is_some());
+    }
+
+    #[test]
+    fn served_in_arrival_order() {
+        let threads = if cfg!(miri) { 3 } else { 8 };
+        let m = FairMutex::new(Vec::new());
+        std::thread::scope(|s| {
+            let guard = m.lock();
+            for i in 0..threads {
+                let m = &m;
+                s.spawn(move || m.lock().push(i));
+                // Wait until this thread has drawn its ticket.
+                while m.next_ticket.load(Ordering::SeqCst) != i + 2 {
+                    std::thread::yield_now();
+                }
+            }
+            drop(guard);
+        });
+        assert_eq!(m.into_inner(), (0..threads).collect::<Vec<_>>());
+    }
+}
@@ -0,0 +1,230 @@
+use crate::mutex::Mutex;
+use crate::sem::RawSem;
+use std::collections::hash_map::{HashMap, RandomState};
+use std::fmt;
+use std::hash::{BuildHasher, Hash};
+use std::sync::Arc;
+
+/// Number of independently locked parts of a [MutexMap].
+const SHARDS: usize = 16;
+
+/// A lock per key, made on demand: threads locking the same key exclude
+/// each other, while different keys don't interact.
+///
+/// A key's lock only exists while some thread holds or waits for it, so
+/// the map stays as small as the number of keys in use.
+///
+/// ```
+/// use xlock::keyed::MutexMap;
+///
+/// let users = MutexMap::new();
+/// std::thread::scope(|s| {
+///     for id in [1, 2, 1, 3] {
+///         let users = &users;
+///         s.spawn(move || {
+///             let _guard = users.lock(id);
+///             // At most one thread per id in here.
+///         });
+///     }
+/// });
+/// assert!(users.is_empty());
+/// ```
+pub struct MutexMap<K> {
+    shards: Box<[Mutex<HashMap<K, Slot>>]>,
+    hasher: RandomState,
+}
+
+/// A key's lock, with the number of threads holding or waiting for it.
+/// The count is only changed under the shard's lock, and the slot is
+/// removed when it drops to zero, so a thread about to wait always finds
+/// the lock it counted itself on.
+struct Slot {
+    lock: Arc<RawSem>,
+    users: usize,
+}
+
+/// A key locked in a [MutexMap], unlocked when dropped.
+#[must_use = "the key is unlocked as soon as the guard is dropped"]
+pub struct KeyGuard<'a, K: Hash + Eq> {
+    map: &'a MutexMap<K>,
+    key: K,
+    lock: Arc<RawSem>,
+}
+
+impl<K: Hash + Eq + Clone> MutexMap<K> {
+    /// Create a map with no keys locked.
+    pub fn new() -> Self {
+        Self {
+            shards: (0..SHARDS).map(|_| Mutex::new(HashMap::new())).collect(),
+            hasher: RandomState::new(),
+        }
+    }
+
+    /// Lock `key`, waiting while another thread holds it.
+    pub fn lock(&self, key: K) -> KeyGuard<'_, K> {
+        let lock = self.join(&key);
+        // The lock is never closed.
+        lock.acquire().expect("MutexMap lock closed");
+        KeyGuard {
+            map: self,
+            key,
+            lock,
+        }
+    }
+
+    /// Lock `key` only if no other thread holds it right now.
+    pub fn try_lock(&self, key: K) -> Option<KeyGuard<'_, K>> {
+        let lock = self.join(&key);
+        if lock.try_acquire().is_err() {
+            self.leave(&key);
+            return None;
+        }
+        Some(KeyGuard {
+            map: self,
+            key,
+            lock,
+        })
+    }
+}
+
+impl<K: Hash + Eq> MutexMap<K> {
+    fn shard(&self, key: &K) -> &Mutex<HashMap<K, Slot>> {
+        &self.shards[self.hasher.hash_one(key) as usize % SHARDS]
+    }
+
+    /// Count the calling thread as a user of `key`'s lock, making the lock
+    /// if nobody uses it yet.
+    fn join(&self, key: &K) -> Arc<RawSem>
+    where
+        K: Clone,
+    {
+        let mut shard = self.shard(key).lock();
+        let slot = shard.entry(key.clone()).or_insert_with(|| Slot {
+            lock: Arc::new(RawSem::new(1)),
+            users: 0,
+        });
+        slot.users += 1;
+        Arc::clone(&slot.lock)
+    }
+
+    /// Stop counting the calling thread as a user of `key`'s lock,
+    /// removing the lock if it was the last.
+    fn leave(&self, key: &K) {
+        let mut shard = self.shard(key).lock();
+        // Whoever counted itself keeps the slot alive until it leaves.
+        let slot = shard.get_mut(key).expect("MutexMap slot missing");
+        slot.users -= 1;
+        if slot.users == 0 {
+            shard.remove(key);
+        }
+    }
+
+    /// Number of keys locked or waited for right now. Only a snapshot.
+    pub fn len(&self) -> usize {
+        self.shards.iter().map(|shard| shard.lock().len()).sum()
+    }
+
+    /// Whether no key is locked or waited for right now. Only a snapshot.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+impl<K: Hash + Eq + Clone> Default for MutexMap<K> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<K: Hash + Eq> KeyGuard<'_, K> {
+    /// The locked key.
+    pub fn key(&self) -> &K {
+        &self.key
+    }
+}
+
+impl<K: Hash + Eq> Drop for KeyGuard<'_, K> {
+    fn drop(&mut self) {
+        // SAFETY: The guard holds the key's only access.
+        unsafe { self.lock.release(1) };
+        self.map.leave(&self.key);
+    }
+}
+
+impl<K> fmt::Debug for MutexMap<K> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("MutexMap").finish_non_exhaustive()
+    }
+}
+
+impl<K: Hash + Eq + fmt::Debug> fmt::Debug for KeyGuard<'_, K> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("KeyGuard").field("key", &self.key).finish()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::sync::atomic::{AtomicU32, Ordering};
+    use std::sync::Barrier;
+
+    #[test]
+    fn same_key_excludes() {
+        let map = MutexMap::new();
+        let active: Vec<_> = (0..3).map(|_| AtomicU32::new(0)).collect();
+        let iterations = if cfg!(miri) { 5 } else { 200 };
+        std::thread::scope(|s| {
+            for t in 0..8 {
+                let (map, active) = (&map, &active);
+                s.spawn(move || {
+                    for i in 0..iterations {
+                        let key = (t + i) % 3;
+                        let _guard = map.lock(key);
+                        assert_eq!(active[key].fetch_add(1, Ordering::SeqCst), 0);
+                        std::thread::yield_now();
+                        active[key].fetch_sub(1, Ordering::SeqCst);
+                    }
+                });
+            }
+        });
+        assert!(map.is_empty());
+    }
+
+    #[test]
+    fn different_keys_are_independent() {
+        let map = MutexMap::new();
+        let both_held = Barrier::new(2);
+        std::thread::scope(|s| {
+            for key in ["alice", "bob"] {
+                let (map, both_held) = (&map, &both_held);
+                s.spawn(move || {
+                    let guard = map.lock(key.to_string());
+                    assert_eq!(guard.key(), key);
+                    // Only returns once the other key is held at the
+                    // same time.
+                    both_held.wait();
+                });
+            }
+        });
+        assert!(map.is_empty());
+    }
+
+    #[test]
+    fn try_lock_and_cleanup() {
+        let map = MutexMap::new();
+        let guard = map.try_lock(7).unwrap();
+        assert!(map.try_lock(7).is_none());
+        let other = map.try_lock(8).unwrap();
+        assert_eq!(map.len(), 2);
+        drop(guard);
+        assert_eq!(map.len(), 1);
+        std::thread::scope(|s| {
+            let waiter = s.spawn(|| drop(map.lock(8)));
+            std::thread::sleep(std::time::Duration::from_millis(20));
+            assert!(!waiter.is_finished());
+            drop(other);
+        });
+        assert!(map.is_empty());
+    }
+}
@@ -0,0 +1,140 @@
+use crate::sys::atomic::{AtomicU32, Ordering};
+use crate::sys::{wait, wait_until, wake_all};
+use std::time::{Duration, Instant};
+
+/// A gate that opens once it has been counted down a fixed number of
+/// times, and then stays open.
+///
+/// Unlike a [WaitGroup](crate::waitgroup::WaitGroup), the threads counting
+/// down needn't hold anything, and any thread may wait. Unlike a
+/// [Barrier](crate::barrier::Barrier), it is used once: waiting on an open
+/// latch returns at once, and counting it down further does nothing.
+///
+/// ```
+/// use xlock::latch::Latch;
+///
+/// let ready = Latch::new(3);
+/// std::thread::scope(|s| {
+///     for _ in 0..3 {
+///         s.spawn(|| ready.count_down());
+///     }
+///     ready.wait();
+/// });
+/// assert!(ready.try_wait());
+/// ```
+pub struct Latch {
+    /// Count downs still needed. Waiters park on this word.
+    count: AtomicU32,
+}
+
+impl Latch {
+    /// Create a latch that opens after `count` calls to
+    /// [Latch::count_down]. With a count of 0 it starts open.
+    pub const fn new(count: u32) -> Self {
+        Self {
+            count: AtomicU32::new(count),
+        }
+    }
+
+    /// Count one event, opening the latch and waking every waiter if it
+    /// was the last one needed. Does nothing once the latch is open.
+    pub fn count_down(&self) {
+        // Release, so waiters see everything done before counting down.
+        let old = self
+            .count
+            .fetch_update(Ordering::Release, Ordering::Relaxed, |n| n.checked_sub(1));
+        if old == Ok(1) {
+            wake_all(&self.count);
+        }
+    }
+
+    /// Count downs still needed before the latch opens. Only a snapshot.
+    pub fn count(&self) -> u32 {
+        self.count.load(Ordering::Relaxed)
+    }
+
+    /// Whether the latch is open, without waiting.
+    pub fn try_wait(&self) -> bool {
+        self.count.load(Ordering::Acquire) == 0
+    }
+
+    /// Wait until the latch is open.
+    pub fn wait(&self) {
+        self.wait_inner(None);
+    }
+
+    /// Wait until the latch is open, giving up after `timeout`. Returns
+    /// whether it opened.
+    pub fn wait_timeout(&self, timeout: Duration) -> bool {
+        self.wait_inner(Some(Instant::now() + timeout))
+    }
+
+    fn wait_inner(&self, deadline: Option<Instant>) -> bool {
+        loop {
+            let n = self.count.load(Ordering::Acquire);
+            if n == 0 {
+                return true;
+            }
+            match deadline {
+                None => wait(&self.count, n),
+                Some(deadline) => {
+                    if !wait_until(&self.count, n, deadline) {
+                        return self.try_wait();
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn opens_after_count_downs_from_other_threads() {
+        let reporters = if cfg!(miri) { 4 } else { 16 };
+        let latch = Latch::new(reporters);
+        let reported = AtomicU32::new(0);
+        std::thread::scope(|s| {
+            for _ in 0..2 {
+                s.spawn(|| {
+                    latch.wait();
+                    assert_eq!(reported.load(Ordering::Relaxed), reporters);
+                });
+            }
+            for _ in 0..reporters {
+                s.spawn(|| {
+                    reported.fetch_add(1, Ordering::Relaxed);
+                    latch.count_down();
+                });
+            }
+        });
+        assert_eq!(latch.count(), 0);
+    }
+
+    #[test]
+    fn stays_open() {
+        let latch = Latch::new(1);
+        assert!(!latch.try_wait());
+        assert!(!latch.wait_timeout(Duration::from_millis(10)));
+        latch.count_down();
+        latch.wait();
+        assert!(latch.wait_timeout(Duration::ZERO));
+        assert!(Latch::new(0).try_wait());
+    }
+
+    #[test]
+    fn extra_count_downs_do_nothing() {
+        let latch = Latch::new(2);
+        std::thread::scope(|s| {
+            for _ in 0..5 {
+                s.spawn(|| latch.count_down());
+            }
+        });
+        assert_eq!(latch.count(), 0);
+        latch.count_down();
+        assert_eq!(latch.count(), 0);
+        latch.wait();
+    }
+}
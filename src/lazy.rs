@@ -0,0 +1,149 @@
+use crate::once::Once;
+use std::cell::UnsafeCell;
+use std::mem::ManuallyDrop;
+use std::ops::Deref;
+
+/// A value computed on first access, usable in statics:
+///
+/// ```
+/// use xlock::lazy::LazyLock;
+///
+/// static PRIMES: LazyLock<Vec<u32>> = LazyLock::new(|| vec![2, 3, 5, 7]);
+///
+/// assert_eq!(PRIMES.len(), 4);
+/// ```
+///
+/// Threads that get there while the initializer runs wait for it. If it
+/// panics, the cell is poisoned and every later access panics too.
+pub struct LazyLock<T, F = fn() -> T> {
+    once: Once,
+    data: UnsafeCell<Data<T, F>>,
+}
+
+/// The initializer until [LazyLock::force] runs it, then the value.
+union Data<T, F> {
+    value: ManuallyDrop<T>,
+    f: ManuallyDrop<F>,
+}
+
+/// SAFETY: The value is shared once written, and it and the initializer
+/// may be created on one thread and used or dropped on another.
+unsafe impl<T, F> Sync for LazyLock<T, F>
+where
+    T: Send + Sync,
+    F: Send,
+{
+}
+
+impl<T, F: FnOnce() -> T> LazyLock<T, F> {
+    /// Create a new lazy value, computed by `f` on first access.
+    pub const fn new(f: F) -> Self {
+        Self {
+            once: Once::new(),
+            data: UnsafeCell::new(Data {
+                f: ManuallyDrop::new(f),
+            }),
+        }
+    }
+
+    /// Compute the value if that hasn't happened yet, and return it.
+    ///
+    /// # Panics
+    ///
+    /// If the initializer panicked, now or on an earlier access.
+    pub fn force(this: &Self) -> &T {
+        if !this.once.is_completed() {
+            this.init();
+        }
+        unsafe { &(*this.data.get()).value }
+    }
+
+    /// The value, if it has been computed already. Never waits.
+    pub fn get(this: &Self) -> Option<&T> {
+        if this.once.is_completed() {
+            Some(unsafe { &(*this.data.get()).value })
+        } else {
+            None
+        }
+    }
+
+    #[cold]
+    fn init(&self) {
+        if self.once.is_poisoned() {
+            panic!("LazyLock poisoned by a panicking initializer");
+        }
+        self.once.call_once(|| {
+            let data = self.data.get();
+            // The initializer is gone from here on, even if it panics.
+            let f = unsafe { ManuallyDrop::take(&mut (*data).f) };
+            let value = f();
+            unsafe { (*data).value = ManuallyDrop::new(value) };
+        });
+    }
+}
+
+impl<T, F: FnOnce() -> T> Deref for LazyLock<T, F> {
+    type Target = T;
+    fn deref(&self) -> &T {
+        LazyLock::force(self)
+    }
+}
+
+impl<T, F> Drop for LazyLock<T, F> {
+    fn drop(&mut self) {
+        let data = self.data.get_mut();
+        if self.once.is_completed() {
+            unsafe { ManuallyDrop::drop(&mut data.value) };
+        } else if !self.once.is_poisoned() {
+            unsafe { ManuallyDrop::drop(&mut data.f) };
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    #[test]
+    fn concurrent_first_deref_runs_once() {
+        let calls = AtomicUsize::new(0);
+        let lazy = LazyLock::new(|| {
+            calls.fetch_add(1, Ordering::Relaxed);
+            std::thread::yield_now();
+            vec![1, 2, 3]
+        });
+        assert!(LazyLock::get(&lazy).is_none());
+        std::thread::scope(|s| {
+            for _ in 0..16 {
+                s.spawn(|| assert_eq!(lazy.len(), 3));
+            }
+        });
+        assert_eq!(calls.load(Ordering::Relaxed), 1);
+        assert_eq!(LazyLock::get(&lazy), Some(&vec![1, 2, 3]));
+    }
+
+    #[test]
+    fn panicking_initializer_does_not_hang() {
+        let lazy: LazyLock<u32, _> = LazyLock::new(|| {
+            std::thread::sleep(std::time::Duration::from_millis(20));
+            panic!("init failed");
+        });
+        std::thread::scope(|s| {
+            let threads: Vec<_> = (0..4).map(|_| s.spawn(|| *lazy)).collect();
+            for thread in threads {
+                assert!(thread.join().is_err());
+            }
+        });
+        assert!(LazyLock::get(&lazy).is_none());
+    }
+
+    #[test]
+    fn unused_initializer_is_dropped() {
+        let token = std::sync::Arc::new(());
+        let held = std::sync::Arc::clone(&token);
+        let lazy = LazyLock::new(move || *held);
+        drop(lazy);
+        assert_eq!(std::sync::Arc::strong_count(&token), 1);
+    }
+}
@@ -0,0 +1,224 @@
+use crate::mutex::Mutex;
+use crate::semaphore::{AcquireError, Semaphore, TryAcquireError};
+use std::fmt;
+use std::time::{Duration, Instant};
+
+/// A [Semaphore] whose permits can also be taken as [Lease]s, which are
+/// reclaimed if not given back or renewed in time, so a holder that gets
+/// stuck doesn't keep its permit for good.
+///
+/// Reclaiming is done by later calls to [LeasedSemaphore::acquire_lease]
+/// and [LeasedSemaphore::try_acquire_lease], which free the permits of
+/// expired leases before taking one; a call waiting for a permit wakes up
+/// as the next lease expires. Acquisitions through
+/// [LeasedSemaphore::semaphore] don't reclaim anything.
+///
+/// ```
+/// use std::time::Duration;
+/// use xlock::lease::LeasedSemaphore;
+///
+/// let sem = LeasedSemaphore::new(1);
+/// std::mem::forget(sem.acquire_lease(Duration::from_millis(10)).unwrap());
+/// // Waits for the forgotten lease to expire, then takes its permit.
+/// let lease = sem.acquire_lease(Duration::from_secs(1)).unwrap();
+/// assert!(lease.remaining().is_some());
+/// ```
+pub struct LeasedSemaphore {
+    sem: Semaphore,
+    /// Permits handed out as leases and not yet given back or reclaimed.
+    leases: Mutex<Leases>,
+}
+
+/// The outstanding leases of a [LeasedSemaphore].
+struct Leases {
+    /// The id of the next lease, never reused, so a lease that was
+    /// reclaimed can't match a later one.
+    next_id: u64,
+    /// Id and expiry of each outstanding lease.
+    active: Vec<(u64, Instant)>,
+}
+
+/// A permit from [LeasedSemaphore::acquire_lease] that is reclaimed once
+/// it expires, unless renewed first. Given back when dropped, which does
+/// nothing if it was reclaimed already.
+#[must_use = "the lease is released as soon as it is dropped"]
+pub struct Lease<'a> {
+    sem: &'a LeasedSemaphore,
+    id: u64,
+    ttl: Duration,
+}
+
+impl LeasedSemaphore {
+    /// Create a new semaphore handing out at most `permits` at a time.
+    ///
+    /// # Panics
+    ///
+    /// If `permits` is more than [Semaphore::MAX_PERMITS].
+    pub const fn new(permits: u32) -> Self {
+        Self {
+            sem: Semaphore::new(permits),
+            leases: Mutex::new(Leases {
+                next_id: 0,
+                active: Vec::new(),
+            }),
+        }
+    }
+
+    /// The semaphore the leases are taken from, for everything else.
+    pub fn semaphore(&self) -> &Semaphore {
+        &self.sem
+    }
+
+    /// Take a permit that is reclaimed if not given back or renewed within
+    /// `ttl`.
+    pub fn acquire_lease(&self, ttl: Duration) -> Result<Lease<'_>, AcquireError> {
+        loop {
+            // Look again once the next lease expires. With none
+            // outstanding, one may be made meanwhile, so don't wait
+            // longer than our own lease would last either.
+            let recheck = self
+                .reclaim_leases()
+                .unwrap_or_else(|| Instant::now() + ttl);
+            match self.sem.inner.acquire_or_recheck(recheck) {
+                Ok(()) => return Ok(self.lease(ttl)),
+                Err(AcquireError::Timeout) => {}
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    /// Take a permit as a [Lease] lasting `ttl` if one is free right now,
+    /// after reclaiming any expired leases.
+    pub fn try_acquire_lease(&self, ttl: Duration) -> Result<Lease<'_>, TryAcquireError> {
+        self.reclaim_leases();
+        self.sem.inner.try_acquire()?;
+        Ok(self.lease(ttl))
+    }
+
+    /// Record a permit just taken as a lease.
+    fn lease(&self, ttl: Duration) -> Lease<'_> {
+        let mut leases = self.leases.lock();
+        let id = leases.next_id;
+        leases.next_id += 1;
+        leases.active.push((id, Instant::now() + ttl));
+        Lease { sem: self, id, ttl }
+    }
+
+    /// Free the permits of expired leases, returning when the next of
+    /// the others expires.
+    fn reclaim_leases(&self) -> Option<Instant> {
+        let now = Instant::now();
+        let mut leases = self.leases.lock();
+        let outstanding = leases.active.len();
+        leases.active.retain(|&(_, expiry)| expiry > now);
+        let expired = outstanding - leases.active.len();
+        let next = leases.active.iter().map(|&(_, expiry)| expiry).min();
+        drop(leases);
+        // SAFETY: Each expired lease held a permit, and its holder can no
+        // longer give it back.
+        unsafe { self.sem.inner.release(expired as u32) };
+        next
+    }
+}
+
+impl Lease<'_> {
+    /// Extend the lease to last `ttl` from now, as given when it was
+    /// taken. Returns false, doing nothing, if it was reclaimed already.
+    pub fn renew(&self) -> bool {
+        let mut leases = self.sem.leases.lock();
+        match leases.active.iter_mut().find(|(id, _)| *id == self.id) {
+            Some((_, expiry)) => {
+                *expiry = Instant::now() + self.ttl;
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// How long until the lease expires, zero if it has but wasn't
+    /// reclaimed yet, or `None` once it was.
+    pub fn remaining(&self) -> Option<Duration> {
+        let leases = self.sem.leases.lock();
+        let &(_, expiry) = leases.active.iter().find(|(id, _)| *id == self.id)?;
+        Some(expiry.saturating_duration_since(Instant::now()))
+    }
+}
+
+impl Drop for Lease<'_> {
+    fn drop(&mut self) {
+        let mut leases = self.sem.leases.lock();
+        let Some(index) = leases.active.iter().position(|(id, _)| *id == self.id) else {
+            // Reclaimed, so the permit was given back already.
+            return;
+        };
+        leases.active.swap_remove(index);
+        drop(leases);
+        // SAFETY: The lease still held its permit.
+        unsafe { self.sem.sem.inner.release(1) };
+    }
+}
+
+impl fmt::Debug for LeasedSemaphore {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("LeasedSemaphore")
+            .field("semaphore", &self.sem)
+            .finish_non_exhaustive()
+    }
+}
+
+impl fmt::Debug for Lease<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Lease")
+            .field("remaining", &self.remaining())
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn leaked_lease_is_reclaimed() {
+        let sem = LeasedSemaphore::new(1);
+        let ttl = Duration::from_millis(50);
+        std::mem::forget(sem.acquire_lease(ttl).unwrap());
+        assert!(sem.try_acquire_lease(ttl).is_err());
+        let start = Instant::now();
+        let lease = sem.acquire_lease(ttl).unwrap();
+        assert!(start.elapsed() >= Duration::from_millis(40));
+        drop(lease);
+        assert_eq!(sem.semaphore().available_permits(), 1);
+    }
+
+    #[test]
+    fn late_drop_of_reclaimed_lease_does_nothing() {
+        let sem = LeasedSemaphore::new(1);
+        let stuck = sem.acquire_lease(Duration::from_millis(10)).unwrap();
+        std::thread::sleep(Duration::from_millis(20));
+        assert_eq!(stuck.remaining(), Some(Duration::ZERO));
+        let taken = sem.try_acquire_lease(Duration::from_secs(10)).unwrap();
+        assert_eq!(stuck.remaining(), None);
+        assert!(!stuck.renew());
+        drop(stuck);
+        // Still held by the new lease, not released a second time.
+        assert_eq!(sem.semaphore().available_permits(), 0);
+        drop(taken);
+        assert_eq!(sem.semaphore().available_permits(), 1);
+    }
+
+    #[test]
+    fn renewed_lease_is_kept() {
+        let sem = LeasedSemaphore::new(1);
+        let ttl = Duration::from_millis(60);
+        let lease = sem.acquire_lease(ttl).unwrap();
+        for _ in 0..5 {
+            std::thread::sleep(Duration::from_millis(20));
+            assert!(lease.renew());
+            assert!(sem.try_acquire_lease(ttl).is_err());
+        }
+        assert!(lease.remaining().unwrap() > Duration::from_millis(20));
+        drop(lease);
+        assert!(sem.try_acquire_lease(ttl).is_ok());
+    }
+}
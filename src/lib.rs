@@ -1,2 +1,77 @@
+//! Synchronization primitives built on a futex-backed semaphore.
+//!
+//! With default features the crate uses `std`. Turning off the `std`
+//! feature makes it `#![no_std]`: [mutex::Mutex], [semaphore::Semaphore],
+//! [fair::FairMutex], [seqlock::SeqLock], [spin::SpinMutex] and
+//! [padded::CachePadded] remain, waiting by spinning instead of parking,
+//! and the other modules and any methods that need a clock, `Arc` or
+//! unwinding are left out.
+
+#![cfg_attr(not(feature = "std"), no_std)]
+
+// Lets the shared code name `core` items through `std` paths.
+#[cfg(not(feature = "std"))]
+extern crate core as std;
+
+#[cfg(feature = "std")]
+pub mod barrier;
+#[cfg(feature = "std")]
+pub mod cancel;
+#[cfg(feature = "std")]
+pub mod channel;
+#[cfg(feature = "std")]
+pub mod condvar;
+#[cfg(feature = "std")]
+pub mod cooldown;
+#[cfg(feature = "deadlock_detection")]
+pub mod deadlock;
+#[cfg(feature = "std")]
+pub mod event;
+pub mod fair;
+#[cfg(feature = "std")]
+pub mod keyed;
+#[cfg(feature = "std")]
+pub mod latch;
+#[cfg(feature = "std")]
+pub mod lazy;
+#[cfg(feature = "std")]
+pub mod lease;
 pub mod mutex;
+#[cfg(feature = "std")]
+pub mod notify;
+#[cfg(feature = "std")]
+pub mod once;
+pub mod padded;
+#[cfg(feature = "std")]
+pub mod pool;
+#[cfg(all(feature = "std", any(target_os = "linux", target_os = "android")))]
+pub mod process;
+#[cfg(feature = "std")]
+pub mod rank;
+#[cfg(all(feature = "std", target_has_atomic = "64"))]
+pub mod ratelimit;
+#[cfg(feature = "std")]
+pub mod raw;
+#[cfg(feature = "std")]
+pub mod reentrant;
+#[cfg(feature = "std")]
+pub mod rwlock;
 mod sem;
+pub mod semaphore;
+#[cfg(target_has_atomic = "64")]
+pub mod semaphore64;
+pub mod seqlock;
+#[cfg(feature = "std")]
+pub mod sharded;
+#[cfg(feature = "std")]
+pub mod shared;
+pub mod spin;
+#[cfg(feature = "stats")]
+pub mod stats;
+mod sys;
+#[cfg(feature = "std")]
+pub mod traits;
+#[cfg(feature = "std")]
+pub mod waitgroup;
+#[cfg(feature = "async")]
+mod wakers;
@@ -1,76 +1,2253 @@
-use crate::sem::{SemGuard, SemVar};
+#[cfg(feature = "std")]
+use crate::cancel::{CancelToken, Cancelled};
+#[cfg(feature = "recursion_check")]
+use crate::reentrant::current_thread;
+use crate::sem::{SemGuard, SemPermit, SemVar};
+#[cfg(feature = "lock_when")]
+use crate::sys::atomic::AtomicU32;
+use crate::sys::atomic::{AtomicBool, Ordering};
+#[cfg(feature = "recursion_check")]
+use crate::sys::atomic::{AtomicPtr, AtomicUsize};
+#[cfg(all(feature = "lock_when", not(feature = "std")))]
+use crate::sys::Instant;
+#[cfg(feature = "lock_when")]
+use crate::sys::{wait, wait_until, wake_all};
 use std::cell::UnsafeCell;
+use std::ffi::c_void;
+use std::fmt;
+#[cfg(feature = "recursion_check")]
+use std::panic::Location;
+#[cfg(feature = "std")]
+use std::sync::Arc;
+#[cfg(feature = "poison")]
+use std::sync::{LockResult, PoisonError};
+#[cfg(feature = "holder_tracking")]
+use std::thread::ThreadId;
+#[cfg(feature = "std")]
+use std::time::{Duration, Instant};
 
 /// A Semaphore-based Mutex.
-pub struct Mutex<T> {
+///
+/// [Mutex::new] is a `const fn`, so a mutex can be a plain `static`:
+///
+/// ```
+/// use xlock::mutex::Mutex;
+///
+/// static COUNTER: Mutex<u64> = Mutex::new(0);
+///
+/// std::thread::scope(|s| {
+///     for _ in 0..4 {
+///         s.spawn(|| *COUNTER.lock() += 1);
+///     }
+/// });
+/// assert_eq!(*COUNTER.lock(), 4);
+/// ```
+///
+/// By default the lock word sits right next to the value, keeping the
+/// mutex small. Enable the `cache_padded` feature to give the word a cache
+/// line of its own, or wrap mutexes stored side by side in
+/// [CachePadded](crate::padded::CachePadded) so they don't share lines.
+///
+/// Unlocking leaves the mutex to whoever takes it first, often the thread
+/// that just unlocked it, which is fast but lets a thread locking in a
+/// loop pass over parked waiters. With the `eventual_fairness` feature,
+/// roughly once a millisecond a contended unlock hands the mutex to a
+/// parked waiter instead, so none waits much longer than that plus its
+/// turn, and `MutexGuard::unlock_fair` hands it over every time.
+pub struct Mutex<T: ?Sized> {
+    /// Wakes threads waiting for a predicate on the value.
+    #[cfg(feature = "lock_when")]
+    when: When,
+    /// Set when a guard is dropped while its thread is panicking.
+    #[cfg(feature = "poison")]
+    poisoned: AtomicBool,
+    /// The thread currently holding the lock. Fields added under a feature
+    /// must stay const-constructible, so [Mutex::new] remains a `const fn`.
+    #[cfg(feature = "holder_tracking")]
+    holder: std::sync::Mutex<Option<ThreadId>>,
+    #[cfg(feature = "recursion_check")]
+    owner: Owner,
+    /// Last, so `T` may be unsized.
     inner: SemVar<UnsafeCell<T>>,
 }
 
 /// SAFETY: It's safe to share across threads since
 /// single access is enforced.
-unsafe impl<T> Sync for Mutex<T> where T: Send {}
+unsafe impl<T: ?Sized> Sync for Mutex<T> where T: Send {}
 
 /// A guard that represents exclusive access to the guarded value.
-pub struct MutexGuard<'a, T>(SemGuard<'a, UnsafeCell<T>>);
+///
+/// Like std's, the guard is `Sync` when `T` is, since sharing it only
+/// shares `&T`, and isn't `Send`. Unlocking has no tie to the locking
+/// thread, though, so the `send_guard` feature makes the guard `Send` when
+/// `T` is, letting another thread finish the work and unlock:
+///
+/// ```
+/// use xlock::mutex::{Mutex, MutexGuard};
+///
+/// fn assert_sync<T: Sync>() {}
+/// assert_sync::<MutexGuard<'static, Vec<u8>>>();
+///
+/// let m = Mutex::new(vec![1u8, 2]);
+/// let guard = m.lock();
+/// std::thread::scope(|s| {
+///     s.spawn(|| assert_eq!(guard.len(), 2));
+/// });
+/// ```
+///
+/// ```compile_fail
+/// # use xlock::mutex::MutexGuard;
+/// fn assert_sync<T: Sync>() {}
+/// // Cell is Send but not Sync.
+/// assert_sync::<MutexGuard<'static, std::cell::Cell<u8>>>();
+/// ```
+///
+/// ```compile_fail
+/// # use xlock::mutex::MutexGuard;
+/// fn assert_sync<T: Sync>() {}
+/// // Rc is neither Send nor Sync.
+/// assert_sync::<MutexGuard<'static, std::rc::Rc<u8>>>();
+/// ```
+///
+#[cfg_attr(not(feature = "send_guard"), doc = "```compile_fail")]
+#[cfg_attr(feature = "send_guard", doc = "```")]
+/// # use xlock::mutex::MutexGuard;
+/// fn assert_send<T: Send>() {}
+/// assert_send::<MutexGuard<'static, Vec<u8>>>();
+/// ```
+pub struct MutexGuard<'a, T: ?Sized> {
+    /// The locked mutex, for relocking after [Condvar::wait](crate::condvar::Condvar::wait).
+    mutex: &'a Mutex<T>,
+    /// Declared before `guard` so the mutex is poisoned before it is
+    /// unlocked.
+    #[cfg(feature = "poison")]
+    _poison: PoisonOnPanic<'a>,
+    /// Declared before `guard` so threads in [Mutex::lock_when] are woken
+    /// for any change made through it.
+    #[cfg(feature = "lock_when")]
+    _changed: Changed<'a>,
+    /// Declared before `guard` so the holder is cleared before the lock
+    /// is released.
+    #[cfg(feature = "holder_tracking")]
+    _holder: Holder<'a>,
+    #[cfg(feature = "recursion_check")]
+    _owner: ClearOwner<'a>,
+    #[cfg(feature = "deadlock_detection")]
+    _held: crate::deadlock::Held,
+    guard: SemGuard<'a, UnsafeCell<T>>,
+}
+
+/// SAFETY: Sharing the guard only shares `&T`. The guard itself stays
+/// `!Send`, through its inner [SemGuard].
+unsafe impl<T: ?Sized> Sync for MutexGuard<'_, T> where T: Sync {}
+
+/// SAFETY: Unlocking is an atomic update and a wake, valid from any
+/// thread, and moving the guard moves `&mut T` along with it.
+#[cfg(feature = "send_guard")]
+unsafe impl<T: ?Sized> Send for MutexGuard<'_, T> where T: Send {}
+
+/// A guard for part of a [Mutex]'s value, made by [MutexGuard::map].
+/// Keeps the whole mutex locked until dropped.
+pub struct MappedMutexGuard<'a, U: ?Sized> {
+    value: NonNull<U>,
+    #[cfg(feature = "poison")]
+    _poison: PoisonOnPanic<'a>,
+    #[cfg(feature = "lock_when")]
+    _changed: Changed<'a>,
+    #[cfg(feature = "holder_tracking")]
+    _holder: Holder<'a>,
+    #[cfg(feature = "recursion_check")]
+    _owner: ClearOwner<'a>,
+    #[cfg(feature = "deadlock_detection")]
+    _held: crate::deadlock::Held,
+    /// Declared last so the lock is released after the fields above.
+    _permit: SemPermit<'a>,
+    _marker: PhantomData<&'a mut U>,
+}
+
+/// SAFETY: Sharing the mapped guard only shares `&U`.
+unsafe impl<U: ?Sized> Sync for MappedMutexGuard<'_, U> where U: Sync {}
+
+/// SAFETY: As for [MutexGuard].
+#[cfg(feature = "send_guard")]
+unsafe impl<U: ?Sized> Send for MappedMutexGuard<'_, U> where U: Send {}
+
+/// A guard made by [Mutex::lock_arc]. It owns a reference to the mutex
+/// instead of borrowing it, so it can be moved anywhere, including to
+/// another thread, and unlocks the mutex wherever it is dropped.
+#[cfg(feature = "std")]
+pub struct ArcMutexGuard<T: ?Sized> {
+    mutex: Arc<Mutex<T>>,
+    /// The `Arc` is `Sync` whenever `T: Send`, but sharing the guard
+    /// shares `&T`, so opt out and re-add `Sync` for `T: Sync` below.
+    _marker: PhantomData<UnsafeCell<()>>,
+}
+
+/// SAFETY: Sharing the guard only shares `&T`.
+#[cfg(feature = "std")]
+unsafe impl<T: ?Sized> Sync for ArcMutexGuard<T> where T: Sync {}
+
+/// The state behind [Mutex::lock_when].
+#[cfg(feature = "lock_when")]
+struct When {
+    /// Bumped by unlocks while anyone is in [Mutex::lock_when]. Threads
+    /// waiting for a predicate park on it.
+    generation: AtomicU32,
+    /// Threads in [Mutex::lock_when], so unlocks only bump `generation`
+    /// when someone waits for it.
+    watchers: AtomicU32,
+}
+
+/// Wakes the threads in [Mutex::lock_when] when dropped, to re-check
+/// their predicates after the value may have changed.
+#[cfg(feature = "lock_when")]
+struct Changed<'a>(&'a When);
+
+/// Poisons a mutex when dropped during a panic.
+#[cfg(feature = "poison")]
+struct PoisonOnPanic<'a>(&'a AtomicBool);
+
+/// Clears the recorded holder of a mutex when dropped.
+#[cfg(feature = "holder_tracking")]
+struct Holder<'a>(&'a std::sync::Mutex<Option<ThreadId>>);
+
+/// The thread holding a mutex and where it took the lock, to catch a
+/// thread locking a mutex it already holds, which would never return.
+///
+/// Guards that can change threads, [ArcMutexGuard] and guards sent with
+/// the `send_guard` feature, aren't followed: an [ArcMutexGuard] isn't
+/// recorded, and a sent guard still counts as held by the thread that
+/// locked it.
+#[cfg(feature = "recursion_check")]
+struct Owner {
+    /// The [current_thread] holding the lock, or 0.
+    thread: AtomicUsize,
+    location: AtomicPtr<Location<'static>>,
+}
+
+/// Clears the recorded owner of a mutex when dropped.
+#[cfg(feature = "recursion_check")]
+struct ClearOwner<'a>(&'a Owner);
+
+/// A [Mutex] that also tracks whether it was ever locked through
+/// [FirstLockMutex::lock_first], for one-time setup under the lock without
+/// a separate `Once`. Everything else is the [Mutex] it derefs to.
+///
+/// ```
+/// use xlock::mutex::FirstLockMutex;
+///
+/// let cache = FirstLockMutex::new(Vec::new());
+/// let (mut guard, first) = cache.lock_first();
+/// if first {
+///     guard.push(1);
+/// }
+/// drop(guard);
+/// assert!(!cache.lock_first().1);
+/// assert_eq!(*cache.lock(), [1]);
+/// ```
+pub struct FirstLockMutex<T: ?Sized> {
+    locked_before: AtomicBool,
+    /// Last, so `T` may be unsized.
+    mutex: Mutex<T>,
+}
+
+/// A token that keeps a [Mutex] locked and unlocks it when dropped, but
+/// gives no access to the protected value. Unlike [MutexGuard] it can be
+/// sent to another thread, whatever `T` is.
+pub struct ReleaseToken<'a> {
+    _permit: SemPermit<'a>,
+}
+
+/// A handle to a [Mutex] locked through [Mutex::lock_raw_ptr], meant to
+/// cross an FFI boundary. Passing it to [guard_unlock] unlocks the mutex;
+/// dropping it leaves the mutex locked.
+#[repr(C)]
+pub struct RawUnlockHandle {
+    mutex: *const c_void,
+    /// Unlocks `mutex`, knowing its type.
+    unlock: unsafe extern "C" fn(*const c_void),
+}
+
+/// SAFETY: The handle only releases the lock, which any thread may do.
+unsafe impl Send for RawUnlockHandle {}
+
+impl<T> Mutex<T> {
+    /// Create a new Mutex guarding value T.
+    ///
+    /// Boxed or reference-counted mutexes coerce to unsized ones, e.g.
+    /// `Arc<Mutex<[u8; 4]>>` to `Arc<Mutex<[u8]>>`.
+    pub const fn new(value: T) -> Self {
+        Self {
+            #[cfg(feature = "lock_when")]
+            when: When::new(),
+            #[cfg(feature = "poison")]
+            poisoned: AtomicBool::new(false),
+            #[cfg(feature = "holder_tracking")]
+            holder: std::sync::Mutex::new(None),
+            #[cfg(feature = "recursion_check")]
+            owner: Owner::new(),
+            inner: SemVar::new_eventually_fair(1, UnsafeCell::new(value)),
+        }
+    }
+
+    /// Consume the mutex and return the protected value, without locking.
+    pub fn into_inner(self) -> T {
+        self.inner.into_inner().into_inner()
+    }
+
+    /// Lock the mutex and hand out a raw pointer to the protected value
+    /// together with a handle that unlocks it through [guard_unlock], for
+    /// C code that runs while the lock is held. C callbacks usually take
+    /// the pointer as `void *`, which `data.cast::<c_void>()` gives.
+    ///
+    /// The pointer is only valid until the handle is passed to
+    /// [guard_unlock]; using it afterwards is a data race. Until then the
+    /// lock counts as held by this thread, as with [MutexGuard::leak].
+    ///
+    /// ```
+    /// use std::ffi::c_void;
+    /// use xlock::mutex::{guard_unlock, Mutex, RawUnlockHandle};
+    ///
+    /// extern "C" fn callback(data: *mut c_void, handle: RawUnlockHandle) {
+    ///     // SAFETY: `data` is not used after unlocking.
+    ///     unsafe {
+    ///         *data.cast::<u32>() += 1;
+    ///         guard_unlock(handle);
+    ///     }
+    /// }
+    ///
+    /// let m = Mutex::new(0u32);
+    /// let (data, handle) = m.lock_raw_ptr();
+    /// callback(data.cast::<c_void>(), handle);
+    /// assert_eq!(*m.lock(), 1);
+    /// ```
+    pub fn lock_raw_ptr(&self) -> (*mut T, RawUnlockHandle) {
+        /// Unlock a `Mutex<T>` left locked by [Mutex::lock_raw_ptr].
+        unsafe extern "C" fn unlock<T>(mutex: *const c_void) {
+            (*mutex.cast::<Mutex<T>>()).force_unlock();
+        }
+
+        let data: *mut T = MutexGuard::leak(self.lock());
+        let handle = RawUnlockHandle {
+            mutex: (self as *const Self).cast(),
+            unlock: unlock::<T>,
+        };
+        (data, handle)
+    }
+}
+
+impl<T: ?Sized> Mutex<T> {
+    /// Try to gain access to the protected value. Returns
+    /// a [SemGuard].
+    ///
+    /// With the `recursion_check` feature, locking a mutex the thread
+    /// already holds panics instead of waiting forever.
+    #[inline]
+    #[cfg_attr(feature = "recursion_check", track_caller)]
+    pub fn lock(&self) -> MutexGuard<'_, T> {
+        #[cfg(feature = "recursion_check")]
+        self.owner.check();
+        let guard = self.access();
+        self.guard(guard)
+    }
+
+    /// Take the lock, waiting as long as needed.
+    #[cfg(not(feature = "deadlock_detection"))]
+    #[inline]
+    fn access(&self) -> SemGuard<'_, UnsafeCell<T>> {
+        self.inner.access()
+    }
+
+    /// Take the lock, recording the wait for [check_deadlock] if it
+    /// blocks.
+    ///
+    /// [check_deadlock]: crate::deadlock::check_deadlock
+    #[cfg(feature = "deadlock_detection")]
+    fn access(&self) -> SemGuard<'_, UnsafeCell<T>> {
+        self.inner.try_access().unwrap_or_else(|| {
+            let _waiting = crate::deadlock::wait_for(address(self), false);
+            self.inner.access()
+        })
+    }
+
+    /// Lock the mutex without blocking the thread: the returned future
+    /// waits for the lock, woken by unlocks from threads and tasks alike.
+    /// Dropping the future before it completes gives up its place without
+    /// swallowing a wakeup meant for another waiter.
+    #[cfg(feature = "async")]
+    pub async fn lock_async(&self) -> MutexGuard<'_, T> {
+        // A mutex's semaphore is never closed.
+        self.inner.acquire_async().await.expect("SemVar closed");
+        // SAFETY: The access was just taken.
+        let guard = unsafe { self.inner.assume_access() };
+        self.guard(guard)
+    }
+
+    /// Borrow the protected value mutably, without locking: the exclusive
+    /// borrow already guarantees no guard exists.
+    pub fn get_mut(&mut self) -> &mut T {
+        self.inner.get_mut().get_mut()
+    }
+
+    /// Lock the mutex, reporting whether a previous holder panicked while
+    /// holding it. The error still carries the guard, so the data can be
+    /// recovered with [PoisonError::into_inner].
+    ///
+    /// [Mutex::lock] and the other locking methods ignore poisoning.
+    #[cfg_attr(feature = "recursion_check", track_caller)]
+    #[cfg(feature = "poison")]
+    pub fn lock_checked(&self) -> LockResult<MutexGuard<'_, T>> {
+        let guard = self.lock();
+        // The lock orders this load after the poisoning store.
+        if self.poisoned.load(Ordering::Relaxed) {
+            Err(PoisonError::new(guard))
+        } else {
+            Ok(guard)
+        }
+    }
+
+    /// Whether the mutex is locked right now. A racy snapshot for
+    /// monitoring and debug assertions: the lock may be taken or released
+    /// as soon as this returns, so don't decide whether to lock based on
+    /// it. Use [Mutex::try_lock] for that.
+    pub fn is_locked(&self) -> bool {
+        self.inner.available() <= 0
+    }
+
+    /// Counters of acquisitions, parking and wait and hold times, to find
+    /// hot locks. See [stats](crate::stats).
+    #[cfg(feature = "stats")]
+    pub fn stats(&self) -> crate::stats::LockStats {
+        self.inner.stats()
+    }
+
+    /// Zero the counters returned by [Mutex::stats].
+    #[cfg(feature = "stats")]
+    pub fn reset_stats(&self) {
+        self.inner.reset_stats();
+    }
+
+    /// Whether a thread panicked while holding the lock. Only a
+    /// snapshot: another thread may poison it as soon as this returns.
+    #[cfg(feature = "poison")]
+    pub fn is_poisoned(&self) -> bool {
+        self.poisoned.load(Ordering::Relaxed)
+    }
+
+    /// Mark the mutex as no longer poisoned, once the data is known to be
+    /// consistent again.
+    #[cfg(feature = "poison")]
+    pub fn clear_poison(&self) {
+        self.poisoned.store(false, Ordering::Relaxed);
+    }
+
+    /// Lock the mutex only if it is free right now. Never waits, but
+    /// synchronizes with the previous unlock just like [Mutex::lock].
+    #[cfg_attr(feature = "recursion_check", track_caller)]
+    pub fn try_lock(&self) -> Option<MutexGuard<'_, T>> {
+        let guard = self.inner.try_access()?;
+        Some(self.guard(guard))
+    }
+
+    /// Lock the mutex, giving up after `timeout`.
+    #[cfg_attr(feature = "recursion_check", track_caller)]
+    #[cfg(feature = "std")]
+    pub fn lock_for(&self, timeout: Duration) -> Option<MutexGuard<'_, T>> {
+        self.lock_until(Instant::now() + timeout)
+    }
+
+    /// Lock the mutex, giving up once `deadline` has passed. A timed out
+    /// attempt leaves the mutex exactly as it found it.
+    #[cfg_attr(feature = "recursion_check", track_caller)]
+    #[cfg(feature = "std")]
+    pub fn lock_until(&self, deadline: Instant) -> Option<MutexGuard<'_, T>> {
+        #[cfg(feature = "recursion_check")]
+        self.owner.check();
+        let guard = self.inner.access_until(deadline)?;
+        Some(self.guard(guard))
+    }
+
+    /// Lock the mutex, giving up once `token` is cancelled, or at once if
+    /// it was already. A cancelled attempt leaves the mutex exactly as it
+    /// found it, while other waiters keep waiting.
+    #[cfg_attr(feature = "recursion_check", track_caller)]
+    #[cfg(feature = "std")]
+    pub fn lock_cancellable(&self, token: &CancelToken) -> Result<MutexGuard<'_, T>, Cancelled> {
+        #[cfg(feature = "recursion_check")]
+        self.owner.check();
+        let guard = self.inner.access_cancellable(token).ok_or(Cancelled)?;
+        Ok(self.guard(guard))
+    }
+
+    /// The thread currently holding the lock, for diagnosing hangs.
+    /// Only a snapshot: the holder may change as soon as this returns.
+    #[cfg(feature = "holder_tracking")]
+    pub fn holder(&self) -> Option<ThreadId> {
+        *self.holder.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Wrap an acquired [SemGuard] into a [MutexGuard].
+    #[cfg_attr(feature = "recursion_check", track_caller)]
+    fn guard<'a>(&'a self, guard: SemGuard<'a, UnsafeCell<T>>) -> MutexGuard<'a, T> {
+        #[cfg(feature = "recursion_check")]
+        let _owner = {
+            self.owner.set();
+            ClearOwner(&self.owner)
+        };
+        #[cfg(feature = "holder_tracking")]
+        let _holder = {
+            *self.holder.lock().unwrap_or_else(|e| e.into_inner()) =
+                Some(std::thread::current().id());
+            Holder(&self.holder)
+        };
+        MutexGuard {
+            mutex: self,
+            #[cfg(feature = "poison")]
+            _poison: PoisonOnPanic(&self.poisoned),
+            #[cfg(feature = "lock_when")]
+            _changed: Changed(&self.when),
+            #[cfg(feature = "holder_tracking")]
+            _holder,
+            #[cfg(feature = "recursion_check")]
+            _owner,
+            #[cfg(feature = "deadlock_detection")]
+            _held: crate::deadlock::hold(address(self)),
+            guard,
+        }
+    }
+
+    /// Like [Mutex::lock], but the guard holds a clone of the `Arc`
+    /// rather than a borrow, so it has no lifetime.
+    #[cfg_attr(feature = "recursion_check", track_caller)]
+    #[cfg(feature = "std")]
+    pub fn lock_arc(self: &Arc<Self>) -> ArcMutexGuard<T> {
+        #[cfg(feature = "recursion_check")]
+        self.owner.check();
+        std::mem::forget(self.access());
+        ArcMutexGuard::new(Arc::clone(self))
+    }
+
+    /// Like [Mutex::try_lock], but returns an [ArcMutexGuard].
+    #[cfg(feature = "std")]
+    pub fn try_lock_arc(self: &Arc<Self>) -> Option<ArcMutexGuard<T>> {
+        std::mem::forget(self.inner.try_access()?);
+        Some(ArcMutexGuard::new(Arc::clone(self)))
+    }
+
+    /// Lock the mutex once `pred` holds for the protected value. Every
+    /// unlock wakes the waiting threads to re-check, so there is nothing
+    /// to notify. The predicate is only ever called with the lock held,
+    /// and one that holds right away returns without parking.
+    ///
+    /// ```
+    /// use std::collections::VecDeque;
+    /// use xlock::mutex::Mutex;
+    ///
+    /// let queue = Mutex::new(VecDeque::new());
+    /// std::thread::scope(|s| {
+    ///     s.spawn(|| queue.lock().push_back(1));
+    ///     let mut q = queue.lock_when(|q| !q.is_empty());
+    ///     assert_eq!(q.pop_front(), Some(1));
+    /// });
+    /// ```
+    #[cfg_attr(feature = "recursion_check", track_caller)]
+    #[cfg(feature = "lock_when")]
+    pub fn lock_when<F>(&self, pred: F) -> MutexGuard<'_, T>
+    where
+        F: FnMut(&T) -> bool,
+    {
+        let guard = self.lock_when_inner(pred, None);
+        guard.expect("untimed lock_when gave up")
+    }
+
+    /// Like [Mutex::lock_when], but give up after `timeout`, returning
+    /// `None` if `pred` never held while the lock was taken.
+    #[cfg_attr(feature = "recursion_check", track_caller)]
+    #[cfg(all(feature = "lock_when", feature = "std"))]
+    pub fn lock_when_for<F>(&self, timeout: Duration, pred: F) -> Option<MutexGuard<'_, T>>
+    where
+        F: FnMut(&T) -> bool,
+    {
+        self.lock_when_inner(pred, Some(Instant::now() + timeout))
+    }
+
+    #[cfg_attr(feature = "recursion_check", track_caller)]
+    #[cfg(feature = "lock_when")]
+    fn lock_when_inner<F>(
+        &self,
+        mut pred: F,
+        deadline: Option<Instant>,
+    ) -> Option<MutexGuard<'_, T>>
+    where
+        F: FnMut(&T) -> bool,
+    {
+        let relock = || match deadline {
+            None => Some(self.lock()),
+            #[cfg(feature = "std")]
+            Some(deadline) => self.lock_until(deadline),
+            #[cfg(not(feature = "std"))]
+            Some(deadline) => match deadline {},
+        };
+        let mut guard = relock()?;
+        if pred(&guard) {
+            return Some(guard);
+        }
+        // Counted while holding the lock, so every unlock after ours sees
+        // us, also when the predicate panics.
+        self.when.watchers.fetch_add(1, Ordering::Relaxed);
+        let _watching = Unwatch(&self.when);
+        loop {
+            // Read before unlocking, so an unlock after a change made
+            // after our check always moves it before we park. Our own
+            // unlock changed nothing, so it mustn't move it.
+            let generation = self.when.generation.load(Ordering::Acquire);
+            MutexGuard::unlock_unchanged(guard);
+            match deadline {
+                None => wait(&self.when.generation, generation),
+                Some(deadline) => {
+                    if !wait_until(&self.when.generation, generation, deadline) {
+                        return None;
+                    }
+                }
+            }
+            guard = relock()?;
+            if pred(&guard) {
+                return Some(guard);
+            }
+        }
+    }
+
+    /// A raw pointer to the protected value, without locking. Reading or
+    /// writing through it is only sound while the caller otherwise
+    /// ensures exclusion, e.g. by holding a guard or a leaked lock.
+    pub fn data_ptr(&self) -> *mut T {
+        UnsafeCell::raw_get(self.inner.as_ptr())
+    }
+
+    /// Unlock the mutex without a guard, waking a waiter, after its guard
+    /// was given up with [MutexGuard::leak] or [std::mem::forget].
+    ///
+    /// # Safety
+    ///
+    /// The mutex must be locked, and no guard or reference from
+    /// [MutexGuard::leak] for that lock may be used afterwards. Unlocking
+    /// a mutex that isn't locked is undefined behavior; debug builds
+    /// catch it with an assertion.
+    pub unsafe fn force_unlock(&self) {
+        debug_assert!(self.is_locked(), "force_unlock on an unlocked Mutex");
+        #[cfg(feature = "holder_tracking")]
+        {
+            *self.holder.lock().unwrap_or_else(|e| e.into_inner()) = None;
+        }
+        #[cfg(feature = "recursion_check")]
+        self.owner.clear();
+        #[cfg(feature = "deadlock_detection")]
+        crate::deadlock::released(address(self));
+        #[cfg(feature = "lock_when")]
+        self.when.changed();
+        self.inner.release();
+    }
+
+    /// Take the protected value, leaving `T::default()` in its place.
+    /// The lock is only held for the swap.
+    ///
+    /// ```
+    /// use xlock::mutex::Mutex;
+    ///
+    /// let m = Mutex::new(vec![1, 2]);
+    /// assert_eq!(m.take(), [1, 2]);
+    /// assert!(m.lock().is_empty());
+    /// ```
+    pub fn take(&self) -> T
+    where
+        T: Default,
+    {
+        std::mem::take(&mut *self.lock())
+    }
+
+    /// Clone the protected value out, releasing the lock before
+    /// returning so the caller works on the copy without holding it.
+    ///
+    /// ```
+    /// use xlock::mutex::Mutex;
+    ///
+    /// let m = Mutex::new(String::from("a"));
+    /// let copy = m.get_cloned();
+    /// m.lock().push('b');
+    /// assert_eq!(copy, "a");
+    /// ```
+    #[doc(alias = "read_clone")]
+    pub fn get_cloned(&self) -> T
+    where
+        T: Clone,
+    {
+        self.lock().clone()
+    }
+
+    /// Replace the protected value. The old value is dropped after the
+    /// lock is released.
+    ///
+    /// ```
+    /// use xlock::mutex::Mutex;
+    ///
+    /// let m = Mutex::new(1);
+    /// m.set(2);
+    /// assert_eq!(*m.lock(), 2);
+    /// ```
+    pub fn set(&self, value: T)
+    where
+        T: Sized,
+    {
+        drop(self.replace(value));
+    }
+
+    /// Replace the protected value, returning the old one. The lock is
+    /// only held for the swap.
+    ///
+    /// ```
+    /// use xlock::mutex::Mutex;
+    ///
+    /// let m = Mutex::new(vec![1]);
+    /// assert_eq!(m.replace(vec![2]), [1]);
+    /// assert_eq!(*m.lock(), [2]);
+    /// ```
+    pub fn replace(&self, value: T) -> T
+    where
+        T: Sized,
+    {
+        std::mem::replace(&mut *self.lock(), value)
+    }
+
+    /// Lock, run `f` on the protected value and unlock before returning,
+    /// even if `f` panics.
+    ///
+    /// The result can't borrow from the protected value:
+    ///
+    /// ```compile_fail
+    /// use xlock::mutex::Mutex;
+    ///
+    /// let m = Mutex::new(vec![1]);
+    /// let first = m.with(|v| &v[0]);
+    /// ```
+    pub fn with<R>(&self, f: impl FnOnce(&T) -> R) -> R {
+        f(&self.lock())
+    }
+
+    /// Like [Mutex::with], but `f` can modify the protected value.
+    ///
+    /// ```
+    /// use xlock::mutex::Mutex;
+    ///
+    /// let m = Mutex::new(1);
+    /// let old = m.with_mut(|n| std::mem::replace(n, *n * 10));
+    /// assert_eq!((old, *m.lock()), (1, 10));
+    /// ```
+    #[doc(alias = "update", alias = "with_lock")]
+    pub fn with_mut<R>(&self, f: impl FnOnce(&mut T) -> R) -> R {
+        f(&mut self.lock())
+    }
+
+    /// Like [Mutex::with], but returns `None` without running `f` if the
+    /// mutex is locked.
+    pub fn try_with<R>(&self, f: impl FnOnce(&T) -> R) -> Option<R> {
+        let guard = self.try_lock()?;
+        Some(f(&guard))
+    }
+
+    /// Like [Mutex::with_mut], but returns `None` without running `f` if
+    /// the mutex is locked.
+    pub fn try_with_mut<R>(&self, f: impl FnOnce(&mut T) -> R) -> Option<R> {
+        let mut guard = self.try_lock()?;
+        Some(f(&mut guard))
+    }
+}
+
+/// Lock two mutexes without risking a deadlock against threads locking
+/// the same pair in the opposite order: both are always locked in order
+/// of address, whatever the argument order.
+///
+/// # Panics
+///
+/// If `a` and `b` are the same mutex, which could never be locked twice.
+pub fn lock_both<'a, A: ?Sized, B: ?Sized>(
+    a: &'a Mutex<A>,
+    b: &'a Mutex<B>,
+) -> (MutexGuard<'a, A>, MutexGuard<'a, B>) {
+    let (pa, pb) = (address(a), address(b));
+    assert_ne!(pa, pb, "lock_both called with the same mutex twice");
+    if pa < pb {
+        let a = a.lock();
+        (a, b.lock())
+    } else {
+        let b = b.lock();
+        (a.lock(), b)
+    }
+}
+
+/// Lock two mutexes only if both are free right now. If either is
+/// locked, nothing stays locked. Returns `None` for the same mutex twice.
+pub fn try_lock_both<'a, A: ?Sized, B: ?Sized>(
+    a: &'a Mutex<A>,
+    b: &'a Mutex<B>,
+) -> Option<(MutexGuard<'a, A>, MutexGuard<'a, B>)> {
+    let a = a.try_lock()?;
+    let b = b.try_lock()?;
+    Some((a, b))
+}
+
+/// Lock several mutexes in a deadlock-free order, like [lock_both]. The
+/// guards come back in the order of `mutexes`.
+///
+/// # Panics
+///
+/// If the same mutex appears more than once.
+pub fn lock_all<'a, T: ?Sized, const N: usize>(
+    mutexes: [&'a Mutex<T>; N],
+) -> [MutexGuard<'a, T>; N] {
+    let mut order: [usize; N] = std::array::from_fn(|i| i);
+    order.sort_unstable_by_key(|&i| address(mutexes[i]));
+    for pair in order.windows(2) {
+        assert_ne!(
+            address(mutexes[pair[0]]),
+            address(mutexes[pair[1]]),
+            "lock_all called with the same mutex twice"
+        );
+    }
+    let mut guards: [Option<MutexGuard<'a, T>>; N] = std::array::from_fn(|_| None);
+    for i in order {
+        guards[i] = Some(mutexes[i].lock());
+    }
+    guards.map(|guard| guard.unwrap())
+}
+
+/// The address of a mutex, for ordering locks.
+fn address<T: ?Sized>(mutex: &Mutex<T>) -> usize {
+    (mutex as *const Mutex<T>).cast::<u8>() as usize
+}
+
+/// Unlock a mutex locked by [Mutex::lock_raw_ptr].
+///
+/// # Safety
+///
+/// `handle` must come from [Mutex::lock_raw_ptr] on a mutex that is
+/// still alive, and the data pointer returned with it must not be used
+/// after this call.
+#[no_mangle]
+pub unsafe extern "C" fn guard_unlock(handle: RawUnlockHandle) {
+    (handle.unlock)(handle.mutex);
+}
+
+use std::marker::PhantomData;
+use std::ops::{Deref, DerefMut};
+use std::pin::Pin;
+use std::ptr::NonNull;
+
+impl<'a, T: ?Sized> MutexGuard<'a, T> {
+    /// The mutex this guard locks.
+    #[cfg(feature = "std")]
+    pub(crate) fn mutex(&self) -> &'a Mutex<T> {
+        self.mutex
+    }
+
+    /// Give up access to the protected value but keep the mutex locked
+    /// until the returned token is dropped, possibly on another thread.
+    /// With `holder_tracking`, the mutex reports no holder from here on.
+    pub fn into_release_token(self) -> ReleaseToken<'a> {
+        ReleaseToken {
+            _permit: self.guard.into_permit(),
+        }
+    }
+
+    /// Unlock, handing the mutex to a thread already waiting for it if
+    /// there is one. Neither the calling thread nor a thread that starts
+    /// locking afterwards can take it first. Slower than dropping the
+    /// guard when the unlocking thread would relock right away.
+    #[cfg(feature = "eventual_fairness")]
+    pub fn unlock_fair(this: Self) {
+        let MutexGuard {
+            mutex: _,
+            #[cfg(feature = "poison")]
+            _poison,
+            #[cfg(feature = "lock_when")]
+            _changed,
+            #[cfg(feature = "holder_tracking")]
+            _holder,
+            #[cfg(feature = "recursion_check")]
+            _owner,
+            #[cfg(feature = "deadlock_detection")]
+            _held,
+            guard,
+        } = this;
+        // In declaration order, as when the guard is dropped.
+        #[cfg(feature = "poison")]
+        drop(_poison);
+        #[cfg(feature = "lock_when")]
+        drop(_changed);
+        #[cfg(feature = "holder_tracking")]
+        drop(_holder);
+        #[cfg(feature = "recursion_check")]
+        drop(_owner);
+        #[cfg(feature = "deadlock_detection")]
+        drop(_held);
+        guard.into_permit().release_fair();
+    }
+
+    /// Unlock without waking threads in [Mutex::lock_when], for a guard
+    /// the value wasn't changed through.
+    #[cfg(feature = "lock_when")]
+    fn unlock_unchanged(this: Self) {
+        let MutexGuard { _changed, .. } = this;
+        std::mem::forget(_changed);
+    }
+
+    /// Keep the mutex locked for good and return a reference to the
+    /// protected value that lives as long as the mutex borrow. Other
+    /// threads wait until [Mutex::force_unlock] is called, if ever.
+    ///
+    /// ```
+    /// use xlock::mutex::{Mutex, MutexGuard};
+    ///
+    /// let m = Mutex::new(vec![1]);
+    /// let v = MutexGuard::leak(m.lock());
+    /// v.push(2);
+    /// assert!(m.try_lock().is_none());
+    /// // SAFETY: `v` is not used again.
+    /// unsafe { m.force_unlock() };
+    /// assert_eq!(*m.lock(), [1, 2]);
+    /// ```
+    pub fn leak(this: Self) -> &'a mut T {
+        let data = this.guard.get();
+        std::mem::forget(this);
+        // SAFETY: The lock stays held, so nothing else reaches the value
+        // until force_unlock, whose caller gives up this borrow.
+        unsafe { &mut *data }
+    }
+
+    /// Narrow the guard down to the part of the value returned by `f`.
+    /// The mutex stays locked until the mapped guard is dropped.
+    pub fn map<U: ?Sized>(self, f: impl FnOnce(&mut T) -> &mut U) -> MappedMutexGuard<'a, U> {
+        let data = self.guard.get();
+        let value = NonNull::from(f(unsafe { &mut *data }));
+        self.into_mapped(value)
+    }
+
+    /// Like [MutexGuard::map], but `f` may decline by returning `None`, in
+    /// which case the original guard is handed back.
+    pub fn try_map<U: ?Sized>(
+        self,
+        f: impl FnOnce(&mut T) -> Option<&mut U>,
+    ) -> Result<MappedMutexGuard<'a, U>, Self> {
+        let data = self.guard.get();
+        match f(unsafe { &mut *data }) {
+            Some(value) => {
+                let value = NonNull::from(value);
+                Ok(self.into_mapped(value))
+            }
+            None => Err(self),
+        }
+    }
+
+    fn into_mapped<U: ?Sized>(self, value: NonNull<U>) -> MappedMutexGuard<'a, U> {
+        let MutexGuard {
+            mutex: _,
+            #[cfg(feature = "poison")]
+            _poison,
+            #[cfg(feature = "lock_when")]
+            _changed,
+            #[cfg(feature = "holder_tracking")]
+            _holder,
+            #[cfg(feature = "recursion_check")]
+            _owner,
+            #[cfg(feature = "deadlock_detection")]
+            _held,
+            guard,
+        } = self;
+        MappedMutexGuard {
+            value,
+            #[cfg(feature = "poison")]
+            _poison,
+            #[cfg(feature = "lock_when")]
+            _changed,
+            #[cfg(feature = "holder_tracking")]
+            _holder,
+            #[cfg(feature = "recursion_check")]
+            _owner,
+            #[cfg(feature = "deadlock_detection")]
+            _held,
+            _permit: guard.into_permit(),
+            _marker: PhantomData,
+        }
+    }
+
+    /// Unlock the mutex, run `f`, and lock it again before returning, so
+    /// others get a turn in the middle of a long critical section. The
+    /// guard is borrowed for the duration, so `f` can't use it.
+    ///
+    /// The mutex is relocked the usual way, waiting behind whoever took
+    /// it, and also if `f` panics.
+    pub fn unlocked<R>(&mut self, f: impl FnOnce() -> R) -> R {
+        /// Relocks the mutex when dropped, including during a panic, so
+        /// the guard always holds the lock it releases when dropped.
+        struct Relock<'a, T: ?Sized>(&'a Mutex<T>);
+
+        impl<T: ?Sized> Drop for Relock<'_, T> {
+            fn drop(&mut self) {
+                std::mem::forget(self.0.access());
+                #[cfg(feature = "deadlock_detection")]
+                crate::deadlock::acquired(address(self.0));
+                #[cfg(feature = "recursion_check")]
+                self.0.owner.set();
+                #[cfg(feature = "holder_tracking")]
+                {
+                    *self.0.holder.lock().unwrap_or_else(|e| e.into_inner()) =
+                        Some(std::thread::current().id());
+                }
+            }
+        }
+
+        // SAFETY: The guard holds the lock, and Relock takes it back
+        // before the guard can be used or dropped again.
+        unsafe { self.mutex.force_unlock() };
+        let _relock = Relock(self.mutex);
+        f()
+    }
+
+    /// Give waiters a turn if there are any, like [MutexGuard::unlocked]
+    /// with nothing to run. When nobody is waiting this is cheap and keeps
+    /// the lock.
+    pub fn bump(&mut self) {
+        if self.mutex.inner.has_waiters() {
+            #[cfg(feature = "std")]
+            self.unlocked(std::thread::yield_now);
+            #[cfg(not(feature = "std"))]
+            self.unlocked(std::hint::spin_loop);
+        }
+    }
+
+    /// Get a pinned mutable reference to the protected value.
+    ///
+    /// Pinning is not structural for [Mutex]: any thread can call
+    /// [Mutex::lock] through a shared reference and move the value out of
+    /// the `&mut T` it gets back, so the mutex itself cannot promise the
+    /// value stays put. When the data must be pinned in safe code, store a
+    /// `Pin<Box<T>>` in the mutex and use `Pin::as_mut` on it instead.
+    ///
+    /// # Safety
+    ///
+    /// Same contract as [Pin::new_unchecked]: once this has been called,
+    /// the protected value must not be moved again (by any thread, through
+    /// any guard or method such as [Mutex::take]) until it is dropped.
+    pub unsafe fn as_pin_mut(&mut self) -> Pin<&mut T> {
+        Pin::new_unchecked(&mut **self)
+    }
+}
+
+#[cfg(feature = "std")]
+impl<T: ?Sized> ArcMutexGuard<T> {
+    /// Wrap a mutex whose lock was just taken, with its guard forgotten.
+    fn new(mutex: Arc<Mutex<T>>) -> Self {
+        #[cfg(feature = "deadlock_detection")]
+        crate::deadlock::acquired(address(&*mutex));
+        #[cfg(feature = "holder_tracking")]
+        {
+            *mutex.holder.lock().unwrap_or_else(|e| e.into_inner()) =
+                Some(std::thread::current().id());
+        }
+        Self {
+            mutex,
+            _marker: PhantomData,
+        }
+    }
+
+    /// The mutex this guard locks.
+    pub fn mutex(this: &Self) -> &Arc<Mutex<T>> {
+        &this.mutex
+    }
+}
+
+#[cfg(feature = "std")]
+impl<T: ?Sized> Drop for ArcMutexGuard<T> {
+    fn drop(&mut self) {
+        #[cfg(feature = "poison")]
+        if std::thread::panicking() {
+            self.mutex.poisoned.store(true, Ordering::Relaxed);
+        }
+        #[cfg(feature = "holder_tracking")]
+        {
+            *self.mutex.holder.lock().unwrap_or_else(|e| e.into_inner()) = None;
+        }
+        #[cfg(feature = "deadlock_detection")]
+        crate::deadlock::released(address(&*self.mutex));
+        #[cfg(feature = "lock_when")]
+        self.mutex.when.changed();
+        // SAFETY: The lock was taken in lock_arc and its guard forgotten.
+        unsafe { self.mutex.inner.release() }
+    }
+}
+
+#[cfg(feature = "poison")]
+impl Drop for PoisonOnPanic<'_> {
+    fn drop(&mut self) {
+        // A thread-local check, so unpoisoned unlocks cost no atomics.
+        if std::thread::panicking() {
+            self.0.store(true, Ordering::Relaxed);
+        }
+    }
+}
+
+#[cfg(feature = "lock_when")]
+impl When {
+    const fn new() -> Self {
+        Self {
+            generation: AtomicU32::new(0),
+            watchers: AtomicU32::new(0),
+        }
+    }
+
+    /// Wake the threads in [Mutex::lock_when], if any. Called with the
+    /// lock still held, which orders this load after their count.
+    fn changed(&self) {
+        if self.watchers.load(Ordering::Relaxed) != 0 {
+            self.generation.fetch_add(1, Ordering::Release);
+            wake_all(&self.generation);
+        }
+    }
+}
+
+#[cfg(feature = "lock_when")]
+impl Drop for Changed<'_> {
+    fn drop(&mut self) {
+        self.0.changed();
+    }
+}
+
+/// Counts a thread out of [Mutex::lock_when] when dropped.
+#[cfg(feature = "lock_when")]
+struct Unwatch<'a>(&'a When);
+
+#[cfg(feature = "lock_when")]
+impl Drop for Unwatch<'_> {
+    fn drop(&mut self) {
+        self.0.watchers.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+#[cfg(feature = "holder_tracking")]
+impl Drop for Holder<'_> {
+    fn drop(&mut self) {
+        *self.0.lock().unwrap_or_else(|e| e.into_inner()) = None;
+    }
+}
+
+#[cfg(feature = "recursion_check")]
+impl Owner {
+    const fn new() -> Self {
+        Self {
+            thread: AtomicUsize::new(0),
+            location: AtomicPtr::new(std::ptr::null_mut()),
+        }
+    }
+
+    /// Panic if the current thread holds the lock. Only the owner itself
+    /// ever stores its own id, so relaxed loads can't give a false match.
+    #[track_caller]
+    fn check(&self) {
+        if self.thread.load(Ordering::Relaxed) == current_thread() {
+            // SAFETY: Set together with `thread`, to a 'static location.
+            let location = unsafe { &*self.location.load(Ordering::Relaxed) };
+            panic!("Mutex locked twice by the same thread; it was locked at {location}");
+        }
+    }
+
+    /// Record the current thread and the caller as holding the lock.
+    #[track_caller]
+    fn set(&self) {
+        let location: *const Location<'static> = Location::caller();
+        self.location.store(location.cast_mut(), Ordering::Relaxed);
+        self.thread.store(current_thread(), Ordering::Relaxed);
+    }
+
+    fn clear(&self) {
+        self.thread.store(0, Ordering::Relaxed);
+    }
+}
+
+#[cfg(feature = "recursion_check")]
+impl Drop for ClearOwner<'_> {
+    fn drop(&mut self) {
+        self.0.clear();
+    }
+}
+
+impl<T: ?Sized + fmt::Debug> fmt::Debug for Mutex<T> {
+    /// Shows the value if the mutex is free, without waiting for it.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut d = f.debug_struct("Mutex");
+        match self.try_lock() {
+            Some(guard) => d.field("data", &&*guard),
+            None => d.field("data", &format_args!("<locked>")),
+        };
+        #[cfg(feature = "poison")]
+        d.field("poisoned", &self.is_poisoned());
+        d.finish_non_exhaustive()
+    }
+}
+
+impl<T: Default> Default for Mutex<T> {
+    fn default() -> Self {
+        Self::new(T::default())
+    }
+}
+
+impl<T> From<T> for Mutex<T> {
+    fn from(value: T) -> Self {
+        Self::new(value)
+    }
+}
+
+impl<T: Clone> Clone for Mutex<T> {
+    /// Lock, waiting for any holder to finish, and clone the value into a
+    /// new unlocked mutex.
+    fn clone(&self) -> Self {
+        Self::new(self.get_cloned())
+    }
+}
+
+impl<T> FirstLockMutex<T> {
+    /// Create a new unlocked mutex guarding `value`, never locked before.
+    pub const fn new(value: T) -> Self {
+        Self {
+            locked_before: AtomicBool::new(false),
+            mutex: Mutex::new(value),
+        }
+    }
+}
+
+impl<T: ?Sized> FirstLockMutex<T> {
+    /// Lock the mutex, also reporting whether this is the first time it
+    /// has been locked through this method. Exactly one caller ever sees
+    /// `true`, and it holds the lock while it does.
+    pub fn lock_first(&self) -> (MutexGuard<'_, T>, bool) {
+        let guard = self.mutex.lock();
+        // The lock orders this swap against every other caller.
+        let first = !self.locked_before.swap(true, Ordering::Relaxed);
+        (guard, first)
+    }
+}
+
+impl<T: ?Sized + fmt::Debug> fmt::Debug for FirstLockMutex<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(&self.mutex, f)
+    }
+}
+
+impl<T: Default> Default for FirstLockMutex<T> {
+    fn default() -> Self {
+        Self::new(T::default())
+    }
+}
+
+impl<T> From<T> for FirstLockMutex<T> {
+    fn from(value: T) -> Self {
+        Self::new(value)
+    }
+}
+
+impl<T: ?Sized + fmt::Debug> fmt::Debug for MutexGuard<'_, T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(&**self, f)
+    }
+}
+
+impl<U: ?Sized + fmt::Debug> fmt::Debug for MappedMutexGuard<'_, U> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(&**self, f)
+    }
+}
+
+#[cfg(feature = "std")]
+impl<T: ?Sized + fmt::Debug> fmt::Debug for ArcMutexGuard<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(&**self, f)
+    }
+}
+
+impl<T: ?Sized> Deref for MutexGuard<'_, T> {
+    type Target = T;
+    fn deref(&self) -> &T {
+        unsafe { &*self.guard.deref().get() }
+    }
+}
+
+impl<U: ?Sized> Deref for MappedMutexGuard<'_, U> {
+    type Target = U;
+    fn deref(&self) -> &U {
+        unsafe { self.value.as_ref() }
+    }
+}
+
+impl<U: ?Sized> DerefMut for MappedMutexGuard<'_, U> {
+    fn deref_mut(&mut self) -> &mut U {
+        unsafe { self.value.as_mut() }
+    }
+}
+
+#[cfg(feature = "std")]
+impl<T: ?Sized> Deref for ArcMutexGuard<T> {
+    type Target = T;
+    fn deref(&self) -> &T {
+        // SAFETY: The guard holds the lock.
+        unsafe { &*self.mutex.inner.get_unchecked().get() }
+    }
+}
+
+#[cfg(feature = "std")]
+impl<T: ?Sized> DerefMut for ArcMutexGuard<T> {
+    fn deref_mut(&mut self) -> &mut T {
+        // SAFETY: The guard holds the lock.
+        unsafe { &mut *self.mutex.inner.get_unchecked().get() }
+    }
+}
+
+impl<T: ?Sized> DerefMut for MutexGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.guard.deref().get() }
+    }
+}
+
+impl<T: ?Sized> Deref for FirstLockMutex<T> {
+    type Target = Mutex<T>;
+    fn deref(&self) -> &Mutex<T> {
+        &self.mutex
+    }
+}
+
+#[cfg(all(test, feature = "std"))]
+mod test {
+    use super::*;
+
+    #[cfg(feature = "async")]
+    #[test]
+    fn lock_async_with_blocking_lockers() {
+        use crate::wakers::block_on;
+        fn assert_send<F: Send>(f: F) -> F {
+            f
+        }
+
+        let m = Mutex::new(0);
+        std::thread::scope(|s| {
+            for i in 0..4 {
+                let m = &m;
+                s.spawn(move || {
+                    for _ in 0..100 {
+                        if i % 2 == 0 {
+                            *block_on(assert_send(m.lock_async())) += 1;
+                        } else {
+                            *m.lock() += 1;
+                        }
+                    }
+                });
+            }
+        });
+        assert_eq!(*m.lock(), 400);
+    }
+
+    #[cfg(feature = "send_guard")]
+    #[test]
+    fn guard_unlocked_on_another_thread() {
+        let m = Mutex::new(0);
+        std::thread::scope(|s| {
+            let mut guard = m.lock();
+            *guard += 1;
+            s.spawn(move || {
+                *guard += 1;
+                drop(guard);
+            })
+            .join()
+            .unwrap();
+            assert_eq!(*m.lock(), 2);
+            let mapped = m.lock().map(|v| v);
+            s.spawn(move || drop(mapped)).join().unwrap();
+            assert!(m.try_lock().is_some());
+        });
+    }
+
+    #[test]
+    fn is_locked_follows_guard() {
+        let m = Mutex::new(0);
+        assert!(!m.is_locked());
+        let guard = m.lock();
+        assert!(m.is_locked());
+        drop(guard);
+        assert!(!m.is_locked());
+    }
+
+    #[test]
+    fn arc_guard_dropped_on_another_thread() {
+        let m = Arc::new(Mutex::new(0));
+        let (tx, rx) = std::sync::mpsc::channel::<ArcMutexGuard<i32>>();
+        let worker = std::thread::spawn(move || {
+            let mut guard = rx.recv().unwrap();
+            *guard += 1;
+        });
+        let guard = m.lock_arc();
+        assert!(m.try_lock().is_none());
+        tx.send(guard).unwrap();
+        worker.join().unwrap();
+        assert_eq!(*m.lock(), 1);
+        assert!(m.try_lock_arc().is_some());
+    }
+
+    #[test]
+    fn arc_guard_excludes_other_lockers() {
+        let m = Arc::new(Mutex::new(0));
+        let workers: Vec<_> = (0..4)
+            .map(|_| {
+                let m = Arc::clone(&m);
+                std::thread::spawn(move || {
+                    for _ in 0..100 {
+                        let mut guard = m.lock_arc();
+                        // Unlock on a different thread half of the time.
+                        if *guard % 2 == 0 {
+                            *guard += 1;
+                            std::thread::spawn(move || drop(guard)).join().unwrap();
+                        } else {
+                            *guard += 1;
+                        }
+                    }
+                })
+            })
+            .collect();
+        for worker in workers {
+            worker.join().unwrap();
+        }
+        assert_eq!(*m.lock(), 400);
+    }
+
+    #[test]
+    fn mutex_test_single_thread() {
+        let m = Mutex::new(0);
+        std::hint::black_box(&m);
+        for _ in 0..100 {
+            *m.lock() += 1;
+        }
+        assert_eq!(*m.lock(), 100);
+    }
+
+    #[test]
+    fn mutex_test_multi_threads() {
+        let m = Mutex::new(0);
+        std::hint::black_box(&m);
+        std::thread::scope(|s| {
+            for _ in 0..4 {
+                s.spawn(|| {
+                    for _ in 0..100 {
+                        *m.lock() += 1;
+                    }
+                });
+            }
+        });
+        assert_eq!(*m.lock(), 400);
+    }
+
+    /// Throughput of tiny critical sections under contention. Run with
+    /// `cargo test --release -- --ignored --nocapture contended_throughput`.
+    #[test]
+    #[ignore]
+    fn contended_throughput() {
+        const PER_THREAD: u64 = 200_000;
+        for threads in [4, 8] {
+            let m = Mutex::new(0u64);
+            let start = Instant::now();
+            std::thread::scope(|s| {
+                for _ in 0..threads {
+                    s.spawn(|| {
+                        for _ in 0..PER_THREAD {
+                            *m.lock() += 1;
+                        }
+                    });
+                }
+            });
+            let elapsed = start.elapsed();
+            assert_eq!(m.into_inner(), threads * PER_THREAD);
+            println!(
+                "{threads} threads: {:.1} Mlocks/s",
+                (threads * PER_THREAD) as f64 / elapsed.as_secs_f64() / 1e6
+            );
+        }
+    }
 
-impl<T> Mutex<T> {
-    /// Create a new Mutex guarding value T.
-    pub fn new(value: T) -> Self {
-        Self {
-            inner: SemVar::new(1, UnsafeCell::new(value)),
+    /// Cost of an uncontended lock and unlock, which make no syscall. Run
+    /// with `cargo test --release -- --ignored --nocapture uncontended`.
+    #[test]
+    #[ignore]
+    fn uncontended_lock_unlock() {
+        const ITERATIONS: u32 = 10_000_000;
+        let m = Mutex::new(0u32);
+        let start = Instant::now();
+        for _ in 0..ITERATIONS {
+            *std::hint::black_box(&m).lock() += 1;
         }
+        let elapsed = start.elapsed();
+        assert_eq!(m.into_inner(), ITERATIONS);
+        println!(
+            "{:.1} ns per lock/unlock",
+            elapsed.as_nanos() as f64 / f64::from(ITERATIONS)
+        );
     }
 
-    /// Try to gain access to the protected value. Returns
-    /// a [SemGuard].
-    pub fn lock(&self) -> MutexGuard<T> {
-        let guard = self.inner.access();
-        MutexGuard(guard)
+    #[test]
+    fn with_releases_the_lock() {
+        let m = Mutex::new(vec![1, 2]);
+        assert_eq!(m.with(|v| v.len()), 2);
+        m.with_mut(|v| v.push(3));
+        assert_eq!(m.try_with(|v| v[2]), Some(3));
+        let guard = m.lock();
+        assert_eq!(m.try_with_mut(|v| v.clear()), None);
+        drop(guard);
+        assert_eq!(m.try_with_mut(|v| v.pop()), Some(Some(3)));
     }
-}
 
-use std::ops::{Deref, DerefMut};
+    #[test]
+    fn with_releases_when_closure_panics() {
+        let m = Mutex::new(0);
+        let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            m.with_mut(|_| panic!("boom"))
+        }));
+        assert!(result.is_err());
+        assert!(m.try_lock().is_some());
+        #[cfg(feature = "poison")]
+        assert!(m.is_poisoned());
+    }
 
-impl<T> Deref for MutexGuard<'_, T> {
-    type Target = T;
-    fn deref(&self) -> &T {
-        unsafe { &*self.0.deref().get() }
+    #[test]
+    fn unlocked_lets_others_in() {
+        let m = Mutex::new(0);
+        let mut guard = m.lock();
+        guard.unlocked(|| {
+            std::thread::scope(|s| {
+                s.spawn(|| *m.lock() = 5);
+            });
+        });
+        assert_eq!(*guard, 5);
+        assert!(m.try_lock().is_none());
     }
-}
 
-impl<T> DerefMut for MutexGuard<'_, T> {
-    fn deref_mut(&mut self) -> &mut T {
-        unsafe { &mut *self.0.deref().get() }
+    #[test]
+    fn unlocked_relocks_on_panic() {
+        let m = Mutex::new(0);
+        let mut guard = m.lock();
+        let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            guard.unlocked(|| panic!("boom"))
+        }));
+        assert!(result.is_err());
+        assert!(m.try_lock().is_none());
+        drop(guard);
+        assert!(m.try_lock().is_some());
     }
-}
 
-#[cfg(test)]
-mod test {
-    use super::*;
+    #[test]
+    fn bump_hands_over_to_waiter() {
+        let m = Mutex::new(false);
+        let mut guard = m.lock();
+        // Nobody waiting yet: keeps the lock.
+        guard.bump();
+        assert!(m.try_lock().is_none());
+        std::thread::scope(|s| {
+            s.spawn(|| *m.lock() = true);
+            while !*guard {
+                guard.bump();
+                std::thread::yield_now();
+            }
+        });
+    }
 
     #[test]
-    fn mutex_test_single_thread() {
+    fn opposite_order_transfers_dont_deadlock() {
+        let iterations = if cfg!(miri) { 20 } else { 10_000 };
+        let a = Mutex::new(1_000i64);
+        let b = Mutex::new(1_000i64);
+        std::thread::scope(|s| {
+            s.spawn(|| {
+                for _ in 0..iterations {
+                    let (mut from, mut to) = lock_both(&a, &b);
+                    *from -= 1;
+                    *to += 1;
+                }
+            });
+            s.spawn(|| {
+                for _ in 0..iterations {
+                    let (mut from, mut to) = lock_both(&b, &a);
+                    *from -= 1;
+                    *to += 1;
+                }
+            });
+            s.spawn(|| {
+                for _ in 0..iterations {
+                    let [x, y] = lock_all([&b, &a]);
+                    assert_eq!(*x + *y, 2_000);
+                }
+            });
+        });
+        assert_eq!((*a.lock(), *b.lock()), (1_000, 1_000));
+    }
+
+    #[test]
+    #[should_panic(expected = "same mutex twice")]
+    fn lock_both_rejects_aliasing() {
         let m = Mutex::new(0);
-        std::hint::black_box(&m);
-        for _ in 0..100 {
-            *m.lock() += 1;
+        let _ = lock_both(&m, &m);
+    }
+
+    #[test]
+    fn try_lock_both_releases_on_failure() {
+        let a = Mutex::new(0);
+        let b = Mutex::new(0);
+        let held = b.lock();
+        assert!(try_lock_both(&a, &b).is_none());
+        assert!(a.try_lock().is_some());
+        drop(held);
+        assert!(try_lock_both(&a, &b).is_some());
+        assert!(try_lock_both(&a, &a).is_none());
+        let c = Mutex::new(7);
+        let [x, y, z] = lock_all([&b, &a, &c]);
+        assert_eq!((*x, *y, *z), (0, 0, 7));
+    }
+
+    #[test]
+    fn debug_output() {
+        let poisoned = if cfg!(feature = "poison") {
+            "poisoned: false, "
+        } else {
+            ""
+        };
+        let m = Mutex::new(vec![1]);
+        assert_eq!(
+            format!("{m:?}"),
+            format!("Mutex {{ data: [1], {poisoned}.. }}")
+        );
+        let guard = m.lock();
+        assert_eq!(format!("{guard:?}"), "[1]");
+        assert_eq!(
+            format!("{m:?}"),
+            format!("Mutex {{ data: <locked>, {poisoned}.. }}")
+        );
+    }
+
+    #[test]
+    fn default_from_and_clone() {
+        assert_eq!(Mutex::<u8>::default().into_inner(), 0);
+        assert_eq!(Mutex::from(3).into_inner(), 3);
+
+        let m = Mutex::new(0);
+        let (tx, rx) = std::sync::mpsc::channel();
+        std::thread::scope(|s| {
+            s.spawn(|| {
+                let mut guard = m.lock();
+                *guard = 1;
+                tx.send(()).unwrap();
+                std::thread::sleep(Duration::from_millis(20));
+                *guard = 2;
+            });
+            rx.recv().unwrap();
+            // Waits for the holder instead of copying the halfway state.
+            assert_eq!(m.clone().into_inner(), 2);
+        });
+    }
+
+    #[cfg(feature = "recursion_check")]
+    #[test]
+    fn relocking_panics_with_location() {
+        let m = Mutex::new(0);
+        std::thread::scope(|s| {
+            let err = s
+                .spawn(|| {
+                    let _guard = m.lock();
+                    // try_lock just fails.
+                    assert!(m.try_lock().is_none());
+                    let _again = m.lock();
+                })
+                .join()
+                .unwrap_err();
+            let message = err.downcast_ref::<String>().unwrap();
+            assert!(message.contains("locked twice"), "{message}");
+            assert!(message.contains(file!()), "{message}");
+        });
+        // The panic released the lock, and other threads are unaffected.
+        assert!(m.try_lock().is_some());
+        let _guard = m.lock();
+        std::thread::scope(|s| {
+            s.spawn(|| assert!(m.try_lock().is_none()));
+        });
+    }
+
+    #[test]
+    fn take_leaves_default() {
+        let m = Mutex::new(vec![1u8, 2, 3]);
+        assert_eq!(m.take(), vec![1, 2, 3]);
+        assert!(m.lock().is_empty());
+    }
+
+    #[test]
+    fn take_under_contention() {
+        let m = Mutex::new(Vec::<u8>::new());
+        let taken = Mutex::new(0);
+        std::thread::scope(|s| {
+            for _ in 0..4 {
+                s.spawn(|| {
+                    for _ in 0..100 {
+                        m.lock().push(1);
+                    }
+                });
+            }
+            s.spawn(|| {
+                for _ in 0..100 {
+                    *taken.lock() += m.take().len();
+                }
+            });
+        });
+        assert_eq!(*taken.lock() + m.take().len(), 400);
+        assert!(m.lock().is_empty());
+    }
+
+    #[test]
+    fn get_cloned_is_independent() {
+        let m = Mutex::new(vec![1, 2]);
+        let mut copy = m.get_cloned();
+        m.lock().push(3);
+        copy.push(4);
+        assert_eq!(*m.lock(), vec![1, 2, 3]);
+        assert_eq!(copy, vec![1, 2, 4]);
+    }
+
+    #[test]
+    fn pinned_access_to_not_unpin_value() {
+        use std::marker::PhantomPinned;
+
+        struct Node {
+            value: u32,
+            _pin: PhantomPinned,
         }
-        assert_eq!(*m.lock(), 100);
+
+        impl Node {
+            fn bump(self: Pin<&mut Self>) {
+                // SAFETY: `value` is not structurally pinned.
+                unsafe { self.get_unchecked_mut().value += 1 }
+            }
+        }
+
+        let m = Mutex::new(Node {
+            value: 0,
+            _pin: PhantomPinned,
+        });
+        for _ in 0..3 {
+            let mut guard = m.lock();
+            // SAFETY: the node is never moved out of the mutex.
+            unsafe { guard.as_pin_mut() }.bump();
+        }
+        assert_eq!(m.lock().value, 3);
     }
 
     #[test]
-    fn mutex_test_multi_threads() {
+    fn release_token_unlocks_from_another_thread() {
+        use std::rc::Rc;
+        use std::sync::atomic::{AtomicBool, Ordering};
+
+        // Rc is neither Send nor Sync, the token must still cross threads.
+        let m = Mutex::new(Rc::new(0));
+        let released = AtomicBool::new(false);
+        let token = m.lock().into_release_token();
+
+        std::thread::scope(|s| {
+            s.spawn(|| {
+                std::thread::sleep(std::time::Duration::from_millis(50));
+                released.store(true, Ordering::SeqCst);
+                drop(token);
+            });
+            // Blocks until the other thread drops the token.
+            let guard = m.lock();
+            assert!(released.load(Ordering::SeqCst));
+            assert_eq!(**guard, 0);
+        });
+    }
+
+    #[test]
+    fn lock_first_is_true_exactly_once() {
+        let m = FirstLockMutex::new(Vec::new());
+        std::thread::scope(|s| {
+            for id in 0..8 {
+                let m = &m;
+                s.spawn(move || {
+                    let (mut guard, first) = m.lock_first();
+                    if first {
+                        assert!(guard.is_empty());
+                        guard.push(id);
+                    }
+                });
+            }
+        });
+        assert_eq!(m.lock().len(), 1);
+        assert!(!m.lock_first().1);
+    }
+
+    #[test]
+    fn with_mut_mixed_with_lock() {
+        let iterations = if cfg!(miri) { 10 } else { 1_000 };
         let m = Mutex::new(0);
-        std::hint::black_box(&m);
+        std::thread::scope(|s| {
+            for i in 0..4 {
+                let m = &m;
+                s.spawn(move || {
+                    for _ in 0..iterations {
+                        if i % 2 == 0 {
+                            m.with_mut(|n| *n += 1);
+                        } else {
+                            *m.lock() += 1;
+                        }
+                    }
+                });
+            }
+        });
+        assert_eq!(m.replace(0), 4 * iterations);
+        assert!(!m.is_locked());
+        assert_eq!(m.get_cloned(), 0);
+    }
+
+    #[cfg(feature = "lock_when")]
+    #[test]
+    fn lock_when_producer_consumer() {
+        let queue = Mutex::new(Vec::new());
+        std::thread::scope(|s| {
+            let consumer = s.spawn(|| {
+                let mut received = 0;
+                while received < 100 {
+                    let mut guard = queue.lock_when(|q| !q.is_empty());
+                    received += guard.len();
+                    guard.clear();
+                }
+                received
+            });
+            for i in 0..100 {
+                queue.lock().push(i);
+            }
+            assert_eq!(consumer.join().unwrap(), 100);
+        });
+    }
+
+    #[cfg(feature = "lock_when")]
+    #[test]
+    fn lock_when_wakes_on_plain_unlocks() {
+        let items = if cfg!(miri) { 10 } else { 100 };
+        let queue = Mutex::new(std::collections::VecDeque::new());
+        std::thread::scope(|s| {
+            let consumer = s.spawn(|| {
+                (0..items)
+                    .map(|_| queue.lock_when(|q| !q.is_empty()).pop_front().unwrap())
+                    .collect::<Vec<_>>()
+            });
+            for i in 0..items {
+                queue.lock().push_back(i);
+            }
+            assert_eq!(consumer.join().unwrap(), (0..items).collect::<Vec<_>>());
+        });
+        assert_eq!(queue.when.watchers.load(Ordering::Relaxed), 0);
+    }
+
+    #[cfg(feature = "lock_when")]
+    #[test]
+    fn lock_when_checks_only_under_the_lock() {
+        let m = Mutex::new(0);
+        let checks = AtomicU32::new(0);
+        let guard = m.lock_when(|_| {
+            assert!(m.is_locked());
+            checks.fetch_add(1, Ordering::Relaxed);
+            true
+        });
+        assert_eq!(checks.load(Ordering::Relaxed), 1);
+        drop(guard);
+        assert!(!m.is_locked());
+    }
+
+    #[cfg(feature = "lock_when")]
+    #[test]
+    fn lock_when_sleeps_while_idle() {
+        let m = Mutex::new(0);
+        let checks = AtomicU32::new(0);
+        let guard = m.lock_when_for(Duration::from_millis(100), |_| {
+            checks.fetch_add(1, Ordering::Relaxed);
+            false
+        });
+        assert!(guard.is_none());
+        // The first check, and maybe a few spurious wakeups.
+        assert!(checks.load(Ordering::Relaxed) <= 5, "{checks:?}");
+    }
+
+    #[cfg(feature = "lock_when")]
+    #[test]
+    fn lock_when_for_gives_up() {
+        let m = Mutex::new(0);
+        std::thread::scope(|s| {
+            s.spawn(|| {
+                for _ in 0..5 {
+                    *m.lock() += 1;
+                    std::thread::sleep(Duration::from_millis(5));
+                }
+            });
+            let start = Instant::now();
+            assert!(m
+                .lock_when_for(Duration::from_millis(50), |&n| n < 0)
+                .is_none());
+            assert!(start.elapsed() >= Duration::from_millis(50));
+        });
+        assert_eq!(m.when.watchers.load(Ordering::Relaxed), 0);
+        assert!(!m.is_locked());
+        let guard = m.lock_when_for(Duration::from_millis(50), |&n| n == 5);
+        assert_eq!(guard.as_deref(), Some(&5));
+    }
+
+    #[cfg(feature = "holder_tracking")]
+    #[test]
+    fn holder_reports_locking_thread() {
+        let m = Mutex::new(0);
+        assert_eq!(m.holder(), None);
+        std::thread::scope(|s| {
+            let (tx, rx) = std::sync::mpsc::channel();
+            let (done_tx, done_rx) = std::sync::mpsc::channel::<()>();
+            let m = &m;
+            let locker = s.spawn(move || {
+                let _guard = m.lock();
+                tx.send(()).unwrap();
+                done_rx.recv().unwrap();
+            });
+            rx.recv().unwrap();
+            assert_eq!(m.holder(), Some(locker.thread().id()));
+            done_tx.send(()).unwrap();
+            locker.join().unwrap();
+        });
+        assert_eq!(m.holder(), None);
+    }
+
+    #[test]
+    fn arc_mutex_with_mut() {
+        use std::sync::Arc;
+
+        let m = Arc::new(Mutex::new(0u32));
+        let handles: Vec<_> = (0..4)
+            .map(|_| {
+                let m = Arc::clone(&m);
+                std::thread::spawn(move || {
+                    for _ in 0..100 {
+                        m.with_mut(|n| *n += 1);
+                    }
+                })
+            })
+            .collect();
+        for handle in handles {
+            handle.join().unwrap();
+        }
+        assert_eq!(m.with(|n| *n), 400);
+    }
+
+    #[test]
+    fn raw_ptr_round_trip() {
+        let m = Mutex::new(0u64);
+
+        extern "C" fn callback(data: *mut u64, handle: RawUnlockHandle) {
+            unsafe {
+                *data += 1;
+                guard_unlock(handle);
+            }
+        }
+
         std::thread::scope(|s| {
             for _ in 0..4 {
                 s.spawn(|| {
                     for _ in 0..100 {
-                        *m.lock() += 1;
+                        let (data, handle) = m.lock_raw_ptr();
+                        callback(data, handle);
                     }
                 });
             }
         });
         assert_eq!(*m.lock(), 400);
     }
+
+    #[cfg(feature = "holder_tracking")]
+    #[test]
+    fn raw_lock_stays_held_until_unlocked() {
+        let m = Mutex::new(0u32);
+        let (_, handle) = m.lock_raw_ptr();
+        assert_eq!(m.holder(), Some(std::thread::current().id()));
+        unsafe { guard_unlock(handle) };
+        assert_eq!(m.holder(), None);
+        assert!(!m.is_locked());
+    }
+
+    #[test]
+    fn leak_then_force_unlock() {
+        let m = Mutex::new(0u32);
+        let iterations = if cfg!(miri) { 5 } else { 100 };
+        for i in 0..iterations {
+            let value = MutexGuard::leak(m.lock());
+            *value += 1;
+            assert!(m.is_locked());
+            assert!(m.try_lock().is_none());
+            unsafe {
+                assert_eq!(*m.data_ptr(), i + 1);
+                m.force_unlock();
+            }
+        }
+        assert!(!m.is_locked());
+        assert_eq!(*m.lock(), iterations);
+    }
+
+    #[test]
+    fn force_unlock_releases_waiters() {
+        let m = Mutex::new(0);
+        let value = MutexGuard::leak(m.lock());
+        std::thread::scope(|s| {
+            let waiters: Vec<_> = (0..3).map(|_| s.spawn(|| *m.lock() += 1)).collect();
+            while !m.inner.has_waiters() {
+                std::thread::yield_now();
+            }
+            *value = 10;
+            unsafe { m.force_unlock() };
+            for waiter in waiters {
+                waiter.join().unwrap();
+            }
+        });
+        assert_eq!(*m.lock(), 13);
+    }
+
+    #[test]
+    fn try_lock_fails_while_held() {
+        let m = Mutex::new(0);
+        std::thread::scope(|s| {
+            let (locked_tx, locked_rx) = std::sync::mpsc::channel();
+            let (release_tx, release_rx) = std::sync::mpsc::channel::<()>();
+            let m = &m;
+            let holder = s.spawn(move || {
+                let mut guard = m.lock();
+                *guard += 1;
+                locked_tx.send(()).unwrap();
+                release_rx.recv().unwrap();
+            });
+            locked_rx.recv().unwrap();
+            assert!(m.try_lock().is_none());
+            release_tx.send(()).unwrap();
+            holder.join().unwrap();
+
+            let mut guard = m.try_lock().expect("mutex was released");
+            assert_eq!(*guard, 1);
+            *guard += 1;
+        });
+        assert_eq!(*m.lock(), 2);
+    }
+
+    #[test]
+    fn lock_for_times_out_while_held() {
+        let m = Mutex::new(0);
+        let _guard = m.lock();
+        std::thread::scope(|s| {
+            s.spawn(|| {
+                let start = Instant::now();
+                assert!(m.lock_for(Duration::from_millis(50)).is_none());
+                assert!(start.elapsed() >= Duration::from_millis(50));
+            });
+        });
+    }
+
+    #[test]
+    fn lock_for_succeeds_when_released_before_deadline() {
+        let m = Mutex::new(0);
+        std::thread::scope(|s| {
+            let guard = m.lock();
+            let waiter = s.spawn(|| {
+                let mut guard = m
+                    .lock_for(Duration::from_secs(10))
+                    .expect("released in time");
+                *guard += 1;
+            });
+            std::thread::sleep(Duration::from_millis(50));
+            drop(guard);
+            waiter.join().unwrap();
+        });
+        assert_eq!(*m.try_lock().unwrap(), 1);
+    }
+
+    #[cfg(feature = "poison")]
+    #[test]
+    fn panic_poisons_until_cleared() {
+        let m = Mutex::new(0);
+        std::thread::scope(|s| {
+            let result = s
+                .spawn(|| {
+                    let mut guard = m.lock();
+                    *guard += 1;
+                    panic!("poison the mutex");
+                })
+                .join();
+            assert!(result.is_err());
+
+            s.spawn(|| {
+                assert!(m.is_poisoned());
+                let Err(poisoned) = m.lock_checked() else {
+                    panic!("expected a poisoned mutex");
+                };
+                let guard = poisoned.into_inner();
+                assert_eq!(*guard, 1);
+                m.clear_poison();
+            })
+            .join()
+            .unwrap();
+        });
+        assert!(!m.is_poisoned());
+        assert!(matches!(m.lock_checked().as_deref(), Ok(1)));
+    }
+
+    #[test]
+    fn get_mut_is_seen_by_lock() {
+        let mut m = Mutex::new(vec![1]);
+        m.get_mut().push(2);
+        assert_eq!(*m.lock(), vec![1, 2]);
+    }
+
+    #[test]
+    fn into_inner_after_concurrent_updates() {
+        let m = Mutex::new(0);
+        std::thread::scope(|s| {
+            for _ in 0..4 {
+                s.spawn(|| {
+                    for _ in 0..100 {
+                        *m.lock() += 1;
+                    }
+                });
+            }
+        });
+        assert_eq!(m.into_inner(), 400);
+    }
+
+    #[test]
+    fn usable_in_statics() {
+        static CONFIG: Mutex<Option<&str>> = Mutex::new(None);
+        static SEM_VAR: SemVar<u8> = SemVar::new(2, 7);
+
+        *CONFIG.lock() = Some("on");
+        assert_eq!(*CONFIG.lock(), Some("on"));
+        assert_eq!(*SEM_VAR.access(), 7);
+    }
+
+    #[cfg(not(any(
+        feature = "lock_when",
+        feature = "poison",
+        feature = "holder_tracking",
+        feature = "recursion_check"
+    )))]
+    #[test]
+    fn no_state_beyond_the_semaphore_by_default() {
+        let sem_var = std::mem::size_of::<SemVar<UnsafeCell<u64>>>();
+        assert_eq!(std::mem::size_of::<Mutex<u64>>(), sem_var);
+    }
+
+    #[test]
+    fn unsized_slice() {
+        let m: Box<Mutex<[u8]>> = Box::new(Mutex::new([0u8; 4]));
+        m.lock()[2] = 7;
+        assert_eq!(&*m.lock(), &[0, 0, 7, 0]);
+    }
+
+    #[test]
+    fn unsized_closure_from_threads() {
+        use std::sync::atomic::AtomicU32;
+
+        let calls = std::sync::Arc::new(AtomicU32::new(0));
+        let counter = std::sync::Arc::clone(&calls);
+        let f: std::sync::Arc<Mutex<dyn FnMut() + Send>> =
+            std::sync::Arc::new(Mutex::new(move || {
+                counter.fetch_add(1, Ordering::Relaxed);
+            }));
+        std::thread::scope(|s| {
+            for _ in 0..4 {
+                s.spawn(|| {
+                    for _ in 0..10 {
+                        (f.lock())();
+                    }
+                });
+            }
+        });
+        assert_eq!(calls.load(Ordering::Relaxed), 40);
+    }
+
+    #[test]
+    fn map_into_vec_element() {
+        let m = Mutex::new((String::from("name"), vec![1, 2, 3]));
+        let mut second = m.lock().map(|(_, v)| &mut v[1]);
+        *second += 10;
+        assert!(m.try_lock().is_none());
+        drop(second);
+        assert_eq!(m.lock().1, vec![1, 12, 3]);
+    }
+
+    #[test]
+    fn try_map_failure_returns_guard() {
+        let m = Mutex::new(vec![1]);
+        let Err(mut guard) = m.lock().try_map(|v| v.get_mut(5)) else {
+            panic!("index 5 should not exist");
+        };
+        guard.push(2);
+        drop(guard);
+        assert_eq!(*m.lock(), vec![1, 2]);
+    }
+
+    #[cfg(feature = "eventual_fairness")]
+    #[test]
+    fn unlock_fair_hands_to_waiter() {
+        let m = Mutex::new(Vec::new());
+        for _ in 0..if cfg!(miri) { 2 } else { 20 } {
+            let guard = m.lock();
+            std::thread::scope(|s| {
+                s.spawn(|| m.lock().push("waiter"));
+                while !m.inner.has_waiters() {
+                    std::thread::yield_now();
+                }
+                MutexGuard::unlock_fair(guard);
+                // Parks until the waiter has had its turn.
+                m.lock().push("unlocker");
+            });
+            let order = std::mem::take(&mut *m.lock());
+            assert_eq!(order, ["waiter", "unlocker"]);
+        }
+    }
+
+    /// A waiter gets the mutex within a few milliseconds even while
+    /// another thread holds it nearly all the time, relocking right after
+    /// each unlock.
+    #[cfg(feature = "eventual_fairness")]
+    #[test]
+    fn eventual_fairness_bounds_waits() {
+        let locks = if cfg!(miri) { 2 } else { 20 };
+        let m = Mutex::new(0u64);
+        let done = AtomicBool::new(false);
+        let longest = std::thread::scope(|s| {
+            s.spawn(|| {
+                while !done.load(Ordering::Relaxed) {
+                    let mut guard = m.lock();
+                    let start = Instant::now();
+                    while start.elapsed() < Duration::from_micros(50) {
+                        *guard += 1;
+                    }
+                }
+            });
+            let mut longest = Duration::ZERO;
+            for _ in 0..locks {
+                std::thread::sleep(Duration::from_millis(1));
+                let asked = Instant::now();
+                drop(m.lock());
+                longest = longest.max(asked.elapsed());
+            }
+            done.store(true, Ordering::Relaxed);
+            longest
+        });
+        assert!(longest < Duration::from_millis(100), "waited {longest:?}");
+    }
 }
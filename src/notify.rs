@@ -0,0 +1,214 @@
+use crate::sys::atomic::{AtomicU32, Ordering};
+use crate::sys::{wait, wake_all, wake_one};
+
+/// One thread counted in [Notify]'s `state` as waiting.
+const WAITER: u32 = 1;
+/// One notification owed to a waiter in `state`.
+const TOKEN: u32 = 1 << 12;
+/// One [Notify::notify_all] counted in `state`.
+const GENERATION: u32 = 1 << 24;
+
+/// The waiter count in a `state` word.
+fn waiters(state: u32) -> u32 {
+    state % TOKEN
+}
+
+/// The notifications owed in a `state` word.
+fn tokens(state: u32) -> u32 {
+    state % GENERATION / TOKEN
+}
+
+/// Wakes threads to tell them something happened, without any data or
+/// lock attached, such as a worker that there may be new work.
+///
+/// Notifications are edge-triggered. [Notify::notify_one] lets exactly one
+/// waiter return; with nobody waiting it is kept as a single permit for
+/// the next [Notify::wait], so one that races ahead of the wait isn't lost.
+/// [Notify::notify_all] lets every thread waiting at the time return, and
+/// leaves nothing behind for later ones.
+///
+/// ```
+/// use xlock::notify::Notify;
+///
+/// let work = Notify::new();
+/// // Sent before anyone waits, so kept for the next wait.
+/// work.notify_one();
+/// work.wait();
+/// ```
+pub struct Notify {
+    /// Waiting threads in the lowest 12 bits, then notifications owed to
+    /// them, then a count of [Notify::notify_all] calls in the top 8 bits.
+    /// With nobody waiting, one owed notification is the stored permit.
+    state: AtomicU32,
+}
+
+impl Notify {
+    /// Create a new Notify with no stored permit.
+    pub const fn new() -> Self {
+        Self {
+            state: AtomicU32::new(0),
+        }
+    }
+
+    /// Wait for a notification, returning at once if a permit is stored.
+    ///
+    /// # Panics
+    ///
+    /// If 4095 threads are waiting already.
+    pub fn wait(&self) {
+        let mut s = self.state.load(Ordering::Relaxed);
+        // Take the stored permit, or count ourselves as waiting.
+        loop {
+            let next = if tokens(s) != 0 {
+                s - TOKEN
+            } else {
+                assert!(waiters(s) != TOKEN - 1, "too many waiters");
+                s + WAITER
+            };
+            match self
+                .state
+                .compare_exchange_weak(s, next, Ordering::Acquire, Ordering::Relaxed)
+            {
+                Ok(_) if tokens(s) != 0 => return,
+                Ok(_) => break,
+                Err(e) => s = e,
+            }
+        }
+
+        let generation = s / GENERATION;
+        s += WAITER;
+        loop {
+            if s / GENERATION != generation {
+                // Released by notify_all, which already counted us out.
+                return;
+            }
+            if tokens(s) != 0 {
+                match self.state.compare_exchange_weak(
+                    s,
+                    s - TOKEN - WAITER,
+                    Ordering::Acquire,
+                    Ordering::Relaxed,
+                ) {
+                    Ok(_) => return,
+                    Err(e) => {
+                        s = e;
+                        continue;
+                    }
+                }
+            }
+            wait(&self.state, s);
+            s = self.state.load(Ordering::Acquire);
+        }
+    }
+
+    /// Let one waiting thread return. With nobody waiting, store a permit
+    /// for the next [Notify::wait] instead, unless one is stored already.
+    pub fn notify_one(&self) {
+        let notified = self
+            .state
+            .fetch_update(Ordering::Release, Ordering::Relaxed, |s| {
+                // Never owe more than one notification per waiter, or one
+                // permit with nobody waiting.
+                (tokens(s) < waiters(s).max(1)).then_some(s + TOKEN)
+            });
+        if let Ok(s) = notified {
+            if waiters(s) != 0 {
+                wake_one(&self.state);
+            }
+        }
+    }
+
+    /// Let every thread waiting right now return. Stores no permit, and
+    /// leaves a stored one alone.
+    pub fn notify_all(&self) {
+        let notified = self
+            .state
+            .fetch_update(Ordering::Release, Ordering::Relaxed, |s| {
+                // Everyone waiting returns, so owes nothing and is counted
+                // out here.
+                (waiters(s) != 0).then_some((s & !(GENERATION - 1)).wrapping_add(GENERATION))
+            });
+        if notified.is_ok() {
+            wake_all(&self.state);
+        }
+    }
+}
+
+impl Default for Notify {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::sync::atomic::AtomicUsize;
+    use std::time::Duration;
+
+    /// Wait until `n` threads are counted as waiting.
+    fn until_waiting(notify: &Notify, n: u32) {
+        while waiters(notify.state.load(Ordering::Relaxed)) != n {
+            std::thread::yield_now();
+        }
+    }
+
+    #[test]
+    fn notify_before_wait_is_kept_once() {
+        let notify = Notify::new();
+        notify.notify_one();
+        notify.notify_one();
+        notify.wait();
+        std::thread::scope(|s| {
+            let waiter = s.spawn(|| notify.wait());
+            until_waiting(&notify, 1);
+            std::thread::sleep(Duration::from_millis(20));
+            // The second notification wasn't kept.
+            assert!(!waiter.is_finished());
+            notify.notify_one();
+        });
+        assert_eq!(notify.state.load(Ordering::Relaxed), 0);
+    }
+
+    #[test]
+    fn notify_one_wakes_exactly_one() {
+        let notify = Notify::new();
+        let woken = AtomicUsize::new(0);
+        std::thread::scope(|s| {
+            for _ in 0..4 {
+                s.spawn(|| {
+                    notify.wait();
+                    woken.fetch_add(1, Ordering::Relaxed);
+                });
+            }
+            until_waiting(&notify, 4);
+            for expected in 1..=4 {
+                notify.notify_one();
+                until_waiting(&notify, 4 - expected as u32);
+                std::thread::sleep(Duration::from_millis(10));
+                assert_eq!(woken.load(Ordering::Relaxed), expected);
+            }
+        });
+    }
+
+    #[test]
+    fn notify_all_wakes_only_current_waiters() {
+        let notify = Notify::new();
+        std::thread::scope(|s| {
+            for _ in 0..3 {
+                s.spawn(|| notify.wait());
+            }
+            until_waiting(&notify, 3);
+            notify.notify_all();
+        });
+        // Nothing was stored for later waiters.
+        assert_eq!(tokens(notify.state.load(Ordering::Relaxed)), 0);
+        std::thread::scope(|s| {
+            let late = s.spawn(|| notify.wait());
+            until_waiting(&notify, 1);
+            std::thread::sleep(Duration::from_millis(20));
+            assert!(!late.is_finished());
+            notify.notify_one();
+        });
+    }
+}
@@ -0,0 +1,295 @@
+use crate::sys::atomic::{AtomicU32, Ordering};
+use crate::sys::{wait, wake_all};
+use std::cell::UnsafeCell;
+use std::mem::MaybeUninit;
+
+/// Nobody has run the initializer yet.
+const INCOMPLETE: u32 = 0;
+/// A thread is running the initializer; others park until it's done.
+const RUNNING: u32 = 1;
+/// The initializer ran to completion.
+const COMPLETE: u32 = 2;
+/// The initializer panicked. Only [Once::call_once_force] may run again.
+const POISONED: u32 = 3;
+
+/// Runs a one-time initialization, parking other callers until it's done.
+pub struct Once {
+    state: AtomicU32,
+}
+
+/// Passed to the closure of [Once::call_once_force].
+#[derive(Debug)]
+pub struct OnceState {
+    poisoned: bool,
+}
+
+impl OnceState {
+    /// Whether an earlier initializer panicked.
+    pub fn is_poisoned(&self) -> bool {
+        self.poisoned
+    }
+}
+
+/// Sets the state and wakes every waiter when dropped, so a panicking
+/// initializer poisons the [Once] instead of leaving waiters parked.
+struct Completion<'a> {
+    state: &'a AtomicU32,
+    set_to: u32,
+}
+
+impl Drop for Completion<'_> {
+    fn drop(&mut self) {
+        self.state.store(self.set_to, Ordering::Release);
+        wake_all(self.state);
+    }
+}
+
+impl Once {
+    /// Create a new Once that hasn't run yet.
+    pub const fn new() -> Self {
+        Self {
+            state: AtomicU32::new(INCOMPLETE),
+        }
+    }
+
+    /// Run `f` if no call has completed yet. Concurrent callers wait for
+    /// the one running `f`, and all return once it completed.
+    ///
+    /// # Panics
+    ///
+    /// If an earlier initializer panicked, poisoning the Once.
+    pub fn call_once<F: FnOnce()>(&self, f: F) {
+        if self.is_completed() {
+            return;
+        }
+        let mut f = Some(f);
+        self.call(false, &mut |_| f.take().unwrap()());
+    }
+
+    /// Like [Once::call_once], but also runs `f` if an earlier initializer
+    /// panicked, which `f` can tell from its [OnceState].
+    pub fn call_once_force<F: FnOnce(&OnceState)>(&self, f: F) {
+        if self.is_completed() {
+            return;
+        }
+        let mut f = Some(f);
+        self.call(true, &mut |state| f.take().unwrap()(state));
+    }
+
+    /// Whether some call has completed. Acquires whatever the initializer
+    /// wrote when it returns `true`.
+    pub fn is_completed(&self) -> bool {
+        self.state.load(Ordering::Acquire) == COMPLETE
+    }
+
+    /// Whether an initializer panicked and nothing has completed since.
+    pub(crate) fn is_poisoned(&self) -> bool {
+        self.state.load(Ordering::Acquire) == POISONED
+    }
+
+    /// Park until some call has completed, also across panics.
+    fn wait_complete(&self) {
+        loop {
+            let state = self.state.load(Ordering::Acquire);
+            if state == COMPLETE {
+                return;
+            }
+            wait(&self.state, state);
+        }
+    }
+
+    #[cold]
+    fn call(&self, ignore_poison: bool, f: &mut dyn FnMut(&OnceState)) {
+        let mut state = self.state.load(Ordering::Acquire);
+        loop {
+            match state {
+                COMPLETE => return,
+                POISONED if !ignore_poison => panic!("Once poisoned by a panicking initializer"),
+                INCOMPLETE | POISONED => {
+                    if let Err(e) = self.state.compare_exchange(
+                        state,
+                        RUNNING,
+                        Ordering::Acquire,
+                        Ordering::Acquire,
+                    ) {
+                        state = e;
+                        continue;
+                    }
+                    let mut completion = Completion {
+                        state: &self.state,
+                        set_to: POISONED,
+                    };
+                    f(&OnceState {
+                        poisoned: state == POISONED,
+                    });
+                    completion.set_to = COMPLETE;
+                    return;
+                }
+                _ => {
+                    wait(&self.state, RUNNING);
+                    state = self.state.load(Ordering::Acquire);
+                }
+            }
+        }
+    }
+}
+
+impl Default for Once {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// A cell written at most once, then readable from any thread.
+pub struct OnceLock<T> {
+    once: Once,
+    value: UnsafeCell<MaybeUninit<T>>,
+}
+
+/// SAFETY: The value is only written once, by the thread running the
+/// [Once], and only read after it completed. It may be written on one
+/// thread and dropped on another, so it must be `Send` too.
+unsafe impl<T> Sync for OnceLock<T> where T: Send + Sync {}
+
+impl<T> OnceLock<T> {
+    /// Create a new empty cell.
+    pub const fn new() -> Self {
+        Self {
+            once: Once::new(),
+            value: UnsafeCell::new(MaybeUninit::uninit()),
+        }
+    }
+
+    /// The value, if it has been set.
+    pub fn get(&self) -> Option<&T> {
+        if self.once.is_completed() {
+            Some(unsafe { (*self.value.get()).assume_init_ref() })
+        } else {
+            None
+        }
+    }
+
+    /// Set the value, unless it was already set or is being initialized;
+    /// then wait for that and hand `value` back.
+    pub fn set(&self, value: T) -> Result<(), T> {
+        let mut value = Some(value);
+        self.get_or_init(|| value.take().unwrap());
+        match value {
+            None => Ok(()),
+            Some(value) => Err(value),
+        }
+    }
+
+    /// The value, initializing it with `f` first if it isn't set. Of all
+    /// concurrent callers, exactly one runs its `f`; the others wait for
+    /// it. If `f` panics, a later call tries again.
+    pub fn get_or_init<F: FnOnce() -> T>(&self, f: F) -> &T {
+        if let Some(value) = self.get() {
+            return value;
+        }
+        self.once.call_once_force(|_| {
+            unsafe { (*self.value.get()).write(f()) };
+        });
+        self.get().unwrap()
+    }
+
+    /// Wait until the value has been set by another thread.
+    pub fn wait(&self) -> &T {
+        self.once.wait_complete();
+        self.get().unwrap()
+    }
+}
+
+impl<T> Default for OnceLock<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T> Drop for OnceLock<T> {
+    fn drop(&mut self) {
+        if self.once.is_completed() {
+            unsafe { self.value.get_mut().assume_init_drop() };
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::sync::atomic::AtomicUsize;
+
+    #[test]
+    fn get_or_init_runs_once() {
+        let threads = if cfg!(miri) { 8 } else { 64 };
+        let cell = OnceLock::new();
+        let calls = AtomicUsize::new(0);
+        std::thread::scope(|s| {
+            for _ in 0..threads {
+                s.spawn(|| {
+                    let value = cell.get_or_init(|| {
+                        calls.fetch_add(1, Ordering::Relaxed);
+                        String::from("init")
+                    });
+                    assert_eq!(value, "init");
+                });
+            }
+        });
+        assert_eq!(calls.load(Ordering::Relaxed), 1);
+    }
+
+    #[test]
+    fn set_races_get_or_init() {
+        let rounds = if cfg!(miri) { 2 } else { 50 };
+        for _ in 0..rounds {
+            let cell = OnceLock::new();
+            let winners = AtomicUsize::new(0);
+            std::thread::scope(|s| {
+                for i in 0..4 {
+                    let (cell, winners) = (&cell, &winners);
+                    s.spawn(move || {
+                        if cell.set(i).is_ok() {
+                            winners.fetch_add(1, Ordering::Relaxed);
+                        }
+                    });
+                    s.spawn(move || {
+                        cell.get_or_init(|| {
+                            winners.fetch_add(1, Ordering::Relaxed);
+                            100
+                        });
+                    });
+                }
+                s.spawn(|| cell.wait());
+            });
+            assert_eq!(winners.load(Ordering::Relaxed), 1);
+            assert!(cell.get().is_some());
+        }
+    }
+
+    #[test]
+    fn panicking_initializer_poisons_once() {
+        let once = Once::new();
+        let result = std::panic::catch_unwind(|| once.call_once(|| panic!("init failed")));
+        assert!(result.is_err());
+        assert!(!once.is_completed());
+        assert!(std::panic::catch_unwind(|| once.call_once(|| {})).is_err());
+
+        let mut saw_poison = false;
+        once.call_once_force(|state| saw_poison = state.is_poisoned());
+        assert!(saw_poison);
+        assert!(once.is_completed());
+    }
+
+    #[test]
+    fn waiters_wake_on_completion() {
+        let cell = OnceLock::new();
+        std::thread::scope(|s| {
+            let waiters: Vec<_> = (0..4).map(|_| s.spawn(|| *cell.wait())).collect();
+            std::thread::sleep(std::time::Duration::from_millis(20));
+            cell.set(7).unwrap();
+            for waiter in waiters {
+                assert_eq!(waiter.join().unwrap(), 7);
+            }
+        });
+    }
+}
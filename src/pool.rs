@@ -0,0 +1,620 @@
+use crate::mutex::Mutex;
+use crate::sem::{RawSem, SemPermit, MAX_PERMITS};
+use crate::sys::atomic::{AtomicBool, Ordering};
+use std::cell::UnsafeCell;
+use std::fmt;
+use std::marker::PhantomData;
+use std::ops::{Deref, DerefMut};
+
+/// A fixed set of reusable values, each borrowed by one thread at a time,
+/// such as scratch buffers too costly to allocate per use.
+///
+/// A semaphore counts the free values, so [Pool::get] waits only while all
+/// of them are out, and then claims one that nobody else holds.
+///
+/// ```
+/// use xlock::pool::Pool;
+///
+/// let buffers = Pool::from_fn(2, |_| Vec::<u8>::with_capacity(1024));
+/// std::thread::scope(|s| {
+///     for i in 0..8 {
+///         let buffers = &buffers;
+///         s.spawn(move || {
+///             let mut buf = buffers.get();
+///             buf.clear();
+///             buf.push(i);
+///         });
+///     }
+/// });
+/// ```
+pub struct Pool<T> {
+    /// One access per value not handed out.
+    free: RawSem,
+    /// Whether each value is handed out. A permit from `free` guarantees
+    /// at least one of these is clear for its holder to claim.
+    claimed: Box<[AtomicBool]>,
+    values: Box<[UnsafeCell<T>]>,
+}
+
+/// SAFETY: Each value is only reached through the one guard that claimed
+/// it, so sharing the pool only moves values between threads.
+unsafe impl<T: Send> Sync for Pool<T> {}
+
+/// Exclusive access to one value of a [Pool], handed back when dropped.
+///
+/// `Send` when `T` is, `Sync` when `T` is also `Sync`.
+pub struct PoolGuard<'a, T> {
+    pool: &'a Pool<T>,
+    index: usize,
+    /// Dropped after the value is unclaimed, so a thread woken by the
+    /// release finds it free.
+    _permit: SemPermit<'a>,
+    _marker: PhantomData<&'a mut T>,
+}
+
+/// A [Pool] that health-checks each value before lending it out, such as
+/// connections that may have been dropped by the other end. A value that
+/// fails the check is replaced in place by a fresh one from `make`, so the
+/// pool always keeps its size.
+///
+/// Fresh values are lent out without being checked, so borrowing never
+/// loops forever even if every value in the pool has gone bad.
+///
+/// ```
+/// use xlock::pool::PoolWithHealth;
+///
+/// // Stands in for a connection: open until closed.
+/// let pool = PoolWithHealth::new(2, || vec![1u8], |conn: &Vec<u8>| !conn.is_empty());
+/// pool.get().clear();
+/// assert_eq!(*pool.get(), [1]);
+/// ```
+pub struct PoolWithHealth<T, F, C> {
+    pool: Pool<T>,
+    make: F,
+    check: C,
+}
+
+/// A [Pool] that only hands a returned value out again after a grace
+/// period: once every [EpochPin] taken before it was returned is gone.
+///
+/// Use it when values can still be reached after being returned, such
+/// as through an index kept in a lock-free structure that readers walk
+/// without borrowing from the pool. Readers pin while they might hold
+/// such an index, and a value isn't reused while any reader that could
+/// have seen it before its return is still pinned. Without such outside
+/// references, a plain [Pool] is enough.
+///
+/// ```
+/// use xlock::pool::EpochPool;
+///
+/// let pool = EpochPool::new(vec![0]);
+/// let reader = pool.pin();
+/// drop(pool.get());
+/// // The reader may still see the value, so it isn't handed out yet.
+/// assert!(pool.try_get().is_none());
+/// drop(reader);
+/// assert!(pool.try_get().is_some());
+/// ```
+pub struct EpochPool<T> {
+    pool: Pool<T>,
+    epochs: Mutex<Epochs>,
+}
+
+/// The pins and returned values of an [EpochPool].
+struct Epochs {
+    /// Bumped by every return, so pins taken later are told apart.
+    current: u64,
+    /// The epoch of each live pin.
+    pins: Vec<u64>,
+    /// Index and return epoch of each value waiting out its grace period.
+    retired: Vec<(usize, u64)>,
+}
+
+/// Exclusive access to one value of an [EpochPool]. When dropped, the
+/// value waits out a grace period before it can be borrowed again.
+pub struct EpochPoolGuard<'a, T> {
+    pool: &'a EpochPool<T>,
+    /// Always `Some` until dropped.
+    guard: Option<PoolGuard<'a, T>>,
+}
+
+/// Marks a reader of an [EpochPool] that might still reach values
+/// returned meanwhile, holding their reuse back until dropped.
+#[must_use = "the pin is released as soon as it is dropped"]
+pub struct EpochPin<'a, T> {
+    pool: &'a EpochPool<T>,
+    epoch: u64,
+}
+
+impl<T> Pool<T> {
+    /// Create a pool of the given values.
+    ///
+    /// An empty pool is allowed, but then [Pool::get] waits forever.
+    ///
+    /// # Panics
+    ///
+    /// If there are more than [Semaphore::MAX_PERMITS](crate::semaphore::Semaphore::MAX_PERMITS)
+    /// values.
+    pub fn new(values: Vec<T>) -> Self {
+        assert!(values.len() <= MAX_PERMITS as usize, "too many values");
+        Self {
+            free: RawSem::new(values.len() as u32),
+            claimed: values.iter().map(|_| AtomicBool::new(false)).collect(),
+            values: values.into_iter().map(UnsafeCell::new).collect(),
+        }
+    }
+
+    /// Create a pool of `n` values, made by calling `f` with each index.
+    ///
+    /// # Panics
+    ///
+    /// If `n` is more than [Semaphore::MAX_PERMITS](crate::semaphore::Semaphore::MAX_PERMITS).
+    pub fn from_fn(n: usize, f: impl FnMut(usize) -> T) -> Self {
+        Self::new((0..n).map(f).collect())
+    }
+
+    /// Borrow a value, waiting until one is free.
+    pub fn get(&self) -> PoolGuard<'_, T> {
+        // The semaphore is never closed.
+        let permit = self.free.acquire_permit().expect("Pool closed");
+        self.claim(permit)
+    }
+
+    /// Borrow a value only if one is free right now.
+    pub fn try_get(&self) -> Option<PoolGuard<'_, T>> {
+        let permit = self.free.try_acquire_permits(1).ok()?;
+        Some(self.claim(permit))
+    }
+
+    /// Claim an unclaimed value for the holder of `permit`.
+    fn claim<'a>(&'a self, permit: SemPermit<'a>) -> PoolGuard<'a, T> {
+        // There are no more permits than unclaimed values, so a pass can
+        // only come up empty if others claimed what this one looked at,
+        // and each of those left another value unclaimed behind it.
+        loop {
+            for (index, claimed) in self.claimed.iter().enumerate() {
+                if !claimed.load(Ordering::Relaxed) && !claimed.swap(true, Ordering::Acquire) {
+                    return PoolGuard {
+                        pool: self,
+                        index,
+                        _permit: permit,
+                        _marker: PhantomData,
+                    };
+                }
+            }
+            std::hint::spin_loop();
+        }
+    }
+
+    /// Number of values in the pool, borrowed or not.
+    pub fn len(&self) -> usize {
+        self.values.len()
+    }
+
+    /// Whether the pool has no values at all.
+    pub fn is_empty(&self) -> bool {
+        self.values.is_empty()
+    }
+
+    /// Values not borrowed right now. Only a snapshot.
+    pub fn available(&self) -> usize {
+        self.free.available().max(0) as usize
+    }
+
+    /// Mutable access to every value. The exclusive borrow rules out
+    /// guards.
+    pub fn get_mut(&mut self) -> impl Iterator<Item = &mut T> {
+        self.values.iter_mut().map(UnsafeCell::get_mut)
+    }
+
+    /// Consume the pool and return its values, in their original order.
+    pub fn into_inner(self) -> Vec<T> {
+        self.values
+            .into_vec()
+            .into_iter()
+            .map(UnsafeCell::into_inner)
+            .collect()
+    }
+}
+
+impl<T, F, C> PoolWithHealth<T, F, C>
+where
+    F: Fn() -> T,
+    C: Fn(&T) -> bool,
+{
+    /// Create a pool of `n` values made by `make`, lent out only while
+    /// `check` passes for them.
+    ///
+    /// # Panics
+    ///
+    /// As for [Pool::from_fn].
+    pub fn new(n: usize, make: F, check: C) -> Self {
+        Self {
+            pool: Pool::from_fn(n, |_| make()),
+            make,
+            check,
+        }
+    }
+
+    /// Borrow a healthy value, waiting until one is free.
+    pub fn get(&self) -> PoolGuard<'_, T> {
+        self.healthy(self.pool.get())
+    }
+
+    /// Borrow a healthy value only if one is free right now.
+    pub fn try_get(&self) -> Option<PoolGuard<'_, T>> {
+        Some(self.healthy(self.pool.try_get()?))
+    }
+
+    /// Replace the borrowed value if it fails the check.
+    fn healthy<'a>(&self, mut guard: PoolGuard<'a, T>) -> PoolGuard<'a, T> {
+        if !(self.check)(&guard) {
+            *guard = (self.make)();
+        }
+        guard
+    }
+
+    /// Number of values in the pool, borrowed or not.
+    pub fn len(&self) -> usize {
+        self.pool.len()
+    }
+
+    /// Whether the pool has no values at all.
+    pub fn is_empty(&self) -> bool {
+        self.pool.is_empty()
+    }
+
+    /// Values not borrowed right now. Only a snapshot.
+    pub fn available(&self) -> usize {
+        self.pool.available()
+    }
+
+    /// Consume the pool and return its values, in their original order.
+    pub fn into_inner(self) -> Vec<T> {
+        self.pool.into_inner()
+    }
+}
+
+impl<T> EpochPool<T> {
+    /// Create a pool of the given values.
+    ///
+    /// # Panics
+    ///
+    /// As for [Pool::new].
+    pub fn new(values: Vec<T>) -> Self {
+        Self {
+            pool: Pool::new(values),
+            epochs: Mutex::new(Epochs {
+                current: 0,
+                pins: Vec::new(),
+                retired: Vec::new(),
+            }),
+        }
+    }
+
+    /// Borrow a value, waiting until one is free and past its grace
+    /// period.
+    pub fn get(&self) -> EpochPoolGuard<'_, T> {
+        EpochPoolGuard {
+            pool: self,
+            guard: Some(self.pool.get()),
+        }
+    }
+
+    /// Borrow a value only if one is free and past its grace period right
+    /// now.
+    pub fn try_get(&self) -> Option<EpochPoolGuard<'_, T>> {
+        Some(EpochPoolGuard {
+            pool: self,
+            guard: Some(self.pool.try_get()?),
+        })
+    }
+
+    /// Pin the current epoch: values returned from now on aren't reused
+    /// until the pin is dropped.
+    pub fn pin(&self) -> EpochPin<'_, T> {
+        let mut epochs = self.epochs.lock();
+        let epoch = epochs.current;
+        epochs.pins.push(epoch);
+        EpochPin { pool: self, epoch }
+    }
+
+    /// Number of values in the pool, borrowed, waiting or free.
+    pub fn len(&self) -> usize {
+        self.pool.len()
+    }
+
+    /// Whether the pool has no values at all.
+    pub fn is_empty(&self) -> bool {
+        self.pool.is_empty()
+    }
+
+    /// Values that can be borrowed right now. Only a snapshot.
+    pub fn available(&self) -> usize {
+        self.pool.available()
+    }
+
+    /// Consume the pool and return its values, in their original order.
+    pub fn into_inner(self) -> Vec<T> {
+        self.pool.into_inner()
+    }
+
+    /// Free the returned values no pin can reach anymore.
+    fn reclaim(&self, epochs: &mut Epochs) {
+        let oldest = epochs.pins.iter().copied().min().unwrap_or(u64::MAX);
+        epochs.retired.retain(|&(index, returned)| {
+            if returned >= oldest {
+                return true;
+            }
+            // Release, so the next borrower sees what the last one wrote.
+            self.pool.claimed[index].store(false, Ordering::Release);
+            // SAFETY: The access was kept taken by the retired guard.
+            unsafe { self.pool.free.release(1) };
+            false
+        });
+    }
+}
+
+impl<T> PoolGuard<'_, T> {
+    /// The position of the borrowed value among those the pool was made
+    /// with.
+    pub fn index(&self) -> usize {
+        self.index
+    }
+}
+
+impl<T> EpochPoolGuard<'_, T> {
+    /// The position of the borrowed value among those the pool was made
+    /// with.
+    pub fn index(&self) -> usize {
+        self.guard.as_ref().expect("guard dropped").index
+    }
+}
+
+impl<T> Drop for EpochPoolGuard<'_, T> {
+    fn drop(&mut self) {
+        let guard = self.guard.take().expect("guard dropped");
+        let index = guard.index;
+        // Keep the value claimed and its access taken until reclaimed.
+        std::mem::forget(guard);
+        let mut epochs = self.pool.epochs.lock();
+        let returned = epochs.current;
+        epochs.current += 1;
+        epochs.retired.push((index, returned));
+        self.pool.reclaim(&mut epochs);
+    }
+}
+
+impl<T> Drop for EpochPin<'_, T> {
+    fn drop(&mut self) {
+        let mut epochs = self.pool.epochs.lock();
+        let index = epochs.pins.iter().position(|&e| e == self.epoch);
+        epochs.pins.swap_remove(index.expect("pin not registered"));
+        self.pool.reclaim(&mut epochs);
+    }
+}
+
+impl<T> Drop for PoolGuard<'_, T> {
+    fn drop(&mut self) {
+        // Release, so the next borrower sees what this one wrote.
+        self.pool.claimed[self.index].store(false, Ordering::Release);
+    }
+}
+
+impl<T> Deref for PoolGuard<'_, T> {
+    type Target = T;
+    fn deref(&self) -> &T {
+        // SAFETY: The guard claimed this value, so it alone reaches it.
+        unsafe { &*self.pool.values[self.index].get() }
+    }
+}
+
+impl<T> DerefMut for PoolGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        // SAFETY: As in deref, and `&mut self` makes this borrow unique.
+        unsafe { &mut *self.pool.values[self.index].get() }
+    }
+}
+
+impl<T> fmt::Debug for Pool<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Pool")
+            .field("len", &self.len())
+            .field("available", &self.available())
+            .finish()
+    }
+}
+
+impl<T> Deref for EpochPoolGuard<'_, T> {
+    type Target = T;
+    fn deref(&self) -> &T {
+        self.guard.as_ref().expect("guard dropped")
+    }
+}
+
+impl<T> DerefMut for EpochPoolGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        self.guard.as_mut().expect("guard dropped")
+    }
+}
+
+impl<T, F, C> fmt::Debug for PoolWithHealth<T, F, C> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("PoolWithHealth")
+            .field("len", &self.pool.len())
+            .field("available", &self.pool.available())
+            .finish_non_exhaustive()
+    }
+}
+
+impl<T> fmt::Debug for EpochPool<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("EpochPool")
+            .field("len", &self.len())
+            .field("available", &self.available())
+            .finish()
+    }
+}
+
+impl<T: fmt::Debug> fmt::Debug for EpochPoolGuard<'_, T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(&**self, f)
+    }
+}
+
+impl<T> fmt::Debug for EpochPin<'_, T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("EpochPin")
+            .field("epoch", &self.epoch)
+            .finish()
+    }
+}
+
+impl<T: fmt::Debug> fmt::Debug for PoolGuard<'_, T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(&**self, f)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::sync::atomic::AtomicU32;
+
+    #[test]
+    fn no_slot_is_handed_out_twice() {
+        const SLOTS: usize = 4;
+        let pool = Pool::from_fn(SLOTS, |i| i);
+        let live: Vec<_> = (0..SLOTS).map(|_| AtomicU32::new(0)).collect();
+        let iterations = if cfg!(miri) { 5 } else { 200 };
+        std::thread::scope(|s| {
+            for _ in 0..32 {
+                s.spawn(|| {
+                    for _ in 0..iterations {
+                        let guard = pool.get();
+                        assert_eq!(*guard, guard.index());
+                        let holders = live[guard.index()].fetch_add(1, Ordering::SeqCst);
+                        assert_eq!(holders, 0, "slot {} handed out twice", guard.index());
+                        std::thread::yield_now();
+                        live[guard.index()].fetch_sub(1, Ordering::SeqCst);
+                    }
+                });
+            }
+        });
+        assert_eq!(pool.available(), SLOTS);
+        assert_eq!(pool.into_inner(), [0, 1, 2, 3]);
+    }
+
+    #[test]
+    fn try_get_and_guards_outliving_others() {
+        let pool = Pool::new(vec![String::new(), String::new()]);
+        let kept = {
+            let mut a = pool.try_get().unwrap();
+            let mut b = pool.try_get().unwrap();
+            assert!(pool.try_get().is_none());
+            a.push('a');
+            b.push('b');
+            a
+        };
+        assert_eq!(pool.available(), 1);
+        let mut other = pool.try_get().unwrap();
+        assert_eq!(*other, "b");
+        other.push('!');
+        assert_eq!(*kept, "a");
+        drop((kept, other));
+        let mut values = pool.into_inner();
+        values.sort();
+        assert_eq!(values, ["a", "b!"]);
+    }
+
+    #[test]
+    fn get_waits_for_a_release() {
+        let pool = Pool::new(vec![0]);
+        let held = pool.get();
+        std::thread::scope(|s| {
+            let waiter = s.spawn(|| *pool.get() += 1);
+            std::thread::sleep(std::time::Duration::from_millis(20));
+            assert!(!waiter.is_finished());
+            drop(held);
+        });
+        assert_eq!(*pool.get(), 1);
+    }
+
+    #[test]
+    fn unhealthy_values_are_replaced() {
+        let made = AtomicU32::new(0);
+        let pool = PoolWithHealth::new(
+            2,
+            || (made.fetch_add(1, Ordering::Relaxed), true),
+            |&(_, healthy)| healthy,
+        );
+        assert_eq!(made.load(Ordering::Relaxed), 2);
+        {
+            let mut guard = pool.get();
+            guard.1 = false;
+        }
+        let (a, b) = (pool.get(), pool.get());
+        assert!(a.1 && b.1);
+        assert_eq!(made.load(Ordering::Relaxed), 3);
+        assert!(pool.try_get().is_none());
+        drop((a, b));
+        assert_eq!(pool.len(), 2);
+        assert_eq!(pool.available(), 2);
+        // One of the first two was replaced by the third made.
+        let ids: Vec<_> = pool.into_inner().into_iter().map(|(id, _)| id).collect();
+        assert!(ids == [0, 2] || ids == [2, 1]);
+    }
+
+    #[test]
+    fn all_unhealthy_values_dont_block() {
+        let pool = PoolWithHealth::new(1, || 0, |_| false);
+        for _ in 0..3 {
+            drop(pool.get());
+        }
+        assert_eq!(pool.available(), 1);
+    }
+
+    #[test]
+    fn returned_value_waits_for_older_pins() {
+        let pool = EpochPool::new(vec![0, 1]);
+        let old = pool.pin();
+        let mut guard = pool.get();
+        let index = guard.index();
+        *guard += 10;
+        drop(guard);
+        // Returned while `old` was pinned: held back until it is gone.
+        let new = pool.pin();
+        let other = pool.try_get().unwrap();
+        assert_ne!(other.index(), index);
+        assert!(pool.try_get().is_none());
+        drop(old);
+        // `new` was pinned after the return, so it doesn't hold it back.
+        let again = pool.try_get().unwrap();
+        assert_eq!(again.index(), index);
+        assert_eq!(*again, index + 10);
+        drop((again, other, new));
+        assert_eq!(pool.available(), 2);
+    }
+
+    #[test]
+    fn get_waits_out_the_grace_period() {
+        let pool = EpochPool::new(vec![()]);
+        let reader = pool.pin();
+        drop(pool.get());
+        std::thread::scope(|s| {
+            let waiter = s.spawn(|| drop(pool.get()));
+            std::thread::sleep(std::time::Duration::from_millis(20));
+            assert!(!waiter.is_finished());
+            drop(reader);
+        });
+        assert_eq!(pool.available(), 1);
+    }
+
+    #[test]
+    fn auto_traits() {
+        fn assert_send_sync<T: Send + Sync>() {}
+        assert_send_sync::<Pool<Vec<u8>>>();
+        assert_send_sync::<PoolGuard<'_, Vec<u8>>>();
+        assert_send_sync::<EpochPool<Vec<u8>>>();
+    }
+}
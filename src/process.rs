@@ -0,0 +1,245 @@
+//! Locks that work across processes, placed in memory they share.
+//!
+//! These hold only their control words, laid out with `#[repr(C)]`, and
+//! wait with shared rather than process-private futex operations, so a
+//! lock in a `MAP_SHARED` mapping coordinates every process that maps it.
+//! They are only available on Linux and Android: other platforms need
+//! their own shared-wait flags, which aren't wired up.
+//!
+//! Exactly one process initializes a lock in place with `init_at`, before
+//! any other process uses it; the others attach to it with `from_raw`.
+//! Nothing releases a lock held by a process that dies, so every process
+//! still using it then waits forever.
+
+use crate::sys::atomic::{AtomicU32, Ordering};
+use crate::sys::shared::{wait, wake_one};
+
+/// `state` of an unlocked [RawSharedMutex].
+const UNLOCKED: u32 = 0;
+/// `state` of a [RawSharedMutex] locked with nobody waiting.
+const LOCKED: u32 = 1;
+/// `state` of a [RawSharedMutex] locked with waiters that may be parked.
+const CONTENDED: u32 = 2;
+
+/// A mutex that protects no value and can be shared between processes.
+///
+/// ```
+/// use xlock::process::RawSharedMutex;
+///
+/// let mut slot = std::mem::MaybeUninit::<RawSharedMutex>::uninit();
+/// // SAFETY: Nothing else uses the slot.
+/// let m = unsafe { RawSharedMutex::init_at(slot.as_mut_ptr()) };
+/// m.lock();
+/// assert!(!m.try_lock());
+/// // SAFETY: Locked just above.
+/// unsafe { m.unlock() };
+/// ```
+#[repr(C)]
+pub struct RawSharedMutex {
+    state: AtomicU32,
+}
+
+impl RawSharedMutex {
+    /// Create a new unlocked mutex.
+    pub const fn new() -> Self {
+        Self {
+            state: AtomicU32::new(UNLOCKED),
+        }
+    }
+
+    /// Initialize an unlocked mutex at `ptr`, and borrow it.
+    ///
+    /// # Safety
+    ///
+    /// `ptr` must be valid for writes and suitably aligned, and nothing may
+    /// use the memory meanwhile. It must stay valid, and not be moved or
+    /// written to other than through the mutex, for `'a`.
+    pub unsafe fn init_at<'a>(ptr: *mut Self) -> &'a Self {
+        ptr.write(Self::new());
+        &*ptr
+    }
+
+    /// Borrow a mutex that was initialized at `ptr`, possibly by another
+    /// process.
+    ///
+    /// # Safety
+    ///
+    /// `ptr` must point to a mutex made by [RawSharedMutex::init_at] or
+    /// [RawSharedMutex::new], which must stay in place for `'a`.
+    pub unsafe fn from_raw<'a>(ptr: *mut Self) -> &'a Self {
+        &*ptr
+    }
+
+    /// Lock the mutex, waiting until it is free.
+    pub fn lock(&self) {
+        if self.try_lock() {
+            return;
+        }
+        // Mark the mutex contended before each wait, so the unlock wakes
+        // us. Taking it this way also keeps it marked for those after us.
+        while self.state.swap(CONTENDED, Ordering::Acquire) != UNLOCKED {
+            wait(&self.state, CONTENDED);
+        }
+    }
+
+    /// Lock the mutex only if it is free right now.
+    pub fn try_lock(&self) -> bool {
+        self.state
+            .compare_exchange(UNLOCKED, LOCKED, Ordering::Acquire, Ordering::Relaxed)
+            .is_ok()
+    }
+
+    /// Unlock the mutex, waking a waiter in any process.
+    ///
+    /// # Safety
+    ///
+    /// The mutex must be locked, by this thread or on its behalf, and the
+    /// lock is given up by this call.
+    pub unsafe fn unlock(&self) {
+        if self.state.swap(UNLOCKED, Ordering::Release) == CONTENDED {
+            wake_one(&self.state);
+        }
+    }
+}
+
+impl Default for RawSharedMutex {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// A counting semaphore that can be shared between processes, with no
+/// capacity: [RawSharedSemaphore::release] may be called by any process
+/// that never acquired, e.g. to signal items in a shared queue.
+#[repr(C)]
+pub struct RawSharedSemaphore {
+    /// Permits free to take. Waiters park on this word.
+    count: AtomicU32,
+    /// Processes' threads that may be parked. Releases skip the wake
+    /// syscall while this is zero.
+    sleepers: AtomicU32,
+}
+
+impl RawSharedSemaphore {
+    /// Create a new semaphore with `permits` free.
+    pub const fn new(permits: u32) -> Self {
+        Self {
+            count: AtomicU32::new(permits),
+            sleepers: AtomicU32::new(0),
+        }
+    }
+
+    /// Initialize a semaphore with `permits` free at `ptr`, and borrow it.
+    ///
+    /// # Safety
+    ///
+    /// As for [RawSharedMutex::init_at].
+    pub unsafe fn init_at<'a>(ptr: *mut Self, permits: u32) -> &'a Self {
+        ptr.write(Self::new(permits));
+        &*ptr
+    }
+
+    /// Borrow a semaphore that was initialized at `ptr`, possibly by
+    /// another process.
+    ///
+    /// # Safety
+    ///
+    /// As for [RawSharedMutex::from_raw].
+    pub unsafe fn from_raw<'a>(ptr: *mut Self) -> &'a Self {
+        &*ptr
+    }
+
+    /// Take a permit, waiting until one is free.
+    pub fn acquire(&self) {
+        if self.try_acquire() {
+            return;
+        }
+        self.sleepers.fetch_add(1, Ordering::SeqCst);
+        // Announced before this look: a release either is seen here or
+        // sees us and wakes the futex.
+        while !self.try_acquire() {
+            wait(&self.count, 0);
+        }
+        self.sleepers.fetch_sub(1, Ordering::SeqCst);
+    }
+
+    /// Take a permit only if one is free right now.
+    pub fn try_acquire(&self) -> bool {
+        self.count
+            .fetch_update(Ordering::Acquire, Ordering::SeqCst, |n| n.checked_sub(1))
+            .is_ok()
+    }
+
+    /// Add a permit, waking a waiter in any process.
+    ///
+    /// # Panics
+    ///
+    /// If `u32::MAX` permits are free already.
+    pub fn release(&self) {
+        let old = self.count.fetch_add(1, Ordering::SeqCst);
+        assert!(old != u32::MAX, "too many permits");
+        if self.sleepers.load(Ordering::SeqCst) != 0 {
+            wake_one(&self.count);
+        }
+    }
+
+    /// Permits free right now. Only a snapshot.
+    pub fn available(&self) -> u32 {
+        self.count.load(Ordering::Relaxed)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// A counter only ever touched with the mutex held.
+    struct Counter(std::cell::UnsafeCell<u32>);
+
+    unsafe impl Sync for Counter {}
+
+    impl Counter {
+        /// # Safety
+        ///
+        /// The mutex must be held.
+        unsafe fn bump(&self) {
+            *self.0.get() += 1;
+        }
+    }
+
+    #[test]
+    fn mutex_excludes_threads() {
+        let iterations = if cfg!(miri) { 10 } else { 1_000 };
+        let m = RawSharedMutex::new();
+        let count = Counter(std::cell::UnsafeCell::new(0));
+        std::thread::scope(|s| {
+            for _ in 0..4 {
+                s.spawn(|| {
+                    for _ in 0..iterations {
+                        m.lock();
+                        // SAFETY: The mutex is held.
+                        unsafe { count.bump() };
+                        unsafe { m.unlock() };
+                    }
+                });
+            }
+        });
+        assert_eq!(count.0.into_inner(), 4 * iterations);
+        assert!(m.try_lock());
+    }
+
+    #[test]
+    fn semaphore_signals_waiters() {
+        let sem = RawSharedSemaphore::new(0);
+        std::thread::scope(|s| {
+            for _ in 0..4 {
+                s.spawn(|| sem.acquire());
+            }
+            for _ in 0..4 {
+                sem.release();
+            }
+        });
+        assert_eq!(sem.available(), 0);
+        assert!(!sem.try_acquire());
+    }
+}
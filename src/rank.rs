@@ -0,0 +1,152 @@
+//! Static lock ordering.
+//!
+//! A [RankedMutex] carries a rank in its type, and locking it requires
+//! proof of what the thread already holds: either the [Unlocked] root
+//! token or the guard of a strictly lower ranked mutex. Acquiring locks in
+//! increasing rank order is then the only thing that compiles, which rules
+//! out lock-order deadlocks between ranked mutexes.
+//!
+//! ```
+//! use xlock::rank::{RankedMutex, Unlocked};
+//!
+//! let accounts: RankedMutex<Vec<u32>, 0> = RankedMutex::new(vec![]);
+//! let audit: RankedMutex<u32, 1> = RankedMutex::new(0);
+//!
+//! let mut root = Unlocked::new();
+//! let mut accounts = accounts.lock(&mut root);
+//! let mut audit = audit.lock(&mut accounts);
+//! *audit += 1;
+//! ```
+//!
+//! Locking in the wrong order is rejected at compile time:
+//!
+//! ```compile_fail
+//! use xlock::rank::{RankedMutex, Unlocked};
+//!
+//! let accounts: RankedMutex<Vec<u32>, 0> = RankedMutex::new(vec![]);
+//! let audit: RankedMutex<u32, 1> = RankedMutex::new(0);
+//!
+//! let mut root = Unlocked::new();
+//! let mut audit = audit.lock(&mut root);
+//! let accounts = accounts.lock(&mut audit);
+//! ```
+//!
+//! The new guard borrows the proof mutably, so the lower guard stays alive
+//! (and the root token stays unusable) until the higher one is dropped:
+//!
+//! ```compile_fail
+//! use xlock::rank::{RankedMutex, Unlocked};
+//!
+//! let accounts: RankedMutex<Vec<u32>, 0> = RankedMutex::new(vec![]);
+//! let audit: RankedMutex<u32, 1> = RankedMutex::new(0);
+//!
+//! let mut root = Unlocked::new();
+//! let audit = audit.lock(&mut root);
+//! let accounts = accounts.lock(&mut root);
+//! drop(audit);
+//! ```
+//!
+//! The guarantee only holds as long as each thread threads a single
+//! [Unlocked] token through its locking; a second root token is
+//! unrestricted. Ranks 0 to 3 are supported.
+
+use crate::mutex::{Mutex, MutexGuard};
+use std::ops::{Deref, DerefMut};
+
+/// A [Mutex] with a lock-ordering rank.
+pub struct RankedMutex<T, const RANK: usize> {
+    inner: Mutex<T>,
+}
+
+/// A guard for a [RankedMutex] of rank `RANK`.
+pub struct RankedGuard<'a, T, const RANK: usize> {
+    guard: MutexGuard<'a, T>,
+}
+
+/// Root token proving the thread holds no ranked locks.
+pub struct Unlocked(());
+
+/// Implemented by proofs that every held ranked lock is below `RANK`.
+pub trait Below<const RANK: usize> {}
+
+impl<const RANK: usize> Below<RANK> for Unlocked {}
+
+macro_rules! impl_below {
+    ($($low:literal < $high:literal),*) => {
+        $(impl<T> Below<$high> for RankedGuard<'_, T, $low> {})*
+    };
+}
+
+impl_below!(0 < 1, 0 < 2, 0 < 3, 1 < 2, 1 < 3, 2 < 3);
+
+impl Unlocked {
+    /// Create the root token for a thread.
+    pub const fn new() -> Self {
+        Self(())
+    }
+}
+
+impl Default for Unlocked {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T, const RANK: usize> RankedMutex<T, RANK> {
+    /// Create a new ranked Mutex guarding value T.
+    pub const fn new(value: T) -> Self {
+        Self {
+            inner: Mutex::new(value),
+        }
+    }
+
+    /// Lock the mutex, given proof that only lower ranked locks are held.
+    pub fn lock<'a, H>(&'a self, _held: &'a mut H) -> RankedGuard<'a, T, RANK>
+    where
+        H: Below<RANK>,
+    {
+        RankedGuard {
+            guard: self.inner.lock(),
+        }
+    }
+}
+
+impl<T, const RANK: usize> Deref for RankedGuard<'_, T, RANK> {
+    type Target = T;
+    fn deref(&self) -> &T {
+        &self.guard
+    }
+}
+
+impl<T, const RANK: usize> DerefMut for RankedGuard<'_, T, RANK> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.guard
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn increasing_rank_order() {
+        let a: RankedMutex<u32, 0> = RankedMutex::new(0);
+        let b: RankedMutex<u32, 2> = RankedMutex::new(0);
+        std::thread::scope(|s| {
+            for _ in 0..4 {
+                s.spawn(|| {
+                    for _ in 0..100 {
+                        let mut root = Unlocked::new();
+                        let mut a = a.lock(&mut root);
+                        *a += 1;
+                        let mut b = b.lock(&mut a);
+                        *b += 1;
+                    }
+                });
+            }
+        });
+        let mut root = Unlocked::new();
+        assert_eq!(*a.lock(&mut root), 400);
+        assert_eq!(*b.lock(&mut root), 400);
+    }
+}
@@ -0,0 +1,188 @@
+use crate::sys::atomic::{AtomicU32, AtomicU64, Ordering};
+use crate::sys::wait_until;
+use std::fmt;
+use std::time::{Duration, Instant};
+
+/// Limits how often something happens: at most `rate` acquisitions per
+/// period on average, with up to `burst` of them back to back.
+///
+/// Tokens aren't handed back; they refill with time instead. The refill is
+/// worked out from the clock on each acquisition, so there is no
+/// background thread, and a thread that has to wait parks until its token
+/// is due.
+///
+/// ```
+/// use std::time::Duration;
+/// use xlock::ratelimit::RateLimiter;
+///
+/// let limiter = RateLimiter::with_burst(100, Duration::from_secs(1), 10);
+/// for _ in 0..10 {
+///     assert!(limiter.try_acquire());
+/// }
+/// assert!(!limiter.try_acquire());
+/// ```
+pub struct RateLimiter {
+    /// Time between tokens at the steady rate, in nanoseconds.
+    interval: u64,
+    /// How far ahead of the clock `next` may run, in nanoseconds: room
+    /// for `burst - 1` tokens beyond the one due now.
+    tolerance: u64,
+    /// When the steady rate would hand out the next token, in nanoseconds
+    /// since `start`. A token may be taken once the clock is within
+    /// `tolerance` of it, and taking one moves it on by `interval`.
+    next: AtomicU64,
+    start: Instant,
+    /// Never changes: threads waiting for their token do a timed wait on
+    /// it, so they sleep in the kernel until the token is due.
+    sleep: AtomicU32,
+}
+
+impl RateLimiter {
+    /// Allow `rate` acquisitions every `per`, evenly spaced, with no
+    /// bursts.
+    ///
+    /// # Panics
+    ///
+    /// If `rate` is 0.
+    pub fn new(rate: u32, per: Duration) -> Self {
+        Self::with_burst(rate, per, 1)
+    }
+
+    /// Allow `rate` acquisitions every `per` on average, and up to `burst`
+    /// at once after a quiet spell. The burst may be larger than the rate.
+    ///
+    /// Starts full, so `burst` tokens can be taken right away.
+    ///
+    /// # Panics
+    ///
+    /// If `rate` or `burst` is 0.
+    pub fn with_burst(rate: u32, per: Duration, burst: u32) -> Self {
+        assert!(rate != 0, "rate must be nonzero");
+        assert!(burst != 0, "burst must be nonzero");
+        let interval = (per.as_nanos() / u128::from(rate)).max(1) as u64;
+        Self {
+            interval,
+            tolerance: interval.saturating_mul(u64::from(burst - 1)),
+            next: AtomicU64::new(0),
+            start: Instant::now(),
+            sleep: AtomicU32::new(0),
+        }
+    }
+
+    /// Take a token, waiting until one is due. Waiting threads are served
+    /// in the order they arrived.
+    pub fn acquire(&self) {
+        let due = self.reserve(None).expect("unbounded reservation");
+        self.sleep_until(due);
+    }
+
+    /// Take a token only if one is available right now.
+    pub fn try_acquire(&self) -> bool {
+        self.reserve(Some(Duration::ZERO)).is_some()
+    }
+
+    /// Take a token if one is due within `timeout`, waiting for it.
+    /// Returns `false` at once, taking nothing, if none would be.
+    pub fn acquire_timeout(&self, timeout: Duration) -> bool {
+        match self.reserve(Some(timeout)) {
+            Some(due) => {
+                self.sleep_until(due);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Claim the next token if it is due within `max_wait`, returning
+    /// when it is due.
+    fn reserve(&self, max_wait: Option<Duration>) -> Option<Instant> {
+        let now = self.now();
+        let mut next = self.next.load(Ordering::Relaxed);
+        loop {
+            // A token not taken in time doesn't pile up beyond the burst.
+            let start = next.max(now);
+            let wait = start.saturating_sub(now.saturating_add(self.tolerance));
+            if max_wait.is_some_and(|max| u128::from(wait) > max.as_nanos()) {
+                return None;
+            }
+            match self.next.compare_exchange_weak(
+                next,
+                start + self.interval,
+                Ordering::Relaxed,
+                Ordering::Relaxed,
+            ) {
+                Ok(_) => return Some(self.start + Duration::from_nanos(now + wait)),
+                Err(actual) => next = actual,
+            }
+        }
+    }
+
+    fn sleep_until(&self, due: Instant) {
+        // Returns early on spurious wakeups, and false once `due` passed.
+        while wait_until(&self.sleep, 0, due) {}
+    }
+
+    /// Nanoseconds since the limiter was made. `Instant` is monotonic, so
+    /// this never goes back.
+    fn now(&self) -> u64 {
+        self.start.elapsed().as_nanos() as u64
+    }
+}
+
+impl fmt::Debug for RateLimiter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("RateLimiter")
+            .field("interval", &Duration::from_nanos(self.interval))
+            .field("burst", &(self.tolerance / self.interval + 1))
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn steady_rate() {
+        let limiter = RateLimiter::new(10, Duration::from_secs(1));
+        let start = Instant::now();
+        for _ in 0..25 {
+            limiter.acquire();
+        }
+        // The first token is free, the other 24 come 100ms apart.
+        let elapsed = start.elapsed();
+        assert!(elapsed >= Duration::from_millis(2400), "{elapsed:?}");
+        assert!(elapsed < Duration::from_millis(2900), "{elapsed:?}");
+    }
+
+    #[test]
+    fn burst_when_cold() {
+        let limiter = RateLimiter::with_burst(2, Duration::from_secs(1), 5);
+        for _ in 0..5 {
+            assert!(limiter.try_acquire());
+        }
+        assert!(!limiter.try_acquire());
+        assert!(!limiter.acquire_timeout(Duration::from_millis(100)));
+        assert!(limiter.acquire_timeout(Duration::from_millis(600)));
+    }
+
+    #[test]
+    fn threads_share_the_rate() {
+        const THREADS: u32 = 4;
+        let per_thread = if cfg!(miri) { 2 } else { 5 };
+        let limiter = RateLimiter::new(200, Duration::from_secs(1));
+        let start = Instant::now();
+        std::thread::scope(|s| {
+            for _ in 0..THREADS {
+                s.spawn(|| {
+                    for _ in 0..per_thread {
+                        limiter.acquire();
+                    }
+                });
+            }
+        });
+        // 5ms apart, after the first.
+        let tokens = THREADS * per_thread;
+        assert!(start.elapsed() >= Duration::from_millis(5) * (tokens - 1));
+    }
+}
@@ -0,0 +1,110 @@
+//! Data-less locks, for code that pairs the lock with its data itself.
+//!
+//! [RawMutex] follows the shape of `lock_api::RawMutex` and
+//! `lock_api::RawMutexTimed` (`INIT`, `lock`, `try_lock`, an unsafe
+//! `unlock`, `try_lock_for`/`try_lock_until`), so implementing those
+//! traits on it only needs to forward to these methods.
+
+use crate::sem::RawSem;
+use std::time::{Duration, Instant};
+
+/// A mutex that protects no value: the capacity 1 semaphore behind
+/// [Mutex](crate::mutex::Mutex), without the data.
+pub struct RawMutex {
+    sem: RawSem,
+}
+
+impl RawMutex {
+    /// An unlocked mutex, for initializing statics and arrays.
+    #[allow(clippy::declare_interior_mutable_const)]
+    pub const INIT: RawMutex = RawMutex::new();
+
+    /// Create a new unlocked mutex.
+    pub const fn new() -> Self {
+        Self {
+            sem: RawSem::new(1),
+        }
+    }
+
+    /// Lock the mutex, waiting until it is free.
+    pub fn lock(&self) {
+        // A mutex's semaphore is never closed.
+        self.sem.acquire().expect("RawMutex closed");
+    }
+
+    /// Lock the mutex only if it is free right now.
+    pub fn try_lock(&self) -> bool {
+        self.sem.try_acquire().is_ok()
+    }
+
+    /// Lock the mutex, giving up after `timeout`.
+    pub fn try_lock_for(&self, timeout: Duration) -> bool {
+        self.try_lock_until(Instant::now() + timeout)
+    }
+
+    /// Lock the mutex, giving up once `deadline` has passed.
+    pub fn try_lock_until(&self, deadline: Instant) -> bool {
+        self.sem.acquire_until(deadline).is_ok()
+    }
+
+    /// Unlock the mutex, waking a waiter.
+    ///
+    /// # Safety
+    ///
+    /// The mutex must be locked, by this thread or on its behalf, and the
+    /// lock is given up by this call.
+    pub unsafe fn unlock(&self) {
+        self.sem.release(1);
+    }
+}
+
+impl Default for RawMutex {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::cell::UnsafeCell;
+
+    #[test]
+    fn lock_unlock() {
+        let m = RawMutex::INIT;
+        m.lock();
+        assert!(!m.try_lock());
+        assert!(!m.try_lock_for(Duration::from_millis(10)));
+        unsafe { m.unlock() };
+        assert!(m.try_lock());
+        unsafe { m.unlock() };
+    }
+
+    #[test]
+    fn guards_external_data() {
+        struct Counter {
+            lock: RawMutex,
+            value: UnsafeCell<u32>,
+        }
+        unsafe impl Sync for Counter {}
+
+        let counter = Counter {
+            lock: RawMutex::new(),
+            value: UnsafeCell::new(0),
+        };
+        std::thread::scope(|s| {
+            // Capture the whole struct, not its fields.
+            let counter = &counter;
+            for _ in 0..4 {
+                s.spawn(move || {
+                    for _ in 0..100 {
+                        counter.lock.lock();
+                        unsafe { *counter.value.get() += 1 };
+                        unsafe { counter.lock.unlock() };
+                    }
+                });
+            }
+        });
+        assert_eq!(counter.value.into_inner(), 400);
+    }
+}
@@ -0,0 +1,193 @@
+use crate::sem::{RawSem, SemPermit};
+use crate::sys::atomic::{AtomicUsize, Ordering};
+use std::cell::UnsafeCell;
+use std::marker::PhantomData;
+use std::ops::Deref;
+
+/// A mutex that the thread holding it can lock again without
+/// deadlocking. The lock is released when the outermost guard drops.
+///
+/// Guards only give shared access, since a thread may hold several at
+/// once. Use a `RefCell` inside for mutation:
+///
+/// ```
+/// use std::cell::RefCell;
+/// use xlock::reentrant::ReentrantMutex;
+///
+/// let log = ReentrantMutex::new(RefCell::new(Vec::new()));
+/// let outer = log.lock();
+/// outer.borrow_mut().push("outer");
+/// // A callback running under the lock can log too.
+/// log.lock().borrow_mut().push("inner");
+/// assert_eq!(*outer.borrow(), ["outer", "inner"]);
+/// ```
+pub struct ReentrantMutex<T: ?Sized> {
+    sem: RawSem,
+    /// The [current_thread] holding the lock, or 0.
+    owner: AtomicUsize,
+    /// How many guards the owner holds. Only touched by the owner.
+    depth: UnsafeCell<u32>,
+    /// Last, so `T` may be unsized.
+    value: T,
+}
+
+/// SAFETY: Only the owning thread reaches the value, and only through
+/// shared references, so `T` needn't be `Sync`.
+unsafe impl<T: ?Sized> Sync for ReentrantMutex<T> where T: Send {}
+
+/// A guard that represents access to the value of a [ReentrantMutex].
+/// It can't leave its thread, which is what the lock is tied to, so the
+/// `send_guard` feature doesn't apply to it.
+pub struct ReentrantMutexGuard<'a, T: ?Sized> {
+    lock: &'a ReentrantMutex<T>,
+    _not_send: PhantomData<*const ()>,
+}
+
+/// A nonzero number unique to each thread, never reused, so a lock left
+/// held by a thread that exited can't be taken for a new thread's.
+pub(crate) fn current_thread() -> usize {
+    static NEXT: AtomicUsize = AtomicUsize::new(1);
+    thread_local! {
+        static ID: usize = NEXT.fetch_add(1, Ordering::Relaxed);
+    }
+    ID.with(|&id| id)
+}
+
+impl<T> ReentrantMutex<T> {
+    /// Create a new ReentrantMutex guarding value T.
+    pub const fn new(value: T) -> Self {
+        Self {
+            sem: RawSem::new(1),
+            owner: AtomicUsize::new(0),
+            depth: UnsafeCell::new(0),
+            value,
+        }
+    }
+}
+
+impl<T: ?Sized> ReentrantMutex<T> {
+    /// Lock the mutex, waiting for other threads to unlock it. Returns at
+    /// once if this thread holds it already.
+    pub fn lock(&self) -> ReentrantMutexGuard<'_, T> {
+        let me = current_thread();
+        // Only this thread ever stores `me`, so seeing it means we hold
+        // the lock; seeing anything else means we don't.
+        if self.owner.load(Ordering::Relaxed) != me {
+            let permit = self.sem.acquire_permit().expect("never closed");
+            self.hold(permit, me);
+        }
+        self.enter()
+    }
+
+    /// Lock the mutex if it is free or already held by this thread.
+    pub fn try_lock(&self) -> Option<ReentrantMutexGuard<'_, T>> {
+        let me = current_thread();
+        if self.owner.load(Ordering::Relaxed) != me {
+            let permit = self.sem.try_acquire_permits(1).ok()?;
+            self.hold(permit, me);
+        }
+        Some(self.enter())
+    }
+
+    /// Record a freshly acquired lock as held by `me`, keeping it locked
+    /// until the outermost guard drops.
+    fn hold(&self, permit: SemPermit<'_>, me: usize) {
+        permit.into_raw();
+        self.owner.store(me, Ordering::Relaxed);
+    }
+
+    fn enter(&self) -> ReentrantMutexGuard<'_, T> {
+        let depth = unsafe { &mut *self.depth.get() };
+        *depth = depth.checked_add(1).expect("lock count overflow");
+        ReentrantMutexGuard {
+            lock: self,
+            _not_send: PhantomData,
+        }
+    }
+}
+
+impl<T: ?Sized> Deref for ReentrantMutexGuard<'_, T> {
+    type Target = T;
+    fn deref(&self) -> &T {
+        &self.lock.value
+    }
+}
+
+impl<T: ?Sized> Drop for ReentrantMutexGuard<'_, T> {
+    fn drop(&mut self) {
+        let depth = unsafe { &mut *self.lock.depth.get() };
+        *depth -= 1;
+        if *depth == 0 {
+            self.lock.owner.store(0, Ordering::Relaxed);
+            // SAFETY: `hold` leaked this permit when the lock was taken.
+            drop(unsafe { SemPermit::from_raw(&self.lock.sem) });
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::cell::Cell;
+
+    #[test]
+    fn same_thread_triple_lock() {
+        let m = ReentrantMutex::new(Cell::new(0));
+        let a = m.lock();
+        let b = m.lock();
+        let c = m.lock();
+        c.set(c.get() + 1);
+        assert_eq!(a.get() + b.get(), 2);
+    }
+
+    #[test]
+    fn excludes_other_threads() {
+        let m = ReentrantMutex::new(Cell::new(0));
+        std::thread::scope(|s| {
+            for _ in 0..4 {
+                s.spawn(|| {
+                    for _ in 0..100 {
+                        let outer = m.lock();
+                        let inner = m.lock();
+                        inner.set(outer.get() + 1);
+                    }
+                });
+            }
+        });
+        assert_eq!(m.lock().get(), 400);
+    }
+
+    #[test]
+    fn lock_of_exited_thread_is_not_reentered() {
+        let m = ReentrantMutex::new(());
+        let ids: Vec<_> = (0..8)
+            .map(|_| std::thread::spawn(current_thread).join().unwrap())
+            .collect();
+        for (i, id) in ids.iter().enumerate() {
+            assert!(!ids[i + 1..].contains(id));
+        }
+        std::thread::scope(|s| {
+            s.spawn(|| std::mem::forget(m.lock()));
+        });
+        for _ in 0..8 {
+            std::thread::scope(|s| {
+                assert!(s.spawn(|| m.try_lock().is_none()).join().unwrap());
+            });
+        }
+    }
+
+    #[test]
+    fn inner_guards_do_not_unlock() {
+        let m = ReentrantMutex::new(());
+        let outer = m.lock();
+        let inner = m.lock();
+        drop(inner);
+        std::thread::scope(|s| {
+            assert!(s.spawn(|| m.try_lock().is_none()).join().unwrap());
+        });
+        drop(outer);
+        std::thread::scope(|s| {
+            assert!(s.spawn(|| m.try_lock().is_some()).join().unwrap());
+        });
+    }
+}
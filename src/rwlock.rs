@@ -0,0 +1,830 @@
+use crate::sem::{SemGuard, SemVar};
+use crate::sys::atomic::{AtomicU32, Ordering};
+use crate::sys::{wait, wake_all, wake_one};
+use std::cell::UnsafeCell;
+use std::marker::PhantomData;
+use std::ops::{Deref, DerefMut};
+use std::ptr::NonNull;
+
+/// The `state` of a write-locked [RwLock].
+const WRITE_LOCKED: u32 = u32::MAX;
+
+/// Set in `state` while a writer is waiting. New readers queue behind it.
+const WRITER_WAITING: u32 = 1;
+
+/// How much each reader adds to `state`.
+const READER: u32 = 2;
+
+/// Whether a reader may join in `state`. Both [WRITE_LOCKED] and a
+/// waiting writer make it odd; a recursive reader only minds the waiting
+/// writer while no readers are left for it to join.
+fn can_read(s: u32, recursive: bool) -> bool {
+    if recursive {
+        s != WRITE_LOCKED && (s & WRITER_WAITING == 0 || s >= READER)
+    } else {
+        s & WRITER_WAITING == 0
+    }
+}
+
+/// A reader-writer lock: any number of readers, or a single writer.
+///
+/// The lock prefers writers: once a writer is waiting, new readers wait
+/// until it has had its turn, so a steady stream of readers can't starve
+/// writers. [RwLock::read_recursive] is the exception, for code that may
+/// take read access again while already holding it.
+pub struct RwLock<T: ?Sized> {
+    /// Twice the number of readers, plus [WRITER_WAITING] if a writer is
+    /// waiting; or [WRITE_LOCKED].
+    state: AtomicU32,
+    /// Bumped whenever a writer might be able to proceed. Writers park on
+    /// this instead of `state`, so readers coming and going don't wake them.
+    writer_wake_counter: AtomicU32,
+    /// Held by the one upgradable reader.
+    upgradable: SemVar<()>,
+    /// The protected value.
+    value: UnsafeCell<T>,
+}
+
+/// SAFETY: Readers on several threads share `&T`, so `T` must be `Sync`,
+/// and a writer may move values in and out, so it must be `Send`.
+unsafe impl<T: ?Sized> Sync for RwLock<T> where T: Send + Sync {}
+
+/// A guard that represents shared access to the value of an [RwLock].
+pub struct RwLockReadGuard<'a, T: ?Sized> {
+    lock: &'a RwLock<T>,
+}
+
+/// A guard that represents exclusive access to the value of an [RwLock].
+pub struct RwLockWriteGuard<'a, T: ?Sized> {
+    lock: &'a RwLock<T>,
+}
+
+/// A guard that represents shared access to the value of an [RwLock],
+/// which can be upgraded to exclusive access without unlocking first.
+pub struct RwLockUpgradableReadGuard<'a, T: ?Sized> {
+    read: RwLockReadGuard<'a, T>,
+    /// Declared last so it is given back after the read access.
+    _slot: SemGuard<'a, ()>,
+}
+
+/// A guard for part of an [RwLock]'s value, made by
+/// [RwLockReadGuard::map]. Keeps read access until dropped.
+pub struct MappedRwLockReadGuard<'a, U: ?Sized> {
+    value: NonNull<U>,
+    unlock: Unlock<'a>,
+    _marker: PhantomData<&'a U>,
+}
+
+/// A guard for part of an [RwLock]'s value, made by
+/// [RwLockWriteGuard::map]. Keeps the lock write-locked until dropped.
+pub struct MappedRwLockWriteGuard<'a, U: ?Sized> {
+    value: NonNull<U>,
+    unlock: Unlock<'a>,
+    _marker: PhantomData<&'a mut U>,
+}
+
+/// SAFETY: Sharing or sending the mapped guard only shares `&U`.
+unsafe impl<U: ?Sized> Sync for MappedRwLockReadGuard<'_, U> where U: Sync {}
+unsafe impl<U: ?Sized> Send for MappedRwLockReadGuard<'_, U> where U: Sync {}
+
+/// SAFETY: Sharing the mapped guard shares `&U`, and sending it moves
+/// `&mut U` along with it. Unlocking is valid from any thread.
+unsafe impl<U: ?Sized> Sync for MappedRwLockWriteGuard<'_, U> where U: Sync {}
+unsafe impl<U: ?Sized> Send for MappedRwLockWriteGuard<'_, U> where U: Send {}
+
+/// The parts of an [RwLock] needed to give up access, without its value,
+/// so mapped guards needn't name `T`.
+struct Unlock<'a> {
+    state: &'a AtomicU32,
+    writer_wake_counter: &'a AtomicU32,
+    #[cfg(feature = "deadlock_detection")]
+    address: usize,
+}
+
+impl Unlock<'_> {
+    /// Give up one read access.
+    fn read(&self) {
+        #[cfg(feature = "deadlock_detection")]
+        crate::deadlock::released(self.address);
+        match self.state.fetch_sub(READER, Ordering::Release) {
+            // The last reader out lets a waiting writer in.
+            s if s == READER + WRITER_WAITING => {
+                self.writer_wake_counter.fetch_add(1, Ordering::Release);
+                wake_one(self.writer_wake_counter);
+            }
+            // The one reader left may be an upgrader waiting for us.
+            // Waking a single thread could pick a plain writer instead.
+            s if s == 2 * READER + WRITER_WAITING => {
+                self.writer_wake_counter.fetch_add(1, Ordering::Release);
+                wake_all(self.writer_wake_counter);
+            }
+            _ => {}
+        }
+    }
+
+    /// Give up the write lock.
+    fn write(&self) {
+        #[cfg(feature = "deadlock_detection")]
+        crate::deadlock::released(self.address);
+        // This also clears WRITER_WAITING; other waiting writers set it
+        // again once woken.
+        self.state.store(0, Ordering::Release);
+        // Hand over to either one writer or all readers.
+        self.writer_wake_counter.fetch_add(1, Ordering::Release);
+        wake_one(self.writer_wake_counter);
+        wake_all(self.state);
+    }
+}
+
+impl<T> RwLock<T> {
+    /// Create a new RwLock guarding value T.
+    pub const fn new(value: T) -> Self {
+        Self {
+            state: AtomicU32::new(0),
+            writer_wake_counter: AtomicU32::new(0),
+            upgradable: SemVar::new(1, ()),
+            value: UnsafeCell::new(value),
+        }
+    }
+}
+
+impl<T: ?Sized> RwLock<T> {
+    /// Gain shared access, waiting while a writer holds the lock or is
+    /// waiting for it.
+    pub fn read(&self) -> RwLockReadGuard<'_, T> {
+        self.read_inner(false)
+    }
+
+    /// Gain shared access, joining the readers already in even if a writer
+    /// is waiting. Only waits while a writer holds the lock, or is about to
+    /// take it with no readers left.
+    ///
+    /// A thread that already holds read access and takes it again with
+    /// [RwLock::read] deadlocks once a writer starts waiting in between:
+    /// the writer waits for the first read to end, and the second for the
+    /// writer. This doesn't. But readers taking it in a steady stream keep
+    /// writers out indefinitely, so only use it on paths that really can
+    /// re-enter a read.
+    pub fn read_recursive(&self) -> RwLockReadGuard<'_, T> {
+        self.read_inner(true)
+    }
+
+    fn read_inner(&self, recursive: bool) -> RwLockReadGuard<'_, T> {
+        let mut s = self.state.load(Ordering::Relaxed);
+        loop {
+            if can_read(s, recursive) {
+                assert!(s < WRITE_LOCKED - READER, "too many readers");
+                match self.state.compare_exchange_weak(
+                    s,
+                    s + READER,
+                    Ordering::Acquire,
+                    Ordering::Relaxed,
+                ) {
+                    Ok(_) => {
+                        #[cfg(feature = "deadlock_detection")]
+                        crate::deadlock::acquired(self.address());
+                        return RwLockReadGuard { lock: self };
+                    }
+                    Err(e) => s = e,
+                }
+            }
+            if !can_read(s, recursive) {
+                #[cfg(feature = "deadlock_detection")]
+                let _waiting = crate::deadlock::wait_for(self.address(), false);
+                wait(&self.state, s);
+                s = self.state.load(Ordering::Relaxed);
+            }
+        }
+    }
+
+    /// Gain exclusive access, waiting for all readers and any writer to
+    /// leave. Readers arriving in the meantime wait behind this call.
+    pub fn write(&self) -> RwLockWriteGuard<'_, T> {
+        let mut s = self.state.load(Ordering::Relaxed);
+        loop {
+            // No readers, with or without a waiting writer (maybe us).
+            if s <= WRITER_WAITING {
+                match self.state.compare_exchange(
+                    s,
+                    WRITE_LOCKED,
+                    Ordering::Acquire,
+                    Ordering::Relaxed,
+                ) {
+                    Ok(_) => {
+                        #[cfg(feature = "deadlock_detection")]
+                        crate::deadlock::acquired(self.address());
+                        return RwLockWriteGuard { lock: self };
+                    }
+                    Err(e) => {
+                        s = e;
+                        continue;
+                    }
+                }
+            }
+            // Hold back new readers.
+            if s & WRITER_WAITING == 0 {
+                if let Err(e) = self.state.compare_exchange(
+                    s,
+                    s | WRITER_WAITING,
+                    Ordering::Relaxed,
+                    Ordering::Relaxed,
+                ) {
+                    s = e;
+                    continue;
+                }
+            }
+            // Park until the last reader or the writer leaves, unless they
+            // already have.
+            let w = self.writer_wake_counter.load(Ordering::Acquire);
+            s = self.state.load(Ordering::Relaxed);
+            if s > WRITER_WAITING {
+                #[cfg(feature = "deadlock_detection")]
+                let _waiting = crate::deadlock::wait_for(self.address(), false);
+                wait(&self.writer_wake_counter, w);
+                s = self.state.load(Ordering::Relaxed);
+            }
+        }
+    }
+
+    /// Gain shared access only if that doesn't require waiting: no
+    /// writer holds the lock or is waiting for it.
+    pub fn try_read(&self) -> Option<RwLockReadGuard<'_, T>> {
+        self.try_read_inner(false)
+    }
+
+    /// Gain shared access only if that doesn't require waiting, joining
+    /// the readers already in even if a writer is waiting. See
+    /// [RwLock::read_recursive].
+    pub fn try_read_recursive(&self) -> Option<RwLockReadGuard<'_, T>> {
+        self.try_read_inner(true)
+    }
+
+    fn try_read_inner(&self, recursive: bool) -> Option<RwLockReadGuard<'_, T>> {
+        let mut s = self.state.load(Ordering::Relaxed);
+        while can_read(s, recursive) && s < WRITE_LOCKED - READER {
+            match self.state.compare_exchange_weak(
+                s,
+                s + READER,
+                Ordering::Acquire,
+                Ordering::Relaxed,
+            ) {
+                Ok(_) => {
+                    #[cfg(feature = "deadlock_detection")]
+                    crate::deadlock::acquired(self.address());
+                    return Some(RwLockReadGuard { lock: self });
+                }
+                Err(e) => s = e,
+            }
+        }
+        None
+    }
+
+    /// Gain exclusive access only if nobody holds the lock right now.
+    pub fn try_write(&self) -> Option<RwLockWriteGuard<'_, T>> {
+        // A waiting writer is about to take the lock, so don't overtake it.
+        self.state
+            .compare_exchange(0, WRITE_LOCKED, Ordering::Acquire, Ordering::Relaxed)
+            .ok()?;
+        #[cfg(feature = "deadlock_detection")]
+        crate::deadlock::acquired(self.address());
+        Some(RwLockWriteGuard { lock: self })
+    }
+
+    /// Gain shared access that can later be upgraded to exclusive access.
+    /// Only one upgradable reader exists at a time, alongside any number
+    /// of plain readers.
+    pub fn upgradable_read(&self) -> RwLockUpgradableReadGuard<'_, T> {
+        let slot = self.upgradable.access();
+        RwLockUpgradableReadGuard {
+            read: self.read(),
+            _slot: slot,
+        }
+    }
+
+    fn unlock(&self) -> Unlock<'_> {
+        Unlock {
+            state: &self.state,
+            writer_wake_counter: &self.writer_wake_counter,
+            #[cfg(feature = "deadlock_detection")]
+            address: self.address(),
+        }
+    }
+
+    /// The address of the lock, identifying it to [check_deadlock].
+    ///
+    /// [check_deadlock]: crate::deadlock::check_deadlock
+    #[cfg(feature = "deadlock_detection")]
+    fn address(&self) -> usize {
+        (self as *const Self).cast::<u8>() as usize
+    }
+}
+
+impl<'a, T: ?Sized> RwLockUpgradableReadGuard<'a, T> {
+    /// Turn this into exclusive access, waiting for the other readers to
+    /// leave. Nobody else can write in between.
+    pub fn upgrade(self) -> RwLockWriteGuard<'a, T> {
+        let lock = self.read.lock;
+        let mut s = lock.state.load(Ordering::Relaxed);
+        loop {
+            // Only our own read access is left.
+            if s & !WRITER_WAITING == READER {
+                match lock.state.compare_exchange(
+                    s,
+                    WRITE_LOCKED,
+                    Ordering::Acquire,
+                    Ordering::Relaxed,
+                ) {
+                    Ok(_) => break,
+                    Err(e) => {
+                        s = e;
+                        continue;
+                    }
+                }
+            }
+            // Hold back new readers, like a waiting writer.
+            if s & WRITER_WAITING == 0 {
+                if let Err(e) = lock.state.compare_exchange(
+                    s,
+                    s | WRITER_WAITING,
+                    Ordering::Relaxed,
+                    Ordering::Relaxed,
+                ) {
+                    s = e;
+                    continue;
+                }
+            }
+            let w = lock.writer_wake_counter.load(Ordering::Acquire);
+            s = lock.state.load(Ordering::Relaxed);
+            if s & !WRITER_WAITING != READER {
+                // Our own read access doesn't count: only the others do.
+                #[cfg(feature = "deadlock_detection")]
+                let _waiting = crate::deadlock::wait_for(lock.address(), true);
+                wait(&lock.writer_wake_counter, w);
+                s = lock.state.load(Ordering::Relaxed);
+            }
+        }
+        // Our read access became the write lock. The slot is only given
+        // back now: a new upgradable reader taking read access before this
+        // point would have waited on us while we waited on it.
+        let Self { read, _slot } = self;
+        std::mem::forget(read);
+        RwLockWriteGuard { lock }
+    }
+
+    /// Turn this into exclusive access only if no other readers are
+    /// left; otherwise hand the guard back.
+    pub fn try_upgrade(self) -> Result<RwLockWriteGuard<'a, T>, Self> {
+        let lock = self.read.lock;
+        let s = lock.state.load(Ordering::Relaxed);
+        if s & !WRITER_WAITING == READER
+            && lock
+                .state
+                .compare_exchange(s, WRITE_LOCKED, Ordering::Acquire, Ordering::Relaxed)
+                .is_ok()
+        {
+            let Self { read, _slot } = self;
+            std::mem::forget(read);
+            Ok(RwLockWriteGuard { lock })
+        } else {
+            Err(self)
+        }
+    }
+}
+
+impl<'a, T: ?Sized> RwLockReadGuard<'a, T> {
+    /// Narrow the guard down to the part of the value returned by `f`.
+    /// Read access is kept until the mapped guard is dropped.
+    pub fn map<U: ?Sized>(self, f: impl FnOnce(&T) -> &U) -> MappedRwLockReadGuard<'a, U> {
+        let value = NonNull::from(f(unsafe { &*self.lock.value.get() }));
+        self.into_mapped(value)
+    }
+
+    /// Like [RwLockReadGuard::map], but `f` may decline by returning
+    /// `None`, in which case the original guard is handed back.
+    pub fn try_map<U: ?Sized>(
+        self,
+        f: impl FnOnce(&T) -> Option<&U>,
+    ) -> Result<MappedRwLockReadGuard<'a, U>, Self> {
+        match f(unsafe { &*self.lock.value.get() }) {
+            Some(value) => {
+                let value = NonNull::from(value);
+                Ok(self.into_mapped(value))
+            }
+            None => Err(self),
+        }
+    }
+
+    fn into_mapped<U: ?Sized>(self, value: NonNull<U>) -> MappedRwLockReadGuard<'a, U> {
+        let lock = self.lock;
+        std::mem::forget(self);
+        MappedRwLockReadGuard {
+            value,
+            unlock: lock.unlock(),
+            _marker: PhantomData,
+        }
+    }
+}
+
+impl<'a, T: ?Sized> RwLockWriteGuard<'a, T> {
+    /// Narrow the guard down to the part of the value returned by `f`.
+    /// The lock stays write-locked until the mapped guard is dropped.
+    pub fn map<U: ?Sized>(self, f: impl FnOnce(&mut T) -> &mut U) -> MappedRwLockWriteGuard<'a, U> {
+        let value = NonNull::from(f(unsafe { &mut *self.lock.value.get() }));
+        self.into_mapped(value)
+    }
+
+    /// Like [RwLockWriteGuard::map], but `f` may decline by returning
+    /// `None`, in which case the original guard is handed back.
+    pub fn try_map<U: ?Sized>(
+        self,
+        f: impl FnOnce(&mut T) -> Option<&mut U>,
+    ) -> Result<MappedRwLockWriteGuard<'a, U>, Self> {
+        match f(unsafe { &mut *self.lock.value.get() }) {
+            Some(value) => {
+                let value = NonNull::from(value);
+                Ok(self.into_mapped(value))
+            }
+            None => Err(self),
+        }
+    }
+
+    fn into_mapped<U: ?Sized>(self, value: NonNull<U>) -> MappedRwLockWriteGuard<'a, U> {
+        let lock = self.lock;
+        std::mem::forget(self);
+        MappedRwLockWriteGuard {
+            value,
+            unlock: lock.unlock(),
+            _marker: PhantomData,
+        }
+    }
+
+    /// Turn exclusive access into shared access, without unlocking in
+    /// between. Readers waiting for this writer get in too.
+    pub fn downgrade(self) -> RwLockReadGuard<'a, T> {
+        let lock = self.lock;
+        std::mem::forget(self);
+        // This clears WRITER_WAITING. Wake a waiting writer so it sets it
+        // again, or the last reader out wouldn't know to wake it.
+        lock.state.store(READER, Ordering::Release);
+        lock.writer_wake_counter.fetch_add(1, Ordering::Release);
+        wake_one(&lock.writer_wake_counter);
+        wake_all(&lock.state);
+        RwLockReadGuard { lock }
+    }
+}
+
+impl<T: ?Sized> Deref for RwLockUpgradableReadGuard<'_, T> {
+    type Target = T;
+    fn deref(&self) -> &T {
+        &self.read
+    }
+}
+
+impl<U: ?Sized> Deref for MappedRwLockReadGuard<'_, U> {
+    type Target = U;
+    fn deref(&self) -> &U {
+        unsafe { self.value.as_ref() }
+    }
+}
+
+impl<U: ?Sized> Deref for MappedRwLockWriteGuard<'_, U> {
+    type Target = U;
+    fn deref(&self) -> &U {
+        unsafe { self.value.as_ref() }
+    }
+}
+
+impl<U: ?Sized> DerefMut for MappedRwLockWriteGuard<'_, U> {
+    fn deref_mut(&mut self) -> &mut U {
+        unsafe { self.value.as_mut() }
+    }
+}
+
+impl<T: ?Sized> Deref for RwLockReadGuard<'_, T> {
+    type Target = T;
+    fn deref(&self) -> &T {
+        unsafe { &*self.lock.value.get() }
+    }
+}
+
+impl<T: ?Sized> Deref for RwLockWriteGuard<'_, T> {
+    type Target = T;
+    fn deref(&self) -> &T {
+        unsafe { &*self.lock.value.get() }
+    }
+}
+
+impl<T: ?Sized> DerefMut for RwLockWriteGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.lock.value.get() }
+    }
+}
+
+impl<T: ?Sized> Drop for RwLockReadGuard<'_, T> {
+    fn drop(&mut self) {
+        self.lock.unlock().read();
+    }
+}
+
+impl<T: ?Sized> Drop for RwLockWriteGuard<'_, T> {
+    fn drop(&mut self) {
+        self.lock.unlock().write();
+    }
+}
+
+impl<U: ?Sized> Drop for MappedRwLockReadGuard<'_, U> {
+    fn drop(&mut self) {
+        self.unlock.read();
+    }
+}
+
+impl<U: ?Sized> Drop for MappedRwLockWriteGuard<'_, U> {
+    fn drop(&mut self) {
+        self.unlock.write();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::collections::HashMap;
+    use std::sync::Barrier;
+
+    #[test]
+    fn try_read_and_try_write() {
+        let lock = RwLock::new(1);
+        let read = lock.try_read().unwrap();
+        assert!(lock.try_write().is_none());
+        let read2 = lock.try_read().unwrap();
+        assert_eq!(*read + *read2, 2);
+        drop((read, read2));
+        let mut write = lock.try_write().unwrap();
+        *write += 1;
+        assert!(lock.try_read().is_none());
+        assert!(lock.try_write().is_none());
+        drop(write);
+        assert_eq!(*lock.try_read().unwrap(), 2);
+    }
+
+    #[test]
+    fn readers_and_writers() {
+        let lock = RwLock::new(vec![0u32]);
+        let iterations = if cfg!(miri) { 10 } else { 200 };
+        std::thread::scope(|s| {
+            for _ in 0..2 {
+                s.spawn(|| {
+                    for _ in 0..iterations {
+                        let mut v = lock.write();
+                        let next = v.last().unwrap() + 1;
+                        v.push(next);
+                    }
+                });
+            }
+            for _ in 0..8 {
+                s.spawn(|| {
+                    for _ in 0..iterations {
+                        let v = lock.read();
+                        // Writers only ever push the next number.
+                        assert_eq!(*v.last().unwrap() as usize, v.len() - 1);
+                    }
+                });
+            }
+        });
+        assert_eq!(lock.read().len(), 2 * iterations + 1);
+    }
+
+    #[test]
+    fn readers_are_concurrent() {
+        let lock = RwLock::new(5);
+        // Both readers must hold the lock at once to pass the barrier.
+        let barrier = Barrier::new(2);
+        std::thread::scope(|s| {
+            for _ in 0..2 {
+                s.spawn(|| {
+                    let value = lock.read();
+                    barrier.wait();
+                    assert_eq!(*value, 5);
+                });
+            }
+        });
+    }
+
+    /// One writer competing with a crowd of looping readers must still
+    /// get all its writes in, well within the time budget.
+    #[test]
+    fn writer_is_not_starved_by_readers() {
+        let (readers, writes) = if cfg!(miri) { (4, 5) } else { (32, 100) };
+        let lock = RwLock::new(0u32);
+        let done = std::sync::atomic::AtomicBool::new(false);
+        let (tx, rx) = std::sync::mpsc::channel();
+
+        std::thread::scope(|s| {
+            for _ in 0..readers {
+                s.spawn(|| {
+                    while !done.load(Ordering::Relaxed) {
+                        let value = lock.read();
+                        std::hint::black_box(*value);
+                    }
+                });
+            }
+            s.spawn(|| {
+                for _ in 0..writes {
+                    *lock.write() += 1;
+                }
+                tx.send(()).unwrap();
+            });
+
+            let finished = rx.recv_timeout(std::time::Duration::from_secs(60));
+            done.store(true, Ordering::Relaxed);
+            assert!(finished.is_ok(), "writer starved by readers");
+        });
+        assert_eq!(*lock.read(), writes);
+    }
+
+    #[test]
+    fn check_then_modify_with_upgrades() {
+        let lock = RwLock::new(Vec::<u32>::new());
+        let iterations = if cfg!(miri) { 5 } else { 100 };
+        std::thread::scope(|s| {
+            for id in 0..4 {
+                let lock = &lock;
+                s.spawn(move || {
+                    for i in 0..iterations {
+                        let value = id * 1000 + i;
+                        let guard = lock.upgradable_read();
+                        if !guard.contains(&value) {
+                            guard.upgrade().push(value);
+                        }
+                    }
+                });
+            }
+            for _ in 0..4 {
+                s.spawn(|| {
+                    for _ in 0..iterations {
+                        std::hint::black_box(lock.read().len());
+                    }
+                });
+            }
+            s.spawn(|| {
+                for _ in 0..iterations {
+                    let guard = lock.write();
+                    std::hint::black_box(guard.len());
+                }
+            });
+        });
+        assert_eq!(lock.read().len(), 4 * iterations as usize);
+    }
+
+    #[test]
+    fn upgrade_waits_for_readers_without_deadlocking_writers() {
+        let lock = RwLock::new(0);
+        std::thread::scope(|s| {
+            let reader = lock.read();
+            let upgradable = lock.upgradable_read();
+            let writer = s.spawn(|| *lock.write() += 10);
+            let upgrader = s.spawn(move || *upgradable.upgrade() += 1);
+            std::thread::sleep(std::time::Duration::from_millis(50));
+            drop(reader);
+            upgrader.join().unwrap();
+            writer.join().unwrap();
+        });
+        assert_eq!(*lock.read(), 11);
+    }
+
+    #[test]
+    fn try_upgrade_fails_with_other_readers() {
+        let lock = RwLock::new(0);
+        let reader = lock.read();
+        let upgradable = lock.upgradable_read();
+        let upgradable = upgradable.try_upgrade().err().unwrap();
+        drop(reader);
+        *upgradable.try_upgrade().ok().unwrap() += 1;
+        assert_eq!(*lock.read(), 1);
+    }
+
+    #[test]
+    fn downgrade_lets_readers_in() {
+        let lock = RwLock::new(0);
+        let mut writer = lock.write();
+        std::thread::scope(|s| {
+            let reader = s.spawn(|| *lock.read());
+            *writer = 7;
+            std::thread::sleep(std::time::Duration::from_millis(20));
+            let read = writer.downgrade();
+            // The parked reader gets in while we still hold read access.
+            assert_eq!(reader.join().unwrap(), 7);
+            assert_eq!(*read, 7);
+        });
+    }
+
+    /// Take read access, then again once a writer is waiting in between;
+    /// true if the second read got in within the timeout. On false the
+    /// reader and writer are left deadlocked.
+    fn reenter_past_waiting_writer(recursive: bool) -> bool {
+        let lock = std::sync::Arc::new(RwLock::new(1));
+        let (done_tx, done_rx) = std::sync::mpsc::channel();
+        let shared = lock.clone();
+        let reader = std::thread::spawn(move || {
+            let lock = &*shared;
+            let first = lock.read();
+            let writing = shared.clone();
+            let writer = std::thread::spawn(move || *writing.write() += 1);
+            while lock.state.load(Ordering::Relaxed) & WRITER_WAITING == 0 {
+                std::thread::yield_now();
+            }
+            let second = if recursive {
+                lock.read_recursive()
+            } else {
+                lock.read()
+            };
+            done_tx.send(*first + *second).unwrap();
+            writer
+        });
+        match done_rx.recv_timeout(std::time::Duration::from_millis(500)) {
+            Ok(sum) => {
+                assert_eq!(sum, 2);
+                // The writer gets in once both reads are over.
+                reader.join().unwrap().join().unwrap();
+                assert_eq!(*lock.read(), 2);
+                true
+            }
+            Err(_) => false,
+        }
+    }
+
+    // The deadlocked threads are never joined, which miri reports.
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn reentrant_read_deadlocks_behind_waiting_writer() {
+        assert!(!reenter_past_waiting_writer(false));
+    }
+
+    #[test]
+    fn read_recursive_joins_past_waiting_writer() {
+        assert!(reenter_past_waiting_writer(true));
+    }
+
+    #[test]
+    fn try_read_recursive() {
+        let lock = RwLock::new(0);
+        // Nobody to join, so it waits for a writer like try_read.
+        let write = lock.write();
+        assert!(lock.try_read_recursive().is_none());
+        drop(write);
+        let read = lock.read();
+        lock.state.fetch_or(WRITER_WAITING, Ordering::Relaxed);
+        assert!(lock.try_read().is_none());
+        let again = lock.try_read_recursive().unwrap();
+        drop((read, again));
+        // Only the waiting writer is left, so readers wait for it.
+        assert_eq!(lock.state.load(Ordering::Relaxed), WRITER_WAITING);
+        assert!(lock.try_read_recursive().is_none());
+    }
+
+    #[test]
+    fn map_read_guard_into_map_value() {
+        let lock = RwLock::new(HashMap::from([("net", vec![1, 2]), ("disk", vec![3])]));
+        let barrier = Barrier::new(3);
+        std::thread::scope(|s| {
+            for key in ["net", "disk"] {
+                let (lock, barrier) = (&lock, &barrier);
+                s.spawn(move || {
+                    let section = lock.read().map(|config| &config[key]);
+                    // All three readers hold the lock at once.
+                    barrier.wait();
+                    assert!(!section.is_empty());
+                });
+            }
+            let whole = lock.read();
+            barrier.wait();
+            assert_eq!(whole.len(), 2);
+        });
+        let missing = lock.read().try_map(|config| config.get("gpu"));
+        let whole = missing.err().unwrap();
+        assert!(lock.try_write().is_none());
+        drop(whole);
+        assert!(lock.try_write().is_some());
+    }
+
+    #[test]
+    fn mapped_write_is_visible_after_release() {
+        let lock = RwLock::new(HashMap::from([("net", vec![1])]));
+        let mut net = lock.write().map(|config| config.get_mut("net").unwrap());
+        net.push(2);
+        assert!(lock.try_read().is_none());
+        drop(net);
+        assert_eq!(lock.read()["net"], [1, 2]);
+
+        // Find the entry or fall back to inserting it.
+        let mut disk = match lock.write().try_map(|config| config.get_mut("disk")) {
+            Ok(disk) => disk,
+            Err(mut config) => {
+                config.insert("disk", Vec::new());
+                config.map(|config| config.get_mut("disk").unwrap())
+            }
+        };
+        disk.push(3);
+        drop(disk);
+        assert_eq!(lock.read()["disk"], [3]);
+    }
+}
@@ -1,66 +1,1390 @@
-use atomic_wait::{wait, wake_one};
-use std::sync::atomic::{AtomicU32, Ordering};
+#[cfg(feature = "std")]
+use crate::cancel::CancelToken;
+#[cfg(feature = "cache_padded")]
+use crate::padded::CachePadded;
+#[cfg(feature = "priority")]
+use crate::semaphore::Priority;
+use crate::semaphore::{AcquireError, TryAcquireError};
+#[cfg(feature = "stats")]
+use crate::stats::{LockStats, Stats};
+use crate::sys::atomic::{AtomicU32, Ordering};
+use crate::sys::{wait, wait_until, wake_all, wake_one, Instant};
+#[cfg(feature = "async")]
+use crate::wakers::WakerQueue;
+#[cfg(feature = "async")]
+use std::future::Future;
+#[cfg(feature = "async")]
+use std::pin::Pin;
+#[cfg(feature = "async")]
+use std::task::{Context, Poll};
 
-/// A type representing a semaphore-protected value.
-pub(crate) struct SemVar<T> {
+/// Stands in for [CancelToken] without `std`, like
+/// [Instant](crate::sys::Instant): it has no values, so there is never a
+/// token to park with.
+#[cfg(not(feature = "std"))]
+enum CancelToken {}
+
+#[cfg(not(feature = "std"))]
+impl CancelToken {
+    fn park(&self, _: &AtomicU32, _: u32, _: Option<Instant>) -> Result<(), AcquireError> {
+        match *self {}
+    }
+}
+
+/// Stands in for [Priority](crate::semaphore::Priority) without the
+/// `priority` feature: every waiter is normal.
+#[cfg(not(feature = "priority"))]
+#[derive(Clone, Copy)]
+enum Priority {
+    Normal,
+}
+
+/// If `word` is `value`, wait until woken, until `deadline`, or until
+/// `cancel` is cancelled, failing with the reason in the latter two cases.
+/// Like [wait], this can return spuriously.
+fn park(
+    word: &AtomicU32,
+    value: u32,
+    deadline: Option<Instant>,
+    cancel: Option<&CancelToken>,
+) -> Result<(), AcquireError> {
+    match (cancel, deadline) {
+        (Some(token), _) => token.park(word, value, deadline),
+        (None, None) => {
+            wait(word, value);
+            Ok(())
+        }
+        (None, Some(deadline)) if wait_until(word, value, deadline) => Ok(()),
+        (None, Some(_)) => Err(AcquireError::Timeout),
+    }
+}
+
+/// The counting core of a semaphore, without any value attached.
+///
+/// Waiters that need more than one access at a time can be starved by a
+/// stream of single-access waiters, since some access is always held. So
+/// the first such waiter that has to park reserves what it needs, and
+/// everyone else then leaves that many accesses alone until it is served.
+pub(crate) struct RawSem {
     /// The maximum allowed accesses at a time.
-    capacity: u32,
-    /// Number of active accesses.
-    count: AtomicU32,
-    /// The value being guarded.
+    capacity: AtomicU32,
+    /// Accesses free to take, offset by [ZERO] so it can go negative when
+    /// the capacity is lowered below the number held, plus the [CLOSED]
+    /// bit. Waiters park on this word, so capacity changes and closing
+    /// wake them like releases do.
+    available: AtomicU32,
+    /// Accesses set aside for a parked multi-access waiter, or 0. Threads
+    /// held back only by the reservation park on this word.
+    reserved: AtomicU32,
+    /// Number of multi-access waiters that may be parked. Releases wake
+    /// everyone while this is nonzero, since waking a single thread could
+    /// pick one that still can't proceed.
+    waiting_many: AtomicU32,
+    /// Number of threads that may be parked on `available`. Releases skip
+    /// the wake syscall while this is zero.
+    sleepers: AtomicU32,
+    /// Parked [Priority::High] waiters in the low half and [Priority::Low]
+    /// ones in the high half; the rest of `sleepers` are normal. Releases
+    /// wake everyone while this is nonzero, since a single thread woken
+    /// could be one that has to let a higher priority go first.
+    #[cfg(feature = "priority")]
+    ranked: AtomicU32,
+    /// Bumped when a waiter leaves that lower priorities held back for.
+    /// Threads held back only by higher priorities park on this word.
+    #[cfg(feature = "priority")]
+    rank_epoch: AtomicU32,
+    /// Accesses freed by a fair release and set aside for threads that
+    /// were already waiting, so the releasing thread can't take them
+    /// straight back. Others held back only by this park on this word.
+    #[cfg(feature = "eventual_fairness")]
+    handoff: AtomicU32,
+    /// Bumped by each fair release, telling its releaser apart from the
+    /// waiters it hands to.
+    #[cfg(feature = "eventual_fairness")]
+    handoff_gen: AtomicU32,
+    /// Whether contended releases are made fair now and then.
+    #[cfg(feature = "eventual_fairness")]
+    eventually_fair: bool,
+    /// When the last fair release was, from [fair_clock].
+    #[cfg(feature = "eventual_fairness")]
+    fair_at: AtomicU32,
+    /// Tasks waiting in [RawSem::acquire_async].
+    #[cfg(feature = "async")]
+    tasks: WakerQueue,
+    #[cfg(feature = "stats")]
+    stats: Stats,
+}
+
+/// A future taking accesses from a [RawSem], made by
+/// [RawSem::acquire_async].
+///
+/// Dropping it after it was woken but before it took anything passes the
+/// wakeup on to the next waiting task, so the release isn't lost.
+#[cfg(feature = "async")]
+pub(crate) struct Acquire<'a> {
+    sem: &'a RawSem,
+    n: u32,
+    /// The key under which this task is queued, if it ever was.
+    key: Option<u64>,
+}
+
+/// The semaphore inside a [SemVar]: kept on its own cache line with the
+/// `cache_padded` feature, otherwise right next to the value.
+#[cfg(feature = "cache_padded")]
+type SemWord = CachePadded<RawSem>;
+#[cfg(not(feature = "cache_padded"))]
+type SemWord = RawSem;
+
+/// A type representing a semaphore-protected value.
+pub(crate) struct SemVar<T: ?Sized> {
+    sem: SemWord,
+    /// The value being guarded. Last, so `T` may be unsized.
     value: T,
 }
 
 /// A guard that represents shared access to the inner value.
-pub(crate) struct SemGuard<'a, T> {
+pub(crate) struct SemGuard<'a, T: ?Sized> {
     inner: &'a SemVar<T>,
 }
 
+/// A permit on a semaphore that gives no access to any value. Releases
+/// like a [SemGuard] when dropped.
+pub(crate) struct SemPermit<'a> {
+    sem: &'a RawSem,
+    /// Number of accesses held.
+    permits: u32,
+}
+
+/// Set in `available` once the semaphore is closed.
+const CLOSED: u32 = 1 << 31;
+
+/// How `available` encodes zero free accesses.
+const ZERO: u32 = 1 << 30;
+
+/// The largest supported capacity, so `available` stays clear of the
+/// [CLOSED] bit.
+pub(crate) const MAX_PERMITS: u32 = ZERO - 1;
+
+/// One parked [Priority::High] waiter in `ranked`.
+#[cfg(feature = "priority")]
+const HIGH_ONE: u32 = 1;
+
+/// One parked [Priority::Low] waiter in `ranked`.
+#[cfg(feature = "priority")]
+const LOW_ONE: u32 = 1 << 16;
+
+/// How long, in microseconds, an eventually fair semaphore lets releases
+/// go to whoever is quickest before handing one to a waiter.
+#[cfg(feature = "eventual_fairness")]
+const FAIR_SLICE: u32 = 1_000;
+
+#[cfg(feature = "eventual_fairness")]
+std::thread_local! {
+    /// The semaphore and `handoff_gen` of this thread's last fair release.
+    /// The thread may not take what it handed off itself.
+    static HANDED_OFF: std::cell::Cell<(usize, u32)> = const { std::cell::Cell::new((0, 0)) };
+}
+
+/// Microseconds since the first call, wrapping. Only differences of less
+/// than an hour or so mean anything.
+#[cfg(feature = "eventual_fairness")]
+fn fair_clock() -> u32 {
+    static START: std::sync::OnceLock<Instant> = std::sync::OnceLock::new();
+    START.get_or_init(Instant::now).elapsed().as_micros() as u32
+}
+
+/// How many times a contended acquisition retries before parking.
+const SPIN_LIMIT: u32 = 100;
+
+/// Free accesses encoded in an `available` word.
+fn free(word: u32) -> i64 {
+    i64::from(word & !CLOSED) - i64::from(ZERO)
+}
+
+/// The unit a parked waiter of `priority` adds to `ranked`, if any.
+#[cfg(feature = "priority")]
+fn rank_one(priority: Priority) -> Option<u32> {
+    match priority {
+        Priority::High => Some(HIGH_ONE),
+        Priority::Normal => None,
+        Priority::Low => Some(LOW_ONE),
+    }
+}
+
+impl RawSem {
+    /// Create a new semaphore allowing `capacity` accesses at a time.
+    ///
+    /// # Panics
+    ///
+    /// If `capacity` is more than [MAX_PERMITS].
+    pub const fn new(capacity: u32) -> Self {
+        Self::with_available(capacity, capacity)
+    }
+
+    /// Create a new semaphore allowing `capacity` accesses at a time, of
+    /// which only `available` are free to begin with. The rest count as
+    /// held until given back with [RawSem::restore].
+    ///
+    /// # Panics
+    ///
+    /// If `capacity` is more than [MAX_PERMITS] or `available` is more
+    /// than `capacity`.
+    pub const fn with_available(capacity: u32, available: u32) -> Self {
+        Self::init(capacity, available, false)
+    }
+
+    const fn init(capacity: u32, available: u32, eventually_fair: bool) -> Self {
+        #[cfg(not(feature = "eventual_fairness"))]
+        let _ = eventually_fair;
+        assert!(capacity <= MAX_PERMITS, "capacity too large");
+        assert!(
+            available <= capacity,
+            "more permits available than the capacity"
+        );
+        Self {
+            capacity: AtomicU32::new(capacity),
+            available: AtomicU32::new(ZERO + available),
+            reserved: AtomicU32::new(0),
+            waiting_many: AtomicU32::new(0),
+            sleepers: AtomicU32::new(0),
+            #[cfg(feature = "priority")]
+            ranked: AtomicU32::new(0),
+            #[cfg(feature = "priority")]
+            rank_epoch: AtomicU32::new(0),
+            #[cfg(feature = "eventual_fairness")]
+            handoff: AtomicU32::new(0),
+            #[cfg(feature = "eventual_fairness")]
+            handoff_gen: AtomicU32::new(0),
+            #[cfg(feature = "eventual_fairness")]
+            eventually_fair,
+            #[cfg(feature = "eventual_fairness")]
+            fair_at: AtomicU32::new(0),
+            #[cfg(feature = "async")]
+            tasks: WakerQueue::new(),
+            #[cfg(feature = "stats")]
+            stats: Stats::new(),
+        }
+    }
+
+    /// Create a new semaphore whose contended releases of single accesses
+    /// hand the access straight to a waiting thread about once a
+    /// millisecond, so a thread releasing and retaking in a loop can't keep
+    /// the others out indefinitely. Only for semaphores whose accesses are
+    /// all taken one at a time.
+    ///
+    /// # Panics
+    ///
+    /// If `capacity` is more than [MAX_PERMITS].
+    #[cfg(feature = "eventual_fairness")]
+    pub const fn new_eventually_fair(capacity: u32) -> Self {
+        Self::init(capacity, capacity, true)
+    }
+
+    /// Take one access, waiting until one is available.
+    #[inline]
+    pub fn acquire(&self) -> Result<(), AcquireError> {
+        self.acquire_many(1)
+    }
+
+    /// Take `n` accesses at once, waiting until all of them are available.
+    /// If `n` is more than the capacity, that means waiting for the
+    /// capacity to be raised.
+    #[inline]
+    pub fn acquire_many(&self, n: u32) -> Result<(), AcquireError> {
+        self.acquire_inner(n, None, Priority::Normal, None)
+    }
+
+    /// Take `n` accesses at once, going ahead of parked waiters of lower
+    /// priority: while any waiter of higher priority is parked, one free
+    /// access is left alone for each.
+    #[cfg(feature = "priority")]
+    pub fn acquire_ranked(&self, n: u32, priority: Priority) -> Result<(), AcquireError> {
+        self.acquire_inner(n, None, priority, None)
+    }
+
+    /// Take one access, waiting at most until `deadline`. On timeout the
+    /// count is left untouched.
+    #[cfg(feature = "std")]
+    pub fn acquire_until(&self, deadline: Instant) -> Result<(), AcquireError> {
+        self.acquire_with(Some(deadline), None)
+    }
+
+    /// Take one access, waiting until one is available or until `token` is
+    /// cancelled. Fails at once if it was cancelled already. On
+    /// cancellation the count is left untouched.
+    #[cfg(feature = "std")]
+    pub fn acquire_cancellable(&self, token: &CancelToken) -> Result<(), AcquireError> {
+        self.acquire_with(None, Some(token))
+    }
+
+    /// Take one access, waiting at most until `deadline` and until `token`
+    /// is cancelled, if given. Fails at once if `token` was cancelled
+    /// already.
+    #[cfg(feature = "std")]
+    pub fn acquire_with(
+        &self,
+        deadline: Option<Instant>,
+        token: Option<&CancelToken>,
+    ) -> Result<(), AcquireError> {
+        let acquired = if token.is_some_and(CancelToken::is_cancelled) {
+            Err(AcquireError::Cancelled)
+        } else {
+            self.acquire_inner(1, deadline, Priority::Normal, token)
+        };
+        #[cfg(feature = "stats")]
+        if let Err(e) = acquired {
+            self.stats.failed(e);
+        }
+        acquired
+    }
+
+    /// Like [RawSem::acquire_until], for callers that look again after a
+    /// timeout: those timeouts aren't counted in the stats.
+    #[cfg(feature = "std")]
+    pub fn acquire_or_recheck(&self, deadline: Instant) -> Result<(), AcquireError> {
+        self.acquire_inner(1, Some(deadline), Priority::Normal, None)
+    }
+
+    #[inline]
+    fn acquire_inner(
+        &self,
+        n: u32,
+        deadline: Option<Instant>,
+        priority: Priority,
+        cancel: Option<&CancelToken>,
+    ) -> Result<(), AcquireError> {
+        // Uncontended: a few loads and a single compare_exchange.
+        if self.take(n, self.held_back(priority)).is_ok() {
+            return Ok(());
+        }
+        self.acquire_contended(n, deadline, priority, cancel)
+    }
+
+    #[cold]
+    fn acquire_contended(
+        &self,
+        n: u32,
+        deadline: Option<Instant>,
+        priority: Priority,
+        cancel: Option<&CancelToken>,
+    ) -> Result<(), AcquireError> {
+        // Short critical sections often end sooner than parking would, so
+        // retry a bounded number of times before giving up the CPU. `take`
+        // only attempts its compare_exchange once enough looks free.
+        for _ in 0..SPIN_LIMIT {
+            std::hint::spin_loop();
+            match self.take(n, self.held_back(priority)) {
+                Ok(()) => return Ok(()),
+                Err(value) if value & CLOSED != 0 => return Err(AcquireError::Closed),
+                Err(_) => {}
+            }
+        }
+
+        let mut registered = false;
+        let mut reserving = false;
+        let mut sleeping = false;
+        #[cfg(feature = "stats")]
+        let mut parked_at = Instant::now();
+
+        let acquired = loop {
+            // Read before the counts, so a waiter ahead leaving after we
+            // looked at them also changes the word we park on.
+            let epoch = self.rank_epoch();
+            let ahead = self.ahead_of(priority);
+            let others = if reserving {
+                0
+            } else {
+                self.reserved.load(Ordering::SeqCst)
+            };
+            let handed_off = self.handed_off(sleeping);
+            let value = match self.take(n, others + ahead + handed_off) {
+                Ok(()) => {
+                    if sleeping && handed_off == 0 {
+                        self.claim_handoff(n);
+                    }
+                    break Ok(());
+                }
+                Err(value) if value & CLOSED != 0 => break Err(AcquireError::Closed),
+                Err(value) => value,
+            };
+
+            if n > 1 {
+                // Register before parking so releases switch to waking
+                // everyone, then look again in case one just slipped by.
+                if !registered {
+                    self.waiting_many.fetch_add(1, Ordering::SeqCst);
+                    registered = true;
+                    continue;
+                }
+                // Only waiters that can't give up reserve: a reservation is
+                // always given up by taking accesses or by closing, which
+                // change `available` and so can't be missed by threads
+                // parked on it.
+                if !reserving && others == 0 && deadline.is_none() && cancel.is_none() {
+                    reserving = self
+                        .reserved
+                        .compare_exchange(0, n, Ordering::SeqCst, Ordering::SeqCst)
+                        .is_ok();
+                    if reserving {
+                        continue;
+                    }
+                }
+            }
+
+            // Announce ourselves before the final look: every change to
+            // `available` is a SeqCst read-modify-write followed by a load of
+            // `sleepers`, so either that look sees the change or the thread
+            // making it sees us and wakes the futex.
+            if !sleeping {
+                self.sleepers.fetch_add(1, Ordering::SeqCst);
+                self.enter_rank(priority);
+                sleeping = true;
+                #[cfg(feature = "stats")]
+                {
+                    parked_at = Instant::now();
+                }
+                continue;
+            }
+
+            // Held back only by someone else's reservation, which is given
+            // up without necessarily changing `available`: wait for
+            // `reserved` to change instead. Likewise for waiters ahead,
+            // which bump `rank_epoch` when they leave, and for a handoff to
+            // others, which changes `handoff` once taken or given up.
+            let free = free(value);
+            let (word, value) = if free < i64::from(n) {
+                (&self.available, value)
+            } else if handed_off != 0 {
+                self.handoff_word(handed_off)
+            } else if free >= i64::from(n) + i64::from(ahead) {
+                (&self.reserved, others)
+            } else {
+                self.rank_word(epoch)
+            };
+            if let Err(e) = park(word, value, deadline, cancel) {
+                break Err(e);
+            }
+        };
+
+        if sleeping {
+            let left = self.sleepers.fetch_sub(1, Ordering::SeqCst) - 1;
+            // Once no waiter is left, or one that may have been woken for
+            // a handoff gave up, nobody might take it: give it up instead.
+            if left == 0 || acquired.is_err() {
+                self.end_handoff();
+            }
+            // A release may have woken us just as we gave up: pass its
+            // wakeup on, or another waiter could sleep with accesses free.
+            if acquired.is_err() && left != 0 {
+                self.notify(false);
+            }
+            self.leave_rank(priority);
+            #[cfg(feature = "stats")]
+            if acquired.is_ok() {
+                self.stats.waited(parked_at);
+            }
+        }
+        if reserving {
+            self.reserved.store(0, Ordering::SeqCst);
+            if self.sleepers.load(Ordering::SeqCst) != 0 {
+                wake_all(&self.reserved);
+            }
+            self.notify(true);
+        }
+        if registered {
+            self.waiting_many.fetch_sub(1, Ordering::SeqCst);
+        }
+        acquired
+    }
+
+    /// How many free accesses a waiter of `priority` must leave alone: the
+    /// reservation, any handoff, plus one for each parked waiter of higher
+    /// priority.
+    #[inline]
+    fn held_back(&self, priority: Priority) -> u32 {
+        self.reserved.load(Ordering::SeqCst) + self.handed_off(false) + self.ahead_of(priority)
+    }
+
+    /// Accesses handed off that the calling thread may not take: all of
+    /// them, unless it was waiting already and didn't hand them off.
+    #[cfg(feature = "eventual_fairness")]
+    #[inline]
+    fn handed_off(&self, sleeping: bool) -> u32 {
+        let handoff = self.handoff.load(Ordering::SeqCst);
+        if sleeping && handoff != 0 {
+            let generation = self.handoff_gen.load(Ordering::SeqCst);
+            if HANDED_OFF.get() != (self.address(), generation) {
+                return 0;
+            }
+        }
+        handoff
+    }
+
+    /// Without fair releases nothing is ever handed off.
+    #[cfg(not(feature = "eventual_fairness"))]
+    #[inline]
+    fn handed_off(&self, _sleeping: bool) -> u32 {
+        0
+    }
+
+    /// The word to park on while held back only by a handoff to others,
+    /// and its value.
+    #[cfg(feature = "eventual_fairness")]
+    fn handoff_word(&self, handed_off: u32) -> (&AtomicU32, u32) {
+        (&self.handoff, handed_off)
+    }
+
+    #[cfg(not(feature = "eventual_fairness"))]
+    fn handoff_word(&self, _handed_off: u32) -> (&AtomicU32, u32) {
+        unreachable!("nothing is handed off without eventual_fairness")
+    }
+
+    /// Count accesses just taken by a waiter as taken from the handoff, if
+    /// there is one, and let threads held back by it look again.
+    fn claim_handoff(&self, n: u32) {
+        #[cfg(feature = "eventual_fairness")]
+        {
+            let claimed =
+                self.handoff
+                    .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |handoff| {
+                        (handoff != 0).then(|| handoff.saturating_sub(n))
+                    });
+            if claimed.is_ok() {
+                wake_all(&self.handoff);
+            }
+        }
+        #[cfg(not(feature = "eventual_fairness"))]
+        let _ = n;
+    }
+
+    /// Give up any handoff, leaving its accesses to whoever takes them.
+    fn end_handoff(&self) {
+        #[cfg(feature = "eventual_fairness")]
+        if self.handoff.load(Ordering::SeqCst) != 0 && self.handoff.swap(0, Ordering::SeqCst) != 0 {
+            wake_all(&self.handoff);
+            // Tasks never take a handoff, so it may have held them back.
+            #[cfg(feature = "async")]
+            self.tasks.wake(true);
+        }
+    }
+
+    /// Identifies the semaphore to [HANDED_OFF].
+    #[cfg(feature = "eventual_fairness")]
+    fn address(&self) -> usize {
+        (self as *const Self).cast::<u8>() as usize
+    }
+
+    /// `rank_epoch`, or 0 without priorities.
+    #[inline]
+    fn rank_epoch(&self) -> u32 {
+        #[cfg(feature = "priority")]
+        return self.rank_epoch.load(Ordering::SeqCst);
+        #[cfg(not(feature = "priority"))]
+        0
+    }
+
+    /// The word to park on while held back only by waiters of higher
+    /// priority, and its value.
+    #[cfg(feature = "priority")]
+    fn rank_word(&self, epoch: u32) -> (&AtomicU32, u32) {
+        (&self.rank_epoch, epoch)
+    }
+
+    #[cfg(not(feature = "priority"))]
+    fn rank_word(&self, _epoch: u32) -> (&AtomicU32, u32) {
+        unreachable!("nobody is ahead without priorities")
+    }
+
+    /// Whether any parked waiter isn't [Priority::Normal].
+    #[inline]
+    fn any_ranked(&self) -> bool {
+        #[cfg(feature = "priority")]
+        return self.ranked.load(Ordering::SeqCst) != 0;
+        #[cfg(not(feature = "priority"))]
+        false
+    }
+
+    /// Count a waiter of `priority` as parked.
+    #[cfg(feature = "priority")]
+    fn enter_rank(&self, priority: Priority) {
+        if let Some(one) = rank_one(priority) {
+            self.ranked.fetch_add(one, Ordering::SeqCst);
+        }
+    }
+
+    #[cfg(not(feature = "priority"))]
+    fn enter_rank(&self, _priority: Priority) {}
+
+    /// Without priorities nobody is ahead.
+    #[cfg(not(feature = "priority"))]
+    #[inline]
+    fn ahead_of(&self, _priority: Priority) -> u32 {
+        0
+    }
+
+    #[cfg(not(feature = "priority"))]
+    fn leave_rank(&self, _priority: Priority) {}
+
+    /// Parked waiters of higher priority than `priority`.
+    #[cfg(feature = "priority")]
+    #[inline]
+    fn ahead_of(&self, priority: Priority) -> u32 {
+        match priority {
+            Priority::High => 0,
+            Priority::Normal => self.ranked.load(Ordering::SeqCst) % LOW_ONE,
+            Priority::Low => {
+                let low = self.ranked.load(Ordering::SeqCst) / LOW_ONE;
+                self.sleepers.load(Ordering::SeqCst).saturating_sub(low)
+            }
+        }
+    }
+
+    /// Count a parked waiter of `priority` out again, and let those of
+    /// lower priority that held back for it look again.
+    #[cfg(feature = "priority")]
+    fn leave_rank(&self, priority: Priority) {
+        if let Some(one) = rank_one(priority) {
+            self.ranked.fetch_sub(one, Ordering::SeqCst);
+        }
+        let behind = match priority {
+            Priority::High => {
+                let ranked = self.ranked.load(Ordering::SeqCst);
+                self.sleepers.load(Ordering::SeqCst) > ranked % LOW_ONE
+            }
+            Priority::Normal => self.ranked.load(Ordering::SeqCst) >= LOW_ONE,
+            Priority::Low => false,
+        };
+        if behind {
+            self.rank_epoch.fetch_add(1, Ordering::SeqCst);
+            wake_all(&self.rank_epoch);
+        }
+        // Tasks are normal and may have been held back too.
+        #[cfg(feature = "async")]
+        if priority == Priority::High {
+            self.tasks.wake(true);
+        }
+    }
+
+    /// Take `n` accesses if that leaves at least `reserved` free and the
+    /// semaphore is open. Returns the `available` word last seen on failure.
+    #[inline]
+    fn take(&self, n: u32, reserved: u32) -> Result<(), u32> {
+        let needed = i64::from(n) + i64::from(reserved);
+        let mut value = self.available.load(Ordering::SeqCst);
+
+        while value & CLOSED == 0 && free(value) >= needed {
+            match self.available.compare_exchange(
+                value,
+                value - n,
+                Ordering::Acquire,
+                Ordering::SeqCst,
+            ) {
+                Ok(_) => {
+                    #[cfg(feature = "stats")]
+                    self.stats.acquired(n);
+                    return Ok(());
+                }
+                Err(e) => value = e,
+            }
+        }
+        Err(value)
+    }
+
+    /// Take one access if one is available right now, without waiting.
+    pub fn try_acquire(&self) -> Result<(), TryAcquireError> {
+        self.try_acquire_many(1)
+    }
+
+    /// Take `n` accesses at once if they are all available right now,
+    /// without waiting. Either all `n` are taken or none are.
+    pub fn try_acquire_many(&self, n: u32) -> Result<(), TryAcquireError> {
+        match self.take(n, self.held_back(Priority::Normal)) {
+            Ok(()) => Ok(()),
+            Err(value) if value & CLOSED != 0 => Err(TryAcquireError::Closed),
+            Err(_) => Err(TryAcquireError::NoPermits),
+        }
+    }
+
+    /// Take `n` accesses as a [SemPermit] if they are available right now.
+    pub fn try_acquire_permits(&self, n: u32) -> Result<SemPermit<'_>, TryAcquireError> {
+        self.try_acquire_many(n)?;
+        Ok(SemPermit {
+            sem: self,
+            permits: n,
+        })
+    }
+
+    /// Take one access as a [SemPermit], waiting until one is available.
+    pub fn acquire_permit(&self) -> Result<SemPermit<'_>, AcquireError> {
+        self.acquire_permits(1)
+    }
+
+    /// Take `n` accesses as a single [SemPermit], waiting until all of them
+    /// are available.
+    pub fn acquire_permits(&self, n: u32) -> Result<SemPermit<'_>, AcquireError> {
+        self.acquire_many(n)?;
+        Ok(SemPermit {
+            sem: self,
+            permits: n,
+        })
+    }
+
+    /// Take one access as a [SemPermit], going ahead of parked waiters of
+    /// lower priority.
+    #[cfg(feature = "priority")]
+    pub fn acquire_permit_ranked(&self, priority: Priority) -> Result<SemPermit<'_>, AcquireError> {
+        self.acquire_ranked(1, priority)?;
+        Ok(SemPermit {
+            sem: self,
+            permits: 1,
+        })
+    }
+
+    /// Take one access as a [SemPermit], waiting until `token` is cancelled
+    /// at most.
+    #[cfg(feature = "std")]
+    pub fn acquire_permit_cancellable(
+        &self,
+        token: &CancelToken,
+    ) -> Result<SemPermit<'_>, AcquireError> {
+        self.acquire_cancellable(token)?;
+        Ok(SemPermit {
+            sem: self,
+            permits: 1,
+        })
+    }
+
+    /// Take one access as a [SemPermit], waiting at most until `deadline`.
+    #[cfg(feature = "std")]
+    pub fn acquire_permit_until(&self, deadline: Instant) -> Result<SemPermit<'_>, AcquireError> {
+        self.acquire_until(deadline)?;
+        Ok(SemPermit {
+            sem: self,
+            permits: 1,
+        })
+    }
+
+    /// Take one access as a [SemPermit], waiting at most until `deadline`
+    /// and until `token` is cancelled, if given. Fails at once if `token`
+    /// was cancelled already.
+    #[cfg(feature = "std")]
+    pub fn acquire_permit_with(
+        &self,
+        deadline: Option<Instant>,
+        token: Option<&CancelToken>,
+    ) -> Result<SemPermit<'_>, AcquireError> {
+        self.acquire_with(deadline, token)?;
+        Ok(SemPermit {
+            sem: self,
+            permits: 1,
+        })
+    }
+
+    /// Take one access as a [SemPermit] like [RawSem::acquire_or_recheck].
+    #[cfg(feature = "std")]
+    pub fn acquire_permit_or_recheck(
+        &self,
+        deadline: Instant,
+    ) -> Result<SemPermit<'_>, AcquireError> {
+        self.acquire_or_recheck(deadline)?;
+        Ok(SemPermit {
+            sem: self,
+            permits: 1,
+        })
+    }
+
+    /// Take `n` accesses at once without blocking the thread: the returned
+    /// future waits until they are available.
+    ///
+    /// Unlike [RawSem::acquire_many], a task waiting for several accesses
+    /// doesn't reserve them, so it can be overtaken by smaller waiters.
+    #[cfg(feature = "async")]
+    pub fn acquire_async(&self, n: u32) -> Acquire<'_> {
+        Acquire {
+            sem: self,
+            n,
+            key: None,
+        }
+    }
+
+    /// Take `n` accesses as a single [SemPermit], without blocking the
+    /// thread.
+    #[cfg(feature = "async")]
+    pub async fn acquire_permits_async(&self, n: u32) -> Result<SemPermit<'_>, AcquireError> {
+        self.acquire_async(n).await?;
+        Ok(SemPermit {
+            sem: self,
+            permits: n,
+        })
+    }
+
+    /// Fail all current and future acquisitions, waking every waiter.
+    /// Accesses already held are released as usual.
+    pub fn close(&self) {
+        self.available.fetch_or(CLOSED, Ordering::SeqCst);
+        self.notify(true);
+    }
+
+    /// Whether [RawSem::close] has been called.
+    pub fn is_closed(&self) -> bool {
+        self.available.load(Ordering::SeqCst) & CLOSED != 0
+    }
+
+    /// Give back `n` accesses and wake waiters to take them. Every guard
+    /// and permit, borrowed or owned, releases through here.
+    ///
+    /// # Safety
+    ///
+    /// `n` accesses must be held, and are given up by this call.
+    pub unsafe fn release(&self, n: u32) {
+        if n == 0 {
+            return;
+        }
+        #[cfg(feature = "eventual_fairness")]
+        if n == 1 && self.eventually_fair && self.sleepers.load(Ordering::SeqCst) != 0 {
+            let now = fair_clock();
+            let last = self.fair_at.load(Ordering::Relaxed);
+            if now.wrapping_sub(last) >= FAIR_SLICE
+                && self
+                    .fair_at
+                    .compare_exchange(last, now, Ordering::Relaxed, Ordering::Relaxed)
+                    .is_ok()
+            {
+                return self.release_fair();
+            }
+        }
+        #[cfg(feature = "stats")]
+        self.stats.released(n);
+        self.available.fetch_add(n, Ordering::SeqCst);
+        self.notify(n != 1 || self.waiting_many.load(Ordering::SeqCst) != 0);
+    }
+
+    /// Give back one access, handing it to a thread already waiting if
+    /// there is one: the calling thread can't take it back first, and
+    /// neither can threads that start waiting later. Only for semaphores
+    /// whose accesses are all taken one at a time.
+    ///
+    /// # Safety
+    ///
+    /// As for [RawSem::release] of one access.
+    #[cfg(feature = "eventual_fairness")]
+    pub unsafe fn release_fair(&self) {
+        #[cfg(feature = "stats")]
+        self.stats.released(1);
+        let generation = self
+            .handoff_gen
+            .fetch_add(1, Ordering::SeqCst)
+            .wrapping_add(1);
+        HANDED_OFF.set((self.address(), generation));
+        self.handoff.fetch_add(1, Ordering::SeqCst);
+        self.available.fetch_add(1, Ordering::SeqCst);
+        if self.sleepers.load(Ordering::SeqCst) == 0 {
+            // Nobody to hand it to after all.
+            self.end_handoff();
+        }
+        self.notify(false);
+    }
+
+    /// Free `n` accesses that no permit holds, such as those held back by
+    /// [RawSem::with_available], and wake waiters to take them. Returns
+    /// false, changing nothing, if that would free more than the capacity.
+    pub fn restore(&self, n: u32) -> bool {
+        let capacity = i64::from(self.capacity());
+        let restored = self
+            .available
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |value| {
+                (free(value) + i64::from(n) <= capacity).then_some(value + n)
+            })
+            .is_ok();
+        if restored && n != 0 {
+            self.notify(n != 1 || self.waiting_many.load(Ordering::SeqCst) != 0);
+        }
+        restored
+    }
+
+    /// Take every access that is free right now, reserved ones included,
+    /// and return how many. They stay taken until [RawSem::restore] frees
+    /// them. Takes nothing once the semaphore is closed.
+    pub fn drain(&self) -> u32 {
+        let drained = self
+            .available
+            .fetch_update(Ordering::Acquire, Ordering::SeqCst, |value| {
+                (value & CLOSED == 0 && free(value) > 0).then_some(ZERO)
+            })
+            .map_or(0, |value| free(value) as u32);
+        #[cfg(feature = "stats")]
+        if drained != 0 {
+            self.stats.acquired(drained);
+        }
+        drained
+    }
+
+    /// The maximum number of accesses at a time.
+    pub fn capacity(&self) -> u32 {
+        self.capacity.load(Ordering::SeqCst)
+    }
+
+    /// Accesses free right now, negative after the capacity was lowered
+    /// below what is held. Only a snapshot.
+    pub fn available(&self) -> i64 {
+        free(self.available.load(Ordering::SeqCst))
+    }
+
+    /// Whether a thread may be parked, or with the `async` feature a task
+    /// queued, waiting for accesses. Only a snapshot.
+    pub fn has_waiters(&self) -> bool {
+        #[cfg(feature = "async")]
+        if !self.tasks.is_empty() {
+            return true;
+        }
+        self.sleepers.load(Ordering::SeqCst) != 0
+    }
+
+    /// Number of threads parked, or about to park, waiting for accesses.
+    /// Only a snapshot.
+    pub fn waiters(&self) -> u32 {
+        self.sleepers.load(Ordering::SeqCst)
+    }
+
+    /// The contention counters so far.
+    #[cfg(feature = "stats")]
+    pub fn stats(&self) -> LockStats {
+        self.stats.snapshot()
+    }
+
+    /// Zero the contention counters.
+    #[cfg(feature = "stats")]
+    pub fn reset_stats(&self) {
+        self.stats.reset();
+    }
+
+    /// Wake a waiter, or all of them if `all` is set, after `available`
+    /// changed. With the `async` feature this covers waiting tasks as well
+    /// as parked threads, so either kind of release wakes either kind of
+    /// waiter.
+    fn notify(&self, all: bool) {
+        if self.sleepers.load(Ordering::SeqCst) == 0 {
+            // Nobody parked, so spare the syscall.
+        } else if all || self.any_ranked() {
+            wake_all(&self.available);
+        } else {
+            wake_one(&self.available);
+        }
+        #[cfg(feature = "async")]
+        self.tasks.wake(all);
+    }
+
+    /// Raise the capacity by `n`, waking waiters that can now proceed.
+    ///
+    /// # Panics
+    ///
+    /// If the capacity would exceed [MAX_PERMITS].
+    pub fn add_permits(&self, n: u32) {
+        self.capacity
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |capacity| {
+                capacity.checked_add(n).filter(|&new| new <= MAX_PERMITS)
+            })
+            .expect("capacity too large");
+        self.available.fetch_add(n, Ordering::SeqCst);
+        self.notify(true);
+    }
+
+    /// Change the capacity to `new`. Lowering it takes effect lazily:
+    /// accesses already held stay valid and new ones wait until enough of
+    /// them are released.
+    ///
+    /// # Panics
+    ///
+    /// If `new` is more than [MAX_PERMITS].
+    pub fn set_capacity(&self, new: u32) {
+        assert!(new <= MAX_PERMITS, "capacity too large");
+        let old = self.capacity.swap(new, Ordering::SeqCst);
+        // Concurrent calls each apply the difference to the capacity they
+        // replaced, so `available` ends up matching whichever came last.
+        self.available
+            .fetch_add(new.wrapping_sub(old), Ordering::SeqCst);
+        if new > old {
+            self.notify(true);
+        }
+    }
+}
+
 impl<T> SemVar<T> {
     /// Create a new semvar with the maximum access limit set
-    /// to `capacity`.
-    pub fn new(capacity: u32, value: T) -> Self {
+    /// to `capacity`. With a capacity of 0 every access waits until
+    /// [SemVar::add_permits] or [SemVar::set_capacity] raises it.
+    ///
+    /// # Panics
+    ///
+    /// If `capacity` is more than [MAX_PERMITS].
+    pub const fn new(capacity: u32, value: T) -> Self {
+        Self {
+            #[cfg(feature = "cache_padded")]
+            sem: CachePadded::new(RawSem::new(capacity)),
+            #[cfg(not(feature = "cache_padded"))]
+            sem: RawSem::new(capacity),
+            value,
+        }
+    }
+
+    /// Like [SemVar::new], but with [RawSem::new_eventually_fair] when the
+    /// `eventual_fairness` feature is enabled. Only for a capacity of 1 or
+    /// single accesses.
+    pub const fn new_eventually_fair(capacity: u32, value: T) -> Self {
+        #[cfg(feature = "eventual_fairness")]
+        let sem = RawSem::new_eventually_fair(capacity);
+        #[cfg(not(feature = "eventual_fairness"))]
+        let sem = RawSem::new(capacity);
         Self {
-            capacity,
-            count: AtomicU32::new(0),
+            #[cfg(feature = "cache_padded")]
+            sem: CachePadded::new(sem),
+            #[cfg(not(feature = "cache_padded"))]
+            sem,
             value,
         }
     }
 
+    /// Consume the semvar and return the protected value. No guards can
+    /// exist, so nothing needs to be acquired.
+    pub fn into_inner(self) -> T {
+        self.value
+    }
+}
+
+impl<T: ?Sized> SemVar<T> {
+    /// Borrow the protected value mutably. The exclusive borrow already
+    /// rules out any guards.
+    pub fn get_mut(&mut self) -> &mut T {
+        &mut self.value
+    }
+
     /// Try to gain access to the protected value. Returns
     /// a [SemGuard].
-    pub fn access(&self) -> SemGuard<T> {
-        let mut value = self.count.load(Ordering::Relaxed);
-
-        loop {
-            if value < self.capacity {
-                match self.count.compare_exchange(
-                    value,
-                    value + 1,
-                    Ordering::Acquire,
-                    Ordering::Relaxed,
-                ) {
-                    Ok(_) => return SemGuard { inner: self },
-                    Err(e) => value = e,
-                }
-            }
+    #[inline]
+    pub fn access(&self) -> SemGuard<'_, T> {
+        // A SemVar's semaphore is never closed.
+        self.sem.acquire().expect("SemVar closed");
+        SemGuard { inner: self }
+    }
+
+    /// Gain access to the protected value, waiting at most until
+    /// `deadline`.
+    #[cfg(feature = "std")]
+    pub fn access_until(&self, deadline: Instant) -> Option<SemGuard<'_, T>> {
+        self.sem.acquire_until(deadline).ok()?;
+        Some(SemGuard { inner: self })
+    }
+
+    /// Gain access to the protected value, waiting until `token` is
+    /// cancelled at most.
+    #[cfg(feature = "std")]
+    pub fn access_cancellable(&self, token: &CancelToken) -> Option<SemGuard<'_, T>> {
+        self.sem.acquire_cancellable(token).ok()?;
+        Some(SemGuard { inner: self })
+    }
+
+    /// Gain access to the protected value only if that doesn't require
+    /// waiting.
+    pub fn try_access(&self) -> Option<SemGuard<'_, T>> {
+        self.sem.try_acquire().ok()?;
+        Some(SemGuard { inner: self })
+    }
+
+    /// Take an access without blocking the thread. Complete it with
+    /// [SemVar::assume_access].
+    ///
+    /// The future only borrows the semaphore, so it is `Send` whatever `T`
+    /// is.
+    #[cfg(feature = "async")]
+    pub fn acquire_async(&self) -> Acquire<'_> {
+        self.sem.acquire_async(1)
+    }
+
+    /// Wrap an access taken through [SemVar::acquire_async] in a guard.
+    ///
+    /// # Safety
+    ///
+    /// Such an access must be held, and is handed over to the guard.
+    #[cfg(feature = "async")]
+    pub unsafe fn assume_access(&self) -> SemGuard<'_, T> {
+        SemGuard { inner: self }
+    }
+
+    /// Whether anyone may be waiting for access. Only a snapshot.
+    pub fn has_waiters(&self) -> bool {
+        self.sem.has_waiters()
+    }
+
+    /// Accesses free right now. Only a snapshot.
+    pub fn available(&self) -> i64 {
+        self.sem.available()
+    }
+
+    /// The maximum number of accesses at a time.
+    #[cfg(feature = "std")]
+    pub fn capacity(&self) -> u32 {
+        self.sem.capacity()
+    }
+
+    /// Allow `n` more accesses at a time, waking waiters.
+    ///
+    /// # Panics
+    ///
+    /// If the capacity would exceed [MAX_PERMITS].
+    #[cfg(feature = "std")]
+    pub fn add_permits(&self, n: u32) {
+        self.sem.add_permits(n);
+    }
+
+    /// Change the maximum number of accesses at a time to `new`.
+    ///
+    /// # Panics
+    ///
+    /// If `new` is more than [MAX_PERMITS].
+    #[cfg(feature = "std")]
+    pub fn set_capacity(&self, new: u32) {
+        self.sem.set_capacity(new);
+    }
+
+    /// The contention counters of the semaphore.
+    #[cfg(feature = "stats")]
+    pub fn stats(&self) -> LockStats {
+        self.sem.stats()
+    }
+
+    /// Zero the contention counters.
+    #[cfg(feature = "stats")]
+    pub fn reset_stats(&self) {
+        self.sem.reset_stats();
+    }
 
-            if value == self.capacity {
-                wait(&self.count, value);
-                value = self.count.load(Ordering::Relaxed);
+    /// A pointer to the protected value. Using it is only sound while an
+    /// access is held.
+    pub fn as_ptr(&self) -> *const T {
+        &self.value
+    }
+
+    /// Borrow the protected value without a guard.
+    ///
+    /// # Safety
+    ///
+    /// An access must be held, e.g. through a forgotten [SemGuard], for
+    /// as long as the borrow lives.
+    #[cfg(feature = "std")]
+    pub unsafe fn get_unchecked(&self) -> &T {
+        &self.value
+    }
+
+    /// Give back an access whose [SemGuard] was forgotten.
+    ///
+    /// # Safety
+    ///
+    /// Such an access must be held, and is given up by this call.
+    pub unsafe fn release(&self) {
+        self.sem.release(1);
+    }
+}
+
+impl<'a, T: ?Sized> SemGuard<'a, T> {
+    /// Give up access to the value while keeping the permit held.
+    pub fn into_permit(self) -> SemPermit<'a> {
+        let permit = SemPermit {
+            sem: &self.inner.sem,
+            permits: 1,
+        };
+        std::mem::forget(self);
+        permit
+    }
+}
+
+impl<'a> SemPermit<'a> {
+    /// Release the single access held with [RawSem::release_fair].
+    #[cfg(feature = "eventual_fairness")]
+    pub fn release_fair(self) {
+        debug_assert_eq!(self.permits, 1, "fair release of several permits");
+        let sem = self.sem;
+        std::mem::forget(self);
+        // SAFETY: The permit held the access.
+        unsafe { sem.release_fair() }
+    }
+
+    /// Leak a single-access permit as a raw pointer to its semaphore.
+    #[cfg(feature = "std")]
+    pub fn into_raw(self) -> *const RawSem {
+        debug_assert_eq!(self.permits, 1);
+        let sem: *const RawSem = self.sem;
+        std::mem::forget(self);
+        sem
+    }
+
+    /// Number of accesses held.
+    pub fn permits(&self) -> u32 {
+        self.permits
+    }
+
+    /// Move `n` of the accesses held into a new permit.
+    ///
+    /// # Panics
+    ///
+    /// If `n` is more than this permit holds.
+    pub fn split(&mut self, n: u32) -> SemPermit<'a> {
+        assert!(
+            n <= self.permits,
+            "cannot split {n} permits off a permit holding {}",
+            self.permits
+        );
+        self.permits -= n;
+        SemPermit {
+            sem: self.sem,
+            permits: n,
+        }
+    }
+
+    /// Take over the accesses of `other`, from the same semaphore.
+    ///
+    /// # Panics
+    ///
+    /// If `other` came from a different semaphore, or the total would
+    /// overflow.
+    pub fn merge(&mut self, other: SemPermit<'a>) {
+        assert!(
+            std::ptr::eq(self.sem, other.sem),
+            "cannot merge permits from different semaphores"
+        );
+        self.permits = self
+            .permits
+            .checked_add(other.permits)
+            .expect("too many permits");
+        std::mem::forget(other);
+    }
+
+    /// Take as many more accesses as this permit holds, as a new permit,
+    /// if they are available right now.
+    pub fn try_clone(&self) -> Result<SemPermit<'a>, TryAcquireError> {
+        self.sem.try_acquire_permits(self.permits)
+    }
+
+    /// Keep the accesses held but drop the borrow of the semaphore,
+    /// returning how many there are. Give them back with
+    /// [RawSem::release].
+    #[cfg(feature = "std")]
+    pub fn leak(self) -> u32 {
+        let permits = self.permits;
+        std::mem::forget(self);
+        permits
+    }
+
+    /// Consume the permit without giving its accesses back or changing the
+    /// capacity, so they stay taken until [RawSem::restore] frees them.
+    pub fn consume(self) {
+        #[cfg(feature = "stats")]
+        self.sem.stats.released(self.permits);
+        std::mem::forget(self);
+    }
+
+    /// Consume the permit without giving its accesses back, lowering the
+    /// capacity by as many, but not below 0. Nobody is woken, since
+    /// nothing was freed.
+    pub fn forget(self) {
+        let permits = self.permits;
+        let (Ok(old) | Err(old)) =
+            self.sem
+                .capacity
+                .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |capacity| {
+                    Some(capacity.saturating_sub(permits))
+                });
+        // Held past a capacity lowered below what was held, the rest of
+        // the accesses were already counted off `available`, which goes
+        // back up by as much now that they are given up.
+        let excess = permits - old.min(permits);
+        if excess != 0 {
+            self.sem.available.fetch_add(excess, Ordering::SeqCst);
+        }
+        // Forgotten accesses are no longer held, as far as stats go.
+        #[cfg(feature = "stats")]
+        self.sem.stats.released(self.permits);
+        std::mem::forget(self);
+    }
+
+    /// Rebuild a permit from [SemPermit::into_raw].
+    ///
+    /// # Safety
+    ///
+    /// `sem` must come from [SemPermit::into_raw], be rebuilt at most
+    /// once and still point at a live semaphore for `'a`.
+    #[cfg(feature = "std")]
+    pub unsafe fn from_raw(sem: *const RawSem) -> Self {
+        Self {
+            sem: &*sem,
+            permits: 1,
+        }
+    }
+}
+
+#[cfg(feature = "async")]
+impl Acquire<'_> {
+    /// Take the accesses if possible. `None` means keep waiting.
+    fn try_take(&mut self) -> Option<Result<(), AcquireError>> {
+        let taken = match self.sem.try_acquire_many(self.n) {
+            Ok(()) => Ok(()),
+            Err(TryAcquireError::Closed) => Err(AcquireError::Closed),
+            // Too large only comes from Semaphore64.
+            Err(TryAcquireError::NoPermits | TryAcquireError::TooLarge) => return None,
+        };
+        if let Some(key) = self.key.take() {
+            self.sem.tasks.remove(key);
+        }
+        Some(taken)
+    }
+}
+
+#[cfg(feature = "async")]
+impl Future for Acquire<'_> {
+    type Output = Result<(), AcquireError>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = self.get_mut();
+        if let Some(taken) = this.try_take() {
+            return Poll::Ready(taken);
+        }
+        // Queue before looking again, so a release that slipped in between
+        // either is seen here or sees this task in the queue.
+        this.sem.tasks.register(&mut this.key, this.n, cx.waker());
+        match this.try_take() {
+            Some(taken) => Poll::Ready(taken),
+            None => Poll::Pending,
+        }
+    }
+}
+
+#[cfg(feature = "async")]
+impl Drop for Acquire<'_> {
+    fn drop(&mut self) {
+        if let Some(key) = self.key.take() {
+            if !self.sem.tasks.remove(key) {
+                // Woken but cancelled: hand the wakeup to the next task.
+                // Parked threads were woken by the release itself.
+                self.sem.tasks.wake(false);
             }
         }
     }
 }
 
-impl<T> Drop for SemGuard<'_, T> {
+impl<T: ?Sized> Drop for SemGuard<'_, T> {
+    fn drop(&mut self) {
+        // SAFETY: The guard holds one access.
+        unsafe { self.inner.sem.release(1) }
+    }
+}
+
+impl Drop for SemPermit<'_> {
     fn drop(&mut self) {
-        self.inner.count.fetch_sub(1, Ordering::Release);
-        wake_one(&self.inner.count);
+        // SAFETY: The permit holds `permits` accesses.
+        unsafe { self.sem.release(self.permits) }
     }
 }
 
-impl<T> std::ops::Deref for SemGuard<'_, T> {
+impl<T: ?Sized> std::ops::Deref for SemGuard<'_, T> {
     type Target = T;
 
     fn deref(&self) -> &T {
@@ -68,10 +1392,11 @@ impl<T> std::ops::Deref for SemGuard<'_, T> {
     }
 }
 
-#[cfg(test)]
+#[cfg(all(test, feature = "std"))]
 mod test {
     use super::*;
     use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::mpsc;
     use std::sync::Arc;
     use std::time::Duration;
 
@@ -96,6 +1421,14 @@ mod test {
                 first_set.push(handle);
             }
 
+            // Collect the first set's guards before starting the second
+            // set, so the second set can't take permits meant for the first.
+            let mut guards = vec![];
+
+            for handle in first_set {
+                guards.push(handle.join().unwrap());
+            }
+
             for _ in 0..10 {
                 let handle = s.spawn(|| {
                     let guard = sem.access();
@@ -105,11 +1438,6 @@ mod test {
                 second_set.push(handle);
             }
 
-            let mut guards = vec![];
-
-            for handle in first_set {
-                guards.push(handle.join().unwrap());
-            }
             std::thread::sleep(Duration::from_secs(1));
             // Since we took ownership of the guards to prevent them
             // being dropped, only the first 10 threads should have run.
@@ -155,4 +1483,81 @@ mod test {
 
         assert_eq!(COUNT.load(Ordering::SeqCst), 100);
     }
+
+    /// A small xorshift generator so the stress test is reproducible
+    /// without pulling in a rand dependency.
+    struct XorShift(u64);
+
+    impl XorShift {
+        fn next(&mut self) -> u64 {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            self.0
+        }
+    }
+
+    /// Randomized stress harness: many threads acquire, hold for a random
+    /// number of spins and release, while every holder checks that the
+    /// number of live guards stays within capacity. The whole run must
+    /// finish within a time budget, so a lost wakeup shows up as a failure
+    /// rather than a hang.
+    ///
+    /// Run on its own with `cargo test stress`.
+    #[test]
+    fn stress_count_never_exceeds_capacity() {
+        const CAPACITY: u32 = 3;
+        let (threads, iterations) = if cfg!(miri) { (4, 20) } else { (16, 2_000) };
+
+        let (tx, rx) = mpsc::channel();
+        let handle = std::thread::spawn(move || {
+            let sem = SemVar::new(CAPACITY, AtomicU32::new(0));
+            std::thread::scope(|s| {
+                for id in 0..threads {
+                    let sem = &sem;
+                    s.spawn(move || {
+                        let mut rng = XorShift(0x9e37_79b9_7f4a_7c15 ^ (id + 1));
+                        for _ in 0..iterations {
+                            let guard = sem.access();
+                            let active = guard.fetch_add(1, Ordering::SeqCst) + 1;
+                            assert!(active <= CAPACITY);
+                            assert!(free(sem.sem.available.load(Ordering::Relaxed)) >= 0);
+                            for _ in 0..rng.next() % 64 {
+                                std::hint::spin_loop();
+                            }
+                            if rng.next().is_multiple_of(8) {
+                                std::thread::yield_now();
+                            }
+                            guard.fetch_sub(1, Ordering::SeqCst);
+                        }
+                    });
+                }
+            });
+            assert_eq!(
+                free(sem.sem.available.load(Ordering::SeqCst)),
+                CAPACITY.into()
+            );
+            tx.send(()).unwrap();
+        });
+
+        if rx.recv_timeout(Duration::from_secs(60)).is_err() {
+            panic!("stress run did not finish in time, possible lost wakeup");
+        }
+        handle.join().unwrap();
+    }
+
+    #[test]
+    fn available_converges_after_shrink() {
+        let sem = RawSem::new(4);
+        let held: Vec<_> = (0..4).map(|_| sem.acquire_permit().unwrap()).collect();
+
+        sem.set_capacity(2);
+        assert_eq!(free(sem.available.load(Ordering::SeqCst)), -2);
+        assert!(sem.try_acquire().is_err());
+
+        drop(held);
+        assert_eq!(free(sem.available.load(Ordering::SeqCst)), 2);
+        assert!(sem.try_acquire_many(2).is_ok());
+        assert!(sem.try_acquire().is_err());
+    }
 }
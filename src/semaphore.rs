@@ -0,0 +1,1565 @@
+#[cfg(feature = "std")]
+use crate::cancel::CancelToken;
+#[cfg(feature = "std")]
+use crate::mutex::Mutex;
+use crate::sem::{RawSem, SemPermit, MAX_PERMITS};
+use std::fmt;
+#[cfg(feature = "std")]
+use std::sync::Arc;
+#[cfg(feature = "holder_tracking")]
+use std::thread::ThreadId;
+#[cfg(feature = "std")]
+use std::time::{Duration, Instant};
+
+/// A counting semaphore limiting how many threads can hold a permit at
+/// a time, without protecting any value.
+pub struct Semaphore {
+    pub(crate) inner: RawSem,
+    /// The thread that acquired each permit held, once per permit.
+    #[cfg(feature = "holder_tracking")]
+    holders: std::sync::Mutex<Vec<ThreadId>>,
+}
+
+/// Every semaphore made by [Semaphore::global], by name. Entries are
+/// never removed, so the semaphores they point to live for good.
+#[cfg(feature = "std")]
+static GLOBALS: Mutex<Vec<(&'static str, &'static Semaphore)>> = Mutex::new(Vec::new());
+
+/// A permit from a [Semaphore], given back when dropped.
+#[must_use = "the permit is released as soon as it is dropped"]
+pub struct SemaphorePermit<'a> {
+    /// Declared before `permit` so the holder is removed before the
+    /// permits are given back.
+    #[cfg(feature = "holder_tracking")]
+    holder: Holder<'a>,
+    permit: SemPermit<'a>,
+}
+
+/// A permit from a [Semaphore] that owns a reference to it rather than
+/// borrowing it, so it can be stored or moved anywhere. Made by
+/// [Semaphore::acquire_owned] and given back when dropped.
+#[must_use = "the permit is released as soon as it is dropped"]
+#[cfg(feature = "std")]
+pub struct OwnedPermit {
+    sem: Arc<Semaphore>,
+    permits: u32,
+    #[cfg(feature = "holder_tracking")]
+    holder: ThreadId,
+}
+
+/// Records a permit as held by a thread in [Semaphore::holders] while
+/// alive.
+#[cfg(feature = "holder_tracking")]
+struct Holder<'a> {
+    holders: &'a std::sync::Mutex<Vec<ThreadId>>,
+    thread: ThreadId,
+}
+
+/// Why a non-blocking acquisition failed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum TryAcquireError {
+    /// Not enough permits were free; waiting could succeed.
+    NoPermits,
+    /// The semaphore was closed.
+    Closed,
+    /// More permits were asked for than the semaphore can ever hand out.
+    TooLarge,
+}
+
+impl fmt::Display for TryAcquireError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TryAcquireError::NoPermits => f.write_str("no permits available"),
+            TryAcquireError::Closed => f.write_str("semaphore closed"),
+            TryAcquireError::TooLarge => f.write_str("more permits requested than the capacity"),
+        }
+    }
+}
+
+impl std::error::Error for TryAcquireError {}
+
+/// Why a waiting acquisition failed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum AcquireError {
+    /// No permit became free before the deadline.
+    Timeout,
+    /// The semaphore was closed.
+    Closed,
+    /// More permits were asked for than the semaphore can ever hand out.
+    TooLarge,
+    /// The [CancelToken](crate::cancel::CancelToken) given was cancelled.
+    Cancelled,
+}
+
+impl fmt::Display for AcquireError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AcquireError::Timeout => f.write_str("timed out waiting for a permit"),
+            AcquireError::Closed => f.write_str("semaphore closed"),
+            AcquireError::TooLarge => f.write_str("more permits requested than the capacity"),
+            AcquireError::Cancelled => f.write_str("wait for a permit cancelled"),
+        }
+    }
+}
+
+impl std::error::Error for AcquireError {}
+
+/// Which waiters a [Semaphore::acquire_with_priority] call goes ahead of.
+///
+/// While a waiter is parked, waiters of lower priority leave one free
+/// permit alone for it, so a release goes to the highest priority waiting.
+/// A steady stream of higher priority acquisitions can starve lower ones.
+/// Needs the `priority` feature.
+#[cfg(feature = "priority")]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub enum Priority {
+    /// Served before everyone else.
+    High,
+    /// What every other acquisition uses.
+    #[default]
+    Normal,
+    /// Served only once no one else is parked.
+    Low,
+}
+
+/// When a [Semaphore::acquire_full] call gives up waiting. The default
+/// waits as long as needed, like [Semaphore::acquire].
+#[cfg(feature = "std")]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct AcquireOptions<'a> {
+    /// Give up with [AcquireError::Timeout] after this long.
+    pub timeout: Option<Duration>,
+    /// Give up with [AcquireError::Cancelled] once this is cancelled.
+    pub cancel: Option<&'a CancelToken>,
+}
+
+impl<'a> SemaphorePermit<'a> {
+    /// The number of permits held.
+    pub fn num_permits(&self) -> u32 {
+        self.permit.permits()
+    }
+
+    /// Move `n` of the permits held into a new permit, so they can be
+    /// released separately.
+    ///
+    /// ```
+    /// use xlock::semaphore::Semaphore;
+    ///
+    /// let sem = Semaphore::new(4);
+    /// let mut all = sem.acquire_many(4).unwrap();
+    /// let half = all.split(2);
+    /// drop(half);
+    /// assert_eq!(sem.available_permits(), 2);
+    /// assert_eq!(all.num_permits(), 2);
+    /// ```
+    ///
+    /// # Panics
+    ///
+    /// If `n` is more than this permit holds.
+    pub fn split(&mut self, n: u32) -> SemaphorePermit<'a> {
+        SemaphorePermit {
+            #[cfg(feature = "holder_tracking")]
+            holder: Holder::new(self.holder.holders, self.holder.thread),
+            permit: self.permit.split(n),
+        }
+    }
+
+    /// Take over the permits held by `other`, from the same semaphore, to
+    /// release them together.
+    ///
+    /// # Panics
+    ///
+    /// If `other` came from a different semaphore.
+    pub fn merge(&mut self, other: SemaphorePermit<'a>) {
+        self.permit.merge(other.permit);
+    }
+
+    /// Acquire as many more permits as this one holds, if they are free
+    /// right now, as a new permit. Merge it in to widen this one.
+    pub fn try_clone(&self) -> Result<SemaphorePermit<'a>, TryAcquireError> {
+        let permit = self.permit.try_clone()?;
+        Ok(SemaphorePermit {
+            #[cfg(feature = "holder_tracking")]
+            holder: Holder::new(self.holder.holders, std::thread::current().id()),
+            permit,
+        })
+    }
+
+    /// Use the permit up: it isn't given back when dropped, but unlike
+    /// [SemaphorePermit::forget] the capacity stays the same, so
+    /// [Semaphore::release] can make it available again.
+    pub fn consume(self) {
+        self.permit.consume();
+    }
+
+    /// Consume the permit without giving it back, permanently lowering the
+    /// semaphore's capacity. [Semaphore::add_permits] can raise it again.
+    pub fn forget(self) {
+        self.permit.forget();
+    }
+}
+
+#[cfg(feature = "std")]
+impl OwnedPermit {
+    /// Take over the accesses of a borrowed permit from `sem`.
+    fn new(sem: &Arc<Semaphore>, permit: SemPermit<'_>) -> Self {
+        #[cfg(feature = "holder_tracking")]
+        let holder = std::thread::current().id();
+        #[cfg(feature = "holder_tracking")]
+        sem.holders
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .push(holder);
+        Self {
+            sem: Arc::clone(sem),
+            permits: permit.leak(),
+            #[cfg(feature = "holder_tracking")]
+            holder,
+        }
+    }
+
+    /// The semaphore this permit came from.
+    pub fn semaphore(&self) -> &Arc<Semaphore> {
+        &self.sem
+    }
+}
+
+#[cfg(feature = "std")]
+impl Drop for OwnedPermit {
+    fn drop(&mut self) {
+        #[cfg(feature = "holder_tracking")]
+        drop(Holder {
+            holders: &self.sem.holders,
+            thread: self.holder,
+        });
+        // SAFETY: The permit holds `permits` accesses, leaked in new().
+        unsafe { self.sem.inner.release(self.permits) }
+    }
+}
+
+#[cfg(feature = "holder_tracking")]
+impl<'a> Holder<'a> {
+    /// Record a permit held by `thread`.
+    fn new(holders: &'a std::sync::Mutex<Vec<ThreadId>>, thread: ThreadId) -> Self {
+        holders
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .push(thread);
+        Self { holders, thread }
+    }
+}
+
+#[cfg(feature = "holder_tracking")]
+impl Drop for Holder<'_> {
+    fn drop(&mut self) {
+        let mut holders = self.holders.lock().unwrap_or_else(|e| e.into_inner());
+        if let Some(i) = holders.iter().position(|&t| t == self.thread) {
+            holders.swap_remove(i);
+        }
+    }
+}
+
+impl fmt::Debug for Semaphore {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Semaphore")
+            .field("capacity", &self.inner.capacity())
+            .field("available", &self.inner.available())
+            .field("closed", &self.is_closed())
+            .finish()
+    }
+}
+
+impl fmt::Debug for SemaphorePermit<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SemaphorePermit")
+            .field("permits", &self.permit.permits())
+            .finish()
+    }
+}
+
+#[cfg(feature = "std")]
+impl fmt::Debug for OwnedPermit {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("OwnedPermit")
+            .field("permits", &self.permits)
+            .finish()
+    }
+}
+
+impl Semaphore {
+    /// The largest supported capacity, 2<sup>30</sup> - 1. The count
+    /// shares its word with state bits, so capacities beyond this are
+    /// rejected with a panic rather than wrapping.
+    pub const MAX_PERMITS: u32 = MAX_PERMITS;
+
+    /// Create a new Semaphore handing out at most `permits` at a time.
+    /// With no permits at all it is shut until [Semaphore::add_permits]
+    /// or [Semaphore::set_capacity] raises the capacity.
+    ///
+    /// # Panics
+    ///
+    /// If `permits` is more than [Semaphore::MAX_PERMITS].
+    pub const fn new(permits: u32) -> Self {
+        Self {
+            inner: RawSem::new(permits),
+            #[cfg(feature = "holder_tracking")]
+            holders: std::sync::Mutex::new(Vec::new()),
+        }
+    }
+
+    /// Create a semaphore handing out at most `capacity` permits at a
+    /// time, with only `available` of them free to begin with. The others
+    /// are taken until [Semaphore::release] gives them back, which makes
+    /// this the counting semaphore of a producer and its consumers:
+    ///
+    /// ```
+    /// use xlock::semaphore::Semaphore;
+    ///
+    /// let items = Semaphore::with_permits(16, 0);
+    /// std::thread::scope(|s| {
+    ///     s.spawn(|| items.acquire().unwrap().consume());
+    ///     items.release(1);
+    /// });
+    /// assert_eq!(items.available_permits(), 0);
+    /// ```
+    ///
+    /// # Panics
+    ///
+    /// If `capacity` is more than [Semaphore::MAX_PERMITS] or `available` is
+    /// more than `capacity`.
+    pub const fn with_permits(capacity: u32, available: u32) -> Self {
+        Self {
+            inner: RawSem::with_available(capacity, available),
+            #[cfg(feature = "holder_tracking")]
+            holders: std::sync::Mutex::new(Vec::new()),
+        }
+    }
+
+    /// The process-wide semaphore called `name`, for a limit shared by
+    /// code that can't easily pass a semaphore around. The first call for
+    /// a name creates it with `permits`; later calls return the same one
+    /// and ignore `permits`.
+    ///
+    /// ```
+    /// use xlock::semaphore::Semaphore;
+    ///
+    /// fn fetch() {
+    ///     let _permit = Semaphore::global("http", 8).acquire().unwrap();
+    /// }
+    ///
+    /// fetch();
+    /// assert_eq!(Semaphore::global("http", 8).available_permits(), 8);
+    /// ```
+    #[cfg(feature = "std")]
+    pub fn global(name: &'static str, permits: u32) -> &'static Semaphore {
+        let mut globals = GLOBALS.lock();
+        if let Some(&(_, sem)) = globals.iter().find(|(n, _)| *n == name) {
+            return sem;
+        }
+        let sem = Box::leak(Box::new(Semaphore::new(permits)));
+        globals.push((name, sem));
+        sem
+    }
+
+    /// Make `n` permits available without dropping a permit: those held
+    /// back by [Semaphore::with_permits] or used up with
+    /// [SemaphorePermit::consume]. Waiters are woken to take them.
+    ///
+    /// # Panics
+    ///
+    /// If more than the capacity would then be available.
+    #[doc(alias = "release_many")]
+    pub fn release(&self, n: u32) {
+        assert!(
+            self.inner.restore(n),
+            "released more permits than the capacity"
+        );
+    }
+
+    /// Take a permit, waiting until one is available. Fails once the
+    /// semaphore is closed.
+    pub fn acquire(&self) -> Result<SemaphorePermit<'_>, AcquireError> {
+        let permit = self.inner.acquire_permit()?;
+        Ok(self.permit(permit))
+    }
+
+    /// Take a permit, waiting until one is available or until `token` is
+    /// cancelled, failing with [AcquireError::Cancelled] then and leaving
+    /// the semaphore as it was. Fails at once if `token` was cancelled
+    /// already.
+    #[cfg(feature = "std")]
+    pub fn acquire_cancellable(
+        &self,
+        token: &CancelToken,
+    ) -> Result<SemaphorePermit<'_>, AcquireError> {
+        let permit = self.inner.acquire_permit_cancellable(token)?;
+        Ok(self.permit(permit))
+    }
+
+    /// Take a permit, going ahead of parked waiters of lower [Priority].
+    /// Every other way of acquiring is [Priority::Normal].
+    #[cfg(feature = "priority")]
+    pub fn acquire_with_priority(
+        &self,
+        priority: Priority,
+    ) -> Result<SemaphorePermit<'_>, AcquireError> {
+        let permit = self.inner.acquire_permit_ranked(priority)?;
+        Ok(self.permit(permit))
+    }
+
+    /// Take `n` permits at once, waiting until all of them are free. They
+    /// are released together when the returned permit is dropped.
+    ///
+    /// A waiter for several permits isn't starved by a steady stream of
+    /// smaller acquisitions: once it has to wait, the first such waiter
+    /// holds back newly freed permits until it has what it needs.
+    ///
+    /// Asking for more permits than the capacity waits until the capacity
+    /// is raised with [Semaphore::add_permits] or [Semaphore::set_capacity].
+    pub fn acquire_many(&self, n: u32) -> Result<SemaphorePermit<'_>, AcquireError> {
+        let permit = self.inner.acquire_permits(n)?;
+        Ok(self.permit(permit))
+    }
+
+    /// Take as many permits as the semaphore has, waiting for every
+    /// outstanding permit to be released. Acquisitions that start while
+    /// this waits queue behind it, so a steady stream of them can't keep
+    /// it out. Dropping the returned permit wakes everyone waiting.
+    ///
+    /// The capacity is read once, when the call starts; raising it
+    /// meanwhile lets others in beside the holder.
+    pub fn acquire_all(&self) -> Result<SemaphorePermit<'_>, AcquireError> {
+        self.acquire_many(self.capacity())
+    }
+
+    /// Take every permit that is free right now and return how many, for
+    /// shutting out new work without waiting for current work to finish.
+    /// They stay taken until handed back with [Semaphore::release].
+    ///
+    /// ```
+    /// use xlock::semaphore::Semaphore;
+    ///
+    /// let sem = Semaphore::new(4);
+    /// let held = sem.acquire().unwrap();
+    /// assert_eq!(sem.drain_permits(), 3);
+    /// assert!(sem.try_acquire().is_err());
+    /// drop(held);
+    /// sem.release(3);
+    /// assert_eq!(sem.available_permits(), 4);
+    /// ```
+    pub fn drain_permits(&self) -> u32 {
+        self.inner.drain()
+    }
+
+    /// Take a permit without blocking the thread: the returned future
+    /// waits until one is available, woken by releases from threads and
+    /// tasks alike. Fails once the semaphore is closed.
+    ///
+    /// Cancel-safe: if the future is dropped after being woken but before
+    /// taking its permit, the wakeup is passed on to the next waiting task.
+    #[cfg(feature = "async")]
+    pub async fn acquire_async(&self) -> Result<SemaphorePermit<'_>, AcquireError> {
+        let permit = self.inner.acquire_permits_async(1).await?;
+        Ok(self.permit(permit))
+    }
+
+    /// Take `n` permits at once without blocking the thread. Unlike
+    /// [Semaphore::acquire_many], a waiting task doesn't hold back freed
+    /// permits for itself.
+    #[cfg(feature = "async")]
+    pub async fn acquire_many_async(&self, n: u32) -> Result<SemaphorePermit<'_>, AcquireError> {
+        let permit = self.inner.acquire_permits_async(n).await?;
+        Ok(self.permit(permit))
+    }
+
+    /// Take a permit, giving up as `opts` says. Closing always fails the
+    /// wait, with [AcquireError::Closed].
+    ///
+    /// When several reasons to stop apply at once, the first of these
+    /// wins: a token cancelled before the call, the semaphore being
+    /// closed, a free permit, then cancellation or the timeout, whichever
+    /// is noticed first. So a free permit is taken even once the timeout
+    /// has passed, but never after the semaphore was closed.
+    ///
+    /// ```
+    /// use std::time::Duration;
+    /// use xlock::cancel::CancelHandle;
+    /// use xlock::semaphore::{AcquireError, AcquireOptions, Semaphore};
+    ///
+    /// let sem = Semaphore::new(1);
+    /// let shutdown = CancelHandle::new();
+    /// let token = shutdown.token();
+    /// let opts = AcquireOptions {
+    ///     timeout: Some(Duration::from_millis(10)),
+    ///     cancel: Some(&token),
+    /// };
+    /// let permit = sem.acquire_full(opts).unwrap();
+    /// assert_eq!(sem.acquire_full(opts).unwrap_err(), AcquireError::Timeout);
+    /// drop(permit);
+    /// shutdown.cancel();
+    /// assert_eq!(sem.acquire_full(opts).unwrap_err(), AcquireError::Cancelled);
+    /// ```
+    #[cfg(feature = "std")]
+    pub fn acquire_full(
+        &self,
+        opts: AcquireOptions<'_>,
+    ) -> Result<SemaphorePermit<'_>, AcquireError> {
+        let deadline = opts.timeout.map(|timeout| Instant::now() + timeout);
+        let permit = self.inner.acquire_permit_with(deadline, opts.cancel)?;
+        Ok(self.permit(permit))
+    }
+
+    /// Like [Semaphore::acquire_until], for callers that look again after
+    /// a timeout: those timeouts aren't counted in the stats.
+    #[cfg(feature = "std")]
+    pub(crate) fn acquire_or_recheck(
+        &self,
+        deadline: Instant,
+    ) -> Result<SemaphorePermit<'_>, AcquireError> {
+        let permit = self.inner.acquire_permit_or_recheck(deadline)?;
+        Ok(self.permit(permit))
+    }
+
+    /// Take a permit, giving up after `timeout`.
+    #[cfg(feature = "std")]
+    pub fn acquire_timeout(&self, timeout: Duration) -> Result<SemaphorePermit<'_>, AcquireError> {
+        self.acquire_until(Instant::now() + timeout)
+    }
+
+    /// Take a permit, giving up once `deadline` has passed.
+    #[cfg(feature = "std")]
+    pub fn acquire_until(&self, deadline: Instant) -> Result<SemaphorePermit<'_>, AcquireError> {
+        let permit = self.inner.acquire_permit_until(deadline)?;
+        Ok(self.permit(permit))
+    }
+
+    /// Like [Semaphore::acquire], but the permit holds a clone of the
+    /// `Arc` instead of a borrow, so it can outlive the caller's scope.
+    #[cfg(feature = "std")]
+    pub fn acquire_owned(self: &Arc<Self>) -> Result<OwnedPermit, AcquireError> {
+        let permit = self.inner.acquire_permit()?;
+        Ok(OwnedPermit::new(self, permit))
+    }
+
+    /// Like [Semaphore::acquire_many], but returns an [OwnedPermit].
+    #[cfg(feature = "std")]
+    pub fn acquire_many_owned(self: &Arc<Self>, n: u32) -> Result<OwnedPermit, AcquireError> {
+        let permit = self.inner.acquire_permits(n)?;
+        Ok(OwnedPermit::new(self, permit))
+    }
+
+    /// Like [Semaphore::try_acquire], but returns an [OwnedPermit].
+    #[cfg(feature = "std")]
+    pub fn try_acquire_owned(self: &Arc<Self>) -> Result<OwnedPermit, TryAcquireError> {
+        let permit = self.inner.try_acquire_permits(1)?;
+        Ok(OwnedPermit::new(self, permit))
+    }
+
+    /// Hand out up to `n` more permits at a time, waking waiters that can
+    /// now proceed.
+    ///
+    /// # Panics
+    ///
+    /// If the total would be more than [Semaphore::MAX_PERMITS].
+    pub fn add_permits(&self, n: u32) {
+        self.inner.add_permits(n);
+    }
+
+    /// Change how many permits are handed out at a time. When lowered,
+    /// outstanding permits stay valid and new acquisitions wait until
+    /// enough of them are returned to get under the new limit.
+    ///
+    /// # Panics
+    ///
+    /// If `capacity` is more than [Semaphore::MAX_PERMITS].
+    pub fn set_capacity(&self, capacity: u32) {
+        self.inner.set_capacity(capacity);
+    }
+
+    /// Take a permit only if one is free right now.
+    pub fn try_acquire(&self) -> Result<SemaphorePermit<'_>, TryAcquireError> {
+        self.try_acquire_many(1)
+    }
+
+    /// Take `n` permits at once only if they are all free right now. The
+    /// permits are taken atomically: never just some of them.
+    pub fn try_acquire_many(&self, n: u32) -> Result<SemaphorePermit<'_>, TryAcquireError> {
+        let permit = self.inner.try_acquire_permits(n)?;
+        Ok(self.permit(permit))
+    }
+
+    /// Take a permit from the first of `sems` that has one free right now,
+    /// returning its index with the permit. Fails with
+    /// [TryAcquireError::Closed] only if every one of them is closed.
+    ///
+    /// There is no blocking version: a thread can only park on one
+    /// semaphore at a time.
+    ///
+    /// ```
+    /// use xlock::semaphore::Semaphore;
+    ///
+    /// let (primary, fallback) = (Semaphore::new(0), Semaphore::new(1));
+    /// let (index, _permit) = Semaphore::try_acquire_any(&[&primary, &fallback]).unwrap();
+    /// assert_eq!(index, 1);
+    /// ```
+    pub fn try_acquire_any<'a>(
+        sems: &[&'a Semaphore],
+    ) -> Result<(usize, SemaphorePermit<'a>), TryAcquireError> {
+        let mut closed = true;
+        for (index, sem) in sems.iter().enumerate() {
+            match sem.try_acquire() {
+                Ok(permit) => return Ok((index, permit)),
+                Err(TryAcquireError::Closed) => {}
+                Err(_) => closed = false,
+            }
+        }
+        Err(if closed && !sems.is_empty() {
+            TryAcquireError::Closed
+        } else {
+            TryAcquireError::NoPermits
+        })
+    }
+
+    /// The threads that acquired the permits held right now, once per
+    /// [SemaphorePermit] or [OwnedPermit], for diagnosing hangs. A permit
+    /// sent to another thread still counts as held by the one that
+    /// acquired it. Only a snapshot.
+    #[cfg(feature = "holder_tracking")]
+    pub fn holders(&self) -> Vec<ThreadId> {
+        self.holders
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .clone()
+    }
+
+    /// Wrap permits just taken into a [SemaphorePermit].
+    fn permit<'a>(&'a self, permit: SemPermit<'a>) -> SemaphorePermit<'a> {
+        SemaphorePermit {
+            #[cfg(feature = "holder_tracking")]
+            holder: Holder::new(&self.holders, std::thread::current().id()),
+            permit,
+        }
+    }
+
+    /// Run `f` while holding a permit, waiting for one first. The permit
+    /// is released before returning, even if `f` panics.
+    pub fn with_permit<R>(&self, f: impl FnOnce() -> R) -> Result<R, AcquireError> {
+        let _permit = self.acquire()?;
+        Ok(f())
+    }
+
+    /// Run `f` while holding a permit, only if one is free right now.
+    pub fn try_with_permit<R>(&self, f: impl FnOnce() -> R) -> Result<R, TryAcquireError> {
+        let _permit = self.try_acquire()?;
+        Ok(f())
+    }
+
+    /// Close the semaphore: every waiter wakes up with
+    /// [AcquireError::Closed], and so do all later acquisitions. Permits
+    /// already handed out stay valid and are given back as usual.
+    pub fn close(&self) {
+        self.inner.close();
+    }
+
+    /// Whether [Semaphore::close] has been called.
+    pub fn is_closed(&self) -> bool {
+        self.inner.is_closed()
+    }
+
+    /// Permits free right now: [Semaphore::capacity] minus those handed
+    /// out, or 0 after the capacity was lowered below that.
+    ///
+    /// Like the other introspection methods this is a racy snapshot, for
+    /// monitoring and debug assertions. By the time it returns, other
+    /// threads may have changed it, so don't base acquisitions on it: use
+    /// [Semaphore::try_acquire] instead.
+    pub fn available_permits(&self) -> u32 {
+        self.inner.available().max(0) as u32
+    }
+
+    /// The most permits handed out at a time, as set by [Semaphore::new],
+    /// [Semaphore::add_permits] and [Semaphore::set_capacity].
+    pub fn capacity(&self) -> u32 {
+        self.inner.capacity()
+    }
+
+    /// Best effort count of the threads parked waiting for permits. A
+    /// racy snapshot: threads are counted a little before they park and
+    /// after they wake, and tasks from `acquire_async` aren't counted.
+    pub fn waiters(&self) -> u32 {
+        self.inner.waiters()
+    }
+
+    /// Counters of acquisitions, parking and wait and hold times, see
+    /// [stats](crate::stats).
+    #[cfg(feature = "stats")]
+    pub fn stats(&self) -> crate::stats::LockStats {
+        self.inner.stats()
+    }
+
+    /// Zero the counters returned by [Semaphore::stats].
+    #[cfg(feature = "stats")]
+    pub fn reset_stats(&self) {
+        self.inner.reset_stats();
+    }
+}
+
+#[cfg(all(test, feature = "std"))]
+mod test {
+    use super::*;
+    use std::sync::atomic::{AtomicU32, Ordering};
+
+    #[test]
+    fn limits_concurrent_permits() {
+        let sem = Semaphore::new(3);
+        let active = AtomicU32::new(0);
+        std::thread::scope(|s| {
+            for _ in 0..8 {
+                s.spawn(|| {
+                    for _ in 0..50 {
+                        let _permit = sem.acquire().unwrap();
+                        let now = active.fetch_add(1, Ordering::SeqCst) + 1;
+                        assert!(now <= 3);
+                        std::thread::yield_now();
+                        active.fetch_sub(1, Ordering::SeqCst);
+                    }
+                });
+            }
+        });
+        assert_eq!(active.load(Ordering::SeqCst), 0);
+    }
+
+    #[test]
+    fn is_send_and_sync() {
+        fn assert_send_sync<T: Send + Sync>() {}
+        assert_send_sync::<Semaphore>();
+        assert_send_sync::<SemaphorePermit<'_>>();
+        assert_send_sync::<OwnedPermit>();
+    }
+
+    #[cfg(feature = "async")]
+    mod with_async {
+        use super::*;
+        use crate::wakers::block_on;
+        use std::future::Future;
+        use std::pin::pin;
+        use std::sync::atomic::AtomicBool;
+        use std::task::{Context, Poll, Wake, Waker};
+
+        struct Flag(AtomicBool);
+
+        impl Wake for Flag {
+            fn wake(self: Arc<Self>) {
+                self.0.store(true, Ordering::SeqCst);
+            }
+        }
+
+        fn flag() -> (Arc<Flag>, Waker) {
+            let flag = Arc::new(Flag(AtomicBool::new(false)));
+            (flag.clone(), Waker::from(flag))
+        }
+
+        #[test]
+        fn blocking_release_wakes_task() {
+            let sem = Semaphore::new(1);
+            let permit = sem.acquire().unwrap();
+            std::thread::scope(|s| {
+                let task = s.spawn(|| block_on(sem.acquire_async()).map(drop));
+                std::thread::sleep(std::time::Duration::from_millis(20));
+                drop(permit);
+                task.join().unwrap().unwrap();
+            });
+        }
+
+        #[test]
+        fn task_release_wakes_thread() {
+            let sem = Semaphore::new(1);
+            let permit = block_on(sem.acquire_async()).unwrap();
+            std::thread::scope(|s| {
+                let thread = s.spawn(|| sem.acquire().map(drop));
+                std::thread::sleep(std::time::Duration::from_millis(20));
+                drop(permit);
+                thread.join().unwrap().unwrap();
+            });
+        }
+
+        #[test]
+        fn cancelled_after_wake_passes_it_on() {
+            let sem = Semaphore::new(1);
+            let permit = sem.acquire().unwrap();
+            let (a, wa) = flag();
+            let (b, wb) = flag();
+            let mut first = Box::pin(sem.acquire_async());
+            let mut second = pin!(sem.acquire_async());
+            assert!(first
+                .as_mut()
+                .poll(&mut Context::from_waker(&wa))
+                .is_pending());
+            assert!(second
+                .as_mut()
+                .poll(&mut Context::from_waker(&wb))
+                .is_pending());
+
+            drop(permit);
+            assert!(a.0.load(Ordering::SeqCst));
+            assert!(!b.0.load(Ordering::SeqCst));
+            // Like losing a `select!` race after being woken.
+            drop(first);
+            assert!(b.0.load(Ordering::SeqCst));
+            let Poll::Ready(Ok(_permit)) = second.poll(&mut Context::from_waker(&wb)) else {
+                panic!("second waiter didn't get the permit");
+            };
+        }
+
+        #[test]
+        fn close_fails_waiting_tasks() {
+            let sem = Semaphore::new(0);
+            std::thread::scope(|s| {
+                let task = s.spawn(|| block_on(sem.acquire_many_async(2)).map(drop));
+                std::thread::sleep(std::time::Duration::from_millis(20));
+                sem.close();
+                assert_eq!(task.join().unwrap(), Err(AcquireError::Closed));
+            });
+        }
+
+        #[test]
+        fn tasks_and_threads_share_capacity() {
+            let sem = Semaphore::new(2);
+            let active = AtomicU32::new(0);
+            let iterations = if cfg!(miri) { 5 } else { 100 };
+            std::thread::scope(|s| {
+                for i in 0..6 {
+                    let (sem, active) = (&sem, &active);
+                    s.spawn(move || {
+                        for _ in 0..iterations {
+                            let _permit = if i % 2 == 0 {
+                                block_on(sem.acquire_async()).unwrap()
+                            } else {
+                                sem.acquire().unwrap()
+                            };
+                            assert!(active.fetch_add(1, Ordering::SeqCst) < 2);
+                            std::thread::yield_now();
+                            active.fetch_sub(1, Ordering::SeqCst);
+                        }
+                    });
+                }
+            });
+        }
+    }
+
+    #[test]
+    fn owned_permits_outlive_their_scope() {
+        let sem = Arc::new(Semaphore::new(3));
+        let mut held = Vec::new();
+        {
+            held.push(sem.acquire_owned().unwrap());
+            held.push(sem.acquire_many_owned(2).unwrap());
+        }
+        assert_eq!(
+            sem.try_acquire_owned().err(),
+            Some(TryAcquireError::NoPermits)
+        );
+        held.pop();
+        assert!(sem.try_acquire_many(2).is_ok());
+        drop(held);
+        assert!(sem.try_acquire_many(3).is_ok());
+    }
+
+    #[test]
+    fn owned_permits_move_across_threads() {
+        let sem = Arc::new(Semaphore::new(2));
+        let active = Arc::new(AtomicU32::new(0));
+        let workers: Vec<_> = (0..6)
+            .map(|_| {
+                // Acquired here, released on the worker.
+                let permit = sem.acquire_owned().unwrap();
+                let active = Arc::clone(&active);
+                std::thread::spawn(move || {
+                    let now = active.fetch_add(1, Ordering::SeqCst) + 1;
+                    assert!(now <= 2);
+                    std::thread::yield_now();
+                    active.fetch_sub(1, Ordering::SeqCst);
+                    drop(permit);
+                })
+            })
+            .collect();
+        for worker in workers {
+            worker.join().unwrap();
+        }
+        assert!(sem.try_acquire_many(2).is_ok());
+    }
+
+    #[test]
+    fn with_permit_holds_for_the_closure() {
+        let sem = Semaphore::new(1);
+        let inner = sem.with_permit(|| sem.try_acquire().err()).unwrap();
+        assert_eq!(inner, Some(TryAcquireError::NoPermits));
+        assert_eq!(sem.try_with_permit(|| 7), Ok(7));
+
+        let panicked = std::panic::catch_unwind(|| sem.with_permit(|| panic!("boom")));
+        assert!(panicked.is_err());
+        assert!(sem.try_acquire().is_ok());
+    }
+
+    #[test]
+    fn debug_output() {
+        let sem = Semaphore::new(3);
+        let permit = sem.acquire_many(2).unwrap();
+        assert_eq!(
+            format!("{sem:?}"),
+            "Semaphore { capacity: 3, available: 1, closed: false }"
+        );
+        assert_eq!(format!("{permit:?}"), "SemaphorePermit { permits: 2 }");
+    }
+
+    #[test]
+    fn try_acquire_fails_when_saturated() {
+        let sem = Semaphore::new(2);
+        let a = sem.try_acquire().unwrap();
+        let _b = sem.try_acquire().unwrap();
+        assert_eq!(sem.try_acquire().err(), Some(TryAcquireError::NoPermits));
+        drop(a);
+        assert!(sem.try_acquire().is_ok());
+    }
+
+    #[test]
+    fn try_acquire_many_is_all_or_nothing() {
+        let sem = Semaphore::new(4);
+        let one = sem.acquire().unwrap();
+        assert!(sem.try_acquire_many(4).is_err());
+        let three = sem.try_acquire_many(3).unwrap();
+        assert!(sem.try_acquire().is_err());
+        drop(one);
+        drop(three);
+        let _all = sem.try_acquire_many(4).unwrap();
+        assert!(sem.try_acquire_many(5).is_err());
+    }
+
+    #[test]
+    fn mixed_blocking_and_try_acquirers() {
+        const CAPACITY: u32 = 4;
+        let iterations = if cfg!(miri) { 10 } else { 500 };
+        let sem = Semaphore::new(CAPACITY);
+        let active = AtomicU32::new(0);
+
+        let hold = |permits: u32| {
+            let now = active.fetch_add(permits, Ordering::SeqCst) + permits;
+            assert!(now <= CAPACITY);
+            std::thread::yield_now();
+            active.fetch_sub(permits, Ordering::SeqCst);
+        };
+
+        std::thread::scope(|s| {
+            for _ in 0..4 {
+                s.spawn(|| {
+                    for _ in 0..iterations {
+                        let _permit = sem.acquire().unwrap();
+                        hold(1);
+                    }
+                });
+            }
+            for n in 1..=4 {
+                let (sem, hold) = (&sem, &hold);
+                s.spawn(move || {
+                    for _ in 0..iterations {
+                        match sem.try_acquire_many(n) {
+                            Ok(_permit) => hold(n),
+                            Err(TryAcquireError::NoPermits) => std::thread::yield_now(),
+                            Err(e) => unreachable!("{e}"),
+                        }
+                    }
+                });
+            }
+        });
+        assert_eq!(active.load(Ordering::SeqCst), 0);
+        assert!(sem.try_acquire_many(CAPACITY).is_ok());
+    }
+
+    #[test]
+    fn acquire_timeout_expires_and_recovers() {
+        let sem = Semaphore::new(1);
+        let held = sem.acquire().unwrap();
+        std::thread::scope(|s| {
+            s.spawn(|| {
+                let timed_out = sem.acquire_timeout(Duration::from_millis(50));
+                assert_eq!(timed_out.err(), Some(AcquireError::Timeout));
+            });
+        });
+        drop(held);
+        // The timed out waiter left no permit behind.
+        let _a = sem.try_acquire().unwrap();
+        assert!(sem.try_acquire().is_err());
+    }
+
+    #[test]
+    fn acquire_timeout_succeeds_when_released_in_time() {
+        let sem = Semaphore::new(1);
+        std::thread::scope(|s| {
+            let held = sem.acquire().unwrap();
+            let waiter = s.spawn(|| sem.acquire_timeout(Duration::from_secs(10)).is_ok());
+            std::thread::sleep(Duration::from_millis(50));
+            drop(held);
+            assert!(waiter.join().unwrap());
+        });
+    }
+
+    #[test]
+    fn acquire_many_interleaves_with_single_permits() {
+        const CAPACITY: u32 = 8;
+        let sem = Semaphore::new(CAPACITY);
+        let active = AtomicU32::new(0);
+        let rounds = if cfg!(miri) { 5 } else { 200 };
+
+        std::thread::scope(|s| {
+            for weight in [1, 1, 1, 3, 5, CAPACITY] {
+                let (sem, active) = (&sem, &active);
+                s.spawn(move || {
+                    for _ in 0..rounds {
+                        let _permit = sem.acquire_many(weight).unwrap();
+                        let now = active.fetch_add(weight, Ordering::SeqCst) + weight;
+                        assert!(now <= CAPACITY);
+                        std::thread::yield_now();
+                        active.fetch_sub(weight, Ordering::SeqCst);
+                    }
+                });
+            }
+        });
+
+        let _all = sem.try_acquire_many(CAPACITY).unwrap();
+    }
+
+    #[test]
+    fn acquire_all_excludes_workers_until_released() {
+        let sem = Semaphore::new(4);
+        let active = AtomicU32::new(0);
+        let rounds = AtomicU32::new(0);
+        let stop = std::sync::atomic::AtomicBool::new(false);
+
+        std::thread::scope(|s| {
+            for _ in 0..4 {
+                s.spawn(|| {
+                    while !stop.load(Ordering::SeqCst) {
+                        let _permit = sem.acquire().unwrap();
+                        active.fetch_add(1, Ordering::SeqCst);
+                        std::thread::yield_now();
+                        active.fetch_sub(1, Ordering::SeqCst);
+                        rounds.fetch_add(1, Ordering::SeqCst);
+                    }
+                });
+            }
+            while rounds.load(Ordering::SeqCst) < 100 {
+                std::thread::yield_now();
+            }
+
+            let all = sem.acquire_all().unwrap();
+            assert_eq!(sem.available_permits(), 0);
+            let before = rounds.load(Ordering::SeqCst);
+            std::thread::sleep(Duration::from_millis(20));
+            assert_eq!(active.load(Ordering::SeqCst), 0);
+            assert_eq!(rounds.load(Ordering::SeqCst), before);
+            drop(all);
+
+            while rounds.load(Ordering::SeqCst) < before + 100 {
+                std::thread::yield_now();
+            }
+            stop.store(true, Ordering::SeqCst);
+        });
+    }
+
+    #[test]
+    fn drain_takes_only_free_permits() {
+        let sem = Semaphore::new(3);
+        let held = sem.acquire().unwrap();
+        assert_eq!(sem.drain_permits(), 2);
+        assert_eq!(sem.drain_permits(), 0);
+        drop(held);
+        assert_eq!(sem.drain_permits(), 1);
+        sem.release(3);
+        sem.close();
+        assert_eq!(sem.drain_permits(), 0);
+    }
+
+    #[test]
+    fn large_waiter_is_not_starved() {
+        let sem = Semaphore::new(4);
+        let stop = std::sync::atomic::AtomicBool::new(false);
+
+        std::thread::scope(|s| {
+            for _ in 0..3 {
+                s.spawn(|| {
+                    while !stop.load(Ordering::SeqCst) {
+                        let _permit = sem.acquire().unwrap();
+                        std::thread::yield_now();
+                    }
+                });
+            }
+            // Some single permit is held at almost all times, so this only
+            // gets through if newly freed permits are held back for it.
+            let _all = sem.acquire_many(4).unwrap();
+            stop.store(true, Ordering::SeqCst);
+        });
+    }
+
+    #[test]
+    fn acquire_many_over_capacity_waits_for_growth() {
+        let sem = Semaphore::new(2);
+        std::thread::scope(|s| {
+            let waiter = s.spawn(|| drop(sem.acquire_many(3).unwrap()));
+            std::thread::sleep(Duration::from_millis(50));
+            assert!(!waiter.is_finished());
+            sem.add_permits(1);
+        });
+    }
+
+    #[test]
+    fn add_permits_releases_waiters() {
+        let sem = Semaphore::new(0);
+        let done = AtomicU32::new(0);
+        std::thread::scope(|s| {
+            for _ in 0..3 {
+                s.spawn(|| {
+                    let _permit = sem.acquire().unwrap();
+                    done.fetch_add(1, Ordering::SeqCst);
+                });
+            }
+            std::thread::sleep(Duration::from_millis(50));
+            assert_eq!(done.load(Ordering::SeqCst), 0);
+            sem.add_permits(3);
+        });
+        assert_eq!(done.load(Ordering::SeqCst), 3);
+    }
+
+    #[test]
+    fn capacity_limit() {
+        let sem = Semaphore::new(Semaphore::MAX_PERMITS);
+        let all = sem.try_acquire_many(Semaphore::MAX_PERMITS).unwrap();
+        assert!(sem.try_acquire().is_err());
+        drop(all);
+        let result = std::panic::catch_unwind(|| sem.add_permits(1));
+        assert!(result.is_err());
+        assert_eq!(sem.capacity(), Semaphore::MAX_PERMITS);
+        assert_eq!(sem.available_permits(), Semaphore::MAX_PERMITS);
+    }
+
+    #[test]
+    fn shrinking_limits_new_acquisitions() {
+        let sem = Semaphore::new(3);
+        let held = sem.try_acquire_many(3).unwrap();
+        sem.set_capacity(1);
+        drop(held);
+        let _one = sem.try_acquire().unwrap();
+        assert!(sem.try_acquire().is_err());
+    }
+
+    #[test]
+    fn forgotten_permits_are_gone_until_added_back() {
+        let sem = Semaphore::new(3);
+        for _ in 0..3 {
+            sem.acquire().unwrap().forget();
+        }
+        assert!(sem.try_acquire().is_err());
+
+        sem.add_permits(1);
+        let _permit = sem.try_acquire().unwrap();
+        assert!(sem.try_acquire().is_err());
+    }
+
+    #[test]
+    fn forget_keeps_capacity_consistent() {
+        let sem = Semaphore::new(4);
+        sem.try_acquire_many(2).unwrap().forget();
+        // The capacity is now 2, so resetting it to 4 frees two permits.
+        sem.set_capacity(4);
+        let _all = sem.try_acquire_many(4).unwrap();
+    }
+
+    #[test]
+    fn forget_after_shrink_stops_at_zero() {
+        let sem = Semaphore::new(4);
+        let held = sem.try_acquire_many(4).unwrap();
+        sem.set_capacity(1);
+        held.forget();
+        assert_eq!(sem.capacity(), 0);
+        assert_eq!(sem.inner.available(), 0);
+        sem.add_permits(2);
+        let _two = sem.try_acquire_many(2).unwrap();
+        assert!(sem.try_acquire().is_err());
+    }
+
+    #[test]
+    fn close_wakes_all_waiters() {
+        let sem = Semaphore::new(1);
+        let held = sem.acquire().unwrap();
+        std::thread::scope(|s| {
+            let sem = &sem;
+            let waiters: Vec<_> = (0..4)
+                .map(|i| {
+                    s.spawn(move || {
+                        if i % 2 == 0 {
+                            sem.acquire().err()
+                        } else {
+                            sem.acquire_many(2).err()
+                        }
+                    })
+                })
+                .collect();
+            std::thread::sleep(Duration::from_millis(50));
+            sem.close();
+            for waiter in waiters {
+                assert_eq!(waiter.join().unwrap(), Some(AcquireError::Closed));
+            }
+        });
+
+        assert!(sem.is_closed());
+        assert_eq!(sem.try_acquire().err(), Some(TryAcquireError::Closed));
+        // Outstanding permits are still given back normally.
+        drop(held);
+        assert_eq!(sem.acquire().err(), Some(AcquireError::Closed));
+    }
+
+    #[test]
+    fn introspection() {
+        let sem = Semaphore::new(3);
+        assert_eq!(sem.capacity(), 3);
+        let one = sem.acquire().unwrap();
+        let two = sem.acquire_many(2).unwrap();
+        assert_eq!(sem.available_permits(), 0);
+        drop(one);
+        assert_eq!(sem.available_permits(), 1);
+        sem.set_capacity(1);
+        assert_eq!(sem.available_permits(), 0);
+        drop(two);
+        assert_eq!(sem.available_permits(), 1);
+
+        let held = sem.acquire().unwrap();
+        assert_eq!(sem.waiters(), 0);
+        std::thread::scope(|s| {
+            for _ in 0..2 {
+                s.spawn(|| drop(sem.acquire().unwrap()));
+            }
+            while sem.waiters() != 2 {
+                std::thread::yield_now();
+            }
+            drop(held);
+        });
+        assert_eq!(sem.waiters(), 0);
+    }
+
+    #[test]
+    fn random_splits_and_clones_balance() {
+        const CAPACITY: u32 = 16;
+        let sem = Semaphore::new(CAPACITY);
+        // Pieces dropped by whichever thread picks them up next.
+        let shared = crate::mutex::Mutex::new(Vec::new());
+        let iterations = if cfg!(miri) { 20 } else { 2_000 };
+        std::thread::scope(|s| {
+            for seed in 1..=4u64 {
+                let (sem, shared) = (&sem, &shared);
+                s.spawn(move || {
+                    let mut x = seed.wrapping_mul(0x9E37_79B9_7F4A_7C15);
+                    let mut next = move || {
+                        x ^= x << 13;
+                        x ^= x >> 7;
+                        x ^= x << 17;
+                        x
+                    };
+                    let mut held: Vec<SemaphorePermit<'_>> = Vec::new();
+                    for _ in 0..iterations {
+                        let r = next();
+                        match (r % 5, held.len()) {
+                            (0, _) | (_, 0) => {
+                                let n = (r >> 8) as u32 % 4 + 1;
+                                if let Ok(permit) = sem.try_acquire_many(n) {
+                                    held.push(permit);
+                                }
+                            }
+                            (1, len) => {
+                                let permit = &mut held[(r >> 8) as usize % len];
+                                let n = (r >> 16) as u32 % (permit.num_permits() + 1);
+                                let piece = permit.split(n);
+                                held.push(piece);
+                            }
+                            (2, len) => {
+                                if let Ok(clone) = held[(r >> 8) as usize % len].try_clone() {
+                                    held.push(clone);
+                                }
+                            }
+                            (3, len) if len >= 2 => {
+                                let other = held.swap_remove((r >> 8) as usize % len);
+                                held[(r >> 16) as usize % (len - 1)].merge(other);
+                            }
+                            (_, len) => {
+                                let piece = held.swap_remove((r >> 8) as usize % len);
+                                if r >> 63 == 0 {
+                                    drop(piece);
+                                } else {
+                                    let mut shared = shared.lock();
+                                    shared.push(piece);
+                                    if shared.len() > 8 {
+                                        shared.clear();
+                                    }
+                                }
+                            }
+                        }
+                        let total: u32 = held.iter().map(|p| p.num_permits()).sum();
+                        assert!(total <= CAPACITY);
+                    }
+                });
+            }
+        });
+        shared.lock().clear();
+        assert_eq!(sem.available_permits(), CAPACITY);
+    }
+
+    #[test]
+    #[should_panic = "cannot split 3 permits off a permit holding 2"]
+    fn split_more_than_held() {
+        let sem = Semaphore::new(2);
+        let mut permit = sem.acquire_many(2).unwrap();
+        let _ = permit.split(3);
+    }
+
+    #[test]
+    fn try_clone_widens_a_hold() {
+        let sem = Semaphore::new(3);
+        let mut permit = sem.acquire().unwrap();
+        let more = permit.try_clone().unwrap();
+        permit.merge(more);
+        assert_eq!(permit.num_permits(), 2);
+        assert_eq!(permit.try_clone().err(), Some(TryAcquireError::NoPermits));
+        drop(permit);
+        assert_eq!(sem.available_permits(), 3);
+    }
+
+    #[test]
+    fn usable_in_statics() {
+        static LIMIT: Semaphore = Semaphore::new(1);
+        let _permit = LIMIT.try_acquire().unwrap();
+        assert!(LIMIT.try_acquire().is_err());
+    }
+
+    #[test]
+    fn consumers_wait_for_released_items() {
+        const ITEMS: u32 = if cfg!(miri) { 10 } else { 1_000 };
+        let items = Semaphore::with_permits(ITEMS, 0);
+        let consumed = AtomicU32::new(0);
+        std::thread::scope(|s| {
+            for _ in 0..4 {
+                s.spawn(|| {
+                    while consumed.fetch_add(1, Ordering::Relaxed) < ITEMS {
+                        items.acquire().unwrap().consume();
+                    }
+                });
+            }
+            for _ in 0..ITEMS {
+                items.release(1);
+            }
+        });
+        assert_eq!(items.available_permits(), 0);
+        assert_eq!(items.capacity(), ITEMS);
+    }
+
+    #[test]
+    fn release_checks_capacity() {
+        let sem = Semaphore::with_permits(3, 1);
+        let permit = sem.try_acquire().unwrap();
+        assert!(sem.try_acquire().is_err());
+        sem.release(2);
+        assert_eq!(sem.available_permits(), 2);
+        drop(permit);
+        assert_eq!(sem.available_permits(), 3);
+        let result = std::panic::catch_unwind(|| sem.release(1));
+        assert!(result.is_err());
+        assert_eq!(sem.available_permits(), 3);
+    }
+
+    #[test]
+    fn global_semaphores_are_shared_by_name() {
+        fn first_site() -> SemaphorePermit<'static> {
+            Semaphore::global("test.shared", 2).acquire().unwrap()
+        }
+        fn second_site() -> Result<SemaphorePermit<'static>, TryAcquireError> {
+            Semaphore::global("test.shared", 5).try_acquire()
+        }
+        let a = first_site();
+        let b = second_site().unwrap();
+        assert_eq!(second_site().unwrap_err(), TryAcquireError::NoPermits);
+        assert_eq!(Semaphore::global("test.other", 1).available_permits(), 1);
+        drop((a, b));
+        assert_eq!(Semaphore::global("test.shared", 2).capacity(), 2);
+    }
+
+    #[test]
+    fn acquire_full_is_granted() {
+        let sem = Semaphore::new(1);
+        let held = sem.acquire().unwrap();
+        let opts = AcquireOptions {
+            timeout: Some(Duration::from_secs(10)),
+            ..Default::default()
+        };
+        std::thread::scope(|s| {
+            let waiter = s.spawn(|| sem.acquire_full(opts).map(drop));
+            std::thread::sleep(Duration::from_millis(20));
+            drop(held);
+            assert_eq!(waiter.join().unwrap(), Ok(()));
+        });
+        assert_eq!(sem.available_permits(), 1);
+    }
+
+    #[test]
+    fn acquire_full_times_out() {
+        let sem = Semaphore::new(1);
+        let _held = sem.acquire().unwrap();
+        let start = Instant::now();
+        let opts = AcquireOptions {
+            timeout: Some(Duration::from_millis(30)),
+            ..Default::default()
+        };
+        assert_eq!(sem.acquire_full(opts).unwrap_err(), AcquireError::Timeout);
+        assert!(start.elapsed() >= Duration::from_millis(30));
+        assert_eq!(sem.waiters(), 0);
+    }
+
+    #[test]
+    fn acquire_full_is_cancelled() {
+        let sem = Semaphore::new(1);
+        let held = sem.acquire().unwrap();
+        let shutdown = crate::cancel::CancelHandle::new();
+        let token = shutdown.token();
+        let opts = AcquireOptions {
+            timeout: Some(Duration::from_secs(10)),
+            cancel: Some(&token),
+        };
+        std::thread::scope(|s| {
+            let waiter = s.spawn(|| sem.acquire_full(opts).map(drop));
+            std::thread::sleep(Duration::from_millis(20));
+            shutdown.cancel();
+            assert_eq!(waiter.join().unwrap(), Err(AcquireError::Cancelled));
+        });
+        drop(held);
+        // Cancelled before the call wins over a free permit.
+        assert_eq!(sem.acquire_full(opts).unwrap_err(), AcquireError::Cancelled);
+        assert_eq!(sem.available_permits(), 1);
+    }
+
+    #[test]
+    fn acquire_full_is_closed() {
+        let sem = Semaphore::new(1);
+        let held = sem.acquire().unwrap();
+        let shutdown = crate::cancel::CancelHandle::new();
+        let token = shutdown.token();
+        let opts = AcquireOptions {
+            timeout: Some(Duration::from_secs(10)),
+            cancel: Some(&token),
+        };
+        std::thread::scope(|s| {
+            let waiter = s.spawn(|| sem.acquire_full(opts).map(drop));
+            std::thread::sleep(Duration::from_millis(20));
+            sem.close();
+            assert_eq!(waiter.join().unwrap(), Err(AcquireError::Closed));
+        });
+        drop(held);
+        // Closed wins over a free permit.
+        assert_eq!(sem.acquire_full(opts).unwrap_err(), AcquireError::Closed);
+    }
+
+    #[test]
+    fn try_acquire_any_takes_a_free_one() {
+        let (full, free) = (Semaphore::new(1), Semaphore::new(1));
+        let _held = full.acquire().unwrap();
+        let (index, permit) = Semaphore::try_acquire_any(&[&full, &free]).unwrap();
+        assert_eq!(index, 1);
+        assert_eq!(free.available_permits(), 0);
+        assert_eq!(
+            Semaphore::try_acquire_any(&[&full, &free]).unwrap_err(),
+            TryAcquireError::NoPermits
+        );
+        drop(permit);
+        free.close();
+        full.close();
+        assert_eq!(
+            Semaphore::try_acquire_any(&[&full, &free]).unwrap_err(),
+            TryAcquireError::Closed
+        );
+    }
+
+    #[test]
+    fn release_returns_consumed_permits() {
+        let sem = Semaphore::new(2);
+        sem.acquire().unwrap().consume();
+        assert_eq!(sem.available_permits(), 1);
+        sem.release(1);
+        assert_eq!(sem.available_permits(), 2);
+        assert_eq!(sem.capacity(), 2);
+        // Returning, not growing: nothing more was taken.
+        assert!(std::panic::catch_unwind(|| sem.release(1)).is_err());
+        assert_eq!(sem.available_permits(), 2);
+    }
+
+    #[cfg(feature = "holder_tracking")]
+    #[test]
+    fn holders_reports_acquiring_threads() {
+        let sem = Arc::new(Semaphore::new(3));
+        assert!(sem.holders().is_empty());
+        let me = std::thread::current().id();
+        let mut permit = sem.acquire_many(2).unwrap();
+        let half = permit.split(1);
+        assert_eq!(sem.holders(), [me, me]);
+        permit.merge(half);
+        assert_eq!(sem.holders(), [me]);
+        let barrier = std::sync::Barrier::new(2);
+        std::thread::scope(|s| {
+            let other = s.spawn(|| {
+                let owned = sem.acquire_owned().unwrap();
+                barrier.wait();
+                barrier.wait();
+                drop(owned);
+            });
+            barrier.wait();
+            let mut holders = sem.holders();
+            holders.sort_by_key(|&t| t != me);
+            assert_eq!(holders, [me, other.thread().id()]);
+            barrier.wait();
+        });
+        assert_eq!(sem.holders(), [me]);
+        drop(permit);
+        assert!(sem.holders().is_empty());
+    }
+
+    #[test]
+    #[should_panic = "more permits available than the capacity"]
+    fn with_permits_above_capacity() {
+        let _ = Semaphore::with_permits(1, 2);
+    }
+
+    #[cfg(feature = "priority")]
+    #[test]
+    fn releases_prefer_higher_priority() {
+        let rounds = if cfg!(miri) { 2 } else { 20 };
+        let sem = Semaphore::new(1);
+        for _ in 0..rounds {
+            let served = std::sync::Mutex::new(Vec::new());
+            let held = sem.acquire().unwrap();
+            std::thread::scope(|s| {
+                for priority in [Priority::Low, Priority::Normal, Priority::High] {
+                    let (sem, served) = (&sem, &served);
+                    s.spawn(move || {
+                        let _permit = sem.acquire_with_priority(priority).unwrap();
+                        served.lock().unwrap().push(priority);
+                    });
+                }
+                while sem.waiters() != 3 {
+                    std::thread::yield_now();
+                }
+                drop(held);
+            });
+            assert_eq!(
+                served.into_inner().unwrap(),
+                [Priority::High, Priority::Normal, Priority::Low]
+            );
+        }
+        assert_eq!(sem.available_permits(), 1);
+    }
+}
@@ -0,0 +1,197 @@
+use crate::semaphore::{AcquireError, TryAcquireError};
+use crate::sys::atomic::{AtomicU32, AtomicU64, Ordering};
+use crate::sys::{wait, wake_all};
+use std::fmt;
+
+/// A semaphore counting permits in a `u64`, for budgets too large for
+/// [Semaphore](crate::semaphore::Semaphore), such as bytes of buffer
+/// space. Each acquisition takes a weight and the permits it holds are
+/// given back when it is dropped.
+///
+/// ```
+/// use xlock::semaphore64::Semaphore64;
+///
+/// let budget = Semaphore64::new(8 << 30);
+/// let a = budget.acquire(6 << 30).unwrap();
+/// assert!(budget.try_acquire(3 << 30).is_err());
+/// drop(a);
+/// let _b = budget.try_acquire(3 << 30).unwrap();
+/// ```
+///
+/// Waiters aren't queued: a large acquisition can be overtaken by a
+/// stream of smaller ones.
+pub struct Semaphore64 {
+    capacity: u64,
+    /// Permits free to take.
+    available: AtomicU64,
+    /// Bumped by every release. The futex only waits on 32 bits, so
+    /// waiters park on this word rather than on `available`.
+    epoch: AtomicU32,
+    /// Number of threads that may be parked on `epoch`. Releases skip the
+    /// wake while this is zero.
+    sleepers: AtomicU32,
+}
+
+/// Permits from a [Semaphore64], given back when dropped.
+#[must_use = "the permits are released as soon as they are dropped"]
+pub struct Semaphore64Permit<'a> {
+    sem: &'a Semaphore64,
+    weight: u64,
+}
+
+impl Semaphore64 {
+    /// Create a semaphore handing out at most `capacity` permits at a
+    /// time.
+    pub const fn new(capacity: u64) -> Self {
+        Self {
+            capacity,
+            available: AtomicU64::new(capacity),
+            epoch: AtomicU32::new(0),
+            sleepers: AtomicU32::new(0),
+        }
+    }
+
+    /// Take `weight` permits at once, waiting until they are all free.
+    /// Fails with [AcquireError::TooLarge], without waiting, if `weight`
+    /// is more than the capacity.
+    pub fn acquire(&self, weight: u64) -> Result<Semaphore64Permit<'_>, AcquireError> {
+        if weight > self.capacity {
+            return Err(AcquireError::TooLarge);
+        }
+        loop {
+            if let Some(permit) = self.take(weight) {
+                return Ok(permit);
+            }
+            // Announce ourselves before the final look: a release bumps
+            // `epoch` and then reads `sleepers`, so either we see its
+            // permits or it sees us and wakes the futex.
+            self.sleepers.fetch_add(1, Ordering::SeqCst);
+            let epoch = self.epoch.load(Ordering::SeqCst);
+            let taken = self.take(weight);
+            if taken.is_none() {
+                wait(&self.epoch, epoch);
+            }
+            self.sleepers.fetch_sub(1, Ordering::SeqCst);
+            if let Some(permit) = taken {
+                return Ok(permit);
+            }
+        }
+    }
+
+    /// Take `weight` permits only if they are all free right now.
+    ///
+    /// A `weight` above the capacity can never succeed, so it fails with
+    /// [TryAcquireError::TooLarge] rather than
+    /// [TryAcquireError::NoPermits].
+    pub fn try_acquire(&self, weight: u64) -> Result<Semaphore64Permit<'_>, TryAcquireError> {
+        if weight > self.capacity {
+            return Err(TryAcquireError::TooLarge);
+        }
+        self.take(weight).ok_or(TryAcquireError::NoPermits)
+    }
+
+    /// The most permits handed out at a time.
+    pub fn capacity(&self) -> u64 {
+        self.capacity
+    }
+
+    /// Permits free right now. Only a snapshot.
+    pub fn available_permits(&self) -> u64 {
+        self.available.load(Ordering::Relaxed)
+    }
+
+    fn take(&self, weight: u64) -> Option<Semaphore64Permit<'_>> {
+        self.available
+            .fetch_update(Ordering::Acquire, Ordering::SeqCst, |free| {
+                free.checked_sub(weight)
+            })
+            .ok()?;
+        Some(Semaphore64Permit { sem: self, weight })
+    }
+}
+
+impl Semaphore64Permit<'_> {
+    /// Number of permits held.
+    pub fn weight(&self) -> u64 {
+        self.weight
+    }
+}
+
+impl Drop for Semaphore64Permit<'_> {
+    fn drop(&mut self) {
+        let sem = self.sem;
+        sem.available.fetch_add(self.weight, Ordering::SeqCst);
+        sem.epoch.fetch_add(1, Ordering::SeqCst);
+        // Waiters want different weights, so let all of them look.
+        if sem.sleepers.load(Ordering::SeqCst) != 0 {
+            wake_all(&sem.epoch);
+        }
+    }
+}
+
+impl fmt::Debug for Semaphore64 {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Semaphore64")
+            .field("capacity", &self.capacity)
+            .field("available", &self.available_permits())
+            .finish()
+    }
+}
+
+impl fmt::Debug for Semaphore64Permit<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Semaphore64Permit")
+            .field("weight", &self.weight)
+            .finish()
+    }
+}
+
+#[cfg(all(test, feature = "std"))]
+mod test {
+    use super::*;
+
+    #[test]
+    fn byte_budget_never_exceeded() {
+        const CAP: u64 = 10 << 30;
+        let sem = Semaphore64::new(CAP);
+        let outstanding = AtomicU64::new(0);
+        let iterations = if cfg!(miri) { 10 } else { 500 };
+        std::thread::scope(|s| {
+            for seed in 1..=6u64 {
+                let (sem, outstanding) = (&sem, &outstanding);
+                s.spawn(move || {
+                    // xorshift, so runs are reproducible without a rand
+                    // dependency.
+                    let mut x = seed.wrapping_mul(0x9E37_79B9_7F4A_7C15);
+                    for _ in 0..iterations {
+                        x ^= x << 13;
+                        x ^= x >> 7;
+                        x ^= x << 17;
+                        let weight = x % CAP + 1;
+                        let permit = sem.acquire(weight).unwrap();
+                        let total = outstanding.fetch_add(weight, Ordering::SeqCst) + weight;
+                        assert!(total <= CAP);
+                        std::thread::yield_now();
+                        outstanding.fetch_sub(weight, Ordering::SeqCst);
+                        drop(permit);
+                    }
+                });
+            }
+        });
+        assert_eq!(sem.available_permits(), CAP);
+    }
+
+    #[test]
+    fn too_large_fails_instead_of_waiting() {
+        let sem = Semaphore64::new(u64::from(u32::MAX) + 1);
+        assert_eq!(sem.acquire(u64::MAX).err(), Some(AcquireError::TooLarge));
+        assert_eq!(
+            sem.try_acquire(sem.capacity() + 1).err(),
+            Some(TryAcquireError::TooLarge)
+        );
+        let all = sem.acquire(sem.capacity()).unwrap();
+        assert_eq!(sem.try_acquire(1).err(), Some(TryAcquireError::NoPermits));
+        drop(all);
+        assert_eq!(sem.available_permits(), sem.capacity());
+    }
+}
@@ -0,0 +1,177 @@
+use crate::sem::{SemGuard, SemVar};
+use crate::sys::atomic::{fence, AtomicU32, Ordering};
+use std::cell::UnsafeCell;
+use std::marker::PhantomData;
+use std::ops::{Deref, DerefMut};
+
+/// A sequence lock for small `Copy` values that are read far more often
+/// than they are written.
+///
+/// Readers never block or write to shared memory: they copy the value
+/// optimistically and retry if a write happened in the meantime. Writers
+/// are serialized by a semaphore with capacity 1.
+///
+/// The optimistic copy may overlap a write. Its result is thrown away
+/// when it does, but miri still reports the overlap as a data race, since
+/// there is no stable way to copy an arbitrary `T` atomically.
+pub struct SeqLock<T> {
+    /// Odd while a write is in progress.
+    seq: AtomicU32,
+    /// Serializes writers.
+    writer: SemVar<()>,
+    /// The protected value.
+    value: UnsafeCell<T>,
+}
+
+/// Exclusive write access to a [SeqLock]'s value, made by
+/// [SeqLock::lock_write]. Readers retry until it is dropped, so keep it
+/// short.
+pub struct SeqLockWriteGuard<'a, T> {
+    lock: &'a SeqLock<T>,
+    /// The sequence number before the write, even.
+    seq: u32,
+    _writer: SemGuard<'a, ()>,
+    /// Shares `&T` when the guard is shared, so `Sync` only if `T` is.
+    _marker: PhantomData<&'a mut T>,
+}
+
+/// SAFETY: Writes are serialized by `writer`, and readers only ever keep
+/// a copy that was validated against `seq`.
+unsafe impl<T> Sync for SeqLock<T> where T: Copy + Send {}
+
+impl<T: Copy> SeqLock<T> {
+    /// Create a new SeqLock holding `value`.
+    pub const fn new(value: T) -> Self {
+        Self {
+            seq: AtomicU32::new(0),
+            writer: SemVar::new(1, ()),
+            value: UnsafeCell::new(value),
+        }
+    }
+
+    /// Read a consistent copy of the value, retrying while a write is in
+    /// progress.
+    pub fn read(&self) -> T {
+        loop {
+            let before = self.seq.load(Ordering::Acquire);
+            if before % 2 == 1 {
+                std::hint::spin_loop();
+                continue;
+            }
+            // The copy may race with a writer; it is only returned once
+            // `seq` shows no write overlapped it. Volatile keeps the
+            // compiler from reusing or tearing it across the check.
+            let value = unsafe { std::ptr::read_volatile(self.value.get()) };
+            fence(Ordering::Acquire);
+            if self.seq.load(Ordering::Relaxed) == before {
+                return value;
+            }
+        }
+    }
+
+    /// Replace the value, waiting for other writers first.
+    pub fn write(&self, value: T) {
+        let guard = self.lock_write();
+        unsafe { std::ptr::write_volatile(guard.lock.value.get(), value) };
+    }
+
+    /// Wait for other writers, then start a write that ends when the
+    /// returned guard is dropped, for updating part of the value in place.
+    pub fn lock_write(&self) -> SeqLockWriteGuard<'_, T> {
+        let writer = self.writer.access();
+        let seq = self.seq.load(Ordering::Relaxed);
+        self.seq.store(seq.wrapping_add(1), Ordering::Relaxed);
+        // Readers that see any of the write also see `seq` odd.
+        fence(Ordering::Release);
+        SeqLockWriteGuard {
+            lock: self,
+            seq,
+            _writer: writer,
+            _marker: PhantomData,
+        }
+    }
+}
+
+impl<T> Drop for SeqLockWriteGuard<'_, T> {
+    fn drop(&mut self) {
+        self.lock
+            .seq
+            .store(self.seq.wrapping_add(2), Ordering::Release);
+    }
+}
+
+impl<T> Deref for SeqLockWriteGuard<'_, T> {
+    type Target = T;
+    fn deref(&self) -> &T {
+        // SAFETY: Only the writer changes the value, and that is us.
+        unsafe { &*self.lock.value.get() }
+    }
+}
+
+impl<T> DerefMut for SeqLockWriteGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        // SAFETY: As in deref. Readers copying the value meanwhile see
+        // `seq` odd or changed, and discard their copy.
+        unsafe { &mut *self.lock.value.get() }
+    }
+}
+
+#[cfg(all(test, feature = "std"))]
+mod test {
+    use super::*;
+    use std::sync::atomic::AtomicBool;
+
+    // The optimistic copy is a data race by the letter of the memory
+    // model, which miri reports even though the result is discarded.
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn readers_never_see_torn_values() {
+        let lock = SeqLock::new((0u64, 0u64));
+        let done = AtomicBool::new(false);
+        let writes = 100_000;
+
+        std::thread::scope(|s| {
+            for _ in 0..4 {
+                s.spawn(|| {
+                    while !done.load(Ordering::Relaxed) {
+                        let (a, b) = lock.read();
+                        assert_eq!(b, a * 2);
+                    }
+                });
+            }
+            for i in 1..=writes {
+                lock.write((i, i * 2));
+            }
+            done.store(true, Ordering::Relaxed);
+        });
+
+        assert_eq!(lock.read(), (writes, writes * 2));
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn write_guard_updates_in_place() {
+        let lock = SeqLock::new((0u64, 0u64));
+        let done = AtomicBool::new(false);
+        let writes = 100_000;
+
+        std::thread::scope(|s| {
+            for _ in 0..4 {
+                s.spawn(|| {
+                    while !done.load(Ordering::Relaxed) {
+                        let (a, b) = lock.read();
+                        assert_eq!(b, a * 2);
+                    }
+                });
+            }
+            for _ in 0..writes {
+                let mut guard = lock.lock_write();
+                guard.0 += 1;
+                guard.1 = guard.0 * 2;
+            }
+            done.store(true, Ordering::Relaxed);
+        });
+
+        assert_eq!(lock.read(), (writes, writes * 2));
+    }
+}
@@ -0,0 +1,204 @@
+use crate::mutex::{Mutex, MutexGuard};
+use crate::padded::CachePadded;
+use std::collections::hash_map::RandomState;
+use std::fmt;
+use std::hash::{BuildHasher, Hash};
+
+/// A value split into independently locked shards, so threads working on
+/// different keys rarely contend, e.g. a large map as a set of smaller
+/// maps. Each shard is a [Mutex] on its own cache line.
+///
+/// A key always maps to the same shard for the life of the value.
+/// Operations over every shard lock them one at a time in index order, so
+/// they can't deadlock with each other or with per-key locking, as long as
+/// no shard guard is held while starting one.
+///
+/// ```
+/// use std::collections::HashMap;
+/// use xlock::sharded::Sharded;
+///
+/// let counts = Sharded::new(8, HashMap::new);
+/// std::thread::scope(|s| {
+///     for word in ["a", "b", "a", "c"] {
+///         let counts = &counts;
+///         s.spawn(move || *counts.shard_for(&word).entry(word).or_insert(0) += 1);
+///     }
+/// });
+/// assert_eq!(counts.fold(0, |n, shard| n + shard.len()), 3);
+/// ```
+pub struct Sharded<T> {
+    shards: Box<[CachePadded<Mutex<T>>]>,
+    hasher: RandomState,
+}
+
+impl<T> Sharded<T> {
+    /// Create `n` shards, each holding a value made by `f`.
+    ///
+    /// # Panics
+    ///
+    /// If `n` isn't a power of two.
+    pub fn new(n: usize, mut f: impl FnMut() -> T) -> Self {
+        assert!(n.is_power_of_two(), "shard count must be a power of two");
+        Self {
+            shards: (0..n).map(|_| CachePadded::new(Mutex::new(f()))).collect(),
+            hasher: RandomState::new(),
+        }
+    }
+
+    /// Create shards with [Sharded::new], four per available CPU rounded
+    /// up to a power of two.
+    pub fn with_default_shards(f: impl FnMut() -> T) -> Self {
+        let cpus = std::thread::available_parallelism().map_or(1, |n| n.get());
+        Self::new((cpus * 4).next_power_of_two(), f)
+    }
+
+    /// The number of shards.
+    pub fn shard_count(&self) -> usize {
+        self.shards.len()
+    }
+
+    /// The index of the shard owning `key`.
+    pub fn index_for<K: Hash + ?Sized>(&self, key: &K) -> usize {
+        self.hasher.hash_one(key) as usize & (self.shards.len() - 1)
+    }
+
+    /// Lock the shard owning `key`.
+    pub fn shard_for<K: Hash + ?Sized>(&self, key: &K) -> MutexGuard<'_, T> {
+        self.shards[self.index_for(key)].lock()
+    }
+
+    /// The shard at `index`.
+    ///
+    /// # Panics
+    ///
+    /// If `index` is not less than [Sharded::shard_count].
+    pub fn get(&self, index: usize) -> &Mutex<T> {
+        &self.shards[index]
+    }
+
+    /// Lock each shard in turn, in index order. Guards already yielded
+    /// stay locked for as long as the caller keeps them.
+    pub fn iter_lock_all(&self) -> impl Iterator<Item = MutexGuard<'_, T>> {
+        self.shards.iter().map(|shard| shard.lock())
+    }
+
+    /// Lock every shard, in index order, and hold them all, for a
+    /// consistent snapshot across shards.
+    pub fn lock_all(&self) -> Vec<MutexGuard<'_, T>> {
+        self.iter_lock_all().collect()
+    }
+
+    /// Combine the shards with `f`, holding only one lock at a time.
+    pub fn fold<B>(&self, init: B, mut f: impl FnMut(B, &T) -> B) -> B {
+        self.iter_lock_all().fold(init, |acc, shard| f(acc, &shard))
+    }
+
+    /// Mutable access to every shard. The exclusive borrow rules out
+    /// guards.
+    pub fn get_mut(&mut self) -> impl Iterator<Item = &mut T> {
+        self.shards.iter_mut().map(|shard| shard.get_mut())
+    }
+
+    /// Consume the shards and return their values, in index order.
+    pub fn into_inner(self) -> Vec<T> {
+        self.shards
+            .into_vec()
+            .into_iter()
+            .map(|shard| shard.into_inner().into_inner())
+            .collect()
+    }
+}
+
+impl<T: fmt::Debug> fmt::Debug for Sharded<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_list().entries(self.shards.iter()).finish()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::collections::HashMap;
+    use std::time::Instant;
+
+    #[test]
+    fn counters_sum_across_shards() {
+        const THREADS: u64 = 8;
+        let iterations = if cfg!(miri) { 10 } else { 1_000 };
+        let counts = Sharded::new(4, HashMap::<u64, u64>::new);
+        std::thread::scope(|s| {
+            for t in 0..THREADS {
+                let counts = &counts;
+                s.spawn(move || {
+                    for i in 0..iterations {
+                        let key = (t * 8 + i) % 64;
+                        *counts.shard_for(&key).entry(key).or_default() += 1;
+                        if i % 100 == 0 {
+                            // Racing full traversals mustn't deadlock.
+                            let total: u64 = counts.lock_all().iter().map(|s| s.len() as u64).sum();
+                            assert!(total <= 64);
+                        }
+                    }
+                });
+            }
+        });
+        let total = counts.fold(0, |n, shard| n + shard.values().sum::<u64>());
+        assert_eq!(total, THREADS * iterations);
+        let keys: usize = counts.into_inner().iter().map(HashMap::len).sum();
+        assert_eq!(keys, 64);
+    }
+
+    #[test]
+    fn keys_stay_in_their_shard() {
+        let sharded = Sharded::new(16, Vec::new);
+        for key in 0..100u32 {
+            let index = sharded.index_for(&key);
+            assert_eq!(index, sharded.index_for(&key));
+            sharded.get(index).lock().push(key);
+        }
+        for (index, shard) in sharded.iter_lock_all().enumerate() {
+            assert!(shard.iter().all(|key| sharded.index_for(key) == index));
+        }
+    }
+
+    #[test]
+    #[should_panic = "power of two"]
+    fn shard_count_must_be_power_of_two() {
+        let _ = Sharded::new(3, || ());
+    }
+
+    /// Threads inserting into one `Mutex<HashMap>` against a sharded one.
+    /// Run with `cargo test --release -- --ignored --nocapture sharded_inserts`.
+    #[test]
+    #[ignore]
+    fn sharded_inserts() {
+        const PER_THREAD: u64 = 200_000;
+        const THREADS: u64 = 8;
+
+        fn run(insert: impl Fn(u64) + Sync) -> f64 {
+            let start = Instant::now();
+            std::thread::scope(|s| {
+                for t in 0..THREADS {
+                    let insert = &insert;
+                    s.spawn(move || {
+                        for i in 0..PER_THREAD {
+                            insert(t * PER_THREAD + i);
+                        }
+                    });
+                }
+            });
+            start.elapsed().as_secs_f64() * 1e9 / (THREADS * PER_THREAD) as f64
+        }
+
+        let single = Mutex::new(HashMap::new());
+        let single = run(|key| {
+            single.lock().insert(key, key);
+        });
+        let sharded = Sharded::with_default_shards(HashMap::new);
+        let sharded = run(|key| {
+            sharded.shard_for(&key).insert(key, key);
+        });
+        println!("single:  {single:.1} ns per insert");
+        println!("sharded: {sharded:.1} ns per insert");
+    }
+}
@@ -0,0 +1,225 @@
+use crate::sem::{SemGuard, SemVar};
+use std::fmt;
+use std::ops::Deref;
+use std::time::{Duration, Instant};
+
+/// A value shared by at most a fixed number of threads at a time, e.g. to
+/// limit concurrent users of an expensive resource.
+///
+/// Up to `capacity` guards exist at once and each hands out `&T`, so the
+/// value is read concurrently: `Shared<T>` is `Sync` exactly when `T` is.
+///
+/// ```
+/// use xlock::shared::Shared;
+///
+/// let index = Shared::new(2, vec![1, 2, 3]);
+/// std::thread::scope(|s| {
+///     for _ in 0..4 {
+///         s.spawn(|| assert_eq!(index.access().len(), 3));
+///     }
+/// });
+/// ```
+///
+/// Values that aren't `Sync` can't be shared this way:
+///
+/// ```compile_fail
+/// use std::cell::Cell;
+/// use xlock::shared::Shared;
+///
+/// let counter = Shared::new(2, Cell::new(0u32));
+/// std::thread::scope(|s| {
+///     s.spawn(|| counter.access().set(1));
+/// });
+/// ```
+pub struct Shared<T: ?Sized> {
+    inner: SemVar<T>,
+}
+
+/// A guard that represents one of the concurrent accesses to a [Shared]
+/// value. `Send` and `Sync` when `T` is `Sync`.
+pub struct SharedGuard<'a, T: ?Sized> {
+    guard: SemGuard<'a, T>,
+}
+
+impl<T> Shared<T> {
+    /// Share `value` between at most `capacity` threads at a time.
+    ///
+    /// A capacity of 0 shuts everyone out: accesses wait until
+    /// [Shared::add_permits] or [Shared::set_capacity] raises it.
+    ///
+    /// # Panics
+    ///
+    /// If `capacity` is more than [Semaphore::MAX_PERMITS](crate::semaphore::Semaphore::MAX_PERMITS).
+    pub const fn new(capacity: u32, value: T) -> Self {
+        Self {
+            inner: SemVar::new(capacity, value),
+        }
+    }
+
+    /// Consume the container and return the value.
+    pub fn into_inner(self) -> T {
+        self.inner.into_inner()
+    }
+}
+
+impl<T: ?Sized> Shared<T> {
+    /// Access the value, waiting while `capacity` others are.
+    pub fn access(&self) -> SharedGuard<'_, T> {
+        SharedGuard {
+            guard: self.inner.access(),
+        }
+    }
+
+    /// Access the value only if that doesn't require waiting.
+    pub fn try_access(&self) -> Option<SharedGuard<'_, T>> {
+        let guard = self.inner.try_access()?;
+        Some(SharedGuard { guard })
+    }
+
+    /// Access the value, giving up after `timeout`.
+    pub fn access_timeout(&self, timeout: Duration) -> Option<SharedGuard<'_, T>> {
+        let guard = self.inner.access_until(Instant::now() + timeout)?;
+        Some(SharedGuard { guard })
+    }
+
+    /// The most threads allowed to access the value at a time.
+    pub fn capacity(&self) -> u32 {
+        self.inner.capacity()
+    }
+
+    /// Allow `n` more threads at a time, waking waiters that can now
+    /// proceed.
+    ///
+    /// # Panics
+    ///
+    /// If the capacity would be more than [Semaphore::MAX_PERMITS](crate::semaphore::Semaphore::MAX_PERMITS).
+    pub fn add_permits(&self, n: u32) {
+        self.inner.add_permits(n);
+    }
+
+    /// Change how many threads may access the value at a time. Lowering
+    /// it leaves current accesses alone; new ones wait until enough of
+    /// them end.
+    ///
+    /// # Panics
+    ///
+    /// If `capacity` is more than [Semaphore::MAX_PERMITS](crate::semaphore::Semaphore::MAX_PERMITS).
+    pub fn set_capacity(&self, capacity: u32) {
+        self.inner.set_capacity(capacity);
+    }
+
+    /// Borrow the value mutably. The exclusive borrow rules out guards.
+    pub fn get_mut(&mut self) -> &mut T {
+        self.inner.get_mut()
+    }
+}
+
+impl<T: ?Sized + fmt::Debug> fmt::Debug for Shared<T> {
+    /// Shows the value if an access is free, without waiting for one.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut d = f.debug_struct("Shared");
+        match self.try_access() {
+            Some(guard) => d.field("data", &&*guard),
+            None => d.field("data", &format_args!("<saturated>")),
+        };
+        d.finish()
+    }
+}
+
+impl<T: ?Sized + fmt::Debug> fmt::Debug for SharedGuard<'_, T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(&**self, f)
+    }
+}
+
+impl<T: ?Sized> Deref for SharedGuard<'_, T> {
+    type Target = T;
+    fn deref(&self) -> &T {
+        &self.guard
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::semaphore::Semaphore;
+    use std::sync::atomic::{AtomicU32, Ordering};
+
+    #[test]
+    fn limits_concurrent_access() {
+        let shared = Shared::new(3, AtomicU32::new(0));
+        let iterations = if cfg!(miri) { 5 } else { 100 };
+        std::thread::scope(|s| {
+            for _ in 0..8 {
+                s.spawn(|| {
+                    for _ in 0..iterations {
+                        let active = shared.access();
+                        assert!(active.fetch_add(1, Ordering::SeqCst) < 3);
+                        std::thread::yield_now();
+                        active.fetch_sub(1, Ordering::SeqCst);
+                    }
+                });
+            }
+        });
+    }
+
+    #[test]
+    fn try_access_and_timeout() {
+        let shared = Shared::new(1, 5);
+        let held = shared.access();
+        assert!(shared.try_access().is_none());
+        assert!(shared.access_timeout(Duration::from_millis(10)).is_none());
+        drop(held);
+        assert_eq!(*shared.try_access().unwrap(), 5);
+    }
+
+    #[test]
+    fn debug_output() {
+        let shared = Shared::new(1, 5);
+        assert_eq!(format!("{shared:?}"), "Shared { data: 5 }");
+        let guard = shared.access();
+        assert_eq!(format!("{guard:?}"), "5");
+        assert_eq!(format!("{shared:?}"), "Shared { data: <saturated> }");
+    }
+
+    #[test]
+    fn zero_capacity_waits_for_growth() {
+        let shared = Shared::new(0, 5);
+        assert!(shared.try_access().is_none());
+        std::thread::scope(|s| {
+            let waiter = s.spawn(|| *shared.access());
+            std::thread::sleep(Duration::from_millis(50));
+            assert!(!waiter.is_finished());
+            shared.add_permits(1);
+            assert_eq!(waiter.join().unwrap(), 5);
+        });
+        shared.set_capacity(0);
+        assert!(shared.try_access().is_none());
+    }
+
+    #[test]
+    fn capacity_limit() {
+        let shared = Shared::new(Semaphore::MAX_PERMITS - 1, ());
+        shared.add_permits(1);
+        assert_eq!(shared.capacity(), Semaphore::MAX_PERMITS);
+        let result = std::panic::catch_unwind(|| shared.add_permits(1));
+        assert!(result.is_err());
+        let result = std::panic::catch_unwind(|| shared.set_capacity(u32::MAX));
+        assert!(result.is_err());
+        assert_eq!(shared.capacity(), Semaphore::MAX_PERMITS);
+        let _guard = shared.try_access().unwrap();
+    }
+
+    #[test]
+    #[should_panic = "capacity too large"]
+    fn capacity_above_limit() {
+        let _ = Shared::new(Semaphore::MAX_PERMITS + 1, ());
+    }
+
+    #[test]
+    fn auto_traits() {
+        fn assert_send_sync<T: Send + Sync>() {}
+        assert_send_sync::<Shared<Vec<u8>>>();
+        assert_send_sync::<SharedGuard<'_, Vec<u8>>>();
+    }
+}
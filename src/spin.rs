@@ -0,0 +1,219 @@
+use crate::sys::atomic::{AtomicBool, Ordering};
+use std::cell::UnsafeCell;
+use std::fmt;
+use std::marker::PhantomData;
+use std::ops::{Deref, DerefMut};
+
+/// Spins between attempts double each round up to this many, then stay
+/// there: a spin lock has nothing better to do than keep looking.
+const MAX_SPINS: u32 = 1 << 6;
+
+/// A mutex that only ever spins, for critical sections of a handful of
+/// instructions where even the unlock side of a futex is too slow, such
+/// as on realtime threads. It has the same `lock`/`try_lock`/guard API as
+/// [Mutex](crate::mutex::Mutex), so switching is a change of type.
+///
+/// **Prefer [Mutex](crate::mutex::Mutex) almost everywhere.** A waiter
+/// here never sleeps: it burns its CPU for as long as the lock is held,
+/// and if the holder is preempted, every waiter spins until the holder is
+/// scheduled again, which may take a whole time slice or, with realtime
+/// priorities on one core, forever. Only use it when the critical section
+/// is tiny and can't block, the holder can't be descheduled meanwhile,
+/// and waiting threads run on other cores. It has none of the extras of
+/// [Mutex](crate::mutex::Mutex): no poisoning, fairness, or debugging
+/// features.
+///
+/// It doesn't use the OS at all, so it works the same without `std`.
+///
+/// ```
+/// use xlock::spin::SpinMutex;
+///
+/// static TICKS: SpinMutex<u64> = SpinMutex::new(0);
+///
+/// *TICKS.lock() += 1;
+/// assert_eq!(*TICKS.lock(), 1);
+/// ```
+pub struct SpinMutex<T: ?Sized> {
+    locked: AtomicBool,
+    /// Last, so `T` may be unsized.
+    value: UnsafeCell<T>,
+}
+
+/// SAFETY: Only one guard at a time reaches the value.
+unsafe impl<T: ?Sized> Sync for SpinMutex<T> where T: Send {}
+
+/// SAFETY: The mutex owns its value.
+unsafe impl<T: ?Sized> Send for SpinMutex<T> where T: Send {}
+
+/// A guard that represents exclusive access to the value of a
+/// [SpinMutex]. `Sync` and `Send` like a
+/// [MutexGuard](crate::mutex::MutexGuard), including the `send_guard`
+/// feature.
+pub struct SpinMutexGuard<'a, T: ?Sized> {
+    mutex: &'a SpinMutex<T>,
+    /// Makes the guard `!Send`, as MutexGuard is without `send_guard`.
+    _marker: PhantomData<*const ()>,
+}
+
+/// SAFETY: Sharing the guard only shares `&T`.
+unsafe impl<T: ?Sized> Sync for SpinMutexGuard<'_, T> where T: Sync {}
+
+/// SAFETY: Unlocking is a single store, valid from any thread, and moving
+/// the guard moves `&mut T` along with it.
+#[cfg(feature = "send_guard")]
+unsafe impl<T: ?Sized> Send for SpinMutexGuard<'_, T> where T: Send {}
+
+impl<T> SpinMutex<T> {
+    /// Create a new unlocked spin mutex guarding `value`.
+    pub const fn new(value: T) -> Self {
+        Self {
+            locked: AtomicBool::new(false),
+            value: UnsafeCell::new(value),
+        }
+    }
+
+    /// Consume the mutex and return the protected value, without locking.
+    pub fn into_inner(self) -> T {
+        self.value.into_inner()
+    }
+}
+
+impl<T: ?Sized> SpinMutex<T> {
+    /// Lock the mutex, spinning until it is free.
+    #[inline]
+    pub fn lock(&self) -> SpinMutexGuard<'_, T> {
+        if let Some(guard) = self.try_lock() {
+            return guard;
+        }
+        self.lock_contended()
+    }
+
+    #[cold]
+    fn lock_contended(&self) -> SpinMutexGuard<'_, T> {
+        let mut spins = 1;
+        loop {
+            // Only try to take it once it looks free, so waiters spin on
+            // a shared cache line instead of fighting over it.
+            while self.locked.load(Ordering::Relaxed) {
+                for _ in 0..spins {
+                    std::hint::spin_loop();
+                }
+                spins = (spins * 2).min(MAX_SPINS);
+            }
+            if let Some(guard) = self.try_lock() {
+                return guard;
+            }
+        }
+    }
+
+    /// Lock the mutex only if it is free right now.
+    #[inline]
+    pub fn try_lock(&self) -> Option<SpinMutexGuard<'_, T>> {
+        self.locked
+            .compare_exchange(false, true, Ordering::Acquire, Ordering::Relaxed)
+            .ok()?;
+        Some(SpinMutexGuard {
+            mutex: self,
+            _marker: PhantomData,
+        })
+    }
+
+    /// Whether the mutex is locked right now. Only a snapshot.
+    pub fn is_locked(&self) -> bool {
+        self.locked.load(Ordering::Relaxed)
+    }
+
+    /// Borrow the protected value mutably, without locking: the exclusive
+    /// borrow already guarantees no guard exists.
+    pub fn get_mut(&mut self) -> &mut T {
+        self.value.get_mut()
+    }
+}
+
+impl<T: ?Sized> Drop for SpinMutexGuard<'_, T> {
+    #[inline]
+    fn drop(&mut self) {
+        self.mutex.locked.store(false, Ordering::Release);
+    }
+}
+
+impl<T: ?Sized> Deref for SpinMutexGuard<'_, T> {
+    type Target = T;
+    fn deref(&self) -> &T {
+        // SAFETY: The guard holds the lock.
+        unsafe { &*self.mutex.value.get() }
+    }
+}
+
+impl<T: ?Sized> DerefMut for SpinMutexGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        // SAFETY: The guard holds the lock.
+        unsafe { &mut *self.mutex.value.get() }
+    }
+}
+
+impl<T: ?Sized + fmt::Debug> fmt::Debug for SpinMutex<T> {
+    /// Shows the value if the mutex is free, without waiting for it.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut d = f.debug_struct("SpinMutex");
+        match self.try_lock() {
+            Some(guard) => d.field("data", &&*guard),
+            None => d.field("data", &format_args!("<locked>")),
+        };
+        d.finish()
+    }
+}
+
+impl<T: ?Sized + fmt::Debug> fmt::Debug for SpinMutexGuard<'_, T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(&**self, f)
+    }
+}
+
+impl<T: Default> Default for SpinMutex<T> {
+    fn default() -> Self {
+        Self::new(T::default())
+    }
+}
+
+impl<T> From<T> for SpinMutex<T> {
+    fn from(value: T) -> Self {
+        Self::new(value)
+    }
+}
+
+#[cfg(all(test, feature = "std"))]
+mod test {
+    use super::*;
+
+    #[test]
+    fn spin_mutex_multi_threads() {
+        let m = SpinMutex::new(0);
+        std::hint::black_box(&m);
+        std::thread::scope(|s| {
+            for _ in 0..4 {
+                s.spawn(|| {
+                    for _ in 0..100 {
+                        *m.lock() += 1;
+                    }
+                });
+            }
+        });
+        assert_eq!(*m.lock(), 400);
+    }
+
+    #[test]
+    fn try_lock_fails_while_held() {
+        let m = SpinMutex::new(vec![1]);
+        let mut guard = m.try_lock().unwrap();
+        assert!(m.is_locked());
+        assert!(m.try_lock().is_none());
+        std::thread::scope(|s| {
+            s.spawn(|| assert!(m.try_lock().is_none()));
+        });
+        guard.push(2);
+        drop(guard);
+        assert_eq!(*m.try_lock().unwrap(), [1, 2]);
+        assert_eq!(format!("{m:?}"), "SpinMutex { data: [1, 2] }");
+    }
+}
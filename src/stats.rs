@@ -0,0 +1,282 @@
+//! Contention statistics, with the `stats` feature.
+//!
+//! Every [Mutex](crate::mutex::Mutex) and
+//! [Semaphore](crate::semaphore::Semaphore) counts its acquisitions,
+//! releases and failed waits, and how long they waited and held, readable
+//! with `stats()` and cleared with `reset_stats()`. Wait times are only measured when a thread actually
+//! parks; hold times cost a clock read per acquisition and release.
+//!
+//! ```
+//! use xlock::mutex::Mutex;
+//!
+//! let m = Mutex::new(0);
+//! *m.lock() += 1;
+//! let stats = m.stats();
+//! assert_eq!(stats.acquisitions, 1);
+//! assert_eq!(stats.parked, 0);
+//! ```
+
+use crate::semaphore::AcquireError;
+use std::sync::atomic::{AtomicI64, AtomicU64, Ordering};
+use std::sync::OnceLock;
+use std::time::{Duration, Instant};
+
+/// A snapshot of a lock's counters since it was created or last reset.
+///
+/// The counts are `u64`, which can't wrap in practice: at a billion a
+/// second that would take over 500 years.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct LockStats {
+    /// Successful acquisitions, blocking or not.
+    pub acquisitions: u64,
+    /// Releases, counting permits used up or forgotten as released.
+    pub releases: u64,
+    /// Waits that gave up at their timeout or deadline.
+    pub timed_out: u64,
+    /// Waits that gave up because their token was cancelled.
+    pub cancelled: u64,
+    /// Acquisitions that had to park the thread before succeeding.
+    pub parked: u64,
+    /// Time spent parked by those acquisitions, together.
+    pub total_wait: Duration,
+    /// The longest of those waits.
+    pub max_wait: Duration,
+    /// Time the lock was held, summed over every access. Accesses still
+    /// held count up to now.
+    pub total_hold: Duration,
+}
+
+/// The counters behind [LockStats], kept by each semaphore.
+pub(crate) struct Stats {
+    acquisitions: AtomicU64,
+    releases: AtomicU64,
+    timed_out: AtomicU64,
+    cancelled: AtomicU64,
+    parked: AtomicU64,
+    wait_ns: AtomicU64,
+    max_wait_ns: AtomicU64,
+    /// Accesses held right now.
+    held: AtomicI64,
+    /// Release times minus acquisition times, in nanoseconds since
+    /// [epoch], summed per access. Adding `held` times the current time
+    /// gives the total hold time without storing when each access began.
+    /// The terms can overflow for large counts, so all of this wraps:
+    /// they cancel out to the total, which fits.
+    hold_ns: AtomicI64,
+}
+
+/// A fixed point in time that timestamps are measured from.
+fn epoch() -> Instant {
+    static EPOCH: OnceLock<Instant> = OnceLock::new();
+    *EPOCH.get_or_init(Instant::now)
+}
+
+fn now_ns() -> i64 {
+    epoch().elapsed().as_nanos() as i64
+}
+
+impl Stats {
+    pub const fn new() -> Self {
+        Self {
+            acquisitions: AtomicU64::new(0),
+            releases: AtomicU64::new(0),
+            timed_out: AtomicU64::new(0),
+            cancelled: AtomicU64::new(0),
+            parked: AtomicU64::new(0),
+            wait_ns: AtomicU64::new(0),
+            max_wait_ns: AtomicU64::new(0),
+            held: AtomicI64::new(0),
+            hold_ns: AtomicI64::new(0),
+        }
+    }
+
+    /// Count `n` accesses taken.
+    pub fn acquired(&self, n: u32) {
+        self.acquired_at(n, now_ns());
+    }
+
+    fn acquired_at(&self, n: u32, now: i64) {
+        let n = i64::from(n);
+        self.acquisitions.fetch_add(1, Ordering::Relaxed);
+        self.held.fetch_add(n, Ordering::Relaxed);
+        self.hold_ns
+            .fetch_sub(n.wrapping_mul(now), Ordering::Relaxed);
+    }
+
+    /// Count `n` accesses given back.
+    pub fn released(&self, n: u32) {
+        self.released_at(n, now_ns());
+    }
+
+    fn released_at(&self, n: u32, now: i64) {
+        let n = i64::from(n);
+        self.releases.fetch_add(1, Ordering::Relaxed);
+        self.hold_ns
+            .fetch_add(n.wrapping_mul(now), Ordering::Relaxed);
+        self.held.fetch_sub(n, Ordering::Relaxed);
+    }
+
+    /// Count a wait that failed with `error`.
+    pub fn failed(&self, error: AcquireError) {
+        let counter = match error {
+            AcquireError::Timeout => &self.timed_out,
+            AcquireError::Cancelled => &self.cancelled,
+            _ => return,
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Count an acquisition that parked since `start`.
+    pub fn waited(&self, start: Instant) {
+        let ns = start.elapsed().as_nanos() as u64;
+        self.parked.fetch_add(1, Ordering::Relaxed);
+        self.wait_ns.fetch_add(ns, Ordering::Relaxed);
+        self.max_wait_ns.fetch_max(ns, Ordering::Relaxed);
+    }
+
+    /// Read the counters. They are updated separately, so a snapshot
+    /// taken under contention may be slightly inconsistent.
+    pub fn snapshot(&self) -> LockStats {
+        self.snapshot_at(now_ns())
+    }
+
+    fn snapshot_at(&self, now: i64) -> LockStats {
+        let held = self.held.load(Ordering::Relaxed);
+        let hold_ns = self
+            .hold_ns
+            .load(Ordering::Relaxed)
+            .wrapping_add(held.wrapping_mul(now));
+        LockStats {
+            acquisitions: self.acquisitions.load(Ordering::Relaxed),
+            releases: self.releases.load(Ordering::Relaxed),
+            timed_out: self.timed_out.load(Ordering::Relaxed),
+            cancelled: self.cancelled.load(Ordering::Relaxed),
+            parked: self.parked.load(Ordering::Relaxed),
+            total_wait: Duration::from_nanos(self.wait_ns.load(Ordering::Relaxed)),
+            max_wait: Duration::from_nanos(self.max_wait_ns.load(Ordering::Relaxed)),
+            total_hold: Duration::from_nanos(hold_ns.max(0) as u64),
+        }
+    }
+
+    /// Zero the counters. Accesses still held count from now on.
+    pub fn reset(&self) {
+        self.acquisitions.store(0, Ordering::Relaxed);
+        self.releases.store(0, Ordering::Relaxed);
+        self.timed_out.store(0, Ordering::Relaxed);
+        self.cancelled.store(0, Ordering::Relaxed);
+        self.parked.store(0, Ordering::Relaxed);
+        self.wait_ns.store(0, Ordering::Relaxed);
+        self.max_wait_ns.store(0, Ordering::Relaxed);
+        let held = self.held.load(Ordering::Relaxed);
+        self.hold_ns.store(
+            held.wrapping_neg().wrapping_mul(now_ns()),
+            Ordering::Relaxed,
+        );
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::cancel::CancelHandle;
+    use crate::mutex::Mutex;
+    use crate::semaphore::Semaphore;
+    use std::sync::Barrier;
+    use std::time::Duration;
+
+    #[test]
+    fn counts_contention() {
+        const THREADS: u32 = 4;
+        let hold = Duration::from_millis(20);
+        let m = Mutex::new(0);
+        let barrier = Barrier::new(THREADS as usize);
+        std::thread::scope(|s| {
+            for _ in 0..THREADS {
+                s.spawn(|| {
+                    barrier.wait();
+                    let mut guard = m.lock();
+                    std::thread::sleep(hold);
+                    *guard += 1;
+                });
+            }
+        });
+
+        let stats = m.stats();
+        assert_eq!(stats.acquisitions, u64::from(THREADS));
+        assert!(stats.parked >= 1 && stats.parked < u64::from(THREADS));
+        assert!(stats.total_hold >= hold * THREADS);
+        assert!(stats.total_wait >= hold / 2);
+        assert!(stats.max_wait <= stats.total_wait);
+
+        m.reset_stats();
+        assert_eq!(m.stats(), Default::default());
+    }
+
+    #[test]
+    fn counts_held_permits() {
+        let sem = Semaphore::new(3);
+        let permit = sem.acquire_many(2).unwrap();
+        std::thread::sleep(Duration::from_millis(5));
+        let stats = sem.stats();
+        assert_eq!(stats.acquisitions, 1);
+        // Both permits count while still held.
+        assert!(stats.total_hold >= Duration::from_millis(10));
+        drop(permit);
+        let total = sem.stats().total_hold;
+        std::thread::sleep(Duration::from_millis(5));
+        assert_eq!(sem.stats().total_hold, total);
+    }
+
+    #[test]
+    fn counts_match_a_known_workload() {
+        let sem = Semaphore::new(2);
+        for _ in 0..10 {
+            drop(sem.acquire().unwrap());
+        }
+        let held = sem.acquire_many(2).unwrap();
+        for _ in 0..3 {
+            let timeout = Duration::from_millis(1);
+            let err = sem.acquire_timeout(timeout).unwrap_err();
+            assert_eq!(err, AcquireError::Timeout);
+        }
+        let shutdown = CancelHandle::new();
+        let token = shutdown.token();
+        std::thread::scope(|s| {
+            let waiter = s.spawn(|| sem.acquire_cancellable(&token).map(drop));
+            while sem.waiters() == 0 {
+                std::thread::yield_now();
+            }
+            shutdown.cancel();
+            assert_eq!(waiter.join().unwrap(), Err(AcquireError::Cancelled));
+        });
+        // Already cancelled: fails without waiting, but still counts.
+        assert!(sem.acquire_cancellable(&token).is_err());
+        // Failed tries aren't waits.
+        assert!(sem.try_acquire().is_err());
+        drop(held);
+        sem.acquire().unwrap().consume();
+        sem.release(1);
+
+        let stats = sem.stats();
+        assert_eq!(stats.acquisitions, 12);
+        assert_eq!(stats.releases, 12);
+        assert_eq!(stats.timed_out, 3);
+        assert_eq!(stats.cancelled, 2);
+    }
+
+    #[test]
+    fn large_counts_held_long_dont_overflow() {
+        let stats = Stats::new();
+        let n = crate::semaphore::Semaphore::MAX_PERMITS;
+        // Nanosecond timestamps from about ten seconds in on.
+        let start = 10_000_000_000;
+        stats.acquired_at(n, start);
+        let held = stats.snapshot_at(start + 2_000).total_hold;
+        assert_eq!(held, Duration::from_nanos(u64::from(n) * 2_000));
+        stats.released_at(n, start + 3_000);
+        stats.acquired_at(n, start + 5_000);
+        stats.released_at(n, start + 6_000);
+        let total = stats.snapshot_at(start + 9_000).total_hold;
+        assert_eq!(total, Duration::from_nanos(u64::from(n) * 4_000));
+    }
+}
@@ -0,0 +1,494 @@
+//! Platform layer for waiting on and waking an [AtomicU32].
+//!
+//! Untimed waits and wakes come from `atomic_wait`, except on macOS where
+//! its libc++ based implementation can't time out, so every operation goes
+//! through `__ulock_wait`/`__ulock_wake` instead to keep waiters and wakers
+//! on the same mechanism.
+//!
+//! Other targets, or any target built with `--cfg xlock_parking`, fall
+//! back to parking threads with [std::thread::park] and a table of
+//! waiters keyed by address. That needs no OS support beyond threads;
+//! single-threaded wasm never contends, so it never parks at all.
+//!
+//! Without the `std` feature there is no OS to park threads with: waits
+//! spin until the value changes, backing off exponentially, and wakes do
+//! nothing. There is no clock either, so nothing waits with a deadline.
+//!
+//! The lock implementations take their atomics from [atomic] rather than
+//! `std` directly, so there is a single place to swap them out, e.g. for a
+//! model checker.
+//!
+//! Loom is not wired up yet: there is no `cfg(loom)` branch here, no park
+//! shim for it, and no loom tests. That waits on the `loom` crate being
+//! available as a dev-dependency.
+
+use self::atomic::AtomicU32;
+#[cfg(feature = "std")]
+pub(crate) use std::time::Instant;
+
+/// The atomic types and orderings the locks are built on.
+pub(crate) mod atomic {
+    #[cfg(target_has_atomic = "64")]
+    pub(crate) use std::sync::atomic::AtomicU64;
+    // Not every feature combination uses all of them.
+    #[allow(unused_imports)]
+    pub(crate) use std::sync::atomic::{
+        fence, AtomicBool, AtomicPtr, AtomicU32, AtomicUsize, Ordering,
+    };
+}
+
+#[cfg(all(
+    feature = "std",
+    not(xlock_parking),
+    any(
+        target_os = "linux",
+        target_os = "android",
+        target_os = "freebsd",
+        windows
+    )
+))]
+pub(crate) use atomic_wait::{wait, wake_all, wake_one};
+
+#[cfg(all(feature = "std", not(xlock_parking), target_vendor = "apple"))]
+pub(crate) use self::apple::{wait, wake_all, wake_one};
+
+#[cfg(all(
+    feature = "std",
+    any(
+        xlock_parking,
+        not(any(
+            target_os = "linux",
+            target_os = "android",
+            target_os = "freebsd",
+            windows,
+            target_vendor = "apple"
+        ))
+    )
+))]
+pub(crate) use self::parking::{wait, wake_all, wake_one};
+
+#[cfg(all(
+    feature = "std",
+    any(
+        xlock_parking,
+        not(any(
+            target_os = "linux",
+            target_os = "android",
+            target_os = "freebsd",
+            windows,
+            target_vendor = "apple"
+        ))
+    )
+))]
+use self::parking as platform;
+
+#[cfg(not(feature = "std"))]
+pub(crate) use self::spin::{wait, wake_all, wake_one};
+
+/// Stands in for `std::time::Instant` without `std`. It has no values,
+/// so an `Option<Instant>` deadline is always `None`.
+#[cfg(not(feature = "std"))]
+#[derive(Clone, Copy)]
+pub(crate) enum Instant {}
+
+/// Without a clock there are no deadlines to wait for.
+#[cfg(not(feature = "std"))]
+pub(crate) fn wait_until(_: &AtomicU32, _: u32, deadline: Instant) -> bool {
+    match deadline {}
+}
+
+/// If the value is `value`, wait until woken up or until `deadline`.
+///
+/// Like [wait], this can return spuriously; callers re-check their
+/// condition and the deadline in a loop. Returns `false` once the
+/// deadline has passed.
+#[cfg(feature = "std")]
+pub(crate) fn wait_until(atomic: &AtomicU32, value: u32, deadline: Instant) -> bool {
+    let now = Instant::now();
+    if now >= deadline {
+        return false;
+    }
+    platform::wait_timeout(atomic, value, deadline - now);
+    true
+}
+
+#[cfg(all(
+    feature = "std",
+    not(xlock_parking),
+    any(target_os = "linux", target_os = "android")
+))]
+mod platform {
+    use super::atomic::AtomicU32;
+    use std::time::Duration;
+
+    pub fn wait_timeout(atomic: &AtomicU32, value: u32, timeout: Duration) {
+        let timeout = libc::timespec {
+            tv_sec: timeout.as_secs().try_into().unwrap_or(libc::time_t::MAX),
+            tv_nsec: timeout.subsec_nanos() as _,
+        };
+        unsafe {
+            libc::syscall(
+                libc::SYS_futex,
+                atomic,
+                libc::FUTEX_WAIT | libc::FUTEX_PRIVATE_FLAG,
+                value,
+                &timeout,
+            );
+        }
+    }
+}
+
+/// Waits and wakes that work across processes, for atomics in memory
+/// shared between them. The private futex operations used everywhere else
+/// only match waiters and wakers in the same address space.
+#[cfg(all(feature = "std", any(target_os = "linux", target_os = "android")))]
+pub(crate) mod shared {
+    use super::atomic::AtomicU32;
+
+    pub fn wait(atomic: &AtomicU32, value: u32) {
+        unsafe {
+            libc::syscall(
+                libc::SYS_futex,
+                atomic,
+                libc::FUTEX_WAIT,
+                value,
+                std::ptr::null::<libc::timespec>(),
+            );
+        }
+    }
+
+    pub fn wake_one(atomic: &AtomicU32) {
+        unsafe {
+            libc::syscall(libc::SYS_futex, atomic, libc::FUTEX_WAKE, 1);
+        }
+    }
+}
+
+#[cfg(all(feature = "std", not(xlock_parking), target_os = "freebsd"))]
+mod platform {
+    use super::atomic::AtomicU32;
+    use std::time::Duration;
+
+    pub fn wait_timeout(atomic: &AtomicU32, value: u32, timeout: Duration) {
+        let mut timeout = libc::timespec {
+            tv_sec: timeout.as_secs().try_into().unwrap_or(libc::time_t::MAX),
+            tv_nsec: timeout.subsec_nanos() as _,
+        };
+        let ptr: *const AtomicU32 = atomic;
+        // With a timespec size in `uaddr`, `uaddr2` is a relative timeout.
+        unsafe {
+            libc::_umtx_op(
+                ptr as *mut libc::c_void,
+                libc::UMTX_OP_WAIT_UINT_PRIVATE,
+                value as libc::c_ulong,
+                std::mem::size_of::<libc::timespec>() as *mut libc::c_void,
+                &mut timeout as *mut libc::timespec as *mut libc::c_void,
+            );
+        }
+    }
+}
+
+#[cfg(all(feature = "std", not(xlock_parking), windows))]
+mod platform {
+    use super::atomic::AtomicU32;
+    use std::time::Duration;
+    use windows_sys::Win32::System::Threading::WaitOnAddress;
+
+    pub fn wait_timeout(atomic: &AtomicU32, value: u32, timeout: Duration) {
+        // Round up so a short timeout doesn't become a busy loop, and stay
+        // below INFINITE (u32::MAX).
+        let millis = timeout
+            .as_nanos()
+            .div_ceil(1_000_000)
+            .min(u32::MAX as u128 - 1) as u32;
+        let ptr: *const AtomicU32 = atomic;
+        let expected: *const u32 = &value;
+        unsafe { WaitOnAddress(ptr.cast(), expected.cast(), 4, millis) };
+    }
+}
+
+#[cfg(all(feature = "std", not(xlock_parking), target_vendor = "apple"))]
+use self::apple as platform;
+
+#[cfg(all(feature = "std", not(xlock_parking), target_vendor = "apple"))]
+mod apple {
+    use super::atomic::AtomicU32;
+    use std::ffi::{c_int, c_void};
+    use std::time::Duration;
+
+    const UL_COMPARE_AND_WAIT: u32 = 1;
+    const ULF_WAKE_ALL: u32 = 0x100;
+    const ULF_NO_ERRNO: u32 = 0x0100_0000;
+
+    extern "C" {
+        fn __ulock_wait(operation: u32, addr: *mut c_void, value: u64, timeout_us: u32) -> c_int;
+        fn __ulock_wake(operation: u32, addr: *mut c_void, wake_value: u64) -> c_int;
+    }
+
+    pub fn wait(atomic: &AtomicU32, value: u32) {
+        let ptr: *const AtomicU32 = atomic;
+        // A timeout of 0 waits forever.
+        unsafe {
+            __ulock_wait(
+                UL_COMPARE_AND_WAIT | ULF_NO_ERRNO,
+                ptr as *mut c_void,
+                value as u64,
+                0,
+            )
+        };
+    }
+
+    pub fn wait_timeout(atomic: &AtomicU32, value: u32, timeout: Duration) {
+        // Round up, and never pass 0, which would mean no timeout at all.
+        let micros = timeout
+            .as_nanos()
+            .div_ceil(1_000)
+            .clamp(1, u32::MAX as u128) as u32;
+        let ptr: *const AtomicU32 = atomic;
+        unsafe {
+            __ulock_wait(
+                UL_COMPARE_AND_WAIT | ULF_NO_ERRNO,
+                ptr as *mut c_void,
+                value as u64,
+                micros,
+            )
+        };
+    }
+
+    pub fn wake_one(ptr: *const AtomicU32) {
+        unsafe { __ulock_wake(UL_COMPARE_AND_WAIT | ULF_NO_ERRNO, ptr as *mut c_void, 0) };
+    }
+
+    pub fn wake_all(ptr: *const AtomicU32) {
+        unsafe {
+            __ulock_wake(
+                UL_COMPARE_AND_WAIT | ULF_WAKE_ALL | ULF_NO_ERRNO,
+                ptr as *mut c_void,
+                0,
+            )
+        };
+    }
+}
+
+/// The portable backend: waiters park in a table keyed by the address
+/// they wait on, and wakes unpark them. Also built for tests, so it is
+/// exercised on every host.
+#[cfg(all(
+    feature = "std",
+    any(
+        test,
+        xlock_parking,
+        not(any(
+            target_os = "linux",
+            target_os = "android",
+            target_os = "freebsd",
+            windows,
+            target_vendor = "apple"
+        ))
+    )
+))]
+mod parking {
+    use super::atomic::{AtomicBool, AtomicU32, Ordering};
+    use std::sync::{Arc, Mutex, MutexGuard};
+    use std::thread::Thread;
+    use std::time::{Duration, Instant};
+
+    /// Waiters are spread over this many lists by address, so unrelated
+    /// locks rarely share one.
+    const BUCKETS: usize = 64;
+
+    struct Waiter {
+        addr: usize,
+        thread: Thread,
+        /// Set by the waker before unparking, to tell a wake from a
+        /// spurious return of `park`.
+        woken: Arc<AtomicBool>,
+    }
+
+    static WAITERS: [Mutex<Vec<Waiter>>; BUCKETS] = [const { Mutex::new(Vec::new()) }; BUCKETS];
+
+    fn bucket(addr: usize) -> MutexGuard<'static, Vec<Waiter>> {
+        // Atomics are at least 4-byte aligned, so drop the low bits.
+        WAITERS[(addr >> 2) % BUCKETS]
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+    }
+
+    pub fn wait(atomic: &AtomicU32, value: u32) {
+        park(atomic, value, None);
+    }
+
+    pub fn wait_timeout(atomic: &AtomicU32, value: u32, timeout: Duration) {
+        park(atomic, value, Some(Instant::now() + timeout));
+    }
+
+    fn park(atomic: &AtomicU32, value: u32, deadline: Option<Instant>) {
+        let addr = atomic as *const AtomicU32 as usize;
+        let woken = Arc::new(AtomicBool::new(false));
+        {
+            let mut waiters = bucket(addr);
+            // Wakers change the value before taking the bucket lock, so
+            // either this sees the change or the waker sees us below.
+            if atomic.load(Ordering::SeqCst) != value {
+                return;
+            }
+            waiters.push(Waiter {
+                addr,
+                thread: std::thread::current(),
+                woken: woken.clone(),
+            });
+        }
+        while !woken.load(Ordering::Acquire) {
+            match deadline {
+                None => std::thread::park(),
+                Some(deadline) => {
+                    let now = Instant::now();
+                    if now >= deadline {
+                        // Nobody woke us, so we're still listed.
+                        bucket(addr).retain(|w| !Arc::ptr_eq(&w.woken, &woken));
+                        return;
+                    }
+                    std::thread::park_timeout(deadline - now);
+                }
+            }
+        }
+    }
+
+    fn wake(ptr: *const AtomicU32, all: bool) {
+        let addr = ptr as usize;
+        let mut waiters = bucket(addr);
+        let mut i = 0;
+        while i < waiters.len() {
+            if waiters[i].addr != addr {
+                i += 1;
+                continue;
+            }
+            let waiter = waiters.remove(i);
+            waiter.woken.store(true, Ordering::Release);
+            waiter.thread.unpark();
+            if !all {
+                break;
+            }
+        }
+    }
+
+    pub fn wake_one(ptr: *const AtomicU32) {
+        wake(ptr, false);
+    }
+
+    pub fn wake_all(ptr: *const AtomicU32) {
+        wake(ptr, true);
+    }
+}
+
+/// The spinning backend used without `std`. Also built for tests, so it
+/// can be exercised on the host.
+#[cfg(any(not(feature = "std"), test))]
+mod spin {
+    use super::atomic::{AtomicU32, Ordering};
+
+    /// Spins double each round up to this many, after which [wait]
+    /// returns so the caller re-checks its condition.
+    const MAX_SPINS: u32 = 1 << 10;
+
+    /// Spin while the value is `value`, for a bounded time. Like a futex
+    /// wait this may return before the value changes.
+    pub fn wait(atomic: &AtomicU32, value: u32) {
+        let mut spins = 1;
+        while atomic.load(Ordering::Relaxed) == value && spins <= MAX_SPINS {
+            for _ in 0..spins {
+                std::hint::spin_loop();
+            }
+            spins *= 2;
+        }
+    }
+
+    /// Nothing to do: spinning waiters see the change themselves.
+    pub fn wake_one(_: *const AtomicU32) {}
+
+    /// Nothing to do: spinning waiters see the change themselves.
+    pub fn wake_all(_: *const AtomicU32) {}
+}
+
+#[cfg(all(test, feature = "std"))]
+mod test {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn wait_until_times_out() {
+        let atomic = AtomicU32::new(0);
+        let deadline = Instant::now() + Duration::from_millis(50);
+        while wait_until(&atomic, 0, deadline) {}
+        assert!(Instant::now() >= deadline);
+    }
+
+    #[test]
+    fn wait_until_returns_on_mismatch() {
+        let atomic = AtomicU32::new(1);
+        let start = Instant::now();
+        assert!(wait_until(&atomic, 0, start + Duration::from_secs(60)));
+        assert!(start.elapsed() < Duration::from_secs(30));
+    }
+
+    #[test]
+    fn spin_wait_returns_on_mismatch_or_after_backoff() {
+        let atomic = AtomicU32::new(1);
+        spin::wait(&atomic, 0);
+        // Nobody changes the value, but the wait still gives up.
+        spin::wait(&atomic, 1);
+    }
+
+    #[test]
+    fn spin_wait_sees_change_without_wake() {
+        let atomic = AtomicU32::new(0);
+        std::thread::scope(|s| {
+            s.spawn(|| {
+                while atomic.load(atomic::Ordering::SeqCst) == 0 {
+                    spin::wait(&atomic, 0);
+                }
+            });
+            std::thread::sleep(Duration::from_millis(10));
+            atomic.store(1, atomic::Ordering::SeqCst);
+            spin::wake_one(&atomic);
+            spin::wake_all(&atomic);
+        });
+    }
+
+    #[test]
+    fn parking_wakes_waiters_on_the_same_address() {
+        let (a, b) = (AtomicU32::new(0), AtomicU32::new(0));
+        std::thread::scope(|s| {
+            let waiters: Vec<_> = [&a, &a, &b]
+                .into_iter()
+                .map(|atomic| {
+                    s.spawn(move || {
+                        while atomic.load(atomic::Ordering::SeqCst) == 0 {
+                            parking::wait(atomic, 0);
+                        }
+                    })
+                })
+                .collect();
+            std::thread::sleep(Duration::from_millis(20));
+            a.store(1, atomic::Ordering::SeqCst);
+            parking::wake_all(&a);
+            b.store(1, atomic::Ordering::SeqCst);
+            parking::wake_one(&b);
+            for waiter in waiters {
+                waiter.join().unwrap();
+            }
+        });
+    }
+
+    #[test]
+    fn parking_wait_times_out() {
+        let atomic = AtomicU32::new(0);
+        let start = Instant::now();
+        parking::wait_timeout(&atomic, 0, Duration::from_millis(20));
+        assert!(start.elapsed() >= Duration::from_millis(20));
+        // The timed out waiter is no longer listed, so this wakes nobody.
+        parking::wake_all(&atomic);
+        // A changed value returns immediately.
+        parking::wait(&atomic, 1);
+    }
+}
@@ -0,0 +1,240 @@
+//! Traits for code generic over the crate's locks.
+//!
+//! [ReadLock] is for locks that hand out shared access to their value and
+//! [Lock] for those that also hand out exclusive access. A [Mutex] serves
+//! reads with its ordinary lock, an [RwLock] with a read lock, and a
+//! [ReentrantMutex], whose guard is shared, is only a [ReadLock].
+//!
+//! ```
+//! use xlock::mutex::Mutex;
+//! use xlock::rwlock::RwLock;
+//! use xlock::traits::Lock;
+//!
+//! fn bump<L: Lock<Target = u32>>(lock: &L) {
+//!     *lock.lock() += 1;
+//! }
+//!
+//! let (m, rw) = (Mutex::new(0), RwLock::new(0));
+//! bump(&m);
+//! bump(&rw);
+//! assert_eq!((*m.lock(), *rw.read()), (1, 1));
+//! ```
+
+use crate::fair::{FairMutex, FairMutexGuard};
+use crate::mutex::{Mutex, MutexGuard};
+use crate::reentrant::{ReentrantMutex, ReentrantMutexGuard};
+use crate::rwlock::{RwLock, RwLockReadGuard, RwLockWriteGuard};
+use std::ops::{Deref, DerefMut};
+
+/// A lock handing out shared access to a value.
+pub trait ReadLock {
+    /// The protected value.
+    type Target: ?Sized;
+
+    /// The guard for shared access, releasing it when dropped.
+    type ReadGuard<'a>: Deref<Target = Self::Target>
+    where
+        Self: 'a;
+
+    /// Gain shared access, waiting as long as needed.
+    fn read(&self) -> Self::ReadGuard<'_>;
+
+    /// Gain shared access only if that doesn't require waiting.
+    fn try_read(&self) -> Option<Self::ReadGuard<'_>>;
+}
+
+/// A lock that also hands out exclusive access to its value.
+pub trait Lock: ReadLock {
+    /// The guard for exclusive access, releasing it when dropped.
+    type Guard<'a>: DerefMut<Target = Self::Target>
+    where
+        Self: 'a;
+
+    /// Gain exclusive access, waiting as long as needed.
+    fn lock(&self) -> Self::Guard<'_>;
+
+    /// Gain exclusive access only if that doesn't require waiting.
+    fn try_lock(&self) -> Option<Self::Guard<'_>>;
+}
+
+impl<T: ?Sized> ReadLock for Mutex<T> {
+    type Target = T;
+    type ReadGuard<'a>
+        = MutexGuard<'a, T>
+    where
+        T: 'a;
+
+    fn read(&self) -> MutexGuard<'_, T> {
+        Mutex::lock(self)
+    }
+
+    fn try_read(&self) -> Option<MutexGuard<'_, T>> {
+        Mutex::try_lock(self)
+    }
+}
+
+impl<T: ?Sized> Lock for Mutex<T> {
+    type Guard<'a>
+        = MutexGuard<'a, T>
+    where
+        T: 'a;
+
+    fn lock(&self) -> MutexGuard<'_, T> {
+        Mutex::lock(self)
+    }
+
+    fn try_lock(&self) -> Option<MutexGuard<'_, T>> {
+        Mutex::try_lock(self)
+    }
+}
+
+impl<T: ?Sized> ReadLock for FairMutex<T> {
+    type Target = T;
+    type ReadGuard<'a>
+        = FairMutexGuard<'a, T>
+    where
+        T: 'a;
+
+    fn read(&self) -> FairMutexGuard<'_, T> {
+        FairMutex::lock(self)
+    }
+
+    fn try_read(&self) -> Option<FairMutexGuard<'_, T>> {
+        FairMutex::try_lock(self)
+    }
+}
+
+impl<T: ?Sized> Lock for FairMutex<T> {
+    type Guard<'a>
+        = FairMutexGuard<'a, T>
+    where
+        T: 'a;
+
+    fn lock(&self) -> FairMutexGuard<'_, T> {
+        FairMutex::lock(self)
+    }
+
+    fn try_lock(&self) -> Option<FairMutexGuard<'_, T>> {
+        FairMutex::try_lock(self)
+    }
+}
+
+impl<T: ?Sized> ReadLock for RwLock<T> {
+    type Target = T;
+    type ReadGuard<'a>
+        = RwLockReadGuard<'a, T>
+    where
+        T: 'a;
+
+    fn read(&self) -> RwLockReadGuard<'_, T> {
+        RwLock::read(self)
+    }
+
+    fn try_read(&self) -> Option<RwLockReadGuard<'_, T>> {
+        RwLock::try_read(self)
+    }
+}
+
+impl<T: ?Sized> Lock for RwLock<T> {
+    type Guard<'a>
+        = RwLockWriteGuard<'a, T>
+    where
+        T: 'a;
+
+    fn lock(&self) -> RwLockWriteGuard<'_, T> {
+        RwLock::write(self)
+    }
+
+    fn try_lock(&self) -> Option<RwLockWriteGuard<'_, T>> {
+        RwLock::try_write(self)
+    }
+}
+
+impl<T: ?Sized> ReadLock for ReentrantMutex<T> {
+    type Target = T;
+    type ReadGuard<'a>
+        = ReentrantMutexGuard<'a, T>
+    where
+        T: 'a;
+
+    fn read(&self) -> ReentrantMutexGuard<'_, T> {
+        ReentrantMutex::lock(self)
+    }
+
+    fn try_read(&self) -> Option<ReentrantMutexGuard<'_, T>> {
+        ReentrantMutex::try_lock(self)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::collections::HashMap;
+
+    /// A memoizing cache over any lock: lookups only need shared access.
+    struct Cache<L> {
+        map: L,
+    }
+
+    impl<L: Lock<Target = HashMap<u64, u64>>> Cache<L> {
+        fn get_or_insert(&self, key: u64, f: impl FnOnce(u64) -> u64) -> u64 {
+            if let Some(&value) = self.map.read().get(&key) {
+                return value;
+            }
+            *self.map.lock().entry(key).or_insert_with(|| f(key))
+        }
+
+        fn len(&self) -> usize {
+            self.map.read().len()
+        }
+    }
+
+    fn exercise<L: Lock<Target = HashMap<u64, u64>> + Sync>(cache: Cache<L>) {
+        let calls = std::sync::atomic::AtomicU32::new(0);
+        std::thread::scope(|s| {
+            for _ in 0..4 {
+                s.spawn(|| {
+                    for key in 0..20 {
+                        let value = cache.get_or_insert(key, |k| {
+                            calls.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+                            k * k
+                        });
+                        assert_eq!(value, key * key);
+                    }
+                });
+            }
+        });
+        assert_eq!(cache.len(), 20);
+        // Racing misses may each compute, but only one value is kept.
+        assert!(calls.into_inner() >= 20);
+        let guard = cache.map.try_lock().unwrap();
+        assert!(cache.map.try_lock().is_none());
+        drop(guard);
+    }
+
+    #[test]
+    fn cache_over_mutex_and_rwlock() {
+        exercise(Cache {
+            map: Mutex::new(HashMap::new()),
+        });
+        exercise(Cache {
+            map: RwLock::new(HashMap::new()),
+        });
+        exercise(Cache {
+            map: FairMutex::new(HashMap::new()),
+        });
+    }
+
+    #[test]
+    fn reentrant_reads() {
+        fn sum<L: ReadLock<Target = Vec<u32>>>(lock: &L) -> u32 {
+            lock.read().iter().sum()
+        }
+        let m = ReentrantMutex::new(vec![1, 2, 3]);
+        let outer = m.lock();
+        // Reentrant, so reading again on the same thread doesn't block.
+        assert_eq!(sum(&m), 6);
+        assert!(ReadLock::try_read(&m).is_some());
+        drop(outer);
+    }
+}
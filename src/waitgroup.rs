@@ -0,0 +1,125 @@
+use crate::sys::atomic::{AtomicU32, Ordering};
+use crate::sys::{wait, wake_all};
+use std::sync::Arc;
+
+/// Waits for a group of tasks to finish, without joining threads.
+///
+/// Every handle counts as one unfinished task, as does every [WaitGroup::add]
+/// not yet matched by a [WaitGroup::done]. [WaitGroup::wait] gives up its
+/// own handle and returns once the count reaches zero. Since a clone can
+/// only be made from a live handle, the count can't be zero while one is
+/// being made.
+pub struct WaitGroup {
+    count: Arc<AtomicU32>,
+}
+
+impl WaitGroup {
+    /// Create a new group, counting this handle.
+    pub fn new() -> Self {
+        Self {
+            count: Arc::new(AtomicU32::new(1)),
+        }
+    }
+
+    /// Count `n` more tasks, each finished by a call to [WaitGroup::done].
+    pub fn add(&self, n: u32) {
+        self.count
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |count| {
+                count.checked_add(n)
+            })
+            .expect("too many tasks");
+    }
+
+    /// Mark one task counted by [WaitGroup::add] as finished.
+    pub fn done(&self) {
+        finish(&self.count);
+    }
+
+    /// Drop this handle and wait until every other handle is dropped and
+    /// every added task is done. Returns at once if none are left.
+    pub fn wait(self) {
+        let count = Arc::clone(&self.count);
+        drop(self);
+        loop {
+            let n = count.load(Ordering::Acquire);
+            if n == 0 {
+                return;
+            }
+            wait(&count, n);
+        }
+    }
+}
+
+/// Count one task as finished, waking the waiters if it was the last.
+fn finish(count: &AtomicU32) {
+    // Release, so the waiter sees everything the tasks did.
+    let old = count.fetch_sub(1, Ordering::Release);
+    assert!(old != 0, "more tasks done than added");
+    if old == 1 {
+        wake_all(count);
+    }
+}
+
+impl Clone for WaitGroup {
+    fn clone(&self) -> Self {
+        self.add(1);
+        Self {
+            count: Arc::clone(&self.count),
+        }
+    }
+}
+
+impl Drop for WaitGroup {
+    fn drop(&mut self) {
+        finish(&self.count);
+    }
+}
+
+impl Default for WaitGroup {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::sync::atomic::AtomicUsize;
+
+    #[test]
+    fn waits_for_all_tasks() {
+        let tasks = if cfg!(miri) { 10 } else { 100 };
+        let finished = Arc::new(AtomicUsize::new(0));
+        let wg = WaitGroup::new();
+        for _ in 0..tasks {
+            let (wg, finished) = (wg.clone(), Arc::clone(&finished));
+            std::thread::spawn(move || {
+                finished.fetch_add(1, Ordering::Relaxed);
+                drop(wg);
+            });
+        }
+        wg.wait();
+        assert_eq!(finished.load(Ordering::Relaxed), tasks);
+    }
+
+    #[test]
+    fn wait_races_last_done() {
+        let rounds = if cfg!(miri) { 5 } else { 200 };
+        for _ in 0..rounds {
+            let wg = WaitGroup::new();
+            wg.add(1);
+            let done = wg.clone();
+            let handle = std::thread::spawn(move || {
+                done.done();
+                drop(done);
+            });
+            wg.wait();
+            handle.join().unwrap();
+        }
+    }
+
+    #[test]
+    fn wait_with_nothing_left_returns() {
+        WaitGroup::new().wait();
+    }
+}
@@ -0,0 +1,177 @@
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::Mutex;
+use std::task::Waker;
+
+/// The tasks waiting on a semaphore, woken by its releases the way
+/// parked threads are.
+pub(crate) struct WakerQueue {
+    /// Number of queued tasks, so releases skip the lock when there are
+    /// none. Changed with the lock held, read without it.
+    len: AtomicU32,
+    queue: Mutex<Queue>,
+}
+
+struct Queue {
+    next_key: u64,
+    /// Number of queued tasks waiting for more than one access. While
+    /// nonzero, waking one task could pick one that still can't proceed,
+    /// so everyone is woken instead.
+    many: u32,
+    tasks: VecDeque<Task>,
+}
+
+struct Task {
+    key: u64,
+    many: bool,
+    waker: Waker,
+}
+
+impl WakerQueue {
+    pub const fn new() -> Self {
+        Self {
+            len: AtomicU32::new(0),
+            queue: Mutex::new(Queue {
+                next_key: 0,
+                many: 0,
+                tasks: VecDeque::new(),
+            }),
+        }
+    }
+
+    /// Whether no task is queued. Only a snapshot.
+    pub fn is_empty(&self) -> bool {
+        self.len.load(Ordering::SeqCst) == 0
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, Queue> {
+        self.queue.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Queue a task waiting for `n` accesses, or update its waker if it is
+    /// still queued under `key`. A task that was woken is queued again at
+    /// the back.
+    pub fn register(&self, key: &mut Option<u64>, n: u32, waker: &Waker) {
+        let mut queue = self.lock();
+        if let Some(k) = *key {
+            if let Some(task) = queue.tasks.iter_mut().find(|t| t.key == k) {
+                task.waker.clone_from(waker);
+                return;
+            }
+        }
+        let k = queue.next_key;
+        queue.next_key += 1;
+        queue.many += u32::from(n > 1);
+        queue.tasks.push_back(Task {
+            key: k,
+            many: n > 1,
+            waker: waker.clone(),
+        });
+        self.len.fetch_add(1, Ordering::SeqCst);
+        *key = Some(k);
+    }
+
+    /// Take a task out of the queue. Returns false if it had already been
+    /// woken and removed.
+    pub fn remove(&self, key: u64) -> bool {
+        let mut queue = self.lock();
+        match queue.tasks.iter().position(|t| t.key == key) {
+            Some(i) => {
+                let task = queue.tasks.remove(i).unwrap();
+                queue.many -= u32::from(task.many);
+                self.len.fetch_sub(1, Ordering::SeqCst);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Wake the first queued task, or all of them if `all` is set or any
+    /// of them wants more than one access.
+    pub fn wake(&self, all: bool) {
+        if self.len.load(Ordering::SeqCst) == 0 {
+            return;
+        }
+        let mut queue = self.lock();
+        if all || queue.many > 0 {
+            let tasks = std::mem::take(&mut queue.tasks);
+            queue.many = 0;
+            self.len.store(0, Ordering::SeqCst);
+            drop(queue);
+            for task in tasks {
+                task.waker.wake();
+            }
+        } else if let Some(task) = queue.tasks.pop_front() {
+            self.len.fetch_sub(1, Ordering::SeqCst);
+            drop(queue);
+            task.waker.wake();
+        }
+    }
+}
+
+/// Runs a future to completion on the current thread, for tests.
+#[cfg(test)]
+pub(crate) fn block_on<F: std::future::Future>(future: F) -> F::Output {
+    use std::sync::Arc;
+    use std::task::{Context, Poll, Wake};
+    use std::thread::Thread;
+
+    struct Unpark(Thread);
+
+    impl Wake for Unpark {
+        fn wake(self: Arc<Self>) {
+            self.0.unpark();
+        }
+    }
+
+    let mut future = std::pin::pin!(future);
+    let waker = Waker::from(Arc::new(Unpark(std::thread::current())));
+    let mut cx = Context::from_waker(&waker);
+    loop {
+        match future.as_mut().poll(&mut cx) {
+            Poll::Ready(output) => return output,
+            Poll::Pending => std::thread::park(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::sync::atomic::AtomicBool;
+    use std::sync::Arc;
+    use std::task::Wake;
+
+    struct Flag(AtomicBool);
+
+    impl Wake for Flag {
+        fn wake(self: Arc<Self>) {
+            self.0.store(true, Ordering::SeqCst);
+        }
+    }
+
+    fn flag() -> (Arc<Flag>, Waker) {
+        let flag = Arc::new(Flag(AtomicBool::new(false)));
+        (flag.clone(), Waker::from(flag))
+    }
+
+    #[test]
+    fn wakes_in_order_unless_many() {
+        let queue = WakerQueue::new();
+        let (a, wa) = flag();
+        let (b, wb) = flag();
+        let (mut ka, mut kb) = (None, None);
+        queue.register(&mut ka, 1, &wa);
+        queue.register(&mut kb, 1, &wb);
+        queue.wake(false);
+        assert!(a.0.load(Ordering::SeqCst) && !b.0.load(Ordering::SeqCst));
+        assert!(!queue.remove(ka.unwrap()));
+
+        let (c, wc) = flag();
+        let mut kc = None;
+        queue.register(&mut kc, 2, &wc);
+        queue.wake(false);
+        assert!(b.0.load(Ordering::SeqCst) && c.0.load(Ordering::SeqCst));
+        assert!(!queue.remove(kb.unwrap()));
+    }
+}
@@ -0,0 +1,72 @@
+//! The locks that remain without the `std` feature. Run against the
+//! spinning backend with
+//!
+//! ```sh
+//! cargo test --no-default-features --test no_std
+//! ```
+//!
+//! The library is then built `#![no_std]`; only this test uses `std`, for
+//! threads. With default features the same tests run on the futex.
+
+use xlock::fair::FairMutex;
+use xlock::mutex::Mutex;
+use xlock::semaphore::Semaphore;
+use xlock::seqlock::SeqLock;
+
+const THREADS: usize = 4;
+const ITERATIONS: usize = if cfg!(miri) { 10 } else { 1_000 };
+
+#[test]
+fn mutex_excludes() {
+    static COUNTER: Mutex<usize> = Mutex::new(0);
+    std::thread::scope(|s| {
+        for _ in 0..THREADS {
+            s.spawn(|| {
+                for _ in 0..ITERATIONS {
+                    *COUNTER.lock() += 1;
+                }
+            });
+        }
+    });
+    assert_eq!(*COUNTER.lock(), THREADS * ITERATIONS);
+}
+
+#[test]
+fn semaphore_limits_permits() {
+    let sem = Semaphore::new(2);
+    let active = Mutex::new(0);
+    std::thread::scope(|s| {
+        for _ in 0..THREADS {
+            s.spawn(|| {
+                for _ in 0..ITERATIONS / 10 {
+                    let _permit = sem.acquire().unwrap();
+                    *active.lock() += 1;
+                    assert!(*active.lock() <= 2);
+                    std::thread::yield_now();
+                    *active.lock() -= 1;
+                }
+            });
+        }
+    });
+    assert_eq!(sem.available_permits(), 2);
+}
+
+#[test]
+fn fair_mutex_and_seqlock() {
+    let fair = FairMutex::new(0);
+    let seq = SeqLock::new((0u64, 0u64));
+    std::thread::scope(|s| {
+        for _ in 0..THREADS {
+            s.spawn(|| {
+                for _ in 0..ITERATIONS {
+                    let mut n = fair.lock();
+                    *n += 1;
+                    seq.write((*n, *n));
+                    let (a, b) = seq.read();
+                    assert_eq!(a, b);
+                }
+            });
+        }
+    });
+    assert_eq!(fair.into_inner(), (THREADS * ITERATIONS) as u64);
+}
@@ -0,0 +1,85 @@
+//! Mutual exclusion between processes: a parent and a forked child
+//! increment a counter in shared memory under a [RawSharedMutex] there.
+
+#![cfg(all(feature = "std", any(target_os = "linux", target_os = "android")))]
+
+use std::ptr;
+use xlock::process::{RawSharedMutex, RawSharedSemaphore};
+
+/// What the parent and child map: the lock, a turn signal and the
+/// counter they both increment.
+#[repr(C)]
+struct Shared {
+    mutex: RawSharedMutex,
+    child_ready: RawSharedSemaphore,
+    count: u64,
+}
+
+const INCREMENTS: u64 = 100_000;
+
+/// Increment the shared counter `INCREMENTS` times, a non-atomic read and
+/// write each time, with the mutex held.
+fn increment(shared: *mut Shared) {
+    // SAFETY: The parent initialized the mutex before forking.
+    let mutex = unsafe { RawSharedMutex::from_raw(ptr::addr_of_mut!((*shared).mutex)) };
+    for _ in 0..INCREMENTS {
+        mutex.lock();
+        // SAFETY: The mutex is held. Volatile, so each increment really
+        // reads and writes the shared memory.
+        unsafe {
+            let count = ptr::addr_of_mut!((*shared).count);
+            count.write_volatile(count.read_volatile() + 1);
+        }
+        // SAFETY: Locked just above.
+        unsafe { mutex.unlock() };
+    }
+}
+
+#[test]
+#[cfg_attr(miri, ignore)]
+fn parent_and_child_exclude_each_other() {
+    let size = std::mem::size_of::<Shared>();
+    // SAFETY: A fresh anonymous mapping, shared with children.
+    let map = unsafe {
+        libc::mmap(
+            ptr::null_mut(),
+            size,
+            libc::PROT_READ | libc::PROT_WRITE,
+            libc::MAP_SHARED | libc::MAP_ANONYMOUS,
+            -1,
+            0,
+        )
+    };
+    assert_ne!(map, libc::MAP_FAILED);
+    let shared = map.cast::<Shared>();
+    // SAFETY: The mapping is page aligned, large enough and not yet used.
+    unsafe {
+        RawSharedMutex::init_at(ptr::addr_of_mut!((*shared).mutex));
+        RawSharedSemaphore::init_at(ptr::addr_of_mut!((*shared).child_ready), 0);
+        ptr::addr_of_mut!((*shared).count).write(0);
+    }
+
+    // SAFETY: The child only runs async-signal-safe code: the lock, plain
+    // memory accesses and _exit.
+    let pid = unsafe { libc::fork() };
+    assert!(pid >= 0, "fork failed");
+    if pid == 0 {
+        // SAFETY: Initialized before forking.
+        unsafe { RawSharedSemaphore::from_raw(ptr::addr_of_mut!((*shared).child_ready)) }.release();
+        increment(shared);
+        unsafe { libc::_exit(0) };
+    }
+
+    // Start together, so the two processes contend for the lock.
+    unsafe { RawSharedSemaphore::from_raw(ptr::addr_of_mut!((*shared).child_ready)) }.acquire();
+    increment(shared);
+    let mut status = 0;
+    // SAFETY: `pid` is our child.
+    assert_eq!(unsafe { libc::waitpid(pid, &mut status, 0) }, pid);
+    assert!(libc::WIFEXITED(status) && libc::WEXITSTATUS(status) == 0);
+
+    // SAFETY: The child has exited, so nothing else touches the memory.
+    let count = unsafe { ptr::addr_of!((*shared).count).read_volatile() };
+    assert_eq!(count, 2 * INCREMENTS);
+    unsafe { libc::munmap(map, size) };
+}
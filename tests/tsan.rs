@@ -0,0 +1,58 @@
+//! Workloads meant to be run under ThreadSanitizer. They hammer the
+//! locks from many threads in tight loops, so any missing happens-before
+//! edge between an unlock and the next lock is reported as a data race.
+//! The tests only touch shared data through the locks, so they should be
+//! free of races of their own.
+//!
+//! ```sh
+//! RUSTFLAGS="-Zsanitizer=thread" RUSTDOCFLAGS="-Zsanitizer=thread" \
+//!     cargo +nightly test -Zbuild-std --target x86_64-unknown-linux-gnu --test tsan
+//! ```
+//!
+//! They also run as ordinary tests.
+
+use xlock::mutex::Mutex;
+
+const THREADS: usize = 8;
+const ITERATIONS: usize = if cfg!(miri) { 10 } else { 10_000 };
+
+#[test]
+fn tsan_mutex_counter() {
+    let counter = Mutex::new(0usize);
+    std::thread::scope(|s| {
+        for _ in 0..THREADS {
+            s.spawn(|| {
+                for _ in 0..ITERATIONS {
+                    *counter.lock() += 1;
+                }
+            });
+        }
+    });
+    assert_eq!(*counter.lock(), THREADS * ITERATIONS);
+}
+
+#[test]
+fn tsan_mutex_vec() {
+    let items = Mutex::new(Vec::new());
+    std::thread::scope(|s| {
+        for id in 0..THREADS {
+            let items = &items;
+            s.spawn(move || {
+                for i in 0..ITERATIONS {
+                    let mut guard = items.lock();
+                    // Reallocations move the buffer, so every thread reads
+                    // and writes memory the previous holder allocated.
+                    guard.push((id, i));
+                    if guard.len() % 64 == 0 {
+                        guard.shrink_to_fit();
+                    }
+                }
+            });
+        }
+    });
+    let mut items = items.take();
+    assert_eq!(items.len(), THREADS * ITERATIONS);
+    items.sort_unstable();
+    items.dedup();
+    assert_eq!(items.len(), THREADS * ITERATIONS);
+}